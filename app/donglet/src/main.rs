@@ -11,9 +11,18 @@ extern crate stm32g0;
 
 use cortex_m_rt::entry;
 
+#[cfg(all(feature = "tickless", feature = "g031"))]
+mod sleep;
+
 #[entry]
 fn main() -> ! {
     const CYCLES_PER_MS: u32 = 16_000;
 
+    #[cfg(all(feature = "tickless", feature = "g031"))]
+    unsafe {
+        sleep::init();
+        kern::arch::set_idle_hooks(&sleep::IDLE_HOOKS);
+    }
+
     unsafe { kern::startup::start_kernel(CYCLES_PER_MS) }
 }
@@ -0,0 +1,163 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Deep sleep for tickless idle.
+//!
+//! When the kernel finds the idle task with nothing due for a while, these
+//! hooks put the G031 into Stop 1 mode, in which the core clock (and with it
+//! SysTick) stops, and arm LPTIM1 -- clocked by the LSI, which keeps running --
+//! to wake us at the next deadline. We run from HSI16, which is also what the
+//! chip wakes up on, so there's no clock tree to put back afterwards.
+//!
+//! The LSI is only good to a few percent, so the kernel's clock runs a little
+//! fast or slow while we sleep.
+
+use stm32g0::stm32g031 as device;
+
+/// Our interrupt line, which LPTIM1 shares with TIM6 and the DAC (neither of
+/// which the G031 has).
+const LPTIM1_IRQ: u32 = 17;
+
+/// Prescaling the (nominally 32 kHz) LSI by 32 gives us one count per
+/// millisecond, which is one kernel tick.
+const LPTIM_PRESC_DIV32: u32 = 0b101 << 9;
+
+/// Waits shorter than this aren't worth stopping the clocks for.
+const MIN_TICKS: u32 = 4;
+
+const LPTIM_ISR_ARRM: u32 = 1 << 1;
+const LPTIM_ISR_ARROK: u32 = 1 << 4;
+const LPTIM_IER_ARRMIE: u32 = 1 << 1;
+const LPTIM_CR_ENABLE: u32 = 1 << 0;
+const LPTIM_CR_SNGSTRT: u32 = 1 << 1;
+
+const RCC_APBENR1_PWREN: u32 = 1 << 28;
+const RCC_APBENR1_LPTIM1EN: u32 = 1 << 31;
+const RCC_CCIPR_LPTIM1SEL_LSI: u32 = 0b01 << 18;
+const RCC_CCIPR_LPTIM1SEL_MASK: u32 = 0b11 << 18;
+const RCC_CSR_LSION: u32 = 1 << 0;
+const RCC_CSR_LSIRDY: u32 = 1 << 1;
+
+const PWR_CR1_LPMS_MASK: u32 = 0b111;
+const PWR_CR1_LPMS_STOP1: u32 = 0b001;
+
+const SCB_SCR_SLEEPDEEP: u32 = 1 << 2;
+
+pub static IDLE_HOOKS: kern::arch::IdleHooks = kern::arch::IdleHooks {
+    enter: lptim_sleep,
+    exit: lptim_wake,
+};
+
+/// Turns on the LSI and LPTIM1, and selects Stop 1 as our deep sleep mode,
+/// so that `lptim_sleep` only has to arm the timer.
+pub fn init() {
+    // Safety: we're before the kernel starts, and these are ours alone.
+    let (rcc, pwr) = unsafe { (&*device::RCC::PTR, &*device::PWR::PTR) };
+
+    rcc.csr
+        .modify(|r, w| unsafe { w.bits(r.bits() | RCC_CSR_LSION) });
+    while rcc.csr.read().bits() & RCC_CSR_LSIRDY == 0 {
+        // spin.
+    }
+
+    rcc.ccipr.modify(|r, w| unsafe {
+        w.bits((r.bits() & !RCC_CCIPR_LPTIM1SEL_MASK) | RCC_CCIPR_LPTIM1SEL_LSI)
+    });
+    rcc.apbenr1.modify(|r, w| unsafe {
+        w.bits(r.bits() | RCC_APBENR1_PWREN | RCC_APBENR1_LPTIM1EN)
+    });
+    cortex_m::asm::dsb();
+
+    pwr.cr1.modify(|r, w| unsafe {
+        w.bits((r.bits() & !PWR_CR1_LPMS_MASK) | PWR_CR1_LPMS_STOP1)
+    });
+}
+
+fn lptim() -> &'static device::lptim1::RegisterBlock {
+    // Safety: LPTIM1 is only touched by these hooks, which the kernel calls
+    // from its own interrupt handlers.
+    unsafe { &*device::LPTIM1::PTR }
+}
+
+fn lptim_sleep(ticks: u32) -> bool {
+    if ticks < MIN_TICKS {
+        return false;
+    }
+
+    // Safety: we're only reading NVIC and setting our own bits in it and the
+    // SCB, from the kernel.
+    let (nvic, scb) = unsafe {
+        (
+            &*cortex_m::peripheral::NVIC::PTR,
+            &*cortex_m::peripheral::SCB::PTR,
+        )
+    };
+
+    // A task with an interrupt enabled is waiting on a peripheral, which may
+    // well stop along with the clocks; stay awake for it.
+    if nvic.iser[0].read() != 0 {
+        return false;
+    }
+
+    let lptim = lptim();
+    let counts = ticks.min(0xffff);
+
+    // The interrupt enable and configuration can only be written while the
+    // timer is disabled, and the autoreload only while it's enabled.
+    unsafe {
+        lptim.cfgr.write(|w| w.bits(LPTIM_PRESC_DIV32));
+        lptim.ier.write(|w| w.bits(LPTIM_IER_ARRMIE));
+        lptim.cr.write(|w| w.bits(LPTIM_CR_ENABLE));
+        lptim.arr.write(|w| w.bits(counts - 1));
+    }
+    while lptim.isr.read().bits() & LPTIM_ISR_ARROK == 0 {
+        // spin.
+    }
+    unsafe {
+        lptim.icr.write(|w| w.bits(LPTIM_ISR_ARROK));
+        lptim
+            .cr
+            .write(|w| w.bits(LPTIM_CR_ENABLE | LPTIM_CR_SNGSTRT));
+
+        nvic.iser[0].write(1 << LPTIM1_IRQ);
+        scb.scr.modify(|v| v | SCB_SCR_SLEEPDEEP);
+    }
+
+    true
+}
+
+fn lptim_wake() -> u32 {
+    // Safety: as in `lptim_sleep`, these are our own bits.
+    let (nvic, scb) = unsafe {
+        (
+            &*cortex_m::peripheral::NVIC::PTR,
+            &*cortex_m::peripheral::SCB::PTR,
+        )
+    };
+    let lptim = lptim();
+
+    let elapsed = if lptim.isr.read().bits() & LPTIM_ISR_ARRM != 0 {
+        lptim.arr.read().bits() + 1
+    } else {
+        // The counter runs from the LSI, so a read can catch it changing;
+        // it's right when two reads in a row agree.
+        loop {
+            let cnt = lptim.cnt.read().bits();
+            if lptim.cnt.read().bits() == cnt {
+                break cnt;
+            }
+        }
+    };
+
+    unsafe {
+        lptim.cr.write(|w| w.bits(0));
+        lptim.icr.write(|w| w.bits(LPTIM_ISR_ARRM));
+
+        nvic.icer[0].write(1 << LPTIM1_IRQ);
+        nvic.icpr[0].write(1 << LPTIM1_IRQ);
+        scb.scr.modify(|v| v & !SCB_SCR_SLEEPDEEP);
+    }
+
+    elapsed
+}
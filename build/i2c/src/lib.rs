@@ -6,7 +6,7 @@ use anyhow::{bail, Context, Result};
 use convert_case::{Case, Casing};
 use indexmap::IndexMap;
 use multimap::MultiMap;
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 use std::collections::{BTreeMap, HashMap, HashSet};
 use std::fmt::Write;
 use std::fs::File;
@@ -21,6 +21,16 @@ struct Config {
     i2c: I2cConfig,
 }
 
+//
+// The same, for tools outside the build, which have to cope with apps that
+// don't use I2C at all.
+//
+#[derive(Clone, Debug, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+struct OptionalConfig {
+    i2c: Option<I2cConfig>,
+}
+
 #[derive(Clone, Debug, Deserialize)]
 #[serde(rename_all = "kebab-case", deny_unknown_fields)]
 struct I2cConfig {
@@ -284,7 +294,7 @@ struct DeviceBusNameKey {
     kind: Sensor,
 }
 
-#[derive(Debug, Clone, PartialEq, Eq)]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub struct DeviceSensor {
     pub name: Option<String>,
     pub kind: Sensor,
@@ -449,7 +459,16 @@ pub enum Disposition {
 }
 
 #[derive(
-    Copy, Clone, Deserialize, Debug, PartialEq, Eq, Hash, Ord, PartialOrd,
+    Copy,
+    Clone,
+    Deserialize,
+    Serialize,
+    Debug,
+    PartialEq,
+    Eq,
+    Hash,
+    Ord,
+    PartialOrd,
 )]
 #[serde(rename_all = "kebab-case")]
 pub enum Sensor {
@@ -520,7 +539,10 @@ impl ConfigGenerator {
                 panic!("malformed config.i2c: {:?}", err);
             }
         };
+        Self::from_config(i2c, disposition)
+    }
 
+    fn from_config(i2c: I2cConfig, disposition: Disposition) -> Self {
         let mut controllers = vec![];
         let mut buses = HashMap::new();
         let mut ports = IndexMap::new();
@@ -708,8 +730,10 @@ impl ConfigGenerator {
     #[allow(unused_imports)]
     use drv_stm32xx_i2c::{{I2cPins, I2cGpio}};
 
-    pub fn pins() -> [I2cPins; {}] {{"##,
-            len
+    #[allow(dead_code)]
+    pub const NPORTS: usize = {len};
+
+    pub fn pins() -> [I2cPins; NPORTS] {{"##
         )?;
 
         if len > 0 {
@@ -790,10 +814,12 @@ impl ConfigGenerator {
     #[allow(dead_code)]
     pub const NMUXEDBUSES: usize = {nmuxedbuses};
 
+    #[allow(dead_code)]
+    pub const NMUXES: usize = {len};
+
     use drv_stm32xx_i2c::I2cMux;
 
-    pub fn muxes() -> [I2cMux<'static>; {}] {{"##,
-            len
+    pub fn muxes() -> [I2cMux<'static>; NMUXES] {{"##
         )?;
 
         if len > 0 {
@@ -1709,6 +1735,12 @@ pub struct I2cDeviceDescription {
     pub device: String,
     pub description: String,
     pub sensors: Vec<DeviceSensor>,
+    pub controller: u8,
+    /// Index of the port, as used by the generated `PortIndex`
+    pub port: usize,
+    pub mux: Option<u8>,
+    pub segment: Option<u8>,
+    pub address: u8,
 }
 
 ///
@@ -1723,13 +1755,152 @@ pub fn device_descriptions() -> impl Iterator<Item = I2cDeviceDescription> {
 
     assert_eq!(sensors.device_sensors.len(), g.devices.len());
 
+    let locations = g
+        .devices
+        .iter()
+        .map(|d| g.lookup_controller_port(d))
+        .collect::<Vec<_>>();
+
     // Matches the ordering of the `match` produced by `generate_validation()`
     // above; if we change the order here, it must change there as well.
-    g.devices.into_iter().zip(sensors.device_sensors).map(
-        |(device, sensors)| I2cDeviceDescription {
-            device: device.device,
-            description: device.description,
-            sensors,
-        },
-    )
+    g.devices
+        .into_iter()
+        .zip(sensors.device_sensors)
+        .zip(locations)
+        .map(
+            |((device, sensors), (controller, port))| I2cDeviceDescription {
+                device: device.device,
+                description: device.description,
+                sensors,
+                controller,
+                port,
+                mux: device.mux,
+                segment: device.segment,
+                address: device.address,
+            },
+        )
+}
+
+///
+/// A bus -- or a segment of one of its muxes -- on which there are I2C
+/// devices.
+///
+pub struct I2cBusDescription {
+    pub controller: u8,
+    /// Index of the port, as used by the generated `PortIndex`
+    pub port: usize,
+    pub mux: Option<u8>,
+    pub segment: Option<u8>,
+    /// Addresses of the muxes on the bus, which answer on any segment
+    pub mux_addresses: Vec<u8>,
+}
+
+///
+/// Returns each bus and mux segment that has devices on it, in the order in
+/// which they first appear in [`device_descriptions`].
+///
+pub fn bus_descriptions() -> Vec<I2cBusDescription> {
+    let g = ConfigGenerator::new(Disposition::Validation);
+    let mut buses: Vec<I2cBusDescription> = vec![];
+
+    for d in &g.devices {
+        let (controller, port) = g.lookup_controller_port(d);
+
+        if buses.iter().any(|b| {
+            (b.controller, b.port, b.mux, b.segment)
+                == (controller, port, d.mux, d.segment)
+        }) {
+            continue;
+        }
+
+        let mux_addresses = g
+            .controllers
+            .iter()
+            .find(|c| c.controller == controller)
+            .and_then(|c| c.ports.values().nth(port))
+            .map(|p| p.muxes.iter().map(|mux| mux.address).collect())
+            .unwrap_or_default();
+
+        buses.push(I2cBusDescription {
+            controller,
+            port,
+            mux: d.mux,
+            segment: d.segment,
+            mux_addresses,
+        });
+    }
+
+    buses
+}
+
+/// Where an I2C device lives and which sensors it provides, for tools outside
+/// the build (such as the image description written by `xtask`).
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct I2cDeviceInfo {
+    /// Index of the device, as used by the generated `validate()` command
+    pub index: usize,
+    pub device: String,
+    pub name: Option<String>,
+    pub description: String,
+    pub refdes: Option<String>,
+    pub bus: Option<String>,
+    pub controller: u8,
+    /// Index of the port, as used by the generated `PortIndex`
+    pub port: usize,
+    pub port_name: String,
+    pub mux: Option<u8>,
+    pub segment: Option<u8>,
+    pub address: u8,
+    pub removable: bool,
+    pub sensors: Vec<DeviceSensor>,
+}
+
+///
+/// Describes every I2C device in an app's global configuration, which is read
+/// from `config` rather than from the environment, so that this can be used
+/// outside of a build script.  Apps without an I2C configuration have no
+/// devices.
+///
+pub fn describe_devices<'de, D: serde::Deserializer<'de>>(
+    config: D,
+) -> Result<Vec<I2cDeviceInfo>> {
+    let i2c = match OptionalConfig::deserialize(config) {
+        Ok(OptionalConfig { i2c: Some(i2c) }) => i2c,
+        Ok(OptionalConfig { i2c: None }) => return Ok(vec![]),
+        Err(err) => bail!("malformed config.i2c: {err}"),
+    };
+    let g = ConfigGenerator::from_config(i2c, Disposition::Validation);
+    let sensors = g.sensors_description();
+
+    // As in `device_descriptions`, the order here matches `validate()`.
+    Ok(g.devices
+        .iter()
+        .zip(sensors.device_sensors)
+        .enumerate()
+        .map(|(index, (d, sensors))| {
+            let (controller, port) = g.lookup_controller_port(d);
+            let port_name = g
+                .ports
+                .iter()
+                .find(|(&(c, _), &p)| c == controller && p == port)
+                .map(|((_, name), _)| name.clone())
+                .unwrap_or_default();
+            I2cDeviceInfo {
+                index,
+                device: d.device.clone(),
+                name: d.name.clone(),
+                description: d.description.clone(),
+                refdes: d.refdes.clone(),
+                bus: d.bus.clone(),
+                controller,
+                port,
+                port_name,
+                mux: d.mux,
+                segment: d.segment,
+                address: d.address,
+                removable: d.removable,
+                sensors,
+            }
+        })
+        .collect())
 }
@@ -71,6 +71,14 @@ pub struct TaskConfig {
 
     /// Should this task be started automatically on boot?
     pub start_at_boot: bool,
+
+    /// Should this task inherit the priority of more important tasks that are
+    /// blocked on it?
+    pub inherit_priority: bool,
+
+    /// Should this task run in the non-secure state (on ARMv8-M parts with
+    /// TrustZone)?
+    pub non_secure: bool,
 }
 
 /// An address within an owned region of memory.
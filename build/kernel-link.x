@@ -146,6 +146,19 @@ SECTIONS
     __veneer_limit = .;
   } > FLASH
 
+  /* ### .trustzone_ns
+     The kernel's non-secure exception trampoline and vector table, used only
+     when some task runs non-secure. This is made non-secure by the SAU, so it
+     must also be 32 byte aligned; the vector table needs 128. */
+  .trustzone_ns : {
+    . = ALIGN(128);
+    __trustzone_ns_base = .;
+    KEEP(*(.trustzone_ns.vectors))
+    *(.trustzone_ns.text*)
+    . = ALIGN(32);
+    __trustzone_ns_limit = .;
+  } > FLASH
+
   /*
    * Fill the remaining flash space with a known value
    */
@@ -196,6 +209,8 @@ SECTIONS
     *(.ARM.exidx);
     *(.ARM.exidx.*);
     *(.ARM.extab.*);
+    /* Kernel ring buffers can't be exported by the supervisor */
+    *(.ringbuf_registry);
   }
 }
 
@@ -85,12 +85,30 @@ SECTIONS
     KEEP(*(.caboose_pos_table));
   }
 
+  /* ## .ringbuf_registry */
+  /* Locations of ring buffers, and the supervisor's table of them. Used to
+     export ring buffers through the supervisor during packaging. */
+  .ringbuf_registry (INFO) : {
+    . = .;
+    KEEP(*(.ringbuf_registry));
+  }
+  .ringbuf_export_table (INFO) : {
+    . = .;
+    KEEP(*(.ringbuf_export_table));
+  }
+
   /* ## .idolatry */
   .idolatry (INFO) : {
     . = .;
     KEEP(*(.idolatry));
   }
 
+  /* Per-function stack usage, for `xtask sizes`; not loaded */
+  .stack_sizes (INFO) :
+  {
+    KEEP(*(.stack_sizes));
+  }
+
   /* ## Discarded sections */
   /DISCARD/ :
   {
@@ -71,6 +71,18 @@ SECTIONS
     KEEP(*(.caboose_pos_table));
   }
 
+  /* ## .ringbuf_registry */
+  /* Locations of ring buffers, and the supervisor's table of them. Used to
+     export ring buffers through the supervisor during packaging. */
+  .ringbuf_registry (INFO) : {
+    . = .;
+    KEEP(*(.ringbuf_registry));
+  }
+  .ringbuf_export_table (INFO) : {
+    . = .;
+    KEEP(*(.ringbuf_export_table));
+  }
+
   /* ## .idolatry */
   .idolatry (INFO) : {
     . = .;
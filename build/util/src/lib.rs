@@ -166,6 +166,13 @@ pub fn other_task_full_config_toml(
     other_task_full_config(name)
 }
 
+/// Pulls the full task configuration blocks of every task, keyed by name
+pub fn all_task_full_configs_toml(
+) -> Result<IndexMap<String, toml_task::Task<ordered_toml::Value>>> {
+    toml_from_env("HUBRIS_ALL_TASK_CONFIGS")?
+        .ok_or_else(|| anyhow!("HUBRIS_ALL_TASK_CONFIGS is not defined"))
+}
+
 /// Returns a map of task names to their IDs.
 pub fn task_ids() -> TaskIds {
     let tasks = crate::env_var("HUBRIS_TASKS").expect("missing HUBRIS_TASKS");
@@ -80,6 +80,8 @@ pub fn run(
             let kconfig = crate::dist::make_kconfig(
                 &toml,
                 &allocs.tasks,
+                &allocs.chunks,
+                &allocs.shared,
                 &entry_points,
                 &toml.image_names[0],
             )?;
@@ -10,6 +10,7 @@ use std::path::{Path, PathBuf};
 use anyhow::{anyhow, bail, Context, Result};
 use indexmap::IndexMap;
 use serde::{Deserialize, Serialize};
+use syn::{ext::IdentExt, parse::ParseStream, punctuated::Punctuated, Token};
 
 use crate::auxflash::{build_auxflash, AuxFlash, AuxFlashData};
 
@@ -44,6 +45,8 @@ struct RawConfig {
     config: Option<ordered_toml::Value>,
     auxflash: Option<AuxFlash>,
     caboose: Option<CabooseConfig>,
+    #[serde(default)]
+    shared_regions: IndexMap<String, SharedRegion>,
 }
 
 #[derive(Clone, Debug)]
@@ -71,6 +74,9 @@ pub struct Config {
     pub app_config: String,
     pub auxflash: Option<AuxFlashData>,
     pub caboose: Option<CabooseConfig>,
+    pub shared_regions: IndexMap<String, SharedRegion>,
+    /// The files that `app_config` was assembled from, for error reporting
+    sources: TomlSources,
 }
 
 impl Config {
@@ -106,6 +112,49 @@ pub struct CabooseConfig {
     pub default: bool,
 }
 
+/// A named block of memory that is mapped into several tasks at once.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct SharedRegion {
+    /// Name of the memory (e.g. "ram") from which the region is allocated
+    pub memory: String,
+
+    /// Size of the region, in bytes
+    pub size: u32,
+
+    /// Tasks that may read the region
+    #[serde(default)]
+    pub read: Vec<String>,
+
+    /// Tasks that may read and write the region
+    #[serde(default)]
+    pub write: Vec<String>,
+
+    /// If `true`, the region may also be used for DMA
+    #[serde(default)]
+    pub dma: bool,
+}
+
+impl SharedRegion {
+    /// Returns `Some(true)` if `task` may write this region, `Some(false)` if
+    /// it may only read it, and `None` if it can't touch it at all.
+    pub fn access(&self, task: &str) -> Option<bool> {
+        if self.write.iter().any(|t| t == task) {
+            Some(true)
+        } else if self.read.iter().any(|t| t == task) {
+            Some(false)
+        } else {
+            None
+        }
+    }
+
+    /// Returns the name of the kernel region used to map this shared region,
+    /// which differs by access so that each gets its own attributes.
+    pub fn kernel_region_name(name: &str, writable: bool) -> String {
+        format!("shm.{name}.{}", if writable { "rw" } else { "ro" })
+    }
+}
+
 impl Config {
     pub fn from_file(cfg: &Path) -> Result<Self> {
         Self::from_file_with_hasher(cfg, DefaultHasher::new())
@@ -115,11 +164,19 @@ impl Config {
         cfg: &Path,
         mut hasher: DefaultHasher,
     ) -> Result<Self> {
-        let doc =
-            read_and_flatten_toml(cfg, &mut hasher, &mut BTreeSet::new())?;
+        let mut sources = TomlSources::default();
+        let doc = read_and_flatten_toml(
+            cfg,
+            &mut hasher,
+            &mut BTreeSet::new(),
+            &mut sources,
+        )?;
         let cfg_contents = doc.to_string();
 
-        let toml: RawConfig = toml::from_str(&cfg_contents)?;
+        let toml: RawConfig = match toml::from_str(&cfg_contents) {
+            Ok(toml) => toml,
+            Err(e) => bail!("{}", sources.explain(&cfg_contents, &e)),
+        };
         if toml.tasks.contains_key("kernel") {
             bail!("'kernel' is reserved and cannot be used as a task name");
         }
@@ -188,32 +245,17 @@ impl Config {
             app_toml_path: cfg.to_owned(),
             app_config: cfg_contents,
             caboose: toml.caboose,
+            shared_regions: toml.shared_regions,
+            sources,
         })
     }
 
     pub fn task_name_suggestion(&self, name: &str) -> String {
-        // Suggest only for very small differences
-        // High number can result in inaccurate suggestions for short queries e.g. `rls`
-        const MAX_DISTANCE: usize = 3;
-
-        let mut scored: Vec<_> = self
-            .tasks
-            .keys()
-            .filter_map(|s| {
-                let distance = strsim::damerau_levenshtein(name, s);
-                if distance <= MAX_DISTANCE {
-                    Some((distance, s))
-                } else {
-                    None
-                }
-            })
-            .collect();
-        scored.sort();
-        let mut out = format!("'{}' is not a valid task name.", name);
-        if let Some((_, s)) = scored.get(0) {
-            out.push_str(&format!(" Did you mean '{}'?", s));
-        }
-        out
+        format!(
+            "'{}' is not a valid task name.{}",
+            name,
+            did_you_mean(name, self.tasks.keys())
+        )
     }
 
     fn common_build_config<'a>(
@@ -460,6 +502,30 @@ impl Config {
         self.mpu_alignment() == MpuAlignment::PowerOfTwo
     }
 
+    /// Returns the smallest region the MPU can map, if the MPU needs
+    /// power-of-two regions. Only these chips benefit from splitting a task's
+    /// memory across several regions.
+    pub fn mpu_split_granule(&self) -> Option<u32> {
+        match self.target.as_str() {
+            "thumbv7em-none-eabihf" => Some(32),
+            "thumbv6m-none-eabi" => Some(256),
+            _ => None,
+        }
+    }
+
+    /// Returns the number of SAU regions left over to describe a non-secure
+    /// task's memory, or `None` if the target has no SAU.
+    ///
+    /// Every ARMv8-M part we support implements eight SAU regions, and the
+    /// kernel keeps two of them for the non-secure trampoline and the secure
+    /// gateway veneers.
+    pub fn sau_task_regions(&self) -> Option<usize> {
+        match self.target.as_str() {
+            "thumbv8m.main-none-eabihf" => Some(8 - 2),
+            _ => None,
+        }
+    }
+
     /// Suggests an appropriate size for the given task (or "kernel"), given
     /// its true size.  The size depends on MMU implementation, dispatched
     /// based on the `target` in the config file.
@@ -483,6 +549,17 @@ impl Config {
         self.image_names.contains(name)
     }
 
+    /// Returns the shared regions that `task` may access, along with whether
+    /// it may write to each.
+    pub fn shared_regions_for<'a>(
+        &'a self,
+        task: &'a str,
+    ) -> impl Iterator<Item = (&'a str, &'a SharedRegion, bool)> + 'a {
+        self.shared_regions.iter().filter_map(move |(name, r)| {
+            r.access(task).map(|w| (name.as_str(), r, w))
+        })
+    }
+
     pub fn extern_regions_for(
         &self,
         task: &str,
@@ -512,6 +589,232 @@ impl Config {
             })
             .collect::<Result<IndexMap<String, Range<u32>>>>()
     }
+
+    /// Checks the things that deserializing the config can't: that names
+    /// refer to tasks, peripherals, memories and interrupts that exist, and
+    /// that each task's `config` block has the keys its code reads. Every
+    /// problem found is reported, along with the file and line it comes from.
+    ///
+    /// `metadata` is used to find each task's crate.
+    pub fn validate(&self, metadata: &cargo_metadata::Metadata) -> Result<()> {
+        let mut problems = Problems::new(&self.sources);
+
+        for (name, task) in &self.tasks {
+            let name = name.as_str();
+            for (slot, callee) in &task.task_slots {
+                if !self.tasks.contains_key(callee) {
+                    problems.add(
+                        &["tasks", name, "task-slots"],
+                        format!(
+                            "task '{name}' slot '{slot}': {}",
+                            self.task_name_suggestion(callee)
+                        ),
+                    );
+                }
+            }
+            for (i, p) in task.uses.iter().enumerate() {
+                if !self.peripherals.contains_key(p)
+                    && !self.extratext.contains_key(p)
+                {
+                    problems.add(
+                        &["tasks", name, "uses", &i.to_string()],
+                        format!(
+                            "task '{name}' uses '{p}', which is not a \
+                             peripheral of {}.{}",
+                            self.chip,
+                            did_you_mean(
+                                p,
+                                self.peripherals
+                                    .keys()
+                                    .chain(self.extratext.keys())
+                            )
+                        ),
+                    );
+                }
+            }
+            for (i, r) in task.extern_regions.iter().enumerate() {
+                if !self.outputs.contains_key(r) {
+                    problems.add(
+                        &["tasks", name, "extern-regions", &i.to_string()],
+                        format!(
+                            "task '{name}' maps memory '{r}', which does \
+                             not exist.{}",
+                            did_you_mean(r, self.outputs.keys())
+                        ),
+                    );
+                }
+            }
+            for (irq, notification) in &task.interrupts {
+                let at = ["tasks", name, "interrupts", irq.as_str()];
+                match irq.split_once('.') {
+                    None => problems.add(
+                        &at,
+                        format!(
+                            "task '{name}' interrupt '{irq}' should be of \
+                             the form 'peripheral.interrupt'"
+                        ),
+                    ),
+                    Some((pname, iname)) => match self.peripherals.get(pname) {
+                        None => problems.add(
+                            &at,
+                            format!(
+                                "task '{name}' interrupt '{irq}' refers to \
+                                 peripheral '{pname}', which does not \
+                                 exist.{}",
+                                did_you_mean(pname, self.peripherals.keys())
+                            ),
+                        ),
+                        Some(p) if !p.interrupts.contains_key(iname) => {
+                            problems.add(
+                                &at,
+                                format!(
+                                    "task '{name}' interrupt '{irq}': \
+                                     peripheral '{pname}' has no interrupt \
+                                     '{iname}'.{}",
+                                    did_you_mean(iname, p.interrupts.keys())
+                                ),
+                            )
+                        }
+                        Some(_) => (),
+                    },
+                }
+                if !task.notifications.contains(notification) {
+                    problems.add(
+                        &at,
+                        format!(
+                            "task '{name}' interrupt '{irq}' posts \
+                             '{notification}', which is not in its \
+                             notifications"
+                        ),
+                    );
+                }
+            }
+        }
+
+        if let Some(caboose) = &self.caboose {
+            for (i, t) in caboose.tasks.iter().enumerate() {
+                if !self.tasks.contains_key(t) {
+                    problems.add(
+                        &["caboose", "tasks", &i.to_string()],
+                        format!("caboose: {}", self.task_name_suggestion(t)),
+                    );
+                }
+            }
+        }
+        for (region, shared) in &self.shared_regions {
+            if !self.outputs.contains_key(&shared.memory) {
+                problems.add(
+                    &["shared-regions", region, "memory"],
+                    format!(
+                        "shared region '{region}' is in memory '{}', which \
+                         does not exist.{}",
+                        shared.memory,
+                        did_you_mean(&shared.memory, self.outputs.keys())
+                    ),
+                );
+            }
+            for (access, tasks) in
+                [("read", &shared.read), ("write", &shared.write)]
+            {
+                for (i, t) in tasks.iter().enumerate() {
+                    if !self.tasks.contains_key(t) {
+                        problems.add(
+                            &["shared-regions", region, access, &i.to_string()],
+                            format!(
+                                "shared region '{region}': {}",
+                                self.task_name_suggestion(t)
+                            ),
+                        );
+                    }
+                }
+            }
+        }
+
+        self.check_task_configs(metadata, &mut problems)?;
+        problems.finish()
+    }
+
+    /// Checks each task's `config` block against the keys its crate reads,
+    /// for crates where we can work that out from the source.
+    fn check_task_configs(
+        &self,
+        metadata: &cargo_metadata::Metadata,
+        problems: &mut Problems,
+    ) -> Result<()> {
+        let cwd = std::env::current_dir()?;
+
+        for (name, task) in &self.tasks {
+            let pkg =
+                match metadata.packages.iter().find(|p| p.name == task.name) {
+                    Some(pkg) => pkg,
+                    None => continue,
+                };
+            let dir = Path::new(pkg.manifest_path.parent().unwrap().as_str());
+            let schema = match ConfigSchema::read(dir)? {
+                Some(schema) => schema,
+                None => continue,
+            };
+            let from =
+                schema.source.strip_prefix(&cwd).unwrap_or(&schema.source);
+            let from = from.display();
+
+            let at = ["tasks", name.as_str(), "config"];
+            let config = match &task.config {
+                Some(config) => config,
+                None => {
+                    if !schema.optional {
+                        problems.add(
+                            &at[..2],
+                            format!(
+                                "task '{name}' has no config block, but \
+                                 {from} expects one"
+                            ),
+                        );
+                    }
+                    continue;
+                }
+            };
+            let keys: Vec<&String> = match config.as_table() {
+                Some(table) => table.keys().collect(),
+                None => {
+                    problems.add(
+                        &at,
+                        format!("task '{name}' config must be a table"),
+                    );
+                    continue;
+                }
+            };
+
+            for key in &keys {
+                if schema.keys.contains_key(key.as_str()) {
+                    continue;
+                }
+                let path = [at[0], at[1], at[2], key.as_str()];
+                let message = format!(
+                    "task '{name}' config has unknown key '{key}', which \
+                     {from} doesn't read.{}",
+                    did_you_mean(key, schema.keys.keys())
+                );
+                match schema.unknown {
+                    UnknownKeys::Deny => problems.add(&path, message),
+                    UnknownKeys::Warn => problems.warn(&path, message),
+                    UnknownKeys::Allow => (),
+                }
+            }
+            for (key, required) in &schema.keys {
+                if *required && !keys.contains(&key) {
+                    problems.add(
+                        &at,
+                        format!(
+                            "task '{name}' config is missing '{key}', which \
+                             {from} requires"
+                        ),
+                    );
+                }
+            }
+        }
+        Ok(())
+    }
 }
 
 /// Represents an MPU's desired alignment strategy
@@ -663,6 +966,7 @@ fn read_and_flatten_toml(
     cfg: &Path,
     hasher: &mut DefaultHasher,
     seen: &mut BTreeSet<PathBuf>,
+    sources: &mut TomlSources,
 ) -> Result<toml_edit::Document> {
     use toml_patch::merge_toml_documents;
 
@@ -689,6 +993,7 @@ fn read_and_flatten_toml(
         .context("failed to parse TOML file")?;
     let Some(inherited_from) = doc.remove("inherit") else {
         // No further inheritance, so return the current document
+        sources.push(cfg, cfg_contents, &doc);
         return Ok(doc);
     };
 
@@ -697,7 +1002,7 @@ fn read_and_flatten_toml(
         // Single inheritance
         Item::Value(Value::String(s)) => {
             let file = cfg.parent().unwrap().join(s.value());
-            read_and_flatten_toml(&file, hasher, seen)
+            read_and_flatten_toml(&file, hasher, seen, sources)
                 .with_context(|| format!("Could not load {file:?}"))?
         }
         // Multiple inheritance, applied sequentially
@@ -707,7 +1012,7 @@ fn read_and_flatten_toml(
                 if let Value::String(s) = a {
                     let file = cfg.parent().unwrap().join(s.value());
                     let next: toml_edit::Document =
-                        read_and_flatten_toml(&file, hasher, seen)
+                        read_and_flatten_toml(&file, hasher, seen, sources)
                             .with_context(|| {
                                 format!("Could not load {file:?}")
                             })?;
@@ -725,6 +1030,621 @@ fn read_and_flatten_toml(
     };
 
     // Finally, apply any changes that are local in this file
+    sources.push(cfg, cfg_contents, &doc);
     merge_toml_documents(&mut original, doc)?;
     Ok(original)
 }
+
+/// Returns a suggestion of the form " Did you mean 'x'?" if one of
+/// `candidates` is close to `name`, or an empty string otherwise.
+fn did_you_mean<'a>(
+    name: &str,
+    candidates: impl IntoIterator<Item = &'a String>,
+) -> String {
+    // Suggest only for very small differences
+    // High number can result in inaccurate suggestions for short queries e.g. `rls`
+    const MAX_DISTANCE: usize = 3;
+
+    candidates
+        .into_iter()
+        .map(|s| (strsim::damerau_levenshtein(name, s), s))
+        .filter(|(distance, _)| *distance <= MAX_DISTANCE)
+        .min()
+        .map(|(_, s)| format!(" Did you mean '{s}'?"))
+        .unwrap_or_default()
+}
+
+/// The files that make up an `app.toml`, in the order in which they were
+/// merged (so later files override earlier ones).
+#[derive(Clone, Debug, Default)]
+struct TomlSources(Vec<TomlSource>);
+
+#[derive(Clone, Debug)]
+struct TomlSource {
+    path: PathBuf,
+    text: String,
+    /// The file as parsed, which remembers where everything in it came from
+    doc: toml_edit::Document,
+}
+
+impl TomlSources {
+    fn push(&mut self, path: &Path, text: &str, doc: &toml_edit::Document) {
+        self.0.push(TomlSource {
+            path: path.to_owned(),
+            text: text.to_owned(),
+            doc: doc.clone(),
+        });
+    }
+
+    /// Returns `file:line:column` for the item at `path` (a list of table keys
+    /// and array indices), in the last file to set it. If no file sets it, we
+    /// fall back to its nearest parent that is set.
+    fn locate(&self, path: &[&str]) -> String {
+        for src in self.0.iter().rev() {
+            if let Some(offset) = src.find(path) {
+                let before = &src.text[..offset];
+                let line = before.matches('\n').count() + 1;
+                let col = offset - before.rfind('\n').map_or(0, |i| i + 1) + 1;
+                return format!("{}:{line}:{col}", src.path.display());
+            }
+        }
+        match path.split_last() {
+            Some((_, parent)) => self.locate(parent),
+            None => self
+                .0
+                .last()
+                .map(|src| src.path.display().to_string())
+                .unwrap_or_default(),
+        }
+    }
+
+    /// Explains an error from deserializing `merged` (the flattened document)
+    /// in terms of the file and line responsible for it.
+    fn explain(&self, merged: &str, err: &toml::de::Error) -> String {
+        let offset = match err.span() {
+            Some(span) => span.start,
+            None => return err.to_string(),
+        };
+        let doc = match merged.parse::<toml_edit::Document>() {
+            Ok(doc) => doc,
+            Err(_) => return err.to_string(),
+        };
+        let mut path = vec![];
+        path_at(doc.as_table(), offset, &mut path);
+        let path: Vec<&str> = path.iter().map(String::as_str).collect();
+        format!("{}: {}", self.locate(&path), err.message())
+    }
+}
+
+impl TomlSource {
+    /// Returns the byte offset at which the item at `path` is written in this
+    /// file, preferring its key to its value.
+    fn find(&self, path: &[&str]) -> Option<usize> {
+        let mut item = self.doc.as_item();
+        let mut offset = None;
+        for key in path {
+            let (span, next) = match item.as_table_like() {
+                Some(table) => {
+                    let (k, v) = table.get_key_value(key)?;
+                    (k.span().or_else(|| v.span()), v)
+                }
+                None => {
+                    let v = item.get(key.parse::<usize>().ok()?)?;
+                    (v.span(), v)
+                }
+            };
+            offset = span.map(|s| s.start).or(offset);
+            item = next;
+        }
+        offset
+    }
+}
+
+/// Finds the innermost key or value in `table` that covers byte `offset` of
+/// the text it was parsed from, pushing the keys that lead to it onto `path`.
+/// Returns `false` if there is none.
+fn path_at(
+    table: &dyn toml_edit::TableLike,
+    offset: usize,
+    path: &mut Vec<String>,
+) -> bool {
+    use toml_edit::{Item, Value};
+    for (key, item) in table.iter() {
+        path.push(key.to_owned());
+        let inner = match item {
+            Item::Table(t) => path_at(t, offset, path),
+            Item::Value(Value::InlineTable(t)) => path_at(t, offset, path),
+            Item::ArrayOfTables(a) => a.iter().enumerate().any(|(i, t)| {
+                path.push(i.to_string());
+                let found = path_at(t, offset, path);
+                if !found {
+                    path.pop();
+                }
+                found
+            }),
+            _ => false,
+        };
+        if inner {
+            return true;
+        }
+        let key_span = table.get_key_value(key).and_then(|(k, _)| k.span());
+        if key_span
+            .into_iter()
+            .chain(item.span())
+            .any(|span| span.contains(&offset))
+        {
+            return true;
+        }
+        path.pop();
+    }
+    false
+}
+
+/// Problems found while validating a config, each with where it came from.
+struct Problems<'a> {
+    sources: &'a TomlSources,
+    found: Vec<String>,
+}
+
+impl<'a> Problems<'a> {
+    fn new(sources: &'a TomlSources) -> Self {
+        Self {
+            sources,
+            found: vec![],
+        }
+    }
+
+    fn add(&mut self, path: &[&str], message: String) {
+        self.found
+            .push(format!("{}: {message}", self.sources.locate(path)));
+    }
+
+    /// Reports something that's probably a mistake, but won't stop the build.
+    fn warn(&self, path: &[&str], message: String) {
+        println!("warning: {}: {message}", self.sources.locate(path));
+    }
+
+    fn finish(self) -> Result<()> {
+        match self.found.len() {
+            0 => Ok(()),
+            1 => bail!("{}", self.found[0]),
+            n => bail!("{n} problems in config:\n{}", self.found.join("\n")),
+        }
+    }
+}
+
+/// The keys a task reads from its `config` block, as far as we can tell from
+/// its source.
+#[derive(Debug)]
+struct ConfigSchema {
+    /// File in which the keys are declared
+    source: PathBuf,
+    /// Map from key to whether it must be present
+    keys: BTreeMap<String, bool>,
+    /// Whether the whole block may be left out
+    optional: bool,
+    /// What happens to keys other than `keys`
+    unknown: UnknownKeys,
+}
+
+/// How a task treats keys in its `config` block that it doesn't read
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+enum UnknownKeys {
+    /// Ignored, though probably a mistake
+    Warn,
+    /// Rejected, by `deny_unknown_fields`
+    Deny,
+    /// Ignored, by design
+    Allow,
+}
+
+impl ConfigSchema {
+    /// Looks for a `task_config!` macro in the crate at `dir`, or failing that,
+    /// for the type its build script passes to `build_util::task_config`.
+    /// Returns `None` if the crate does neither, or does something we can't
+    /// follow, and an error if it has more than one `task_config!`.
+    fn read(dir: &Path) -> Result<Option<Self>> {
+        let mut found: Option<Self> = None;
+        // Sorted, so that any error names the same files every time.
+        for entry in walkdir::WalkDir::new(dir.join("src"))
+            .sort_by(|a, b| a.file_name().cmp(b.file_name()))
+        {
+            let entry = match entry {
+                Ok(entry) => entry,
+                Err(_) => continue,
+            };
+            if entry.path().extension() != Some("rs".as_ref()) {
+                continue;
+            }
+            let text = std::fs::read_to_string(entry.path())?;
+            if let Some(schema) = Self::from_macro(entry.path(), &text)? {
+                if let Some(first) = &found {
+                    bail!(
+                        "{} and {} both invoke task_config!, but a task can \
+                         only have one config",
+                        first.source.display(),
+                        schema.source.display()
+                    );
+                }
+                found = Some(schema);
+            }
+        }
+        if found.is_some() {
+            return Ok(found);
+        }
+
+        let build_rs = dir.join("build.rs");
+        match std::fs::read_to_string(&build_rs) {
+            Ok(text) => Ok(Self::from_build_script(&build_rs, &text)),
+            Err(_) => Ok(None),
+        }
+    }
+
+    /// Finds a `task_config!` (or `optional_task_config!`) invocation in
+    /// `text`, the contents of `source`, failing if there's more than one.
+    fn from_macro(source: &Path, text: &str) -> Result<Option<Self>> {
+        let file = match syn::parse_file(text) {
+            Ok(file) => file,
+            Err(_) => return Ok(None),
+        };
+        let mut finder = MacroFinder(vec![]);
+        syn::visit::visit_file(&mut finder, &file);
+        if finder.0.len() > 1 {
+            bail!(
+                "{} invokes task_config! {} times, but a task can only have \
+                 one config",
+                source.display(),
+                finder.0.len()
+            );
+        }
+        let (optional, fields) = match finder.0.pop() {
+            Some(found) => found,
+            None => return Ok(None),
+        };
+
+        Ok(Some(Self {
+            source: source.to_owned(),
+            // The macro panics on any missing field, and ignores anything
+            // else.
+            keys: fields
+                .iter()
+                .filter_map(|f| f.ident.as_ref())
+                .map(|ident| (ident.unraw().to_string(), true))
+                .collect(),
+            optional,
+            unknown: UnknownKeys::Warn,
+        }))
+    }
+
+    /// Finds the type that the build script in `text` (the contents of
+    /// `source`) deserializes its config into, and works out its keys from
+    /// its fields and `serde` attributes.
+    fn from_build_script(source: &Path, text: &str) -> Option<Self> {
+        let file = syn::parse_file(text).ok()?;
+        let mut finder = ConfigTypeFinder::default();
+        syn::visit::visit_file(&mut finder, &file);
+        let (optional, ty) = finder.call?;
+        let def = finder.structs.into_iter().find(|s| &s.ident == ty)?;
+
+        let args = serde_args(&def.attrs);
+        let kebab = match string_arg(&args, "rename_all").as_deref() {
+            None => false,
+            Some("kebab-case") => true,
+            Some(_) => return None,
+        };
+        let all_default = has_arg(&args, "default");
+
+        let fields = match &def.fields {
+            syn::Fields::Named(fields) => &fields.named,
+            _ => return None,
+        };
+        let mut keys = BTreeMap::new();
+        for field in fields {
+            let args = serde_args(&field.attrs);
+            if has_arg(&args, "flatten") {
+                return None;
+            }
+            let required = !all_default
+                && !has_arg(&args, "default")
+                && !is_option(&field.ty);
+            let ident = field.ident.as_ref()?.unraw().to_string();
+            let name = match string_arg(&args, "rename") {
+                Some(name) => name,
+                None if kebab => ident.replace('_', "-"),
+                None => ident,
+            };
+            keys.insert(name, required);
+        }
+        Some(Self {
+            source: source.to_owned(),
+            keys,
+            optional,
+            unknown: if has_arg(&args, "deny_unknown_fields") {
+                UnknownKeys::Deny
+            } else {
+                UnknownKeys::Allow
+            },
+        })
+    }
+}
+
+/// Looks for `task_config!` and `optional_task_config!` invocations, recording
+/// whether each was optional and its fields.
+struct MacroFinder(Vec<(bool, Vec<syn::Field>)>);
+
+impl<'ast> syn::visit::Visit<'ast> for MacroFinder {
+    fn visit_macro(&mut self, mac: &'ast syn::Macro) {
+        let optional = match mac.path.segments.last() {
+            Some(s) if s.ident == "task_config" => false,
+            Some(s) if s.ident == "optional_task_config" => true,
+            _ => return,
+        };
+        let fields = mac.parse_body_with(|input: ParseStream| {
+            Punctuated::<syn::Field, Token![,]>::parse_terminated_with(
+                input,
+                syn::Field::parse_named,
+            )
+        });
+        if let Ok(fields) = fields {
+            self.0.push((optional, fields.into_iter().collect()));
+        }
+    }
+}
+
+/// Looks for a call to `task_config::<T>` or `task_maybe_config::<T>`,
+/// recording whether it was the latter and the name of `T`, and gathers every
+/// struct definition so that we can find `T`.
+#[derive(Default)]
+struct ConfigTypeFinder<'ast> {
+    call: Option<(bool, &'ast syn::Ident)>,
+    structs: Vec<&'ast syn::ItemStruct>,
+}
+
+impl<'ast> syn::visit::Visit<'ast> for ConfigTypeFinder<'ast> {
+    fn visit_path_segment(&mut self, segment: &'ast syn::PathSegment) {
+        let optional = if segment.ident == "task_config" {
+            false
+        } else if segment.ident == "task_maybe_config" {
+            true
+        } else {
+            return syn::visit::visit_path_segment(self, segment);
+        };
+        if let syn::PathArguments::AngleBracketed(args) = &segment.arguments {
+            if let Some(syn::GenericArgument::Type(syn::Type::Path(ty))) =
+                args.args.first()
+            {
+                if let Some(ident) = ty.path.get_ident() {
+                    self.call.get_or_insert((optional, ident));
+                }
+            }
+        }
+    }
+
+    fn visit_item_struct(&mut self, item: &'ast syn::ItemStruct) {
+        self.structs.push(item);
+    }
+}
+
+/// Returns the arguments of every `#[serde(...)]` attribute in `attrs`.
+fn serde_args(attrs: &[syn::Attribute]) -> Vec<syn::Meta> {
+    attrs
+        .iter()
+        .filter(|a| a.path.is_ident("serde"))
+        .filter_map(|a| match a.parse_meta() {
+            Ok(syn::Meta::List(list)) => Some(list.nested),
+            _ => None,
+        })
+        .flatten()
+        .filter_map(|n| match n {
+            syn::NestedMeta::Meta(m) => Some(m),
+            syn::NestedMeta::Lit(_) => None,
+        })
+        .collect()
+}
+
+/// Checks for a `serde` argument, either on its own (`default`) or with a
+/// value (`default = "path"`).
+fn has_arg(args: &[syn::Meta], name: &str) -> bool {
+    args.iter().any(|m| m.path().is_ident(name))
+}
+
+/// Returns the value of a `serde` argument like `rename = "value"`.
+fn string_arg(args: &[syn::Meta], name: &str) -> Option<String> {
+    args.iter().find_map(|m| match m {
+        syn::Meta::NameValue(syn::MetaNameValue {
+            path,
+            lit: syn::Lit::Str(value),
+            ..
+        }) if path.is_ident(name) => Some(value.value()),
+        _ => None,
+    })
+}
+
+/// Checks whether `ty` is an `Option`, which serde lets us leave out.
+fn is_option(ty: &syn::Type) -> bool {
+    match ty {
+        syn::Type::Path(ty) if ty.qself.is_none() => ty
+            .path
+            .segments
+            .last()
+            .map_or(false, |s| s.ident == "Option"),
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn from_macro(text: &str) -> Option<ConfigSchema> {
+        ConfigSchema::from_macro(Path::new("main.rs"), text).unwrap()
+    }
+
+    fn from_build_script(text: &str) -> Option<ConfigSchema> {
+        ConfigSchema::from_build_script(Path::new("build.rs"), text)
+    }
+
+    fn keys(schema: &ConfigSchema) -> Vec<(&str, bool)> {
+        schema.keys.iter().map(|(k, &r)| (k.as_str(), r)).collect()
+    }
+
+    #[test]
+    fn task_config_macro() {
+        let schema = from_macro(
+            r#"
+            task_config::task_config! {
+                // A comment, with a comma
+                count: usize,
+                leds: &'static [(drv_stm32xx_sys_api::PinSet, bool)],
+                map: [(u8, u16); 3],
+            }
+            fn main() {}
+            "#,
+        )
+        .unwrap();
+        assert_eq!(
+            keys(&schema),
+            [("count", true), ("leds", true), ("map", true)]
+        );
+        assert!(!schema.optional);
+        assert_eq!(schema.unknown, UnknownKeys::Warn);
+    }
+
+    #[test]
+    fn optional_task_config_macro() {
+        let schema = from_macro("optional_task_config! { on: bool }").unwrap();
+        assert_eq!(keys(&schema), [("on", true)]);
+        assert!(schema.optional);
+    }
+
+    #[test]
+    fn no_macro() {
+        assert!(from_macro("fn main() { println!(\"hi\"); }").is_none());
+        assert!(from_macro("this isn't Rust").is_none());
+    }
+
+    #[test]
+    fn macros_in_two_files() {
+        let dir = std::env::temp_dir()
+            .join(format!("xtask-config-schema-{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("src")).unwrap();
+        std::fs::write(dir.join("src/main.rs"), "task_config! { a: u8 }")
+            .unwrap();
+        std::fs::write(dir.join("src/bsp.rs"), "task_config! { b: u8 }")
+            .unwrap();
+
+        let err = ConfigSchema::read(&dir).unwrap_err().to_string();
+        // Files are read in order, whatever order the filesystem has them in.
+        let bsp = err.find("bsp.rs").unwrap();
+        let main = err.find("main.rs").unwrap();
+        assert!(bsp < main, "{err}");
+
+        std::fs::remove_file(dir.join("src/bsp.rs")).unwrap();
+        let schema = ConfigSchema::read(&dir).unwrap().unwrap();
+        assert_eq!(keys(&schema), [("a", true)]);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn two_macros() {
+        let text = "task_config! { a: u8 } mod x { task_config! { b: u8 } }";
+        assert!(ConfigSchema::from_macro(Path::new("main.rs"), text).is_err());
+    }
+
+    #[test]
+    fn build_script_struct() {
+        let schema = from_build_script(
+            r#"
+            fn main() -> Result<()> {
+                let cfg = build_util::task_maybe_config::<Config>()?;
+                Ok(())
+            }
+
+            /// Not this one
+            struct Other {
+                nope: u32,
+            }
+
+            #[derive(Deserialize)]
+            #[serde(rename_all = "kebab-case", deny_unknown_fields)]
+            struct Config {
+                /// Doc comments are attributes too
+                on_state_change: BTreeMap<String, String>,
+                #[serde(default)]
+                allowed_callers: BTreeMap<String, Vec<String>>,
+                #[serde(default = "default_count")]
+                count: u32,
+                #[serde(rename = "type")]
+                kind: String,
+                restart_policy: Option<RestartPolicy>,
+            }
+            "#,
+        )
+        .unwrap();
+        assert_eq!(
+            keys(&schema),
+            [
+                ("allowed-callers", false),
+                ("count", false),
+                ("on-state-change", true),
+                ("restart-policy", false),
+                ("type", true),
+            ]
+        );
+        assert!(schema.optional);
+        assert_eq!(schema.unknown, UnknownKeys::Deny);
+    }
+
+    #[test]
+    fn build_script_container_default() {
+        let schema = from_build_script(
+            r#"
+            fn main() {
+                let cfg: Config = build_util::task_config::<Config>().unwrap();
+            }
+            #[derive(Deserialize, Default)]
+            #[serde(default)]
+            struct Config {
+                a: u32,
+                b_c: u32,
+            }
+            "#,
+        )
+        .unwrap();
+        assert_eq!(keys(&schema), [("a", false), ("b_c", false)]);
+        assert!(!schema.optional);
+        assert_eq!(schema.unknown, UnknownKeys::Allow);
+    }
+
+    #[test]
+    fn build_script_unfollowable() {
+        // Flattened fields could be anything.
+        assert!(from_build_script(
+            r#"
+            fn main() { build_util::task_config::<Config>(); }
+            struct Config {
+                #[serde(flatten)]
+                rest: Other,
+            }
+            "#,
+        )
+        .is_none());
+        // So could a type defined elsewhere.
+        assert!(from_build_script(
+            "fn main() { build_util::task_config::<other::Config>(); }"
+        )
+        .is_none());
+        // And we only know kebab-case.
+        assert!(from_build_script(
+            r#"
+            fn main() { build_util::task_config::<Config>(); }
+            #[serde(rename_all = "camelCase")]
+            struct Config {
+                a_b: u32,
+            }
+            "#,
+        )
+        .is_none());
+    }
+}
@@ -0,0 +1,685 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Comparing two build archives.
+//!
+//! Everything here works from the archives alone (as written by
+//! `build_archive`), so the two images can come from different commits, or
+//! from releases that were never built on this machine.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs::File;
+use std::io::{Read, Seek};
+use std::path::Path;
+
+use anyhow::{anyhow, bail, Context, Result};
+use goblin::Object;
+use indexmap::IndexMap;
+use serde::Serialize;
+
+use crate::config::Output;
+
+/// The parts of a build archive that we compare.
+struct ArchiveInfo {
+    /// `app.toml`, flattened into dotted keys
+    config: BTreeMap<String, String>,
+    /// `memory.toml`, flattened into dotted keys
+    memory: BTreeMap<String, String>,
+    image_id: Option<u64>,
+    /// Caboose contents, by tag; `None` if the image has no caboose
+    caboose: Option<BTreeMap<String, String>>,
+    /// Layout of each task (and the kernel), by name
+    tasks: BTreeMap<String, TaskLayout>,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+struct TaskLayout {
+    /// Space occupied in each memory, by memory name
+    regions: BTreeMap<String, Placement>,
+    /// Allocated sections, by section name
+    sections: BTreeMap<String, Placement>,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize)]
+struct Placement {
+    address: u64,
+    size: u64,
+}
+
+/// A value that differs between archive `a` and archive `b`; `None` means it's
+/// absent from that archive.
+#[derive(Debug, Serialize)]
+struct Change<T> {
+    a: Option<T>,
+    b: Option<T>,
+}
+
+#[derive(Debug, Default, Serialize)]
+struct ArchiveDiff {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    image_id: Option<Change<String>>,
+    config: BTreeMap<String, Change<String>>,
+    memory: BTreeMap<String, Change<String>>,
+    caboose: BTreeMap<String, Change<String>>,
+    tasks: BTreeMap<String, TaskDiff>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "lowercase")]
+enum TaskStatus {
+    Added,
+    Removed,
+    Changed,
+}
+
+#[derive(Debug, Serialize)]
+struct TaskDiff {
+    status: TaskStatus,
+    regions: BTreeMap<String, Change<Placement>>,
+    sections: BTreeMap<String, Change<Placement>>,
+}
+
+impl ArchiveDiff {
+    fn is_empty(&self) -> bool {
+        self.image_id.is_none()
+            && self.config.is_empty()
+            && self.memory.is_empty()
+            && self.caboose.is_empty()
+            && self.tasks.is_empty()
+    }
+}
+
+/// Compares the build archives at `a` and `b`, printing the differences as
+/// text or JSON.
+pub fn run(a: &Path, b: &Path, json: bool) -> Result<()> {
+    let a_info = ArchiveInfo::load(a)
+        .with_context(|| format!("could not load {}", a.display()))?;
+    let b_info = ArchiveInfo::load(b)
+        .with_context(|| format!("could not load {}", b.display()))?;
+
+    let diff = diff_archives(&a_info, &b_info);
+    if json {
+        println!("{}", serde_json::to_string_pretty(&diff)?);
+    } else {
+        println!("--- {}", a.display());
+        println!("+++ {}", b.display());
+        print_diff(&diff);
+    }
+    Ok(())
+}
+
+impl ArchiveInfo {
+    fn load(path: &Path) -> Result<Self> {
+        Self::from_zip(File::open(path)?)
+    }
+
+    fn from_zip(archive: impl Read + Seek) -> Result<Self> {
+        let mut zip = zip::ZipArchive::new(archive)?;
+
+        let mut read = |name: &str| -> Result<Option<Vec<u8>>> {
+            let mut f = match zip.by_name(name) {
+                Ok(f) => f,
+                Err(zip::result::ZipError::FileNotFound) => return Ok(None),
+                Err(e) => return Err(e.into()),
+            };
+            let mut data = vec![];
+            f.read_to_end(&mut data)?;
+            Ok(Some(data))
+        };
+
+        let app_toml = read("app.toml")?
+            .ok_or_else(|| anyhow!("archive has no app.toml"))?;
+        let app_toml: toml::Value =
+            toml::from_str(std::str::from_utf8(&app_toml)?)
+                .context("could not parse app.toml")?;
+        let mut config = BTreeMap::new();
+        flatten("", &app_toml, &mut config);
+
+        let memory_toml = read("memory.toml")?
+            .ok_or_else(|| anyhow!("archive has no memory.toml"))?;
+        let memory_toml = std::str::from_utf8(&memory_toml)?;
+        let outputs: IndexMap<String, Vec<Output>> =
+            toml::from_str(memory_toml)
+                .context("could not parse memory.toml")?;
+        let mut memory = BTreeMap::new();
+        flatten("", &toml::from_str(memory_toml)?, &mut memory);
+
+        let caboose = match read("img/final.bin")? {
+            Some(bin) => read_caboose(&bin)?,
+            None => None,
+        };
+
+        let mut elves = BTreeMap::new();
+        if let Some(kernel) = read("elf/kernel")? {
+            elves.insert("kernel".to_owned(), kernel);
+        }
+        let task_names: Vec<String> = zip
+            .file_names()
+            .filter_map(|n| n.strip_prefix("elf/task/"))
+            .map(str::to_owned)
+            .collect();
+        for name in task_names {
+            let mut f = zip.by_name(&format!("elf/task/{name}"))?;
+            let mut data = vec![];
+            f.read_to_end(&mut data)?;
+            elves.insert(name, data);
+        }
+
+        let mut image_id = None;
+        let mut tasks = BTreeMap::new();
+        for (name, data) in &elves {
+            let elf = match Object::parse(data)? {
+                Object::Elf(elf) => elf,
+                o => bail!("{name}: invalid object {o:?}"),
+            };
+            if name == "kernel" {
+                image_id = read_image_id(&elf, data);
+            }
+            tasks.insert(name.clone(), TaskLayout::new(&elf, &outputs));
+        }
+
+        Ok(Self {
+            config,
+            memory,
+            image_id,
+            caboose,
+            tasks,
+        })
+    }
+}
+
+impl TaskLayout {
+    fn new(
+        elf: &goblin::elf::Elf,
+        outputs: &IndexMap<String, Vec<Output>>,
+    ) -> Self {
+        let memory_for = |addr: u64| {
+            outputs.iter().find_map(|(name, out)| {
+                out.iter()
+                    .any(|o| {
+                        addr >= u64::from(o.address)
+                            && addr < u64::from(o.address) + u64::from(o.size)
+                    })
+                    .then(|| name.clone())
+            })
+        };
+
+        // As in `load_task_size`, track the bounds of what's loaded into each
+        // memory rather than adding up sizes, so alignment gaps are counted.
+        let mut bounds: BTreeMap<String, (u64, u64)> = BTreeMap::new();
+        let mut record = |start: u64, size: u64| {
+            if let Some(mem) = memory_for(start) {
+                let b = bounds.entry(mem).or_insert((start, start + size));
+                b.0 = b.0.min(start);
+                b.1 = b.1.max(start + size);
+            }
+        };
+        for phdr in &elf.program_headers {
+            if phdr.p_type != goblin::elf::program_header::PT_LOAD {
+                continue;
+            }
+            record(phdr.p_vaddr, phdr.p_memsz);
+            if phdr.p_vaddr != phdr.p_paddr {
+                record(phdr.p_paddr, phdr.p_filesz);
+            }
+        }
+        let regions = bounds
+            .into_iter()
+            .map(|(mem, (start, end))| {
+                (
+                    mem,
+                    Placement {
+                        address: start,
+                        size: end - start,
+                    },
+                )
+            })
+            .collect();
+
+        let mut sections = BTreeMap::new();
+        for sh in &elf.section_headers {
+            if sh.sh_flags & u64::from(goblin::elf::section_header::SHF_ALLOC)
+                == 0
+                || sh.sh_size == 0
+            {
+                continue;
+            }
+            if let Some(name) = elf.shdr_strtab.get_at(sh.sh_name) {
+                sections.insert(
+                    name.to_owned(),
+                    Placement {
+                        address: sh.sh_addr,
+                        size: sh.sh_size,
+                    },
+                );
+            }
+        }
+
+        Self { regions, sections }
+    }
+}
+
+/// Flattens a TOML value into `out`, with nested keys joined by dots. Arrays
+/// are kept whole, since their elements don't have stable names.
+fn flatten(
+    prefix: &str,
+    value: &toml::Value,
+    out: &mut BTreeMap<String, String>,
+) {
+    match value {
+        toml::Value::Table(t) => {
+            for (k, v) in t {
+                let key = if prefix.is_empty() {
+                    k.clone()
+                } else {
+                    format!("{prefix}.{k}")
+                };
+                flatten(&key, v, out);
+            }
+        }
+        v => {
+            out.insert(prefix.to_owned(), v.to_string());
+        }
+    }
+}
+
+/// Reads the `HUBRIS_IMAGE_ID` static out of the kernel.
+fn read_image_id(elf: &goblin::elf::Elf, data: &[u8]) -> Option<u64> {
+    let sym = elf.syms.iter().find(|sym| {
+        elf.strtab.get_at(sym.st_name) == Some("HUBRIS_IMAGE_ID")
+    })?;
+    let offset =
+        crate::elf::get_file_offset_by_vma(elf, sym.st_value).ok()? as usize;
+    let bytes = data.get(offset..offset + 8)?;
+    Some(u64::from_le_bytes(bytes.try_into().unwrap()))
+}
+
+/// Decodes the caboose at the end of the image, if there is one.
+///
+/// The caboose is laid out as `[CABOOSE_MAGIC, TLV-C data..., length]`, where
+/// `length` covers the whole thing; see `package` in `dist.rs`.
+fn read_caboose(bin: &[u8]) -> Result<Option<BTreeMap<String, String>>> {
+    let word = |offset: usize| {
+        u32::from_le_bytes(bin[offset..offset + 4].try_into().unwrap())
+    };
+    if bin.len() < 8 {
+        return Ok(None);
+    }
+    let len = word(bin.len() - 4) as usize;
+    if len < 8 || len > bin.len() {
+        return Ok(None);
+    }
+    let start = bin.len() - len;
+    if word(start) != abi::CABOOSE_MAGIC {
+        return Ok(None);
+    }
+
+    let mut out = BTreeMap::new();
+    let body = &bin[start + 4..bin.len() - 4];
+    let mut reader = tlvc::TlvcReader::begin(body)
+        .map_err(|e| anyhow!("bad caboose: {e:?}"))?;
+    // An unwritten caboose is all 0xFF, which the reader sees as the end.
+    while let Ok(Some(chunk)) = reader.next() {
+        let tag = chunk.header().tag;
+        let mut value = vec![0; chunk.header().len.get() as usize];
+        chunk
+            .read_exact(0, &mut value)
+            .map_err(|e| anyhow!("bad caboose: {e:?}"))?;
+        let value = match std::str::from_utf8(&value) {
+            Ok(s) => format!("{s:?}"),
+            Err(_) => hex::encode(&value),
+        };
+        out.insert(String::from_utf8_lossy(&tag).into_owned(), value);
+    }
+    Ok(Some(out))
+}
+
+/// Returns the entries that differ between `a` and `b`.
+fn diff_maps<T: Clone + PartialEq>(
+    a: &BTreeMap<String, T>,
+    b: &BTreeMap<String, T>,
+) -> BTreeMap<String, Change<T>> {
+    let keys: BTreeSet<&String> = a.keys().chain(b.keys()).collect();
+    keys.into_iter()
+        .filter_map(|k| {
+            let (a, b) = (a.get(k), b.get(k));
+            (a != b).then(|| {
+                (
+                    k.clone(),
+                    Change {
+                        a: a.cloned(),
+                        b: b.cloned(),
+                    },
+                )
+            })
+        })
+        .collect()
+}
+
+fn diff_archives(a: &ArchiveInfo, b: &ArchiveInfo) -> ArchiveDiff {
+    let mut out = ArchiveDiff {
+        config: diff_maps(&a.config, &b.config),
+        memory: diff_maps(&a.memory, &b.memory),
+        ..Default::default()
+    };
+
+    if a.image_id != b.image_id {
+        let hex = |id: Option<u64>| id.map(|id| format!("{id:#018x}"));
+        out.image_id = Some(Change {
+            a: hex(a.image_id),
+            b: hex(b.image_id),
+        });
+    }
+
+    let empty = BTreeMap::new();
+    out.caboose = diff_maps(
+        a.caboose.as_ref().unwrap_or(&empty),
+        b.caboose.as_ref().unwrap_or(&empty),
+    );
+
+    let names: BTreeSet<&String> =
+        a.tasks.keys().chain(b.tasks.keys()).collect();
+    for name in names {
+        let (ta, tb) = (a.tasks.get(name), b.tasks.get(name));
+        if ta == tb {
+            continue;
+        }
+        let status = match (ta, tb) {
+            (None, _) => TaskStatus::Added,
+            (_, None) => TaskStatus::Removed,
+            _ => TaskStatus::Changed,
+        };
+        let regions = |t: Option<&TaskLayout>| {
+            t.map(|t| t.regions.clone()).unwrap_or_default()
+        };
+        let sections = |t: Option<&TaskLayout>| {
+            t.map(|t| t.sections.clone()).unwrap_or_default()
+        };
+        out.tasks.insert(
+            name.clone(),
+            TaskDiff {
+                status,
+                regions: diff_maps(&regions(ta), &regions(tb)),
+                sections: diff_maps(&sections(ta), &sections(tb)),
+            },
+        );
+    }
+
+    out
+}
+
+fn print_diff(diff: &ArchiveDiff) {
+    if diff.is_empty() {
+        println!("archives are equivalent");
+        return;
+    }
+
+    let show = |v: &Option<String>| match v {
+        Some(v) => v.clone(),
+        None => "(absent)".to_owned(),
+    };
+    let print_values = |title: &str, map: &BTreeMap<String, Change<String>>| {
+        if map.is_empty() {
+            return;
+        }
+        println!("{title}:");
+        for (k, c) in map {
+            println!("  {k}: {} -> {}", show(&c.a), show(&c.b));
+        }
+    };
+
+    if let Some(c) = &diff.image_id {
+        println!("image id: {} -> {}", show(&c.a), show(&c.b));
+    }
+    print_values("config", &diff.config);
+    print_values("memory map", &diff.memory);
+    print_values("caboose", &diff.caboose);
+
+    if diff.tasks.is_empty() {
+        return;
+    }
+    println!("tasks:");
+    for (name, t) in &diff.tasks {
+        let mark = match t.status {
+            TaskStatus::Added => '+',
+            TaskStatus::Removed => '-',
+            TaskStatus::Changed => '~',
+        };
+        println!("  {mark} {name}");
+        for (mem, c) in &t.regions {
+            println!("      {mem:<12} {}", describe(c));
+        }
+        // Sections are only interesting when the task is in both images;
+        // otherwise the regions say it all.
+        if let TaskStatus::Changed = t.status {
+            for (sec, c) in &t.sections {
+                println!("      {sec:<12} {}", describe(c));
+            }
+        }
+    }
+}
+
+/// Describes a change in placement, e.g. `0x8000..0x8400 -> 0x8000..0x8480
+/// (+128 bytes)`.
+fn describe(c: &Change<Placement>) -> String {
+    let range = |p: &Option<Placement>| match p {
+        Some(p) => format!("{:#x}..{:#x}", p.address, p.address + p.size),
+        None => "(absent)".to_owned(),
+    };
+    let size = |p: &Option<Placement>| p.map(|p| p.size as i64).unwrap_or(0);
+    let delta = size(&c.b) - size(&c.a);
+    if delta == 0 {
+        format!("{} -> {}", range(&c.a), range(&c.b))
+    } else {
+        format!("{} -> {} ({delta:+} bytes)", range(&c.a), range(&c.b))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Cursor, Write};
+
+    const APP_TOML: &str = r#"
+        name = "demo"
+        [kernel]
+        features = ["dump"]
+        [tasks.idle]
+        priority = 5
+        [tasks.idle.config]
+        count = 1
+    "#;
+
+    const MEMORY_TOML: &str = r#"
+        [[flash]]
+        address = 0x08000000
+        size = 0x10000
+        read = true
+        execute = true
+    "#;
+
+    /// `memory.toml`'s `flash` array, as `flatten` shows it.
+    const MEMORY_TOML_FLASH: &str =
+        "[{ address = 134217728, execute = true, read = true, size = 65536 }]";
+
+    fn zip(files: &[(&str, &[u8])]) -> Cursor<Vec<u8>> {
+        let mut zip = zip::ZipWriter::new(Cursor::new(vec![]));
+        for (name, data) in files {
+            zip.start_file(*name, zip::write::FileOptions::default())
+                .unwrap();
+            zip.write_all(data).unwrap();
+        }
+        zip.finish().unwrap()
+    }
+
+    /// Builds an image of `len` bytes ending in a `size`-byte caboose holding
+    /// `chunks`, laid out as `package` does it.
+    fn image_with_caboose(len: usize, size: usize, chunks: &[u8]) -> Vec<u8> {
+        let mut caboose = vec![0xff; size];
+        caboose[..4].copy_from_slice(&abi::CABOOSE_MAGIC.to_le_bytes());
+        caboose[4..4 + chunks.len()].copy_from_slice(chunks);
+        caboose[size - 4..].copy_from_slice(&(size as u32).to_le_bytes());
+
+        let mut image = vec![0; len - size];
+        image.extend(caboose);
+        image
+    }
+
+    fn caboose_chunks() -> Vec<u8> {
+        let chunk = |tag: &[u8; 4], value: &[u8]| {
+            tlvc_text::Piece::Chunk(
+                tlvc_text::Tag::new(*tag),
+                vec![tlvc_text::Piece::Bytes(value.to_vec())],
+            )
+        };
+        tlvc_text::pack(&[
+            chunk(b"GITC", b"abc123"),
+            chunk(b"SIGN", &[0, 0xff]),
+        ])
+    }
+
+    fn layout(regions: &[(&str, u64, u64)]) -> TaskLayout {
+        TaskLayout {
+            regions: regions
+                .iter()
+                .map(|&(mem, address, size)| {
+                    (mem.to_owned(), Placement { address, size })
+                })
+                .collect(),
+            sections: BTreeMap::new(),
+        }
+    }
+
+    fn info(tasks: Vec<(&str, TaskLayout)>) -> ArchiveInfo {
+        ArchiveInfo {
+            config: BTreeMap::new(),
+            memory: BTreeMap::new(),
+            image_id: None,
+            caboose: None,
+            tasks: tasks
+                .into_iter()
+                .map(|(name, t)| (name.to_owned(), t))
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn flatten_tables() {
+        let value: toml::Value = toml::from_str(APP_TOML).unwrap();
+        let mut out = BTreeMap::new();
+        flatten("", &value, &mut out);
+        assert_eq!(
+            out.iter()
+                .map(|(k, v)| (k.as_str(), v.as_str()))
+                .collect::<Vec<_>>(),
+            [
+                ("kernel.features", r#"["dump"]"#),
+                ("name", r#""demo""#),
+                ("tasks.idle.config.count", "1"),
+                ("tasks.idle.priority", "5"),
+            ]
+        );
+    }
+
+    #[test]
+    fn caboose_contents() {
+        let image = image_with_caboose(1024, 256, &caboose_chunks());
+        let caboose = read_caboose(&image).unwrap().unwrap();
+        // Text is quoted, and anything else is shown in hex.
+        assert_eq!(caboose["GITC"], r#""abc123""#);
+        assert_eq!(caboose["SIGN"], "00ff");
+        assert_eq!(caboose.len(), 2);
+    }
+
+    #[test]
+    fn unwritten_caboose_is_empty() {
+        let image = image_with_caboose(1024, 256, &[]);
+        assert_eq!(read_caboose(&image).unwrap(), Some(BTreeMap::new()));
+    }
+
+    #[test]
+    fn no_caboose() {
+        assert_eq!(read_caboose(&[0; 4]).unwrap(), None);
+        // A plausible length, but no magic.
+        let mut image = vec![0; 1024];
+        image[1020..].copy_from_slice(&256u32.to_le_bytes());
+        assert_eq!(read_caboose(&image).unwrap(), None);
+        // A length longer than the image.
+        image[1020..].copy_from_slice(&2048u32.to_le_bytes());
+        assert_eq!(read_caboose(&image).unwrap(), None);
+    }
+
+    #[test]
+    fn load_archive() {
+        let image = image_with_caboose(1024, 256, &caboose_chunks());
+        let archive = zip(&[
+            ("app.toml", APP_TOML.as_bytes()),
+            ("memory.toml", MEMORY_TOML.as_bytes()),
+            ("img/final.bin", &image),
+        ]);
+        let info = ArchiveInfo::from_zip(archive).unwrap();
+
+        assert_eq!(info.config["tasks.idle.priority"], "5");
+        assert_eq!(info.memory["flash"], MEMORY_TOML_FLASH);
+        assert_eq!(info.caboose.unwrap().len(), 2);
+        assert_eq!(info.image_id, None);
+        assert!(info.tasks.is_empty());
+    }
+
+    #[test]
+    fn load_archive_needs_config() {
+        let archive = zip(&[("memory.toml", MEMORY_TOML.as_bytes())]);
+        assert!(ArchiveInfo::from_zip(archive).is_err());
+    }
+
+    #[test]
+    fn same_archives_no_diff() {
+        let a = info(vec![("idle", layout(&[("flash", 0x8000, 0x100)]))]);
+        let b = info(vec![("idle", layout(&[("flash", 0x8000, 0x100)]))]);
+        assert!(diff_archives(&a, &b).is_empty());
+    }
+
+    #[test]
+    fn diff_tasks_and_values() {
+        let mut a = info(vec![
+            ("idle", layout(&[("flash", 0x8000, 0x100)])),
+            ("old", layout(&[("flash", 0x8100, 0x100)])),
+            ("same", layout(&[("ram", 0x2000, 0x400)])),
+        ]);
+        let mut b = info(vec![
+            ("idle", layout(&[("flash", 0x8000, 0x180)])),
+            ("new", layout(&[("flash", 0x8200, 0x100)])),
+            ("same", layout(&[("ram", 0x2000, 0x400)])),
+        ]);
+        a.config.insert("name".to_owned(), "a".to_owned());
+        b.config.insert("name".to_owned(), "b".to_owned());
+        b.caboose = Some([("GITC".to_owned(), "x".to_owned())].into());
+        a.image_id = Some(1);
+
+        let diff = diff_archives(&a, &b);
+        let image_id = diff.image_id.unwrap();
+        assert_eq!(image_id.a.as_deref(), Some("0x0000000000000001"));
+        assert_eq!(image_id.b, None);
+        assert_eq!(diff.config["name"].b.as_deref(), Some("b"));
+        assert!(diff.memory.is_empty());
+        // No caboose at all looks like an empty one.
+        assert_eq!(diff.caboose["GITC"].a, None);
+
+        assert_eq!(
+            diff.tasks.keys().map(String::as_str).collect::<Vec<_>>(),
+            ["idle", "new", "old"]
+        );
+        assert!(matches!(diff.tasks["idle"].status, TaskStatus::Changed));
+        assert!(matches!(diff.tasks["new"].status, TaskStatus::Added));
+        assert!(matches!(diff.tasks["old"].status, TaskStatus::Removed));
+        assert_eq!(
+            describe(&diff.tasks["idle"].regions["flash"]),
+            "0x8000..0x8100 -> 0x8000..0x8180 (+128 bytes)"
+        );
+        assert_eq!(
+            describe(&diff.tasks["old"].regions["flash"]),
+            "0x8100..0x8200 -> (absent) (-256 bytes)"
+        );
+    }
+}
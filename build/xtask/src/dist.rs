@@ -23,8 +23,10 @@ use zerocopy::AsBytes;
 
 use crate::{
     caboose_pos,
-    config::{BuildConfig, CabooseConfig, Config},
-    elf,
+    config::{BuildConfig, CabooseConfig, Config, SharedRegion},
+    elf, ipc,
+    manifest::{self, BuildManifest},
+    ringbuf_export,
     sizes::load_task_size,
     task_slot,
 };
@@ -73,7 +75,14 @@ pub struct PackageConfig {
     sysroot: PathBuf,
 
     /// Host triple, e.g. `aarch64-apple-darwin`
-    host_triple: String,
+    pub host_triple: String,
+
+    /// Toolchain version, as reported by `rustc -V`
+    pub toolchain: String,
+
+    /// In a reproducible build, the commit timestamp of `HEAD`, which we
+    /// hand to build scripts in place of the current time.
+    source_date_epoch: Option<u64>,
 
     /// List of paths to be remapped by the compiler, to minimize strings in
     /// the resulting binaries.
@@ -90,6 +99,7 @@ impl PackageConfig {
         app_toml_file: &Path,
         verbose: bool,
         edges: bool,
+        reproducible: bool,
     ) -> Result<Self> {
         let toml = Config::from_file(app_toml_file)?;
         let dist_dir = Path::new("target").join(&toml.name).join("dist");
@@ -113,11 +123,29 @@ impl PackageConfig {
         if !host.status.success() {
             bail!("Could not execute rustc to get host");
         }
-        let host_triple = std::str::from_utf8(&host.stdout)?
+        let host = std::str::from_utf8(&host.stdout)?;
+        let host_triple = host
             .lines()
             .find_map(|line| line.strip_prefix("host: "))
             .ok_or_else(|| anyhow!("Could not get host from rustc"))?
             .to_string();
+        let toolchain = host
+            .lines()
+            .next()
+            .ok_or_else(|| anyhow!("Could not get version from rustc"))?
+            .to_string();
+
+        let source_date_epoch = if reproducible {
+            let out = Command::new("git")
+                .args(["log", "-1", "--format=%ct", "HEAD"])
+                .output()?;
+            if !out.status.success() {
+                bail!("Could not get commit time from git");
+            }
+            Some(std::str::from_utf8(&out.stdout)?.trim().parse()?)
+        } else {
+            None
+        };
 
         let mut extra_hash = fnv::FnvHasher::default();
         for f in ["task-link.x", "task-rlink.x", "kernel-link.x"] {
@@ -133,7 +161,9 @@ impl PackageConfig {
             dist_dir,
             sysroot,
             host_triple,
-            remap_paths: Self::remap_paths()?,
+            toolchain,
+            source_date_epoch,
+            remap_paths: Self::remap_paths(reproducible)?,
             link_script_hash: extra_hash.finish(),
         })
     }
@@ -150,7 +180,14 @@ impl PackageConfig {
         self.dist_dir.join(name)
     }
 
-    fn remap_paths() -> Result<BTreeMap<PathBuf, &'static str>> {
+    /// Checks whether we're doing a `--reproducible` build.
+    pub fn reproducible(&self) -> bool {
+        self.source_date_epoch.is_some()
+    }
+
+    fn remap_paths(
+        reproducible: bool,
+    ) -> Result<BTreeMap<PathBuf, &'static str>> {
         // Panic messages in crates have a long prefix; we'll shorten it using
         // the --remap-path-prefix argument to reduce message size.  We'll remap
         // local (Hubris) crates to /hubris, crates.io to /crates.io, and git
@@ -163,6 +200,15 @@ impl PackageConfig {
         // `dunce::canonicalize` instead
         if let Ok(home) = std::env::var("CARGO_HOME") {
             let cargo_home = dunce::canonicalize(home)?;
+
+            // The registry path below is only a guess, so a reproducible
+            // build also remaps the whole of CARGO_HOME. rustc applies the
+            // last matching prefix, and this sorts before the more specific
+            // ones, so they still win where they apply.
+            if reproducible {
+                remap_paths.insert(cargo_home.clone(), "/cargo");
+            }
+
             let cargo_git = cargo_home.join("git").join("checkouts");
             remap_paths.insert(cargo_git, "/git");
 
@@ -216,8 +262,21 @@ pub fn package(
     app_toml: &Path,
     tasks_to_build: Option<Vec<String>>,
     dirty_ok: bool,
+    reproducible: bool,
 ) -> Result<BTreeMap<String, AllocationMap>> {
-    let cfg = PackageConfig::new(app_toml, verbose, edges)?;
+    let cfg = PackageConfig::new(app_toml, verbose, edges, reproducible)?;
+
+    // We need to find tasks' crates, both to check their config blocks and to
+    // copy files into the archive. This takes on the order of ~150 ms, so we
+    // only do it once.
+    let metadata = cargo_metadata::MetadataCommand::new()
+        .manifest_path("./Cargo.toml")
+        .no_deps()
+        .exec()?;
+
+    // Catch mistakes in the app.toml here, where we can say which line they're
+    // on, rather than in some task's build script.
+    cfg.toml.validate(&metadata)?;
 
     // Verify that our dump configuration is correct (or absent)
     check_dump_config(&cfg.toml)?;
@@ -231,6 +290,8 @@ pub fn package(
         } else {
             assert!(!cfg.toml.tasks.contains_key("kernel"));
             check_task_priorities(&cfg.toml)?;
+            check_task_security(&cfg.toml)?;
+            ipc::check(&cfg.toml)?;
             (
                 false,
                 cfg.toml
@@ -243,10 +304,22 @@ pub fn package(
         };
 
     std::fs::create_dir_all(&cfg.dist_dir)?;
-    if dirty_ok {
+    if cfg.reproducible() {
+        // A reproducible build has to come from a commit, not from whatever
+        // happens to be in the working tree, and mustn't pick up artifacts
+        // built any other way.
+        let (git_rev, git_dirty) = get_git_status()?;
+        if git_dirty {
+            bail!("reproducible builds require a clean checkout");
+        }
+        println!("note: doing a clean, reproducible build of {git_rev}");
+        clean_all(&cfg.toml)?;
+    } else if dirty_ok {
         println!("note: not doing a clean build because you asked for it");
-    } else {
-        check_rebuild(&cfg.toml)?;
+    }
+    let check_stamps = !dirty_ok && !cfg.reproducible();
+    if check_stamps && tasks_to_build.contains("kernel") {
+        check_rebuild(&cfg, "kernel")?;
     }
 
     // Build all tasks (which are relocatable executables, so they are not
@@ -255,6 +328,9 @@ pub fn package(
     // build changed.
     for name in cfg.toml.tasks.keys() {
         if tasks_to_build.contains(name.as_str()) {
+            if check_stamps {
+                check_rebuild(&cfg, name)?;
+            }
             build_task(&cfg, name)?;
         }
     }
@@ -323,9 +399,9 @@ pub fn package(
             .keys()
             .map(|name| {
                 let ep = if tasks_to_build.contains(name.as_str()) {
-                    // Link tasks regardless of whether they have changed,
-                    // because we don't want to track changes in the other
-                    // linker input (task-link.x, memory.x, table.ld, etc)
+                    // `link` skips tasks whose linker inputs haven't changed,
+                    // but this always gives us a fresh copy of the linked
+                    // task for the patching below.
                     link_task(&cfg, name, image_name, allocs)?;
                     task_entry_point(&cfg, name, image_name)
                 } else {
@@ -343,6 +419,10 @@ pub fn package(
             }
         }
 
+        // Tell the supervisor where everyone's ring buffers are, if it wants
+        // to know.
+        resolve_ringbuf_exports(&cfg, image_name, &tasks_to_build)?;
+
         // Add an empty output section for the caboose
         //
         // This has to be done before building the kernel, because the caboose
@@ -397,8 +477,9 @@ pub fn package(
             }
         }
 
-        // Now that we've resolved the task slots and caboose position, we're
-        // done making low-level modifications to ELF files on disk.  We'll load
+        // Now that we've resolved the task slots, ring buffer exports and
+        // caboose position, we're done making low-level modifications to ELF
+        // files on disk.  We'll load
         // all of their data into our `all_output_sections` variable, which is
         // used as the source of truth for the final (combined) files.
         for task_name in cfg.toml.tasks.keys() {
@@ -454,6 +535,20 @@ pub fn package(
                 println!("{size:#x} ({percent}%)");
             }
         }
+        if cfg.toml.mpu_split_granule().is_some() {
+            println!("MPU alignment waste (single regions -> as packed):");
+            for (name, waste) in &allocs.waste {
+                let before = match waste.unsplit {
+                    Some(b) => format!("{b:#x}"),
+                    None => "doesn't fit".to_string(),
+                };
+                println!(
+                    "  {:<8} {before} -> {:#x}",
+                    format!("{name}:"),
+                    waste.chosen
+                );
+            }
+        }
 
         // Generate a RawHubrisImage, which is our source of truth for combined
         // images and is used to generate all outputs.
@@ -478,7 +573,8 @@ pub fn package(
         .context("constructing image from segments with hubtools")?;
 
         write_gdb_script(&cfg, image_name)?;
-        let archive_name = build_archive(&cfg, image_name, raw_image)?;
+        let archive_name =
+            build_archive(&cfg, image_name, allocs, raw_image, &metadata)?;
 
         // Post-build modifications: populate a default caboose if requested
         if let Some(caboose) = &cfg.toml.caboose {
@@ -635,6 +731,12 @@ fn write_gdb_script(cfg: &PackageConfig, image_name: &str) -> Result<()> {
             cfg.dist_file(name).to_slash().unwrap()
         )?;
     }
+    // Substitutions point at directories on the build machine, which would
+    // make the archived copy of this script differ from one machine to the
+    // next.
+    if cfg.reproducible() {
+        return Ok(());
+    }
     for (path, remap) in &cfg.remap_paths {
         let mut path_str = path
             .to_str()
@@ -654,7 +756,9 @@ fn write_gdb_script(cfg: &PackageConfig, image_name: &str) -> Result<()> {
 fn build_archive(
     cfg: &PackageConfig,
     image_name: &str,
+    allocs: &Allocations,
     raw_image: hubtools::RawHubrisImage,
+    metadata: &cargo_metadata::Metadata,
 ) -> Result<PathBuf> {
     // Bundle everything up into an archive.
     let archive_path =
@@ -672,10 +776,16 @@ fn build_archive(
         - elf/tasks/ contains each task by name.\n\
         - elf/kernel is the kernel.\n\
         - img/ contains the final firmware images.\n\
-        - debug/ contains OpenOCD and GDB scripts, if available.\n",
+        - debug/ contains OpenOCD and GDB scripts, if available.\n\
+        - image.json describes the tasks, memory layout, interrupts, sockets\n  \
+          and I2C devices, for use by other tools.\n\
+        - manifest.json records the toolchain, dependencies and features used,\n  \
+          and hashes of the files above.\n",
     )?;
 
     let (git_rev, git_dirty) = get_git_status()?;
+    let mut manifest =
+        BuildManifest::new(cfg, image_name, &git_rev, git_dirty)?;
     archive
         .text(
             "git-rev",
@@ -701,6 +811,15 @@ fn build_archive(
         )
         .context("could not write memory.toml")?;
 
+    let description =
+        crate::print::describe(&cfg.toml, image_name, Some(allocs))?;
+    archive
+        .text(
+            crate::print::DESCRIPTION_NAME,
+            serde_json::to_string_pretty(&description)?,
+        )
+        .context("could not write image description")?;
+
     let elf_dir = PathBuf::from("elf");
     let tasks_dir = elf_dir.join("task");
     for name in cfg.toml.tasks.keys() {
@@ -753,8 +872,6 @@ fn build_archive(
     archive
         .copy(chip_dir.join("openocd.gdb"), debug_dir.join("openocd.gdb"))?;
 
-    let mut metadata = None;
-
     //
     // Iterate over tasks looking for elements that should be copied into
     // the archive.  These are specified by the "copy-to-archive" array,
@@ -776,24 +893,11 @@ fn build_archive(
                 Some(config) => match config.get(c) {
                     Some(ordered_toml::Value::String(s)) => {
                         //
-                        // This is a bit of a heavy hammer:  we need the
-                        // directory name for the task to find the file to be
-                        // copied into the archive, so we're going to iterate
-                        // over all packages to find the crate assocated with
-                        // this task.  (We cache the metadata itself, as it
-                        // takes on the order of ~150 ms to gather.)
+                        // We need the directory name for the task to find
+                        // the file to be copied into the archive, so we're
+                        // going to iterate over all packages to find the
+                        // crate assocated with this task.
                         //
-                        use cargo_metadata::MetadataCommand;
-                        let metadata = match metadata.as_ref() {
-                            Some(m) => m,
-                            None => {
-                                let d = MetadataCommand::new()
-                                    .manifest_path("./Cargo.toml")
-                                    .exec()?;
-                                metadata.get_or_insert(d)
-                            }
-                        };
-
                         let pkg = metadata
                             .packages
                             .iter()
@@ -829,6 +933,12 @@ fn build_archive(
         }
     }
 
+    manifest.files = archive.hashes.clone();
+    archive.text(
+        manifest::MANIFEST_NAME,
+        serde_json::to_string_pretty(&manifest)?,
+    )?;
+
     archive.finish()?;
     Ok(archive_path)
 }
@@ -854,54 +964,78 @@ fn check_task_names(toml: &Config, task_names: &[String]) -> Result<()> {
     Ok(())
 }
 
-/// Checks the buildstamp file and runs `cargo clean` if invalid
-fn check_rebuild(toml: &Config) -> Result<()> {
-    let buildstamp_file = Path::new("target").join("buildstamp");
-    let rebuild = match std::fs::read(&buildstamp_file) {
-        Ok(contents) => {
-            if let Ok(contents) = std::str::from_utf8(&contents) {
-                if let Ok(cmp) = u64::from_str_radix(contents, 16) {
-                    toml.buildhash != cmp
-                } else {
-                    println!("buildstamp file contents unknown; re-building.");
-                    true
-                }
-            } else {
-                println!("buildstamp file contents corrupt; re-building.");
-                true
-            }
-        }
-        Err(_) => {
-            println!("no buildstamp file found; re-building.");
-            true
-        }
+/// Checks the buildstamp for the crate behind `name` (a task, or `kernel`),
+/// and runs `cargo clean` on that crate if it was last built with a different
+/// configuration.
+///
+/// Cargo notices most configuration changes by itself, because build scripts
+/// read them through `build_util::env_var`; this is the backstop for the ones
+/// that slip through. Stamps are kept per crate and set of features rather
+/// than per app, because that's how Cargo keeps its build products: an app
+/// can build the same crate twice with different features, and the two
+/// builds mustn't be mistaken for one another.
+fn check_rebuild(cfg: &PackageConfig, name: &str) -> Result<()> {
+    let build_config = if name == "kernel" {
+        cfg.toml.kernel_build_config(false, &[], None)
+    } else {
+        cfg.toml
+            .task_build_config(name, false, None)
+            .map_err(|e| anyhow!(e))?
     };
-    // if we need to rebuild, we should clean everything before we start building
-    if rebuild {
-        println!("app.toml has changed; rebuilding all tasks");
-        let mut names = vec![toml.kernel.name.as_str()];
-        for name in toml.tasks.keys() {
-            // This may feel redundant: don't we already have the name?
-            // Well, consider our supervisor:
-            //
-            // [tasks.jefe]
-            // name = "task-jefe"
-            //
-            // The "name" in the key is `jefe`, but the package (crate)
-            // name is in `tasks.jefe.name`, and that's what we need to
-            // give to `cargo`.
-            names.push(toml.tasks[name].name.as_str());
+
+    // The arguments carry the features and the target, so they pick out
+    // which of the crate's builds this is.
+    let mut hasher = fnv::FnvHasher::default();
+    build_config.args.hash(&mut hasher);
+    let key = format!("{}.{:x}", build_config.crate_name, hasher.finish());
+
+    let mut hasher = fnv::FnvHasher::default();
+    for (var, value) in &build_config.env {
+        // These describe every task in the image, so including them would
+        // mean any change to one task cleans all the others. The build scripts
+        // that use them already tell Cargo to watch them.
+        if var != "HUBRIS_TASKS" && var != "HUBRIS_ALL_TASK_CONFIGS" {
+            (var, value).hash(&mut hasher);
         }
-        cargo_clean(&names, &toml.target)?;
+    }
+    let stamp = format!("{:x}", hasher.finish());
+
+    let stamp_dir = Path::new("target").join("buildstamps");
+    std::fs::create_dir_all(&stamp_dir)?;
+    let stamp_file = stamp_dir.join(key);
+    if std::fs::read_to_string(&stamp_file).ok().as_deref()
+        != Some(stamp.as_str())
+    {
+        println!("{name}: configuration has changed; rebuilding");
+        cargo_clean(&[&build_config.crate_name], &cfg.toml.target)?;
     }
 
-    // now that we're clean, update our buildstamp file; any failure to build
+    // now that we're clean, update the buildstamp file; any failure to build
     // from here on need not trigger a clean
-    std::fs::write(&buildstamp_file, format!("{:x}", toml.buildhash))?;
+    std::fs::write(&stamp_file, stamp)?;
 
     Ok(())
 }
 
+/// Runs `cargo clean` on the kernel and every task
+fn clean_all(toml: &Config) -> Result<()> {
+    let mut names = vec![toml.kernel.name.as_str()];
+    for name in toml.tasks.keys() {
+        // This may feel redundant: don't we already have the name?
+        // Well, consider our supervisor:
+        //
+        // [tasks.jefe]
+        // name = "task-jefe"
+        //
+        // The "name" in the key is `jefe`, but the package (crate)
+        // name is in `tasks.jefe.name`, and that's what we need to
+        // give to `cargo`.
+        names.push(toml.tasks[name].name.as_str());
+    }
+    cargo_clean(&names, &toml.target)?;
+    Ok(())
+}
+
 #[derive(Debug, Hash)]
 struct LoadSegment {
     source_file: PathBuf,
@@ -940,7 +1074,12 @@ fn link_task(
     println!("linking task '{}'", name);
     let task_toml = &cfg.toml.tasks[name];
 
-    let extern_regions = cfg.toml.extern_regions_for(name, image_name)?;
+    // Shared regions show up to the task just like extern regions do.
+    let mut extern_regions = cfg.toml.extern_regions_for(name, image_name)?;
+    for (region, _, _) in cfg.toml.shared_regions_for(name) {
+        extern_regions
+            .insert(region.to_owned(), allocs.shared[region].1.clone());
+    }
     generate_task_linker_script(
         "memory.x",
         &allocs.tasks[name],
@@ -955,12 +1094,20 @@ fn link_task(
     .context(format!("failed to generate linker script for {}", name))?;
     fs::copy("build/task-link.x", "target/link.x")?;
 
-    // Link the static archive
+    // Link the static archive. This output is kept as the linker left it, so
+    // that an unchanged task needn't be linked again; the task's image is a
+    // copy of it that we then patch (task slots, caboose position, etc).
+    let linked = format!("{}.linked", name);
     link(
         cfg,
         &format!("{}.elf", name),
-        &format!("{}/{}", image_name, name),
-    )
+        &format!("{}/{}", image_name, linked),
+    )?;
+    fs::copy(
+        cfg.img_file(&linked, image_name),
+        cfg.img_file(name, image_name),
+    )?;
+    Ok(())
 }
 
 /// Link a specific task using a dummy linker script that gives it all possible
@@ -972,12 +1119,23 @@ fn link_dummy_task(
 ) -> Result<()> {
     let task_toml = &cfg.toml.tasks[name];
 
-    let memories = cfg
+    let memories: BTreeMap<_, _> = cfg
         .toml
         .memories(&cfg.toml.image_names[0])?
         .into_iter()
         .collect();
-    let extern_regions = cfg.toml.extern_regions_for(name, image_name)?;
+    let mut extern_regions = cfg.toml.extern_regions_for(name, image_name)?;
+    // Shared regions haven't been allocated yet, but this link only exists to
+    // measure the task, so any address in the right memory will do.
+    for (region, shared, _) in cfg.toml.shared_regions_for(name) {
+        let base = memories
+            .get(&shared.memory)
+            .ok_or_else(|| {
+                anyhow!("invalid memory for shared region {region}")
+            })?
+            .start;
+        extern_regions.insert(region.to_owned(), base..base + shared.size);
+    }
 
     generate_task_linker_script(
         "memory.x",
@@ -1054,8 +1212,14 @@ fn build_kernel(
     all_output_sections.hash(&mut image_id);
 
     // Format the descriptors for the kernel build.
-    let kconfig =
-        make_kconfig(&cfg.toml, &allocs.tasks, entry_points, image_name)?;
+    let kconfig = make_kconfig(
+        &cfg.toml,
+        &allocs.tasks,
+        &allocs.chunks,
+        &allocs.shared,
+        entry_points,
+        image_name,
+    )?;
     let kconfig = ron::ser::to_string(&kconfig)?;
 
     kconfig.hash(&mut image_id);
@@ -1199,6 +1363,18 @@ fn update_image_header(
 fn check_dump_config(toml: &Config) -> Result<()> {
     let dump_support = toml.kernel.features.iter().find(|&f| f == "dump");
 
+    // Exporting ring buffers uses the same kernel support to read task memory
+    if let Some(jefe) = toml.tasks.get("jefe") {
+        if jefe.features.iter().any(|f| f == "ringbuf-export")
+            && dump_support.is_none()
+        {
+            bail!(
+                "jefe has the ringbuf-export feature, but the kernel does not \
+                 have the dump feature enabled"
+            );
+        }
+    }
+
     if let Some(task) = toml.tasks.get("dump_agent") {
         if task.extern_regions.is_empty() {
             bail!(
@@ -1258,12 +1434,21 @@ fn check_task_priorities(toml: &Config) -> Result<()> {
     let idle_priority = toml.tasks["idle"].priority;
     for (i, (name, task)) in toml.tasks.iter().enumerate() {
         for callee in task.task_slots.values() {
-            let p = toml
+            let callee_task = toml
                 .tasks
                 .get(callee)
-                .ok_or_else(|| anyhow!("Invalid task-slot: {}", callee))?
-                .priority;
-            if p >= task.priority && name != callee {
+                .ok_or_else(|| anyhow!("Invalid task-slot: {}", callee))?;
+            if task.ipc_exempt.contains(callee) {
+                continue;
+            }
+            let p = callee_task.priority;
+            // A callee that inherits priority will be boosted to at least our
+            // priority while it works for us, so it can't be starved by tasks
+            // in between.
+            if p >= task.priority
+                && name != callee
+                && !callee_task.inherit_priority
+            {
                 bail!(
                     concat!(
                         "Priority inversion: ",
@@ -1288,6 +1473,34 @@ fn check_task_priorities(toml: &Config) -> Result<()> {
     Ok(())
 }
 
+/// Checks that non-secure tasks are only used where they make sense, and that
+/// IPC only flows from non-secure tasks to secure ones and not the reverse.
+fn check_task_security(toml: &Config) -> Result<()> {
+    let any_non_secure = toml.tasks.values().any(|t| t.non_secure);
+    if any_non_secure && toml.target != "thumbv8m.main-none-eabihf" {
+        bail!("non-secure tasks require an ARMv8-M target");
+    }
+    for (i, (name, task)) in toml.tasks.iter().enumerate() {
+        if i == 0 && task.non_secure {
+            bail!("Supervisor task ({}) cannot be non-secure", name);
+        }
+        if task.non_secure {
+            continue;
+        }
+        for callee in task.task_slots.values() {
+            if toml.tasks.get(callee).map(|t| t.non_secure) == Some(true) {
+                bail!(
+                    "secure task {} calls into non-secure task {}; \
+                     only the reverse is allowed",
+                    name,
+                    callee,
+                );
+            }
+        }
+    }
+    Ok(())
+}
+
 fn generate_task_linker_script(
     name: &str,
     map: &BTreeMap<String, Range<u32>>,
@@ -1374,18 +1587,9 @@ fn append_extern_regions(
     extern_regions: &IndexMap<String, Range<u32>>,
 ) -> Result<()> {
     for (name, out) in extern_regions {
-        writeln!(
-            linkscr,
-            "__REGION_{}_BASE = {:#010x};",
-            name.to_ascii_uppercase(),
-            out.start
-        )?;
-        writeln!(
-            linkscr,
-            "__REGION_{}_END = {:#010x};",
-            name.to_ascii_uppercase(),
-            out.end
-        )?;
+        let name = name.to_ascii_uppercase().replace('-', "_");
+        writeln!(linkscr, "__REGION_{}_BASE = {:#010x};", name, out.start)?;
+        writeln!(linkscr, "__REGION_{}_END = {:#010x};", name, out.end)?;
     }
 
     Ok(())
@@ -1517,12 +1721,17 @@ fn build(
              -C link-arg=-z -C link-arg=max-page-size=0x20 \
              -C llvm-args=--enable-machine-outliner=never \
              -C overflow-checks=y \
+             -Z emit-stack-sizes \
              -C metadata={} \
              {}
              ",
             cfg.link_script_hash, remap_path_prefix,
         ),
     );
+    if let Some(epoch) = cfg.source_date_epoch {
+        cmd.env("SOURCE_DATE_EPOCH", epoch.to_string());
+        cmd.env("CARGO_INCREMENTAL", "0");
+    }
     cmd.arg("--");
 
     // We use attributes to conditionally import based on feature flags;
@@ -1709,8 +1918,8 @@ fn link(
         | "thumbv8m.main-none-eabihf" => "armelf",
         _ => bail!("No target emulation for '{}'", cfg.toml.target),
     };
-    cmd.arg(src_file);
-    cmd.arg("-o").arg(dst_file);
+    cmd.arg(&src_file);
+    cmd.arg("-o").arg(&dst_file);
     cmd.arg("-Tlink.x");
     cmd.arg("--gc-sections");
     cmd.arg("-m").arg(m);
@@ -1719,6 +1928,34 @@ fn link(
 
     cmd.current_dir(working_dir);
 
+    // Each link is quick, but there are two per task per image, so skip any
+    // whose output we already have from exactly the same inputs and linker
+    // arguments. memory.x carries the task's allocation, so this catches
+    // layout changes too. Callers must not modify the output in place, or
+    // the next link would be skipped in favor of the modified file.
+    let dst_path = working_dir.join(&dst_file);
+    let mut stamp_path = dst_path.clone().into_os_string();
+    stamp_path.push(".linkstamp");
+    let mut hasher = fnv::FnvHasher::default();
+    std::fs::read(working_dir.join(&src_file))?.hash(&mut hasher);
+    for f in linker_scripts(working_dir, "link.x")? {
+        f.hash(&mut hasher);
+    }
+    cfg.sysroot.hash(&mut hasher);
+    cfg.toml.target.hash(&mut hasher);
+    for arg in cmd.get_args() {
+        arg.hash(&mut hasher);
+    }
+    let stamp = format!("{:x}", hasher.finish());
+    if dst_path.exists()
+        && std::fs::read_to_string(&stamp_path).ok().as_deref()
+            == Some(stamp.as_str())
+    {
+        return Ok(());
+    }
+    // If this link fails partway, don't let the stamp vouch for the wreckage.
+    let _ = std::fs::remove_file(&stamp_path);
+
     let status = cmd
         .status()
         .context(format!("failed to run linker ({:?})", cmd))?;
@@ -1727,17 +1964,59 @@ fn link(
         bail!("command failed, see output for details");
     }
 
+    std::fs::write(&stamp_path, stamp)?;
+
     Ok(())
 }
 
+/// Reads the linker script `name` from `dir`, followed by every script that it
+/// `INCLUDE`s, recursively.
+fn linker_scripts(dir: &Path, name: &str) -> Result<Vec<Vec<u8>>> {
+    let mut out = vec![];
+    let mut pending = vec![name.to_owned()];
+    let mut seen = BTreeSet::new();
+    while let Some(name) = pending.pop() {
+        if !seen.insert(name.clone()) {
+            continue;
+        }
+        let script = std::fs::read(dir.join(&name))
+            .with_context(|| format!("could not read linker script {name}"))?;
+        for line in String::from_utf8_lossy(&script).lines() {
+            if let Some(included) = line.trim().strip_prefix("INCLUDE ") {
+                pending.push(included.trim().to_owned());
+            }
+        }
+        out.push(script);
+    }
+    Ok(out)
+}
+
 #[derive(Debug, Clone, Default, Hash)]
 pub struct Allocations {
     /// Map from memory-name to address-range
     pub kernel: BTreeMap<String, Range<u32>>,
     /// Map from task-name to memory-name to address-range
     pub tasks: BTreeMap<String, BTreeMap<String, Range<u32>>>,
+    /// Map from task-name to memory-name to the naturally-aligned chunks that
+    /// the task's address-range was split into, for those that were split
+    pub chunks: BTreeMap<String, BTreeMap<String, Vec<Range<u32>>>>,
     /// Optional trailing caboose, located in the given region
     pub caboose: Option<(String, Range<u32>)>,
+    /// Map from shared-region-name to memory-name and address-range
+    pub shared: BTreeMap<String, (String, Range<u32>)>,
+    /// Map from memory-name to how much space MPU alignment cost us there
+    pub waste: BTreeMap<String, PackingWaste>,
+}
+
+/// Bytes lost to MPU size and alignment rules in one memory, beyond what the
+/// kernel and tasks actually need.
+#[derive(Debug, Clone, Default, Hash)]
+pub struct PackingWaste {
+    /// Waste with every task in a single region, or `None` if that wouldn't
+    /// fit at all
+    pub unsplit: Option<u32>,
+    /// Waste in the layout we chose
+    pub chosen: u32,
 }
 
 impl Allocations {
@@ -1754,6 +2033,7 @@ impl Allocations {
                     .flat_map(|(t, v)| v.keys().map(|k| (k, t.to_owned()))),
             )
             .chain(self.caboose.iter().map(|v| (&v.0, "caboose".to_owned())))
+            .chain(self.shared.iter().map(|(n, v)| (&v.0, n.to_owned())))
         {
             out.entry(region.to_owned()).or_default().push(name)
         }
@@ -1821,7 +2101,8 @@ pub fn allocate_all(
         let mut free = toml.memories(image_name)?;
         let kernel_requests = &kernel.requires;
 
-        let mut task_requests: BTreeMap<&str, BTreeMap<u32, VecDeque<&str>>> =
+        // Memory name -> list of (task name, bytes needed)
+        let mut task_requests: BTreeMap<&str, Vec<(&str, u64)>> =
             BTreeMap::new();
 
         for name in tasks.keys() {
@@ -1837,88 +2118,100 @@ pub fn allocate_all(
                 task_requests
                     .entry(mem)
                     .or_default()
-                    .entry(bytes.try_into().unwrap())
-                    .or_default()
-                    .push_back(name.as_str());
+                    .push((name.as_str(), *amt));
             }
         }
+        let max_chunks = plan_mpu_splits(toml, task_sizes);
 
         // Okay! Do memory types one by one, fitting kernel first.
         for (region, avail) in &mut free {
-            let mut k_req = kernel_requests.get(region.as_str());
-            let mut t_reqs = task_requests.get_mut(region.as_str());
-
-            fn reqs_map_not_empty(
-                om: &Option<&mut BTreeMap<u32, VecDeque<&str>>>,
-            ) -> bool {
-                om.iter()
-                    .flat_map(|map| map.values())
-                    .any(|q| !q.is_empty())
-            }
-
-            'fitloop: while k_req.is_some() || reqs_map_not_empty(&t_reqs) {
-                let align = if avail.start == 0 {
-                    // Lie to keep the masks in range. This could be avoided by
-                    // tracking log2 of masks rather than masks.
-                    1 << 31
-                } else {
-                    1 << avail.start.trailing_zeros()
+            let k_req = kernel_requests.get(region.as_str()).copied();
+            let t_reqs = task_requests
+                .get(region.as_str())
+                .map(Vec::as_slice)
+                .unwrap_or_default();
+            let needed = k_req.unwrap_or(0) as u64
+                + t_reqs.iter().map(|(_, amt)| amt).sum::<u64>();
+
+            // First, the simple layout, with every task in one region.
+            let whole: Vec<PackRequest> = t_reqs
+                .iter()
+                .map(|&(task, amt)| PackRequest {
+                    task,
+                    chunks: vec![toml
+                        .suggest_memory_region_size(task, amt)
+                        .try_into()
+                        .unwrap()],
+                })
+                .collect();
+            let mut whole_avail = avail.clone();
+            let mut packed =
+                pack(toml, region, k_req, &whole, &mut whole_avail);
+            let mut packed_avail = whole_avail.clone();
+            let unsplit = packed.as_ref().ok().map(|_| whole_avail.start);
+
+            // Then, where tasks have MPU regions to spare, try splitting them
+            // so that they don't have to round all the way up to the next
+            // power of two. This uses up regions and can cost alignment
+            // padding elsewhere, so we only keep it if it comes out ahead.
+            if let Some(granule) = toml.mpu_split_granule() {
+                let split: Vec<PackRequest> = t_reqs
+                    .iter()
+                    .map(|&(task, amt)| PackRequest {
+                        task,
+                        chunks: mpu_chunks(
+                            amt,
+                            granule,
+                            max_chunks
+                                .get(&(task, region.as_str()))
+                                .copied()
+                                .unwrap_or(1),
+                        ),
+                    })
+                    .collect();
+                let mut split_avail = avail.clone();
+                let split_packed =
+                    pack(toml, region, k_req, &split, &mut split_avail);
+                let better = match (&packed, &split_packed) {
+                    (Err(_), Ok(_)) => true,
+                    (Ok(_), Ok(_)) => split_avail.start < packed_avail.start,
+                    _ => false,
                 };
-
-                // Search order is:
-                // - Kernel.
-                // - Task requests equal to or smaller than this alignment, in
-                //   descending order of size.
-                // - Task requests larger than this alignment, in ascending
-                //   order of size.
-
-                if let Some(&sz) = k_req.take() {
-                    // The kernel wants in on this.
-                    allocs.kernel.insert(
-                        region.to_string(),
-                        allocate_k(region, sz, avail)?,
-                    );
-                    continue 'fitloop;
+                if better {
+                    packed = split_packed;
+                    packed_avail = split_avail;
                 }
+            }
 
-                if let Some(t_reqs) = t_reqs.as_mut() {
-                    for (&sz, q) in t_reqs.range_mut(..=align).rev() {
-                        if let Some(task) = q.pop_front() {
-                            // We can pack an equal or smaller one in.
-                            let align = toml.task_memory_alignment(sz);
-                            allocs
-                                .tasks
-                                .entry(task.to_string())
-                                .or_default()
-                                .insert(
-                                    region.to_string(),
-                                    allocate_one(region, sz, align, avail)?,
-                                );
-                            continue 'fitloop;
-                        }
-                    }
+            let (kernel_range, task_ranges) = packed?;
+            let waste = |end: u32| {
+                (u64::from(end - avail.start) - needed).try_into().unwrap()
+            };
+            allocs.waste.insert(
+                region.to_string(),
+                PackingWaste {
+                    unsplit: unsplit.map(waste),
+                    chosen: waste(packed_avail.start),
+                },
+            );
+            *avail = packed_avail;
 
-                    for (&sz, q) in t_reqs.range_mut(align + 1..) {
-                        if let Some(task) = q.pop_front() {
-                            // We've gotta use a larger one.
-                            let align = toml.task_memory_alignment(sz);
-                            allocs
-                                .tasks
-                                .entry(task.to_string())
-                                .or_default()
-                                .insert(
-                                    region.to_string(),
-                                    allocate_one(region, sz, align, avail)?,
-                                );
-                            continue 'fitloop;
-                        }
-                    }
+            if let Some(r) = kernel_range {
+                allocs.kernel.insert(region.to_string(), r);
+            }
+            for (task, r, chunks) in task_ranges {
+                allocs
+                    .tasks
+                    .entry(task.to_string())
+                    .or_default()
+                    .insert(region.to_string(), r);
+                if chunks.len() > 1 {
+                    allocs
+                        .chunks
+                        .entry(task.to_string())
+                        .or_default()
+                        .insert(region.to_string(), chunks);
                 }
-
-                // If we reach this point, it means our loop condition is wrong,
-                // because one of the above things should really have happened.
-                // Panic here because otherwise it's a hang.
-                panic!("loop iteration without progess made!");
             }
         }
 
@@ -1939,11 +2232,220 @@ pub fn allocate_all(
             ));
         }
 
+        for (name, shared) in &toml.shared_regions {
+            if toml.tasks.contains_key(name) || toml.outputs.contains_key(name)
+            {
+                bail!(
+                    "shared region '{name}' has the same name as a task or \
+                     memory region"
+                );
+            }
+            for t in shared.read.iter().chain(&shared.write) {
+                if !toml.tasks.contains_key(t) {
+                    bail!("shared region '{name}' specifies invalid task {t}");
+                }
+            }
+            let size =
+                toml.suggest_memory_region_size(name, shared.size.into());
+            if size != u64::from(shared.size) {
+                bail!(
+                    "shared region '{name}' has size {}, which the MPU can't \
+                     map; try {size}",
+                    shared.size
+                );
+            }
+            let avail = free.get_mut(&shared.memory).ok_or_else(|| {
+                anyhow!(
+                    "could not find memory {} for shared region '{name}'",
+                    shared.memory
+                )
+            })?;
+            let align = toml.task_memory_alignment(shared.size);
+            allocs.shared.insert(
+                name.clone(),
+                (
+                    shared.memory.clone(),
+                    allocate_one(&shared.memory, shared.size, align, avail)?,
+                ),
+            );
+        }
+
         result.insert(image_name.to_string(), (allocs, free));
     }
     Ok(result)
 }
 
+/// A task's request for space in one memory. The space is laid out as a run of
+/// power-of-two chunks, largest first, so that if the first is naturally
+/// aligned, so are the rest, and each can be its own MPU region.
+struct PackRequest<'a> {
+    task: &'a str,
+    chunks: Vec<u32>,
+}
+
+/// Decides how many MPU regions each task may spend on each of its memories,
+/// returning a map from (task name, memory name) to region count. Memories
+/// not in the map get one region.
+///
+/// Every task gets one region per memory; spare regions go to whichever of
+/// its memories would save the most by being split in two.
+fn plan_mpu_splits<'a>(
+    toml: &'a Config,
+    task_sizes: &HashMap<&'a str, IndexMap<&'a str, u64>>,
+) -> HashMap<(&'a str, &'a str), usize> {
+    let mut out = HashMap::new();
+    let granule = match toml.mpu_split_granule() {
+        Some(g) => g,
+        None => return out,
+    };
+    for (name, task) in &toml.tasks {
+        let sizes = &task_sizes[name.as_str()];
+
+        // The null region, plus everything else the task maps.
+        let used = 1
+            + sizes.len()
+            + task.extern_regions.len()
+            + task.uses.len()
+            + toml.shared_regions_for(name).count()
+            + toml
+                .caboose
+                .iter()
+                .filter(|c| c.tasks.contains(name))
+                .count();
+        let spare = abi::REGIONS_PER_TASK.saturating_sub(used);
+        for mem in spend_spare_regions(sizes, granule, spare) {
+            out.insert((name.as_str(), mem), 2);
+        }
+    }
+    out
+}
+
+/// Picks up to `spare` of a task's memories to split in two, given the bytes
+/// it needs in each, favoring those where splitting saves the most.
+fn spend_spare_regions<'a>(
+    sizes: &IndexMap<&'a str, u64>,
+    granule: u32,
+    spare: usize,
+) -> Vec<&'a str> {
+    let total =
+        |chunks: Vec<u32>| -> u64 { chunks.into_iter().map(u64::from).sum() };
+    let mut savings: Vec<(u64, &str)> = sizes
+        .iter()
+        .map(|(&mem, &amt)| {
+            let whole = total(mpu_chunks(amt, granule, 1));
+            let split = total(mpu_chunks(amt, granule, 2));
+            (whole - split, mem)
+        })
+        .filter(|&(saved, _)| saved > 0)
+        .collect();
+    savings.sort_by(|a, b| b.cmp(a));
+    savings
+        .into_iter()
+        .take(spare)
+        .map(|(_, mem)| mem)
+        .collect()
+}
+
+/// Lays out `size` bytes as at most `max_chunks` power-of-two chunks of at
+/// least `granule` bytes, largest first. Returns a single chunk if splitting
+/// wouldn't save anything.
+fn mpu_chunks(size: u64, granule: u32, max_chunks: usize) -> Vec<u32> {
+    let whole = size.next_power_of_two();
+    let granule = u64::from(granule);
+    let mut rest = (size + granule - 1) / granule * granule;
+    let mut chunks = vec![];
+    while rest > 0 && chunks.len() + 1 < max_chunks {
+        // Largest power of two that fits in what's left
+        let chunk = 1 << (63 - rest.leading_zeros());
+        chunks.push(chunk);
+        rest -= chunk;
+    }
+    if rest > 0 {
+        chunks.push(rest.next_power_of_two().max(granule));
+    }
+    if chunks.len() <= 1 || chunks.iter().sum::<u64>() >= whole {
+        chunks = vec![whole];
+    }
+    chunks.into_iter().map(|c| c.try_into().unwrap()).collect()
+}
+
+/// Lays `chunks` out one after another from `base`, which must be aligned to
+/// the first of them. Since they're largest first, each is then naturally
+/// aligned.
+fn mpu_chunk_ranges(base: u32, chunks: &[u32]) -> Vec<Range<u32>> {
+    let mut out = vec![];
+    let mut start = base;
+    for &size in chunks {
+        out.push(start..start + size);
+        start += size;
+    }
+    out
+}
+
+/// A task's range in one memory, and the chunks it's made of
+type PackedTask<'a> = (&'a str, Range<u32>, Vec<Range<u32>>);
+
+/// Allocates space in `avail` for the kernel's request (if any) and then each
+/// of `requests`, returning the kernel's range and each task's.
+fn pack<'a>(
+    toml: &Config,
+    region: &str,
+    kernel_request: Option<u32>,
+    requests: &[PackRequest<'a>],
+    avail: &mut Range<u32>,
+) -> Result<(Option<Range<u32>>, Vec<PackedTask<'a>>)> {
+    // Queue requests by alignment (which is the size of their first chunk),
+    // then total size. For requests that aren't split, these are the same.
+    let mut queues: BTreeMap<(u32, u32), VecDeque<&PackRequest>> =
+        BTreeMap::new();
+    for r in requests {
+        queues
+            .entry((r.chunks[0], r.chunks.iter().sum()))
+            .or_default()
+            .push_back(r);
+    }
+
+    let mut kernel_range = None;
+    if let Some(sz) = kernel_request {
+        kernel_range = Some(allocate_k(region, sz, avail)?);
+    }
+
+    let mut out = vec![];
+    while queues.values().any(|q| !q.is_empty()) {
+        let align = if avail.start == 0 {
+            // Lie to keep the masks in range. This could be avoided by
+            // tracking log2 of masks rather than masks.
+            1 << 31
+        } else {
+            1 << avail.start.trailing_zeros()
+        };
+
+        // Search order is:
+        // - Task requests needing equal or lesser alignment than we have, in
+        //   descending order of alignment and size.
+        // - Task requests needing more alignment, in ascending order.
+        let key = queues
+            .range(..=(align, u32::MAX))
+            .rev()
+            .chain(queues.range((align + 1, 0)..))
+            .find(|(_, q)| !q.is_empty())
+            .map(|(&k, _)| k)
+            .unwrap();
+        let request = queues.get_mut(&key).unwrap().pop_front().unwrap();
+
+        let (first, size) = key;
+        let align = if first == size {
+            toml.task_memory_alignment(size)
+        } else {
+            first
+        };
+        let range = allocate_one(region, size, align, avail)?;
+        let chunks = mpu_chunk_ranges(range.start, &request.chunks);
+        out.push((request.task, range, chunks));
+    }
+    Ok((kernel_range, out))
+}
+
 fn allocate_k(
     region: &str,
     size: u32,
@@ -2004,6 +2506,8 @@ fn allocate_one(
 pub fn make_kconfig(
     toml: &Config,
     task_allocations: &BTreeMap<String, BTreeMap<String, Range<u32>>>,
+    task_chunks: &BTreeMap<String, BTreeMap<String, Vec<Range<u32>>>>,
+    shared_allocations: &BTreeMap<String, (String, Range<u32>)>,
     entry_points: &HashMap<String, u32>,
     image_name: &str,
 ) -> Result<build_kconfig::KernelConfig> {
@@ -2110,31 +2614,56 @@ pub fn make_kconfig(
             }
         }
 
+        // Map in any shared regions that list this task, with the access it
+        // was given. These are added to the kernel's table below.
+        for (region, _, writable) in toml.shared_regions_for(name) {
+            shared_regions
+                .insert(SharedRegion::kernel_region_name(region, writable));
+        }
+
         let extern_regions = toml.extern_regions_for(name, image_name)?;
-        let owned_regions = task_allocations[name]
-            .iter()
-            .chain(extern_regions.iter())
-            .map(|(out_name, range)| {
-                // Look up region for this image
-                let mut regions = toml.outputs[out_name]
-                    .iter()
-                    .filter(|o| &o.name == image_name);
-                let out = regions.next().expect("no region for name");
-                if regions.next().is_some() {
-                    bail!("multiple {out_name} regions for name {image_name}");
+        let mut owned_regions = BTreeMap::new();
+        for (out_name, range) in
+            task_allocations[name].iter().chain(extern_regions.iter())
+        {
+            // Look up region for this image
+            let mut regions = toml.outputs[out_name]
+                .iter()
+                .filter(|o| &o.name == image_name);
+            let out = regions.next().expect("no region for name");
+            if regions.next().is_some() {
+                bail!("multiple {out_name} regions for name {image_name}");
+            }
+
+            // The allocator may have split the task's own memory into several
+            // chunks to save space; each gets an MPU region, with the first
+            // keeping the plain name so that addresses can be found in it.
+            let split = task_chunks.get(name).and_then(|c| c.get(out_name));
+            let chunks = match split {
+                Some(chunks)
+                    if task_allocations[name].get(out_name) == Some(range) =>
+                {
+                    chunks.clone()
                 }
-                let size = range.end - range.start;
+                _ => vec![range.clone()],
+            };
+            for (j, chunk) in chunks.into_iter().enumerate() {
+                let size = chunk.end - chunk.start;
                 if p2_required && !size.is_power_of_two() {
                     bail!(
                         "memory region for task '{name}' output '{out_name}' \
                         is required to be a power of two, but has size {size}"
                     );
                 }
-
-                Ok((
-                    out_name.to_string(),
+                let region_name = if j == 0 {
+                    out_name.to_string()
+                } else {
+                    format!("{out_name}.{j}")
+                };
+                owned_regions.insert(
+                    region_name,
                     build_kconfig::RegionConfig {
-                        base: range.start,
+                        base: chunk.start,
                         size,
                         attributes: build_kconfig::RegionAttributes {
                             read: out.read,
@@ -2147,9 +2676,24 @@ pub fn make_kconfig(
                             },
                         },
                     },
-                ))
-            })
-            .collect::<Result<BTreeMap<_, _>, _>>()?;
+                );
+            }
+        }
+
+        // While a non-secure task runs, each of its regions -- peripherals
+        // included -- needs an SAU region of its own to be made non-secure.
+        if task.non_secure {
+            let regions =
+                owned_regions.values().filter(|r| r.size != 0).count()
+                    + shared_regions.len();
+            let available = toml.sau_task_regions().unwrap_or(0);
+            if regions > available {
+                bail!(
+                    "non-secure task {name} has {regions} memory regions, \
+                     but the SAU can only describe {available}"
+                );
+            }
+        }
 
         tasks.push(build_kconfig::TaskConfig {
             owned_regions,
@@ -2164,6 +2708,8 @@ pub fn make_kconfig(
             },
             priority: task.priority,
             start_at_boot: task.start,
+            inherit_priority: task.inherit_priority,
+            non_secure: task.non_secure,
         });
 
         // Interrupts.
@@ -2229,6 +2775,35 @@ pub fn make_kconfig(
     // Pare down the list of shared regions.
     flat_shared.retain(|name, _v| used_shared_regions.contains(name.as_str()));
 
+    // Shared regions get one kernel region per kind of access that some task
+    // actually has, so they don't need paring down.
+    for (name, shared) in &toml.shared_regions {
+        let range = &shared_allocations[name].1;
+        for (writable, tasks) in [(false, &shared.read), (true, &shared.write)]
+        {
+            if tasks.is_empty() {
+                continue;
+            }
+            flat_shared.insert(
+                SharedRegion::kernel_region_name(name, writable),
+                build_kconfig::RegionConfig {
+                    base: range.start,
+                    size: range.end - range.start,
+                    attributes: build_kconfig::RegionAttributes {
+                        read: true,
+                        write: writable,
+                        execute: false,
+                        special_role: if shared.dma {
+                            Some(build_kconfig::SpecialRole::Dma)
+                        } else {
+                            None
+                        },
+                    },
+                },
+            );
+        }
+    }
+
     Ok(build_kconfig::KernelConfig {
         irqs,
         tasks,
@@ -2343,6 +2918,8 @@ struct Archive {
     inner: zip::ZipWriter<File>,
     /// Options used for every file.
     opts: zip::write::FileOptions,
+    /// SHA3-256 of each file added so far, by path within the archive.
+    hashes: BTreeMap<String, String>,
 }
 
 impl Archive {
@@ -2365,6 +2942,7 @@ impl Archive {
             inner,
             opts: zip::write::FileOptions::default()
                 .compression_method(zip::CompressionMethod::Bzip2),
+            hashes: BTreeMap::new(),
         })
     }
 
@@ -2374,11 +2952,8 @@ impl Archive {
         src_path: impl AsRef<Path>,
         zip_path: impl AsRef<Path>,
     ) -> Result<()> {
-        let mut input = File::open(src_path)?;
-        self.inner
-            .start_file(zip_path.as_ref().to_slash().unwrap(), self.opts)?;
-        std::io::copy(&mut input, &mut self.inner)?;
-        Ok(())
+        let input = std::fs::read(src_path)?;
+        self.binary(zip_path, input)
     }
 
     /// Creates a text file in the archive at `zip_path` with `contents`.
@@ -2387,10 +2962,7 @@ impl Archive {
         zip_path: impl AsRef<Path>,
         contents: impl AsRef<str>,
     ) -> Result<()> {
-        self.inner
-            .start_file(zip_path.as_ref().to_slash().unwrap(), self.opts)?;
-        self.inner.write_all(contents.as_ref().as_bytes())?;
-        Ok(())
+        self.binary(zip_path, contents.as_ref().as_bytes())
     }
 
     /// Creates a binary file in the archive at `zip_path` with `contents`.
@@ -2399,9 +2971,10 @@ impl Archive {
         zip_path: impl AsRef<Path>,
         contents: impl AsRef<[u8]>,
     ) -> Result<()> {
-        self.inner
-            .start_file(zip_path.as_ref().to_slash().unwrap(), self.opts)?;
+        let name = zip_path.as_ref().to_slash().unwrap().into_owned();
+        self.inner.start_file(&name, self.opts)?;
         self.inner.write_all(contents.as_ref())?;
+        self.hashes.insert(name, manifest::hash(contents.as_ref()));
         Ok(())
     }
 
@@ -2428,7 +3001,7 @@ impl Archive {
 ///
 /// - A `String` containing the git commit hash.
 /// - A `bool` indicating whether the repository has uncommitted changes.
-fn get_git_status() -> Result<(String, bool)> {
+pub fn get_git_status() -> Result<(String, bool)> {
     let mut cmd = Command::new("git");
     cmd.arg("rev-parse").arg("HEAD");
     let out = cmd.output()?;
@@ -2524,6 +3097,94 @@ fn resolve_task_slots(
     Ok(std::fs::write(task_bin, out_task_bin)?)
 }
 
+/// Fills in the table of exported ring buffers in whichever task has one
+/// (which can only be the supervisor), using the ring buffer registries of all
+/// the tasks we've built.
+fn resolve_ringbuf_exports(
+    cfg: &PackageConfig,
+    image_name: &str,
+    tasks_to_build: &BTreeSet<&str>,
+) -> Result<()> {
+    use scroll::Pwrite;
+
+    let mut exporter = None;
+    let mut exports = vec![];
+    for (index, task_name) in cfg.toml.tasks.keys().enumerate() {
+        if !tasks_to_build.contains(task_name.as_str()) {
+            continue;
+        }
+        let task_bin = std::fs::read(cfg.img_file(task_name, image_name))?;
+        let elf = goblin::elf::Elf::parse(&task_bin)?;
+
+        if let Some(table) = ringbuf_export::get_export_table(&task_bin, &elf)?
+        {
+            if index != 0 {
+                bail!(
+                    "task '{task_name}' wants to export ring buffers, but \
+                     only the supervisor can read other tasks' memory"
+                );
+            }
+            exporter = Some((task_name, table));
+        }
+        for entry in ringbuf_export::get_registry_entries(&task_bin, &elf)? {
+            exports.push((index as u32, entry));
+        }
+    }
+
+    let (task_name, table) = match exporter {
+        Some(e) => e,
+        None => return Ok(()),
+    };
+    if exports.len() > table.capacity {
+        bail!(
+            "there are {} ring buffers to export, but '{task_name}' only has \
+             room for {}",
+            exports.len(),
+            table.capacity,
+        );
+    }
+
+    let task_bin = cfg.img_file(task_name, image_name);
+    let in_task_bin = std::fs::read(&task_bin)?;
+    let elf = goblin::elf::Elf::parse(&in_task_bin)?;
+    let mut out_task_bin = in_task_bin.clone();
+
+    // Write every slot, blanking the ones we don't use, so that nothing is
+    // left over from whatever was in the table before.
+    let unbound = (
+        0,
+        ringbuf_export::RegistryEntry {
+            address: 0,
+            size: 0,
+        },
+    );
+    let mut offset = table.file_offset as usize;
+    for (index, entry) in exports
+        .iter()
+        .chain(std::iter::repeat(&unbound))
+        .take(table.capacity)
+    {
+        for word in [*index, entry.address, entry.size] {
+            out_task_bin.pwrite_with::<u32>(
+                word,
+                offset,
+                elf::get_endianness(&elf),
+            )?;
+            offset += 4;
+        }
+    }
+
+    if cfg.verbose {
+        println!(
+            "Task '{task_name}' exports {} ring buffers, table at {:#x}",
+            exports.len(),
+            table.address,
+        );
+    }
+
+    Ok(std::fs::write(task_bin, out_task_bin)?)
+}
+
 fn resolve_caboose_pos(
     cfg: &PackageConfig,
     task_name: &str,
@@ -2564,3 +3225,122 @@ fn resolve_caboose_pos(
 
     Ok(std::fs::write(task_bin, out_task_bin)?)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Smallest MPU regions on ARMv7-M and ARMv6-M
+    const V7M: u32 = 32;
+    const V6M: u32 = 256;
+
+    #[test]
+    fn chunks_stay_whole_unless_splitting_saves() {
+        for granule in [V7M, V6M] {
+            assert_eq!(mpu_chunks(4096, granule, 2), vec![4096]);
+            // Two 2048-byte chunks are no better than one of 4096.
+            assert_eq!(mpu_chunks(4000, granule, 2), vec![4096]);
+            assert_eq!(mpu_chunks(3000, granule, 1), vec![4096]);
+        }
+    }
+
+    #[test]
+    fn chunks_round_up_to_granule() {
+        assert_eq!(mpu_chunks(4097, V7M, 2), vec![4096, 32]);
+        assert_eq!(mpu_chunks(4097, V6M, 2), vec![4096, 256]);
+        assert_eq!(mpu_chunks(3000, V7M, 3), vec![2048, 512, 512]);
+        assert_eq!(mpu_chunks(3000, V6M, 3), vec![2048, 1024]);
+        assert_eq!(mpu_chunks(160, V7M, 2), vec![128, 32]);
+        assert_eq!(mpu_chunks(160, V6M, 2), vec![256]);
+    }
+
+    #[test]
+    fn chunks_cover_size_largest_first() {
+        for granule in [V7M, V6M] {
+            for size in (1..20_000).step_by(37) {
+                for max in 1..=4 {
+                    let chunks = mpu_chunks(size, granule, max);
+                    assert!(chunks.len() <= max);
+                    assert!(chunks.iter().all(|c| c.is_power_of_two()));
+                    assert!(chunks.windows(2).all(|w| w[0] >= w[1]));
+                    let total: u64 = chunks.iter().map(|&c| u64::from(c)).sum();
+                    assert!(total >= size);
+                    assert!(total <= size.next_power_of_two());
+                    if chunks.len() > 1 {
+                        assert!(chunks.iter().all(|&c| c >= granule));
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn chunk_ranges_are_naturally_aligned() {
+        for granule in [V7M, V6M] {
+            for size in (1..20_000).step_by(37) {
+                let chunks = mpu_chunks(size, granule, 3);
+                let base = chunks[0] * 5;
+                let ranges = mpu_chunk_ranges(base, &chunks);
+                assert_eq!(ranges[0].start, base);
+                assert_eq!(
+                    ranges.last().unwrap().end,
+                    base + chunks.iter().sum::<u32>()
+                );
+                assert!(ranges.windows(2).all(|w| w[0].end == w[1].start));
+                for r in &ranges {
+                    let len = r.end - r.start;
+                    assert!(len.is_power_of_two());
+                    assert_eq!(r.start % len, 0);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn spare_regions_go_to_biggest_savings() {
+        let sizes: IndexMap<&str, u64> =
+            [("flash", 17_000), ("ram", 4100), ("sram", 4096)]
+                .into_iter()
+                .collect();
+        for granule in [V7M, V6M] {
+            assert!(spend_spare_regions(&sizes, granule, 0).is_empty());
+            assert_eq!(spend_spare_regions(&sizes, granule, 1), ["flash"]);
+            assert_eq!(
+                spend_spare_regions(&sizes, granule, 5),
+                ["flash", "ram"]
+            );
+        }
+    }
+
+    #[test]
+    fn spare_regions_respect_granule() {
+        // Splitting 160 bytes saves 96 on ARMv7-M, but nothing on ARMv6-M,
+        // where it would need two 256-byte regions.
+        let sizes: IndexMap<&str, u64> = [("ram", 160)].into_iter().collect();
+        assert_eq!(spend_spare_regions(&sizes, V7M, 1), ["ram"]);
+        assert!(spend_spare_regions(&sizes, V6M, 1).is_empty());
+    }
+
+    #[test]
+    fn linker_scripts_follow_includes() {
+        let dir = std::env::temp_dir()
+            .join(format!("xtask-linker-scripts-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("link.x"), "INCLUDE memory.x\nSECTIONS {}\n")
+            .unwrap();
+        std::fs::write(dir.join("memory.x"), "MEMORY {}\n  INCLUDE device.x\n")
+            .unwrap();
+        std::fs::write(dir.join("device.x"), "PROVIDE(a = b);\n").unwrap();
+
+        let scripts = linker_scripts(&dir, "link.x").unwrap();
+        assert_eq!(scripts.len(), 3);
+        assert!(scripts.contains(&b"PROVIDE(a = b);\n".to_vec()));
+
+        // A missing script is an error, rather than something we quietly
+        // leave out of the stamp.
+        std::fs::remove_file(dir.join("device.x")).unwrap();
+        assert!(linker_scripts(&dir, "link.x").is_err());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
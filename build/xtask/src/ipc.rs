@@ -0,0 +1,330 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Whole-image IPC analysis.
+//!
+//! A task can only SEND to the tasks in its `task-slots`, so those slots give
+//! us every edge along which a task might block. `check_task_priorities` looks
+//! at each edge on its own; here we follow whole chains of them, since a
+//! server that SENDs to another server while handling a request keeps its
+//! client waiting too. We look for two problems:
+//!
+//! - Cycles, where each task could end up waiting on the next. Strict
+//!   priority ordering rules these out, but priority inheritance lets them
+//!   back in.
+//! - Chains that go "uphill": a server that has inherited a client's priority
+//!   and then SENDs to a task no more important than that client, which can
+//!   then be starved by tasks in between.
+//!
+//! Edges listed in a task's `ipc-exempt` are left out entirely.
+//!
+//! The graph comes from `task-slots` alone, which makes it conservative in
+//! two ways. We don't know which operations a task calls on each slot, so we
+//! assume that a server handling any request may SEND to any of its slots.
+//! And a SEND to a task found some other way than a slot -- such as jefe's
+//! reset notices, which go by task index -- isn't an edge at all, and is left
+//! to the sender to get right.
+
+use std::collections::{btree_map::Entry, BTreeMap, BTreeSet, VecDeque};
+
+use anyhow::{bail, Result};
+
+use crate::config::Config;
+
+/// Checks the image's IPC graph, failing if it could deadlock or if a chain of
+/// SENDs goes uphill.
+pub fn check(toml: &Config) -> Result<()> {
+    let graph = SendGraph::new(toml)?;
+
+    let mut problems = vec![];
+    for cycle in graph.cycles() {
+        problems.push(format!("possible deadlock: {}", cycle.join(" -> ")));
+    }
+    for path in graph.uphill_paths() {
+        let (server, rest) = path.split_last().unwrap();
+        let client = path[0];
+        problems.push(format!(
+            "{} (priority {}) can be made to wait on {} (priority {}) via {}",
+            client,
+            graph.tasks[client].priority,
+            server,
+            graph.tasks[*server].priority,
+            rest.join(" -> "),
+        ));
+    }
+
+    if !problems.is_empty() {
+        bail!(
+            "IPC analysis found problems:\n  {}\n\
+             If an edge is known to be safe, list the callee in the caller's \
+             `ipc-exempt`.",
+            problems.join("\n  ")
+        );
+    }
+    Ok(())
+}
+
+/// The scheduling details of a task that matter to the analysis.
+#[derive(Copy, Clone, Debug)]
+struct Node {
+    priority: u8,
+    inherit_priority: bool,
+}
+
+/// Directed graph of which tasks may SEND to which.
+struct SendGraph<'a> {
+    tasks: BTreeMap<&'a str, Node>,
+    edges: BTreeMap<&'a str, BTreeSet<&'a str>>,
+}
+
+impl<'a> SendGraph<'a> {
+    fn new(toml: &'a Config) -> Result<Self> {
+        let mut tasks = BTreeMap::new();
+        let mut edges: BTreeMap<&str, BTreeSet<&str>> = BTreeMap::new();
+        for (name, task) in &toml.tasks {
+            for exempt in &task.ipc_exempt {
+                if !task.task_slots.values().any(|t| t == exempt) {
+                    bail!(
+                        "task {}: ipc-exempt names {}, which isn't in its \
+                         task-slots",
+                        name,
+                        exempt
+                    );
+                }
+            }
+            tasks.insert(
+                name.as_str(),
+                Node {
+                    priority: task.priority,
+                    inherit_priority: task.inherit_priority,
+                },
+            );
+            let out = edges.entry(name.as_str()).or_default();
+            for callee in task.task_slots.values() {
+                // Sending to yourself is caught by the kernel, and opting out
+                // means opting out.
+                if callee != name && !task.ipc_exempt.contains(callee) {
+                    out.insert(callee.as_str());
+                }
+            }
+        }
+        Ok(Self { tasks, edges })
+    }
+
+    fn callees(&self, task: &str) -> impl Iterator<Item = &'a str> + '_ {
+        self.edges.get(task).into_iter().flatten().copied()
+    }
+    /// Returns one representative cycle for each set of tasks that can wait
+    /// on one another.
+    fn cycles(&self) -> Vec<Vec<&'a str>> {
+        let mut seen = BTreeSet::new();
+        let mut out = vec![];
+        for &start in self.edges.keys() {
+            // Breadth-first search for the shortest way back to `start`.
+            let mut prev: BTreeMap<&str, &str> = BTreeMap::new();
+            let mut queue: VecDeque<&str> = self.callees(start).collect();
+            for t in self.callees(start) {
+                prev.insert(t, start);
+            }
+            while let Some(t) = queue.pop_front() {
+                if t == start {
+                    let mut cycle = vec![start];
+                    let mut cur = prev[start];
+                    while cur != start {
+                        cycle.push(cur);
+                        cur = prev[cur];
+                    }
+                    cycle[1..].reverse();
+                    cycle.push(start);
+                    // Report each cycle once, whichever task we find it from.
+                    let members: BTreeSet<&str> =
+                        cycle.iter().copied().collect();
+                    if seen.insert(members) {
+                        out.push(cycle);
+                    }
+                    break;
+                }
+                for next in self.callees(t) {
+                    if let Entry::Vacant(e) = prev.entry(next) {
+                        e.insert(t);
+                        queue.push_back(next);
+                    }
+                }
+            }
+        }
+        out
+    }
+
+    /// Returns a path for each edge that goes uphill relative to the client
+    /// at the start of the path, where the client's priority has been
+    /// inherited along the way. Direct edges are `check_task_priorities`'s
+    /// business, so these are always at least two hops long.
+    ///
+    /// Whether an edge is uphill depends only on the task we're at and the
+    /// priority it's running at, so rather than following every path (of
+    /// which there can be exponentially many), we search those pairs
+    /// breadth-first from each client, visiting each at most once.
+    fn uphill_paths(&self) -> Vec<Vec<&'a str>> {
+        let mut reported = BTreeSet::new();
+        let mut out = vec![];
+        for &client in self.edges.keys() {
+            let start = (client, self.tasks[client].priority);
+            let mut prev: BTreeMap<(&str, u8), (&str, u8)> = BTreeMap::new();
+            let mut queue = VecDeque::from([start]);
+            while let Some(state) = queue.pop_front() {
+                let (caller, effective) = state;
+                for callee in self.callees(caller) {
+                    let task = self.tasks[callee];
+                    let next = if task.inherit_priority {
+                        (callee, task.priority.min(effective))
+                    } else {
+                        if task.priority >= effective && state != start {
+                            let mut path = Self::path_to(&prev, start, state);
+                            // Edges that close a cycle are reported as such.
+                            if !path.contains(&callee)
+                                && reported.insert((caller, callee))
+                            {
+                                path.push(callee);
+                                out.push(path);
+                            }
+                        }
+                        (callee, task.priority)
+                    };
+                    if next != start {
+                        if let Entry::Vacant(e) = prev.entry(next) {
+                            e.insert(state);
+                            queue.push_back(next);
+                        }
+                    }
+                }
+            }
+        }
+        out
+    }
+
+    /// Follows `prev` back from `state` to `start`, returning the tasks along
+    /// the way in order.
+    fn path_to(
+        prev: &BTreeMap<(&'a str, u8), (&'a str, u8)>,
+        start: (&'a str, u8),
+        mut state: (&'a str, u8),
+    ) -> Vec<&'a str> {
+        let mut path = vec![state.0];
+        while state != start {
+            state = prev[&state];
+            path.push(state.0);
+        }
+        path.reverse();
+        path
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Builds a graph from `(name, priority, inherit_priority, callees)`.
+    fn graph<'a>(tasks: &[(&'a str, u8, bool, &[&'a str])]) -> SendGraph<'a> {
+        SendGraph {
+            tasks: tasks
+                .iter()
+                .map(|&(name, priority, inherit_priority, _)| {
+                    (
+                        name,
+                        Node {
+                            priority,
+                            inherit_priority,
+                        },
+                    )
+                })
+                .collect(),
+            edges: tasks
+                .iter()
+                .map(|&(name, _, _, callees)| {
+                    (name, callees.iter().copied().collect())
+                })
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn no_problems_downhill() {
+        let g = graph(&[
+            ("client", 3, false, &["server"]),
+            ("server", 2, false, &["driver"]),
+            ("driver", 1, false, &[]),
+        ]);
+        assert!(g.cycles().is_empty());
+        assert!(g.uphill_paths().is_empty());
+    }
+
+    #[test]
+    fn finds_cycle_once() {
+        let g = graph(&[
+            ("a", 2, true, &["b"]),
+            ("b", 2, true, &["c"]),
+            ("c", 2, true, &["a"]),
+            ("d", 1, false, &[]),
+        ]);
+        assert_eq!(g.cycles(), vec![vec!["a", "b", "c", "a"]]);
+    }
+
+    #[test]
+    fn finds_uphill_through_inheriting_server() {
+        // `server` runs at the client's priority 1 while serving it, and then
+        // waits on `logger` at 5, which anything at 2-4 can starve.
+        let g = graph(&[
+            ("client", 1, false, &["server"]),
+            ("server", 6, true, &["logger"]),
+            ("logger", 5, false, &[]),
+        ]);
+        assert_eq!(g.uphill_paths(), vec![vec!["client", "server", "logger"]]);
+    }
+
+    #[test]
+    fn ignores_direct_edges() {
+        // A direct uphill edge is `check_task_priorities`'s to report.
+        let g = graph(&[
+            ("client", 1, false, &["logger"]),
+            ("logger", 5, false, &[]),
+        ]);
+        assert!(g.uphill_paths().is_empty());
+    }
+
+    #[test]
+    fn reports_each_edge_once() {
+        let g = graph(&[
+            ("a", 1, false, &["server"]),
+            ("b", 2, false, &["server"]),
+            ("server", 6, true, &["logger"]),
+            ("logger", 5, false, &[]),
+        ]);
+        assert_eq!(g.uphill_paths(), vec![vec!["a", "server", "logger"]]);
+    }
+
+    #[test]
+    fn wide_graphs_are_quick() {
+        // Sixty layers of two inheriting servers, each calling both in the
+        // next layer: 2^60 paths, but only 120 tasks.
+        let names: Vec<String> = (0..120).map(|i| format!("t{i}")).collect();
+        let callees: Vec<Vec<&str>> = (0..120)
+            .map(|i| {
+                let next = (i / 2 + 1) * 2;
+                names[next.min(120)..(next + 2).min(120)]
+                    .iter()
+                    .map(String::as_str)
+                    .collect()
+            })
+            .collect();
+        let mut tasks: Vec<(&str, u8, bool, &[&str])> = names
+            .iter()
+            .zip(&callees)
+            .map(|(n, c)| (n.as_str(), 4, true, c.as_slice()))
+            .collect();
+        tasks.push(("client", 1, false, &["t0", "t1"]));
+        let g = graph(&tasks);
+        assert!(g.cycles().is_empty());
+        assert!(g.uphill_paths().is_empty());
+    }
+}
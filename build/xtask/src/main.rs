@@ -13,14 +13,19 @@ mod auxflash;
 mod caboose_pos;
 mod clippy;
 mod config;
+mod diff;
 mod dist;
 mod elf;
 mod flash;
 mod graph;
 mod humility;
+mod ipc;
 mod lsp;
+mod manifest;
 mod print;
+mod ringbuf_export;
 mod sizes;
+mod stack;
 mod task_slot;
 
 #[derive(Debug, Parser)]
@@ -43,6 +48,29 @@ enum Xtask {
         /// rebuilding even if it looks like we need to.
         #[clap(long)]
         dirty: bool,
+        /// Do a clean build from a clean checkout, with timestamps and paths
+        /// pinned so that the result can be checked with `xtask verify`.
+        #[clap(long, conflicts_with = "dirty")]
+        reproducible: bool,
+        /// Fail if a task's estimated stack depth exceeds its stack, rather
+        /// than warning about it.
+        #[clap(long)]
+        strict: bool,
+    },
+
+    /// Rebuilds the image in a build archive from the current checkout, and
+    /// checks that the result is identical to the archive.
+    ///
+    /// The archive should have been built with `xtask dist --reproducible`,
+    /// from the same commit and with the same toolchain.
+    Verify {
+        /// Request verbosity from tools we shell out to.
+        #[clap(short)]
+        verbose: bool,
+        /// Path to the image configuration file, in TOML.
+        cfg: PathBuf,
+        /// Path to the build archive to check.
+        archive: PathBuf,
     },
 
     /// Builds one or more cross-compiled binary as it would appear in the
@@ -90,7 +118,8 @@ enum Xtask {
         args: HumilityArgs,
     },
 
-    /// Runs `xtask dist` and reports the sizes of resulting tasks
+    /// Runs `xtask dist` and reports the sizes of resulting tasks, including
+    /// an estimate of how much stack each one needs
     Sizes {
         /// Request verbosity from tools we shell out to.
         #[clap(short)]
@@ -105,6 +134,10 @@ enum Xtask {
         /// Write JSON out to a file?
         #[clap(long)]
         save: bool,
+        /// Fail if a task's estimated stack depth exceeds its stack, rather
+        /// than warning about it.
+        #[clap(long)]
+        strict: bool,
         /// Allow operation in a dirty checkout, i.e. don't clean before
         /// rebuilding even if it looks like we need to.
         #[clap(long)]
@@ -167,9 +200,19 @@ enum Xtask {
         cfg: PathBuf,
     },
 
+    /// Compares two build archives, showing what changed in the app config,
+    /// memory map, task layout and caboose.
+    Diff {
+        /// Print the differences as JSON, rather than text.
+        #[clap(long)]
+        json: bool,
+        /// Path to the old build archive.
+        a: PathBuf,
+        /// Path to the new build archive.
+        b: PathBuf,
+    },
+
     /// Print out information related to the build.
-    ///
-    /// Currently only useful to print the archive path, but may grow over time.
     Print {
         /// Path to the image configuration file, in TOML.
         cfg: PathBuf,
@@ -185,6 +228,10 @@ enum Xtask {
         /// Print the expanded configuration
         #[clap(long)]
         expanded_config: bool,
+
+        /// Print a description of the image as JSON, for use by other tools
+        #[clap(long)]
+        json: bool,
     },
 
     /// Print a JSON blob with configuration info for `rust-analyzer`
@@ -245,12 +292,22 @@ fn run(xtask: Xtask) -> Result<()> {
             edges,
             cfg,
             dirty,
+            reproducible,
+            strict,
         } => {
-            let allocs = dist::package(verbose, edges, &cfg, None, dirty)?;
+            let allocs =
+                dist::package(verbose, edges, &cfg, None, dirty, reproducible)?;
             for (_, (a, _)) in allocs {
-                sizes::run(&cfg, &a, true, false, false)?;
+                sizes::run(&cfg, &a, true, false, false, strict)?;
             }
         }
+        Xtask::Verify {
+            verbose,
+            cfg,
+            archive,
+        } => {
+            manifest::verify(verbose, &cfg, &archive)?;
+        }
         Xtask::Build {
             verbose,
             edges,
@@ -262,11 +319,11 @@ fn run(xtask: Xtask) -> Result<()> {
             if list {
                 dist::list_tasks(&cfg)?;
             } else {
-                dist::package(verbose, edges, &cfg, Some(tasks), dirty)?;
+                dist::package(verbose, edges, &cfg, Some(tasks), dirty, false)?;
             }
         }
         Xtask::Flash { dirty, mut args } => {
-            dist::package(args.verbose, false, &args.cfg, None, dirty)?;
+            dist::package(args.verbose, false, &args.cfg, None, dirty, false)?;
             let toml = Config::from_file(&args.cfg)?;
             let chip = ["-c", crate::flash::chip_name(&toml.board)?];
             args.extra_options.push("--force".to_string());
@@ -288,10 +345,12 @@ fn run(xtask: Xtask) -> Result<()> {
             compare,
             save,
             dirty,
+            strict,
         } => {
-            let allocs = dist::package(verbose, false, &cfg, None, dirty)?;
+            let allocs =
+                dist::package(verbose, false, &cfg, None, dirty, false)?;
             for (_, (a, _)) in allocs {
-                sizes::run(&cfg, &a, false, compare, save)?;
+                sizes::run(&cfg, &a, false, compare, save, strict)?;
             }
         }
         Xtask::Humility { args } => {
@@ -317,7 +376,14 @@ fn run(xtask: Xtask) -> Result<()> {
                 &toml.image_names[0]
             };
             if !noflash {
-                dist::package(args.verbose, false, &args.cfg, None, false)?;
+                dist::package(
+                    args.verbose,
+                    false,
+                    &args.cfg,
+                    None,
+                    false,
+                    false,
+                )?;
                 // Delegate flashing to `humility gdb`, which also modifies
                 // the GDB startup script slightly (adding `stepi`)
                 args.extra_options.push("--load".to_string());
@@ -356,13 +422,17 @@ fn run(xtask: Xtask) -> Result<()> {
         Xtask::Graph { output, cfg } => {
             graph::task_graph(&cfg, &output)?;
         }
+        Xtask::Diff { json, a, b } => {
+            diff::run(&a, &b, json)?;
+        }
         Xtask::Print {
             cfg,
             archive,
             image_name,
             expanded_config,
+            json,
         } => {
-            print::run(&cfg, archive, image_name, expanded_config)
+            print::run(&cfg, archive, image_name, expanded_config, json)
                 .context("could not print information about the build")?;
         }
         Xtask::Lsp { clients, file } => {
@@ -0,0 +1,383 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Build manifests, and checking an archive against a fresh build.
+//!
+//! Every build archive carries a `manifest.json` recording what went into the
+//! build -- toolchain, lockfile, features -- and hashes of what came out. A
+//! `--reproducible` build pins the things that would otherwise vary from one
+//! machine (or one day) to the next, so that anyone with the same checkout and
+//! toolchain should get the same bytes; `xtask verify` checks that claim.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::Debug;
+use std::io::{Cursor, Read};
+use std::path::Path;
+
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+use sha3::{Digest, Sha3_256};
+
+use crate::dist::{self, PackageConfig};
+
+/// Version of the manifest format; bump this when making incompatible changes
+/// to `BuildManifest`.
+pub const MANIFEST_VERSION: u32 = 1;
+
+/// Name of the manifest within a build archive.
+pub const MANIFEST_NAME: &str = "manifest.json";
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BuildManifest {
+    pub version: u32,
+    /// Whether this was built with `--reproducible`
+    pub reproducible: bool,
+    pub git_rev: String,
+    pub git_dirty: bool,
+    /// Output of `rustc -V` for the toolchain used
+    pub toolchain: String,
+    pub host: String,
+    /// SHA3-256 of `Cargo.lock`
+    pub cargo_lock: String,
+    /// Hash of the app.toml and everything it inherits from
+    pub buildhash: String,
+    pub image_name: String,
+    /// Crate and features for the kernel and each task
+    pub components: BTreeMap<String, Component>,
+    /// SHA3-256 of each file in the archive, as written by `xtask dist`
+    pub files: BTreeMap<String, String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Component {
+    #[serde(rename = "crate")]
+    pub crate_name: String,
+    pub features: Vec<String>,
+}
+
+impl BuildManifest {
+    /// Collects everything except `files`, which the caller fills in as the
+    /// archive is written.
+    pub fn new(
+        cfg: &PackageConfig,
+        image_name: &str,
+        git_rev: &str,
+        git_dirty: bool,
+    ) -> Result<Self> {
+        let lock = std::fs::read("Cargo.lock").context("reading Cargo.lock")?;
+
+        let mut components = BTreeMap::new();
+        components.insert(
+            "kernel".to_owned(),
+            Component {
+                crate_name: cfg.toml.kernel.name.clone(),
+                features: cfg.toml.kernel.features.clone(),
+            },
+        );
+        for (name, task) in &cfg.toml.tasks {
+            components.insert(
+                name.clone(),
+                Component {
+                    crate_name: task.name.clone(),
+                    features: task.features.clone(),
+                },
+            );
+        }
+
+        Ok(Self {
+            version: MANIFEST_VERSION,
+            reproducible: cfg.reproducible(),
+            git_rev: git_rev.to_owned(),
+            git_dirty,
+            toolchain: cfg.toolchain.clone(),
+            host: cfg.host_triple.clone(),
+            cargo_lock: hash(&lock),
+            buildhash: format!("{:x}", cfg.toml.buildhash),
+            image_name: image_name.to_owned(),
+            components,
+            files: BTreeMap::new(),
+        })
+    }
+
+    /// Lists the ways in which the inputs recorded in `self` and `other`
+    /// differ. Output hashes aren't compared.
+    fn input_differences(&self, other: &Self) -> Vec<String> {
+        let mut out = vec![];
+        let mut check = |what: &str, a: &dyn Debug, b: &dyn Debug| {
+            let (a, b) = (format!("{a:?}"), format!("{b:?}"));
+            if a != b {
+                out.push(format!("{what}: {a} vs {b}"));
+            }
+        };
+        check("git revision", &self.git_rev, &other.git_rev);
+        check("toolchain", &self.toolchain, &other.toolchain);
+        check("host", &self.host, &other.host);
+        check("Cargo.lock", &self.cargo_lock, &other.cargo_lock);
+        check("app.toml", &self.buildhash, &other.buildhash);
+        let names: BTreeSet<&String> = self
+            .components
+            .keys()
+            .chain(other.components.keys())
+            .collect();
+        for name in names {
+            check(
+                &format!("{name} crate and features"),
+                &self.components.get(name),
+                &other.components.get(name),
+            );
+        }
+        out
+    }
+}
+
+/// Returns the hex-encoded SHA3-256 of `data`.
+pub fn hash(data: &[u8]) -> String {
+    hex::encode(Sha3_256::digest(data))
+}
+
+/// Reads every file in the build archive `data`, returning a map from name to
+/// hash, along with the archive's manifest (if it has one).
+fn read_archive(
+    data: &[u8],
+) -> Result<(BTreeMap<String, String>, Option<BuildManifest>)> {
+    let mut zip = zip::ZipArchive::new(Cursor::new(data))?;
+
+    let mut files = BTreeMap::new();
+    let mut manifest = None;
+    for i in 0..zip.len() {
+        let mut f = zip.by_index(i)?;
+        let mut data = vec![];
+        f.read_to_end(&mut data)?;
+        if f.name() == MANIFEST_NAME {
+            manifest = Some(
+                serde_json::from_slice(&data)
+                    .context("could not parse build manifest")?,
+            );
+        } else {
+            files.insert(f.name().to_owned(), hash(&data));
+        }
+    }
+    Ok((files, manifest))
+}
+
+/// Rebuilds the image described by the archive at `archive` from the current
+/// checkout, and checks that the result is identical.
+pub fn verify(verbose: bool, app_toml: &Path, archive: &Path) -> Result<()> {
+    // We hang on to the whole archive, because it may well be the one that
+    // the rebuild is about to overwrite.
+    let original = std::fs::read(archive)
+        .with_context(|| format!("opening {}", archive.display()))?;
+    let (expected_files, expected) = read_archive(&original)
+        .with_context(|| format!("reading {}", archive.display()))?;
+    let expected = match expected {
+        Some(m) => m,
+        None => bail!(
+            "{} has no build manifest; it predates `xtask verify`",
+            archive.display()
+        ),
+    };
+    if expected.version != MANIFEST_VERSION {
+        bail!(
+            "{} has manifest version {}, but this xtask only understands \
+             version {}",
+            archive.display(),
+            expected.version,
+            MANIFEST_VERSION
+        );
+    }
+    if !expected.reproducible {
+        println!(
+            "warning: {} was not built with --reproducible; differences \
+             are likely",
+            archive.display()
+        );
+    }
+
+    let cfg = PackageConfig::new(app_toml, verbose, false, true)?;
+    if !cfg.toml.image_names.contains(&expected.image_name) {
+        bail!(
+            "{} has no image named {:?}",
+            app_toml.display(),
+            expected.image_name
+        );
+    }
+
+    // Catch the obvious mismatches before spending time on a build.
+    let (git_rev, git_dirty) = dist::get_git_status()?;
+    let current =
+        BuildManifest::new(&cfg, &expected.image_name, &git_rev, git_dirty)?;
+    let mismatches = current.input_differences(&expected);
+    if !mismatches.is_empty() {
+        bail!(
+            "this checkout can't reproduce {}; its inputs differ \
+             (here vs archive):\n  {}",
+            archive.display(),
+            mismatches.join("\n  ")
+        );
+    }
+
+    let mut rebuilt = cfg.img_file(
+        cfg.toml.archive_name(&expected.image_name),
+        &expected.image_name,
+    );
+    let overwritten = rebuilt.exists()
+        && std::fs::canonicalize(archive)? == std::fs::canonicalize(&rebuilt)?;
+
+    dist::package(verbose, false, app_toml, None, false, true)?;
+
+    let rebuilt_data = std::fs::read(&rebuilt)
+        .with_context(|| format!("opening {}", rebuilt.display()))?;
+    if overwritten {
+        // Put the archive back as we found it, and keep the rebuild alongside
+        // it for inspection.
+        std::fs::write(archive, &original)?;
+        let mut path = rebuilt.into_os_string();
+        path.push(".rebuilt");
+        rebuilt = path.into();
+        std::fs::write(&rebuilt, &rebuilt_data)?;
+    }
+    let (actual_files, _) = read_archive(&rebuilt_data)
+        .with_context(|| format!("reading {}", rebuilt.display()))?;
+
+    let names: BTreeSet<&String> =
+        expected_files.keys().chain(actual_files.keys()).collect();
+    let mut differences = vec![];
+    for name in names {
+        match (expected_files.get(name), actual_files.get(name)) {
+            (Some(a), Some(b)) if a == b => (),
+            (Some(_), Some(_)) => differences.push(format!("{name} differs")),
+            (Some(_), None) => {
+                differences.push(format!("{name} is missing from the rebuild"))
+            }
+            (None, _) => {
+                differences.push(format!("{name} is new in the rebuild"))
+            }
+        }
+    }
+    if !differences.is_empty() {
+        bail!(
+            "rebuilt archive {} does not match {}:\n  {}",
+            rebuilt.display(),
+            archive.display(),
+            differences.join("\n  ")
+        );
+    }
+
+    println!(
+        "{} matches the rebuilt archive ({} files)",
+        archive.display(),
+        expected_files.len()
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    fn manifest() -> BuildManifest {
+        BuildManifest {
+            version: MANIFEST_VERSION,
+            reproducible: true,
+            git_rev: "abc123".to_owned(),
+            git_dirty: false,
+            toolchain: "rustc 1.0.0".to_owned(),
+            host: "x86_64-unknown-linux-gnu".to_owned(),
+            cargo_lock: hash(b"lock"),
+            buildhash: "1234".to_owned(),
+            image_name: "default".to_owned(),
+            components: [(
+                "kernel".to_owned(),
+                Component {
+                    crate_name: "demo-app".to_owned(),
+                    features: vec![],
+                },
+            )]
+            .into_iter()
+            .collect(),
+            files: BTreeMap::new(),
+        }
+    }
+
+    fn zip(files: &[(&str, &[u8])]) -> Vec<u8> {
+        let mut zip = zip::ZipWriter::new(Cursor::new(vec![]));
+        for (name, data) in files {
+            zip.start_file(*name, zip::write::FileOptions::default())
+                .unwrap();
+            zip.write_all(data).unwrap();
+        }
+        zip.finish().unwrap().into_inner()
+    }
+
+    #[test]
+    fn same_inputs_match() {
+        let mut other = manifest();
+        // Neither outputs nor dirtiness are inputs.
+        other.files.insert("img/final.bin".to_owned(), hash(b"bin"));
+        other.git_dirty = true;
+        assert!(manifest().input_differences(&other).is_empty());
+    }
+
+    #[test]
+    fn input_differences_are_listed() {
+        let mut other = manifest();
+        other.toolchain = "rustc 2.0.0".to_owned();
+        other
+            .components
+            .get_mut("kernel")
+            .unwrap()
+            .features
+            .push("dump".to_owned());
+        other.components.insert(
+            "idle".to_owned(),
+            Component {
+                crate_name: "task-idle".to_owned(),
+                features: vec![],
+            },
+        );
+
+        let differences = manifest().input_differences(&other);
+        assert_eq!(differences.len(), 3);
+        assert!(differences[0].starts_with("toolchain: "));
+        assert!(differences[1].starts_with("idle crate and features: None vs"));
+        assert!(differences[2].starts_with("kernel crate and features: "));
+    }
+
+    #[test]
+    fn archive_files_are_hashed() {
+        let manifest_json = serde_json::to_vec(&manifest()).unwrap();
+        let archive = zip(&[
+            ("app.toml", b"name = \"demo\""),
+            (MANIFEST_NAME, &manifest_json),
+            ("img/final.bin", b"\x00\x01"),
+        ]);
+
+        let (files, found) = read_archive(&archive).unwrap();
+        assert_eq!(found, Some(manifest()));
+        // The manifest doesn't list itself.
+        assert_eq!(
+            files,
+            [
+                ("app.toml".to_owned(), hash(b"name = \"demo\"")),
+                ("img/final.bin".to_owned(), hash(b"\x00\x01")),
+            ]
+            .into_iter()
+            .collect()
+        );
+    }
+
+    #[test]
+    fn archive_without_manifest() {
+        let (files, found) = read_archive(&zip(&[("app.toml", b"")])).unwrap();
+        assert_eq!(found, None);
+        assert_eq!(files.len(), 1);
+    }
+
+    #[test]
+    fn bad_archives_are_errors() {
+        assert!(read_archive(&zip(&[(MANIFEST_NAME, b"{")])).is_err());
+        assert!(read_archive(b"not a zip").is_err());
+    }
+}
@@ -2,20 +2,36 @@
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at https://mozilla.org/MPL/2.0/.
 
+use std::collections::BTreeMap;
+use std::io::Read;
+use std::ops::Range;
 use std::path::Path;
 
-use anyhow::{bail, Context, Error, Result};
+use anyhow::{anyhow, bail, Context, Error, Result};
+use indexmap::IndexMap;
+use serde::{Deserialize, Serialize};
 
-use crate::{config::Config, dist::PackageConfig};
+use crate::{
+    config::Config,
+    dist::{Allocations, PackageConfig},
+};
+
+/// Version of the image description format; bump this when making
+/// incompatible changes to `ImageDescription`.
+pub const DESCRIPTION_VERSION: u32 = 1;
+
+/// Name of the image description within a build archive.
+pub const DESCRIPTION_NAME: &str = "image.json";
 
 pub fn run(
     cfg: &Path,
     archive: bool,
     image_name: Option<String>,
     expanded_config: bool,
+    json: bool,
 ) -> Result<()> {
     if archive {
-        let config = PackageConfig::new(cfg, false, false)
+        let config = PackageConfig::new(cfg, false, false, false)
             .context("could not create build configuration")?;
 
         let image_name = image_name.unwrap_or(String::from("default"));
@@ -35,9 +51,260 @@ pub fn run(
         let config = Config::from_file(cfg)
             .context("could not load build configuration")?;
         println!("{:#?}", config);
+    } else if json {
+        let config = PackageConfig::new(cfg, false, false, false)
+            .context("could not create build configuration")?;
+        let image_name = match image_name {
+            Some(name) => name,
+            None => config.toml.image_names[0].clone(),
+        };
+        if !config.toml.check_image_name(&image_name) {
+            bail!("cannot find image {}", image_name);
+        }
+
+        // Memory layout isn't known until the image is built. If it has been,
+        // from this same config, the archive has the whole story.
+        let archive =
+            config.img_file(config.toml.archive_name(&image_name), &image_name);
+        let built = read_description(&archive)?
+            .filter(|d| d.buildhash == format!("{:x}", config.toml.buildhash));
+        let description = match built {
+            Some(d) => d,
+            None => {
+                eprintln!(
+                    "note: {} is missing or out of date; \
+                     leaving out memory regions",
+                    archive.display()
+                );
+                describe(&config.toml, &image_name, None)?
+            }
+        };
+        println!("{}", serde_json::to_string_pretty(&description)?);
     } else {
-        bail!("I'm not sure what to print. Currently supported: --archive");
+        bail!(
+            "I'm not sure what to print. Currently supported: --archive, \
+             --expanded-config, --json"
+        );
     }
 
     Ok(())
 }
+
+/// Everything that external tools need to know about an image, and would
+/// otherwise have to work out from its `app.toml` and ELF files.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ImageDescription {
+    pub version: u32,
+    pub name: String,
+    pub image_name: String,
+    pub board: String,
+    pub chip: String,
+    pub target: String,
+    /// Hash of the app.toml and everything it inherits from
+    pub buildhash: String,
+    pub kernel: KernelDescription,
+    /// Tasks, in task index order
+    pub tasks: Vec<TaskDescription>,
+    /// Network sockets, in `SocketName` order
+    pub sockets: Vec<SocketDescription>,
+    /// I2C devices, in the order used by `validate()`
+    pub i2c_devices: Vec<build_i2c::I2cDeviceInfo>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct KernelDescription {
+    #[serde(rename = "crate")]
+    pub crate_name: String,
+    pub features: Vec<String>,
+    /// Memory regions, if the image has been built
+    pub regions: Option<BTreeMap<String, RegionDescription>>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct TaskDescription {
+    pub index: usize,
+    pub name: String,
+    #[serde(rename = "crate")]
+    pub crate_name: String,
+    pub priority: u8,
+    pub start: bool,
+    pub stacksize: Option<u32>,
+    pub features: Vec<String>,
+    /// Map from notification name to its bit mask
+    pub notifications: IndexMap<String, u32>,
+    /// Map from slot name to the task bound to it
+    pub task_slots: IndexMap<String, String>,
+    pub interrupts: Vec<InterruptDescription>,
+    pub uses: Vec<String>,
+    pub extern_regions: Vec<String>,
+    /// Memory regions owned by the task, if the image has been built
+    pub regions: Option<BTreeMap<String, RegionDescription>>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct InterruptDescription {
+    /// Name of the interrupt, as `peripheral.interrupt`
+    pub name: String,
+    pub irq: u32,
+    pub notification: String,
+    pub mask: u32,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct RegionDescription {
+    pub base: u32,
+    pub size: u32,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SocketDescription {
+    pub index: usize,
+    pub name: String,
+    pub kind: String,
+    pub port: u16,
+    pub owner: String,
+    pub notification: String,
+    pub tx_packets: usize,
+    pub tx_bytes: usize,
+    pub rx_packets: usize,
+    pub rx_bytes: usize,
+}
+
+/// Builds a description of `image_name`. Memory regions are only filled in if
+/// `allocs` are given.
+pub fn describe(
+    toml: &Config,
+    image_name: &str,
+    allocs: Option<&Allocations>,
+) -> Result<ImageDescription> {
+    let regions = |map: &BTreeMap<String, Range<u32>>| -> BTreeMap<_, _> {
+        map.iter()
+            .map(|(name, r)| {
+                let region = RegionDescription {
+                    base: r.start,
+                    size: r.end - r.start,
+                };
+                (name.clone(), region)
+            })
+            .collect()
+    };
+
+    let mut tasks = vec![];
+    for (index, (name, task)) in toml.tasks.iter().enumerate() {
+        let notifications = task
+            .notifications
+            .iter()
+            .map(|n| Ok((n.clone(), task.notification_mask(n)?)))
+            .collect::<Result<_>>()?;
+
+        let mut interrupts = vec![];
+        for (irq, notification) in &task.interrupts {
+            let num = irq
+                .split_once('.')
+                .and_then(|(p, i)| toml.peripherals.get(p)?.interrupts.get(i))
+                .ok_or_else(|| {
+                    anyhow!("task {name}: unknown interrupt {irq}")
+                })?;
+            interrupts.push(InterruptDescription {
+                name: irq.clone(),
+                irq: *num,
+                notification: notification.clone(),
+                mask: task.notification_mask(notification)?,
+            });
+        }
+
+        tasks.push(TaskDescription {
+            index,
+            name: name.clone(),
+            crate_name: task.name.clone(),
+            priority: task.priority,
+            start: task.start,
+            stacksize: task.stacksize.or(toml.stacksize),
+            features: task.features.clone(),
+            notifications,
+            task_slots: task.task_slots.clone(),
+            interrupts,
+            uses: task.uses.clone(),
+            extern_regions: task.extern_regions.clone(),
+            regions: allocs.map(|a| regions(&a.tasks[name])),
+        });
+    }
+
+    // The net and I2C build helpers each read their own part of the global
+    // config, so hand it to them the same way the build does: as TOML.
+    let app_config = match &toml.config {
+        Some(c) => toml::to_string(c)?,
+        None => String::new(),
+    };
+
+    #[derive(Deserialize)]
+    struct NetOnly {
+        net: Option<build_net::NetConfig>,
+    }
+    let net: NetOnly =
+        toml::from_str(&app_config).context("malformed config.net")?;
+    let sockets = net
+        .net
+        .map(|net| net.sockets)
+        .unwrap_or_default()
+        .into_iter()
+        .enumerate()
+        .map(|(index, (name, s))| SocketDescription {
+            index,
+            name,
+            kind: s.kind,
+            port: s.port,
+            owner: s.owner.name,
+            notification: s.owner.notification,
+            tx_packets: s.tx.packets,
+            tx_bytes: s.tx.bytes,
+            rx_packets: s.rx.packets,
+            rx_bytes: s.rx.bytes,
+        })
+        .collect();
+
+    let i2c_devices = build_i2c::describe_devices(toml::from_str::<
+        toml::Value,
+    >(&app_config)?)?;
+
+    Ok(ImageDescription {
+        version: DESCRIPTION_VERSION,
+        name: toml.name.clone(),
+        image_name: image_name.to_owned(),
+        board: toml.board.clone(),
+        chip: toml.chip.clone(),
+        target: toml.target.clone(),
+        buildhash: format!("{:x}", toml.buildhash),
+        kernel: KernelDescription {
+            crate_name: toml.kernel.name.clone(),
+            features: toml.kernel.features.clone(),
+            regions: allocs.map(|a| regions(&a.kernel)),
+        },
+        tasks,
+        sockets,
+        i2c_devices,
+    })
+}
+
+/// Reads the image description from the build archive at `path`, returning
+/// `None` if there's no archive there, or it predates image descriptions.
+fn read_description(path: &Path) -> Result<Option<ImageDescription>> {
+    let file = match std::fs::File::open(path) {
+        Ok(f) => f,
+        Err(_) => return Ok(None),
+    };
+    let mut zip = zip::ZipArchive::new(file)
+        .with_context(|| format!("reading {}", path.display()))?;
+    let mut f = match zip.by_name(DESCRIPTION_NAME) {
+        Ok(f) => f,
+        Err(_) => return Ok(None),
+    };
+    let mut data = vec![];
+    f.read_to_end(&mut data)?;
+    let description: ImageDescription = serde_json::from_slice(&data)
+        .context("could not parse image description")?;
+    if description.version != DESCRIPTION_VERSION {
+        return Ok(None);
+    }
+    Ok(Some(description))
+}
@@ -0,0 +1,92 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+use crate::elf;
+use anyhow::{bail, Context, Result};
+use scroll::Pread;
+
+pub const RINGBUF_REGISTRY_SECTION: &str = ".ringbuf_registry";
+pub const RINGBUF_EXPORT_TABLE_SECTION: &str = ".ringbuf_export_table";
+
+/// A ring buffer's static, as recorded by the `ringbuf!` macro
+#[derive(Debug)]
+pub struct RegistryEntry {
+    pub address: u32,
+    pub size: u32,
+}
+
+/// The supervisor's table of exported ring buffers, which we fill in
+#[derive(Debug)]
+pub struct ExportTable {
+    pub address: u64,
+    pub file_offset: u64,
+    pub capacity: usize,
+}
+
+/// Reads the pointer-sized words that make up `section`, or returns `None` if
+/// there's no such section.
+fn section_words(
+    src: &[u8],
+    elf: &goblin::elf::Elf,
+    section: &str,
+) -> Result<Option<Vec<u64>>> {
+    let header = match elf::get_section_by_name(elf, section) {
+        Some(header) => header,
+        None => return Ok(None),
+    };
+    let data = &src[header.sh_offset as usize
+        ..(header.sh_offset + header.sh_size) as usize];
+
+    let endianness = elf::get_endianness(elf);
+    let cur_offset = &mut 0;
+    let mut words = vec![];
+    while *cur_offset < data.len() {
+        words.push(if elf.is_64 {
+            data.gread_with::<u64>(cur_offset, endianness)?
+        } else {
+            data.gread_with::<u32>(cur_offset, endianness)? as u64
+        });
+    }
+    Ok(Some(words))
+}
+
+pub fn get_registry_entries(
+    src: &[u8],
+    elf: &goblin::elf::Elf,
+) -> Result<Vec<RegistryEntry>> {
+    let words =
+        section_words(src, elf, RINGBUF_REGISTRY_SECTION)?.unwrap_or_default();
+    if words.len() % 2 != 0 {
+        bail!("{RINGBUF_REGISTRY_SECTION} has a partial entry");
+    }
+    Ok(words
+        .chunks(2)
+        .map(|w| RegistryEntry {
+            address: w[0] as u32,
+            size: w[1] as u32,
+        })
+        .collect())
+}
+
+pub fn get_export_table(
+    src: &[u8],
+    elf: &goblin::elf::Elf,
+) -> Result<Option<ExportTable>> {
+    // If the section isn't present, this task doesn't export ring buffers.
+    let words = match section_words(src, elf, RINGBUF_EXPORT_TABLE_SECTION)? {
+        Some(words) if !words.is_empty() => words,
+        _ => return Ok(None),
+    };
+    if words.len() != 2 {
+        bail!("expected one entry in {RINGBUF_EXPORT_TABLE_SECTION}");
+    }
+    let address = words[0];
+    let file_offset = elf::get_file_offset_by_vma(elf, address)
+        .context("could not get ring buffer export table file offset")?;
+    Ok(Some(ExportTable {
+        address,
+        file_offset,
+        capacity: words[1] as usize,
+    }))
+}
@@ -16,6 +16,7 @@ use indexmap::IndexMap;
 
 use crate::{
     dist::{Allocations, DEFAULT_KERNEL_STACK},
+    stack::{self, StackReport},
     Config,
 };
 
@@ -28,12 +29,16 @@ struct TaskSizes<'a> {
 /// When `only_suggest` is true, prints only the suggested improvements to
 /// stderr, rather than printing all sizes.  Suggestions are formatted to
 /// match compiler warnings.
+///
+/// When `strict` is true, a task whose estimated stack depth exceeds its
+/// stack is an error rather than a warning.
 pub fn run(
     cfg: &Path,
     allocs: &Allocations,
     only_suggest: bool,
     compare: bool,
     save: bool,
+    strict: bool,
 ) -> Result<()> {
     let toml = Config::from_file(cfg)?;
     let sizes = create_sizes(&toml)?;
@@ -60,12 +65,44 @@ pub fn run(
         Box::new(std::io::stdout())
     };
 
+    let stacks = analyze_stacks(&toml, &mut out)?;
+
     // Print detailed sizes relative to usage
     if !only_suggest {
         let map = build_memory_map(&toml, &sizes, allocs)?;
         print_memory_map(&toml, &map)?;
         print!("\n\n");
         print_task_table(&toml, &map)?;
+        print!("\n\n");
+        print_stack_table(&toml, &stacks)?;
+    }
+
+    // A task whose stack can't hold the deepest call path we found may well
+    // overflow -- but that path may never be taken, so this only fails the
+    // build if asked to.
+    let mut overflows = vec![];
+    for (name, report) in &stacks {
+        let stacksize = task_stacksize(&toml, name);
+        if report.depth > u64::from(stacksize) {
+            overflows.push(format!(
+                "{}: stack of {} bytes, but needs at least {} for {}",
+                name,
+                stacksize,
+                report.depth,
+                report.path.join(" -> "),
+            ));
+        }
+    }
+    if !overflows.is_empty() {
+        let msg = format!(
+            "tasks may overflow their stacks:\n  {}",
+            overflows.join("\n  ")
+        );
+        if strict {
+            bail!("{msg}");
+        }
+        write!(out, "{}", "warning".bold().yellow())?;
+        writeln!(out, ": {msg}")?;
     }
 
     // Because tasks are autosized, the only place where we can improve
@@ -84,17 +121,7 @@ pub fn run(
         }
         if !printed_header {
             printed_header = true;
-            if only_suggest {
-                write!(out, "{}", "warning".bold().yellow())?;
-                writeln!(out, ": memory allocation is sub-optimal")?;
-                writeln!(out, "{}", "Suggested improvements:".bold())?;
-            } else {
-                writeln!(
-                    out,
-                    "{}",
-                    "\n========== Suggested changes ==========".bold()
-                )?;
-            }
+            print_suggestion_header(&mut out, only_suggest)?;
         }
         if !printed_name {
             printed_name = true;
@@ -109,6 +136,113 @@ pub fn run(
         )?;
     }
 
+    // Stacks are the other thing that's sized by hand.
+    for (name, report) in &stacks {
+        let stacksize = task_stacksize(&toml, name);
+        if !report.oversized(stacksize) {
+            continue;
+        }
+        if !printed_header {
+            printed_header = true;
+            print_suggestion_header(&mut out, only_suggest)?;
+        }
+        writeln!(out, "{}:", name)?;
+        writeln!(
+            out,
+            "  {:<6} {: >5} {}",
+            "stack:",
+            report.suggestion(),
+            format!(" (currently {})", stacksize).dimmed()
+        )?;
+    }
+
+    Ok(())
+}
+
+/// Suggestions are formatted to match compiler warnings when `only_suggest` is
+/// set, since they then appear in the middle of build output.
+fn print_suggestion_header(
+    out: &mut dyn Write,
+    only_suggest: bool,
+) -> Result<()> {
+    if only_suggest {
+        write!(out, "{}", "warning".bold().yellow())?;
+        writeln!(out, ": memory allocation is sub-optimal")?;
+        writeln!(out, "{}", "Suggested improvements:".bold())?;
+    } else {
+        writeln!(
+            out,
+            "{}",
+            "\n========== Suggested changes ==========".bold()
+        )?;
+    }
+    Ok(())
+}
+
+fn task_stacksize(toml: &Config, name: &str) -> u32 {
+    toml.tasks[name].stacksize.or(toml.stacksize).unwrap()
+}
+
+/// Runs stack depth analysis on every task. Failing to analyze a task (for
+/// example, because it was built without stack size information) only earns
+/// a warning, since the analysis is advisory.
+fn analyze_stacks<'a>(
+    toml: &'a Config,
+    out: &mut dyn Write,
+) -> Result<IndexMap<&'a str, StackReport>> {
+    let mut stacks = IndexMap::new();
+    for name in toml.tasks.keys() {
+        match stack::analyze_task(toml, name) {
+            Ok(report) => {
+                stacks.insert(name.as_str(), report);
+            }
+            Err(e) => {
+                write!(out, "{}", "warning".bold().yellow())?;
+                writeln!(
+                    out,
+                    ": could not analyze stack of {}: {:#}",
+                    name, e
+                )?;
+            }
+        }
+    }
+    Ok(stacks)
+}
+
+fn print_stack_table(
+    toml: &Config,
+    stacks: &IndexMap<&str, StackReport>,
+) -> Result<()> {
+    let task_pad = stacks
+        .keys()
+        .chain(std::iter::once(&"PROGRAM"))
+        .map(|k| k.len())
+        .max()
+        .unwrap_or(0);
+
+    println!(
+        "{:<task$}  {:<6}  {:<7}  NOTES",
+        "PROGRAM",
+        "STACK",
+        "DEPTH",
+        task = task_pad,
+    );
+    for (name, report) in stacks {
+        // An incomplete analysis only gives us a lower bound.
+        let depth = if report.complete {
+            format!("{}", report.depth)
+        } else {
+            format!(">={}", report.depth)
+        };
+        println!(
+            "{:<task$}  {:<6}  {:<7}  {}",
+            name,
+            task_stacksize(toml, name),
+            depth,
+            report.caveats.join("; ").dimmed(),
+            task = task_pad,
+        );
+    }
     Ok(())
 }
 
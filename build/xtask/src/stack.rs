@@ -0,0 +1,587 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Static stack depth analysis.
+//!
+//! Tasks are built with `-Z emit-stack-sizes`, which makes the compiler record
+//! each function's frame size in a `.stack_sizes` section. We combine that with
+//! a call graph, recovered by scanning each function for Thumb branch-and-link
+//! instructions, to find the deepest path from the task's entry point.
+//!
+//! A branch out of a function without linking, conditional or not, is taken to
+//! be a tail call: the caller has popped its frame by then, so the callee's
+//! stack use replaces the caller's rather than adding to it.
+//!
+//! This can't see through indirect calls (function pointers and trait
+//! objects), and functions written in assembly have no recorded frame, so in
+//! that respect the result can fall short. On the other hand, the call graph
+//! includes paths that can never actually be taken, so the deepest path found
+//! may be deeper than any the task will ever go down. The result is an
+//! estimate, and is treated as one.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::Path;
+
+use anyhow::{bail, Context, Result};
+use goblin::Object;
+
+use crate::config::Config;
+use crate::elf::{get_section_by_name, get_section_by_vma};
+
+/// A function found in a task image.
+#[derive(Debug)]
+struct Function {
+    name: String,
+    /// Frame size from `.stack_sizes`, if the compiler recorded one.
+    frame: Option<u64>,
+    /// Addresses of functions this one calls directly.
+    calls: BTreeSet<u32>,
+    /// Addresses of functions this one branches to without linking.
+    tail_calls: BTreeSet<u32>,
+    /// Whether this function makes any calls we can't follow.
+    indirect: bool,
+}
+
+/// Result of analyzing one task.
+#[derive(Debug)]
+pub struct StackReport {
+    /// Worst-case stack use found, in bytes, including room for an exception
+    /// frame at the deepest point.
+    pub depth: u64,
+    /// Function names along the deepest path, starting at the entry point.
+    pub path: Vec<String>,
+    /// Whether the analysis saw every call, so that `depth` can't fall short
+    /// of the deepest path through the call graph.
+    pub complete: bool,
+    /// Reasons the analysis isn't complete, for display.
+    pub caveats: Vec<String>,
+}
+
+impl StackReport {
+    /// Returns a suggested stack size, with some headroom over `depth`.
+    pub fn suggestion(&self) -> u32 {
+        let with_margin = self.depth + self.depth / 4;
+        // Stacks must be 8-byte aligned.
+        ((with_margin + 7) & !7) as u32
+    }
+
+    /// Checks whether `stacksize` is grossly larger than it needs to be.
+    /// We can only say this when the analysis is complete.
+    pub fn oversized(&self, stacksize: u32) -> bool {
+        self.complete
+            && u64::from(stacksize) > 2 * self.depth
+            && u64::from(stacksize) - self.depth >= 512
+    }
+}
+
+/// Analyzes the stack use of `task`, based on the temporary ELF file left by
+/// the size-measuring link.
+pub fn analyze_task(toml: &Config, task: &str) -> Result<StackReport> {
+    let elf_name = Path::new("target")
+        .join(&toml.name)
+        .join("dist")
+        .join(format!("{}.tmp", task));
+    let buffer = std::fs::read(&elf_name)
+        .with_context(|| format!("reading {}", elf_name.display()))?;
+    let elf = match Object::parse(&buffer)? {
+        Object::Elf(elf) => elf,
+        o => bail!("Invalid Object {:?}", o),
+    };
+
+    let functions = load_functions(&elf, &buffer)?;
+    let entry = functions
+        .iter()
+        .find(|(_, f)| f.name == "_start")
+        .map(|(&addr, _)| addr)
+        .ok_or_else(|| anyhow::anyhow!("{}: no _start symbol", task))?;
+
+    let mut walk = Walk {
+        functions: &functions,
+        memo: BTreeMap::new(),
+        active: BTreeMap::new(),
+        recursive: BTreeSet::new(),
+    };
+    let (depth, path) = walk.deepest(entry);
+
+    // Everything reachable from the entry point, for the caveats.
+    let mut reachable = BTreeSet::new();
+    let mut todo = vec![entry];
+    while let Some(addr) = todo.pop() {
+        if reachable.insert(addr) {
+            let f = &functions[&addr];
+            todo.extend(f.calls.iter().chain(&f.tail_calls).copied());
+        }
+    }
+
+    let mut caveats = vec![];
+    let indirect = reachable
+        .iter()
+        .filter(|addr| functions[addr].indirect)
+        .count();
+    if indirect > 0 {
+        caveats.push(format!("{} functions make indirect calls", indirect));
+    }
+    let unknown = reachable
+        .iter()
+        .filter(|addr| functions[addr].frame.is_none())
+        .count();
+    if unknown > 0 {
+        caveats.push(format!("{} functions have no frame size", unknown));
+    }
+    if !walk.recursive.is_empty() {
+        let names: Vec<_> = walk
+            .recursive
+            .iter()
+            .map(|addr| demangle(&functions[addr].name))
+            .collect();
+        caveats.push(format!("recursion through {}", names.join(", ")));
+    }
+
+    Ok(StackReport {
+        depth: depth + exception_frame_size(&toml.target),
+        path: path
+            .into_iter()
+            .map(|addr| demangle(&functions[&addr].name))
+            .collect(),
+        complete: caveats.is_empty(),
+        caveats,
+    })
+}
+
+/// Room that must be left at the bottom of the stack for the processor to push
+/// an exception frame, since interrupts and syscalls land on the task's stack.
+fn exception_frame_size(target: &str) -> u64 {
+    if target.ends_with("eabihf") {
+        // Basic frame, plus s0-s15, FPSCR and padding.
+        26 * 4
+    } else {
+        8 * 4
+    }
+}
+
+/// Collects every function symbol in `elf`, with its frame size and calls.
+fn load_functions(
+    elf: &goblin::elf::Elf,
+    buffer: &[u8],
+) -> Result<BTreeMap<u32, Function>> {
+    let frames = load_stack_sizes(elf, buffer)?;
+
+    let mut functions = BTreeMap::new();
+    for sym in elf.syms.iter() {
+        if sym.st_type() != goblin::elf::sym::STT_FUNC || sym.st_size == 0 {
+            continue;
+        }
+        // Thumb function symbols have their low bit set.
+        let addr = (sym.st_value & !1) as u32;
+        let name = elf.strtab.get_at(sym.st_name).unwrap_or("?").to_owned();
+
+        let section = match get_section_by_vma(elf, u64::from(addr)) {
+            Some(s) if s.sh_type != goblin::elf::section_header::SHT_NOBITS => {
+                s
+            }
+            _ => continue,
+        };
+        let offset =
+            (u64::from(addr) - section.sh_addr + section.sh_offset) as usize;
+        let code = buffer
+            .get(offset..offset + sym.st_size as usize)
+            .context("function extends past end of file")?;
+        let (calls, tail_calls, indirect) = scan_thumb(code, addr);
+
+        functions.insert(
+            addr,
+            Function {
+                name,
+                frame: frames.get(&addr).copied(),
+                calls,
+                tail_calls,
+                indirect,
+            },
+        );
+    }
+
+    // Drop calls to things that aren't function entry points (e.g. literal
+    // pool data that happened to decode as a branch).
+    let entries: BTreeSet<u32> = functions.keys().copied().collect();
+    for f in functions.values_mut() {
+        f.calls.retain(|addr| entries.contains(addr));
+        f.tail_calls.retain(|addr| entries.contains(addr));
+    }
+    Ok(functions)
+}
+
+/// Parses the `.stack_sizes` section, which is a sequence of (function
+/// address, ULEB128 frame size) pairs.
+fn load_stack_sizes(
+    elf: &goblin::elf::Elf,
+    buffer: &[u8],
+) -> Result<BTreeMap<u32, u64>> {
+    let section = get_section_by_name(elf, ".stack_sizes")
+        .context("no .stack_sizes section; was -Z emit-stack-sizes used?")?;
+    let start = section.sh_offset as usize;
+    let mut data = &buffer[start..start + section.sh_size as usize];
+
+    let mut out = BTreeMap::new();
+    while data.len() >= 4 {
+        let addr = u32::from_le_bytes(data[..4].try_into().unwrap()) & !1;
+        data = &data[4..];
+        let mut size = 0u64;
+        let mut shift = 0;
+        loop {
+            let (&byte, rest) =
+                data.split_first().context("truncated .stack_sizes")?;
+            data = rest;
+            size |= u64::from(byte & 0x7f) << shift;
+            shift += 7;
+            if byte & 0x80 == 0 {
+                break;
+            }
+        }
+        out.insert(addr, size);
+    }
+    Ok(out)
+}
+
+/// Scans Thumb code at `base` for branches that leave the function, returning
+/// the targets of calls and of tail calls, and whether any indirect calls were
+/// found.
+fn scan_thumb(code: &[u8], base: u32) -> (BTreeSet<u32>, BTreeSet<u32>, bool) {
+    let end = base + code.len() as u32;
+    let half = |i: usize| u32::from(u16::from_le_bytes([code[i], code[i + 1]]));
+    let sign_extend =
+        |v: u32, bits: u32| ((v << (32 - bits)) as i32) >> (32 - bits);
+
+    let mut calls = BTreeSet::new();
+    let mut tail_calls = BTreeSet::new();
+    let mut indirect = false;
+    let mut i = 0;
+    while i + 2 <= code.len() {
+        let pc = base + i as u32;
+        let hw1 = half(i);
+        if matches!(hw1 >> 11, 0b11101 | 0b11110 | 0b11111) {
+            // 32-bit instruction.
+            if i + 4 > code.len() {
+                break;
+            }
+            let hw2 = half(i + 2);
+            if hw1 >> 11 == 0b11110 {
+                let s = (hw1 >> 10) & 1;
+                let i1 = !((hw2 >> 13) ^ s) & 1;
+                let i2 = !((hw2 >> 11) ^ s) & 1;
+                let high = s << 24 | i1 << 23 | i2 << 22 | (hw1 & 0x3ff) << 12;
+                let target = match hw2 & 0xd000 {
+                    // BL.
+                    0xd000 => {
+                        let imm = sign_extend(high | (hw2 & 0x7ff) << 1, 25);
+                        Some((&mut calls, (pc + 4).wrapping_add(imm as u32)))
+                    }
+                    // B.W, which is a tail call if it leaves the function.
+                    0x9000 => {
+                        let imm = sign_extend(high | (hw2 & 0x7ff) << 1, 25);
+                        Some((
+                            &mut tail_calls,
+                            (pc + 4).wrapping_add(imm as u32),
+                        ))
+                    }
+                    // B<c>.W, likewise. Conditions 0b111x are other
+                    // instructions (MSR, hints and so on) sharing the
+                    // encoding, and this form's J bits aren't inverted.
+                    0x8000 if (hw1 >> 7) & 0b111 != 0b111 => {
+                        let j1 = (hw2 >> 13) & 1;
+                        let j2 = (hw2 >> 11) & 1;
+                        let imm = sign_extend(
+                            s << 20
+                                | j2 << 19
+                                | j1 << 18
+                                | (hw1 & 0x3f) << 12
+                                | (hw2 & 0x7ff) << 1,
+                            21,
+                        );
+                        Some((
+                            &mut tail_calls,
+                            (pc + 4).wrapping_add(imm as u32),
+                        ))
+                    }
+                    // BLX to ARM code, which we'll never find, but record.
+                    0xc000 => {
+                        let imm = sign_extend(high | (hw2 & 0x7fe) << 1, 25);
+                        Some((
+                            &mut calls,
+                            ((pc + 4) & !3).wrapping_add(imm as u32),
+                        ))
+                    }
+                    _ => None,
+                };
+                if let Some((set, t)) = target {
+                    if t < base || t >= end {
+                        set.insert(t);
+                    }
+                }
+            }
+            i += 4;
+        } else {
+            if hw1 & 0xff87 == 0x4780 {
+                // BLX register.
+                indirect = true;
+            } else if hw1 & 0xff87 == 0x4700 && (hw1 >> 3) & 0xf != 14 {
+                // BX to something other than LR: an indirect tail call.
+                indirect = true;
+            } else if hw1 >> 11 == 0b11100 {
+                // Unconditional B, possibly a tail call.
+                let imm = sign_extend((hw1 & 0x7ff) << 1, 12);
+                let t = (pc + 4).wrapping_add(imm as u32);
+                if t < base || t >= end {
+                    tail_calls.insert(t);
+                }
+            } else if hw1 >> 12 == 0b1101 && (hw1 >> 9) & 0b111 != 0b111 {
+                // B<c>, likewise; conditions 0b111x are UDF and SVC.
+                let imm = sign_extend((hw1 & 0xff) << 1, 9);
+                let t = (pc + 4).wrapping_add(imm as u32);
+                if t < base || t >= end {
+                    tail_calls.insert(t);
+                }
+            }
+            i += 2;
+        }
+    }
+    (calls, tail_calls, indirect)
+}
+
+/// Depth-first search state for finding the deepest call path.
+struct Walk<'a> {
+    functions: &'a BTreeMap<u32, Function>,
+    memo: BTreeMap<u32, (u64, Vec<u32>)>,
+    /// Functions on the current path, with their positions along it, for
+    /// cycle detection.
+    active: BTreeMap<u32, usize>,
+    /// Functions found to be part of a cycle.
+    recursive: BTreeSet<u32>,
+}
+
+impl Walk<'_> {
+    /// Returns the deepest stack use starting at `addr`, and the path taken.
+    fn deepest(&mut self, addr: u32) -> (u64, Vec<u32>) {
+        let (depth, path, _) = self.search(addr);
+        (depth, path)
+    }
+
+    /// Does the work of `deepest`, also returning the position on the current
+    /// path of the earliest function that the search found a cycle back to
+    /// (`usize::MAX` if none). If that's before `addr`, the edge we skipped
+    /// depends on how we got to `addr`, so its result can't be memoized.
+    fn search(&mut self, addr: u32) -> (u64, Vec<u32>, usize) {
+        if let Some((depth, path)) = self.memo.get(&addr) {
+            return (*depth, path.clone(), usize::MAX);
+        }
+        let position = self.active.len();
+        self.active.insert(addr, position);
+
+        let functions = self.functions;
+        let f = &functions[&addr];
+        let frame = f.frame.unwrap_or(0);
+
+        // A call is made with our frame on the stack, while a tail call is
+        // made once it's gone.
+        let mut best = (frame, vec![addr]);
+        let mut cycle_to = usize::MAX;
+        for (&callee, below) in f
+            .calls
+            .iter()
+            .map(|c| (c, frame))
+            .chain(f.tail_calls.iter().map(|c| (c, 0)))
+        {
+            if let Some(&p) = self.active.get(&callee) {
+                // Recursion; we can't bound this, so skip the edge and let the
+                // caller know.
+                self.recursive.insert(callee);
+                cycle_to = cycle_to.min(p);
+                continue;
+            }
+            let (depth, path, c) = self.search(callee);
+            cycle_to = cycle_to.min(c);
+            if below + depth > best.0 {
+                best = (
+                    below + depth,
+                    std::iter::once(addr).chain(path).collect(),
+                );
+            }
+        }
+
+        self.active.remove(&addr);
+        if cycle_to >= position {
+            self.memo.insert(addr, best.clone());
+        }
+        (best.0, best.1, cycle_to)
+    }
+}
+
+/// Makes a legacy-mangled Rust symbol name readable, dropping the hash. Other
+/// names are returned unchanged.
+fn demangle(name: &str) -> String {
+    let mut rest = match name.strip_prefix("_ZN") {
+        Some(r) => r,
+        None => return name.to_owned(),
+    };
+    let mut parts = vec![];
+    while let Some(len_end) = rest.find(|c: char| !c.is_ascii_digit()) {
+        let len: usize = match rest[..len_end].parse() {
+            Ok(len) => len,
+            Err(_) => break,
+        };
+        let part = match rest.get(len_end..len_end + len) {
+            Some(p) => p,
+            None => return name.to_owned(),
+        };
+        rest = &rest[len_end + len..];
+        // The trailing hash looks like `h0123456789abcdef`.
+        if !(part.len() == 17 && part.starts_with('h') && rest == "E") {
+            parts.push(part.replace("$LT$", "<").replace("$GT$", ">"));
+        }
+    }
+    parts.join("::")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Scans a single instruction at `pc`.
+    fn scan(pc: u32, code: &[u8]) -> (Vec<u32>, Vec<u32>, bool) {
+        let (calls, tail_calls, indirect) = scan_thumb(code, pc);
+        (
+            calls.into_iter().collect(),
+            tail_calls.into_iter().collect(),
+            indirect,
+        )
+    }
+
+    /// An instruction at a given address, and what `scan_thumb` should make
+    /// of it: (pc, instruction, calls, tail calls, indirect).
+    type Case = (u32, &'static [u8], &'static [u32], &'static [u32], bool);
+
+    #[test]
+    fn branch_encodings() {
+        // As assembled by llvm-mc.
+        let cases: &[Case] = &[
+            // bl 0x200
+            (0x100, &[0x00, 0xf0, 0x7e, 0xf8], &[0x200], &[], false),
+            // bl 0x0
+            (0x110, &[0xff, 0xf7, 0x76, 0xff], &[0x0], &[], false),
+            // b.w 0x200
+            (0x104, &[0x00, 0xf0, 0x7c, 0xb8], &[], &[0x200], false),
+            // bne.w 0x200
+            (0x108, &[0x40, 0xf0, 0x7a, 0x80], &[], &[0x200], false),
+            // beq.w 0x0
+            (0x10c, &[0x3f, 0xf4, 0x78, 0xaf], &[], &[0x0], false),
+            // b 0x0
+            (0x11a, &[0x71, 0xe7], &[], &[0x0], false),
+            // bgt 0x100
+            (0x11c, &[0xf0, 0xdc], &[], &[0x100], false),
+            // blx 0x300, into ARM code
+            (0x100, &[0x00, 0xf0, 0xfe, 0xe8], &[0x300], &[], false),
+            // blx r3
+            (0x114, &[0x98, 0x47], &[], &[], true),
+            // bx r2
+            (0x116, &[0x10, 0x47], &[], &[], true),
+            // bx lr, which is just a return
+            (0x118, &[0x70, 0x47], &[], &[], false),
+            // mrs r0, apsr, which shares B<c>.W's encoding
+            (0x100, &[0xef, 0xf3, 0x00, 0x80], &[], &[], false),
+            // svc #0, which shares B<c>'s
+            (0x100, &[0x00, 0xdf], &[], &[], false),
+        ];
+        for &(pc, code, calls, tail_calls, indirect) in cases {
+            assert_eq!(
+                scan(pc, code),
+                (calls.to_vec(), tail_calls.to_vec(), indirect),
+                "{code:02x?} at {pc:#x}"
+            );
+        }
+    }
+
+    #[test]
+    fn branches_within_function_ignored() {
+        // bne 0x124; nop
+        assert_eq!(
+            scan(0x122, &[0xff, 0xd1, 0x00, 0xbf]),
+            (vec![], vec![], false)
+        );
+    }
+
+    #[test]
+    fn demangling() {
+        let cases = [
+            ("_ZN4core9panicking5panic17h0123456789abcdefE", "core::panicking::panic"),
+            ("_ZN3foo3barE", "foo::bar"),
+            (
+                "_ZN9task_jefe26Vec$LT$u8$GT$$u20$as$u20$X4push17habcdef0123456789E",
+                "task_jefe::Vec<u8>$u20$as$u20$X::push",
+            ),
+            ("memcpy", "memcpy"),
+            // Truncated names are left alone.
+            ("_ZN4co", "_ZN4co"),
+        ];
+        for (mangled, expected) in cases {
+            assert_eq!(demangle(mangled), expected, "{mangled}");
+        }
+    }
+
+    fn function(frame: u64, calls: &[u32], tail_calls: &[u32]) -> Function {
+        Function {
+            name: String::new(),
+            frame: Some(frame),
+            calls: calls.iter().copied().collect(),
+            tail_calls: tail_calls.iter().copied().collect(),
+            indirect: false,
+        }
+    }
+
+    fn walk(functions: &BTreeMap<u32, Function>, entry: u32) -> Walk<'_> {
+        let mut walk = Walk {
+            functions,
+            memo: BTreeMap::new(),
+            active: BTreeMap::new(),
+            recursive: BTreeSet::new(),
+        };
+        walk.deepest(entry);
+        walk
+    }
+
+    #[test]
+    fn tail_calls_replace_frame() {
+        // 1 calls 2 and 3; 2 tail-calls 4.
+        let functions = [
+            (1, function(16, &[2, 3], &[])),
+            (2, function(32, &[], &[4])),
+            (3, function(40, &[], &[])),
+            (4, function(64, &[], &[])),
+        ]
+        .into_iter()
+        .collect();
+
+        let mut walk = walk(&functions, 1);
+        assert_eq!(walk.deepest(1), (16 + 64, vec![1, 2, 4]));
+        assert!(walk.recursive.is_empty());
+    }
+
+    #[test]
+    fn cycles_not_memoized_short() {
+        // 1 calls 2 and then 3, which call each other; 2 also calls 4, and 3
+        // calls 5. Walking 2 first reaches 3 with 2 on the path, so that
+        // visit of 3 can't count 2 -> 4; its direct call from 1 must.
+        let functions = [
+            (1, function(0, &[2, 3], &[])),
+            (2, function(8, &[3, 4], &[])),
+            (3, function(8, &[2, 5], &[])),
+            (4, function(100, &[], &[])),
+            (5, function(16, &[], &[])),
+        ]
+        .into_iter()
+        .collect();
+
+        let mut walk = walk(&functions, 1);
+        assert_eq!(walk.deepest(1), (8 + 8 + 100, vec![1, 3, 2, 4]));
+        assert!(!walk.recursive.is_empty());
+    }
+}
@@ -0,0 +1,415 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Batched I2C transactions; see [`I2cBatch`]
+
+use crate::{
+    Controller, I2cDevice, I2cMessage, Marshal, Mux, PortIndex, ResponseCode,
+    Segment,
+};
+use userlib::{FromPrimitive, TaskId};
+use zerocopy::{AsBytes, FromBytes};
+
+/// The length of the header that precedes each transaction in a batch.
+pub const BATCH_HEADER_LEN: usize = 7;
+
+const FLAG_BLOCK: u8 = 1 << 0;
+const FLAG_CHAINED: u8 = 1 << 1;
+
+///
+/// The header of one transaction in a batch, which is followed by the
+/// `write_len` bytes to be written.
+///
+#[derive(Copy, Clone, Debug)]
+pub struct BatchHeader {
+    pub address: u8,
+    pub controller: Controller,
+    pub port: PortIndex,
+    pub segment: Option<(Mux, Segment)>,
+    pub write_len: u8,
+    pub read_len: u8,
+    /// The read is an SMBus block read of at most `read_len` bytes.
+    pub block: bool,
+    /// The transaction is only performed if the one before it succeeded.
+    pub chained: bool,
+}
+
+impl Marshal<[u8; BATCH_HEADER_LEN]> for BatchHeader {
+    fn marshal(&self) -> [u8; BATCH_HEADER_LEN] {
+        let msg = (self.address, self.controller, self.port, self.segment);
+        let [address, controller, port, segment] = msg.marshal();
+        let mut flags = 0;
+
+        if self.block {
+            flags |= FLAG_BLOCK;
+        }
+
+        if self.chained {
+            flags |= FLAG_CHAINED;
+        }
+
+        [
+            address,
+            controller,
+            port,
+            segment,
+            self.write_len,
+            self.read_len,
+            flags,
+        ]
+    }
+
+    fn unmarshal(val: &[u8; BATCH_HEADER_LEN]) -> Result<Self, ResponseCode> {
+        let (address, controller, port, segment) =
+            I2cMessage::unmarshal(&[val[0], val[1], val[2], val[3]])?;
+        let flags = val[6];
+
+        if flags & !(FLAG_BLOCK | FLAG_CHAINED) != 0 {
+            return Err(ResponseCode::BadArg);
+        }
+
+        Ok(Self {
+            address,
+            controller,
+            port,
+            segment,
+            write_len: val[4],
+            read_len: val[5],
+            block: flags & FLAG_BLOCK != 0,
+            chained: flags & FLAG_CHAINED != 0,
+        })
+    }
+}
+
+///
+/// The outcome of one transaction in a batch, as written back by the server.
+///
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq, AsBytes, FromBytes)]
+#[repr(C)]
+pub struct BatchResult {
+    code: u8,
+    len: u8,
+}
+
+impl BatchResult {
+    /// Returns the number of bytes that the transaction read, or the error
+    /// with which it failed.
+    pub fn get(&self) -> Result<usize, ResponseCode> {
+        match self.code {
+            0 => Ok(usize::from(self.len)),
+            code => Err(ResponseCode::from_u8(code)
+                .unwrap_or(ResponseCode::BadResponse)),
+        }
+    }
+}
+
+impl From<Result<usize, ResponseCode>> for BatchResult {
+    fn from(result: Result<usize, ResponseCode>) -> Self {
+        match result {
+            Ok(len) => Self {
+                code: 0,
+                len: len as u8,
+            },
+            Err(code) => Self {
+                code: code as u8,
+                len: 0,
+            },
+        }
+    }
+}
+
+///
+/// A transaction that has been queued in an [`I2cBatch`].
+///
+#[derive(Copy, Clone, Debug)]
+pub struct Queued {
+    index: usize,
+    offset: usize,
+    len: usize,
+}
+
+impl Queued {
+    /// Given the read data and results filled in by [`I2cBatch::send`],
+    /// returns the bytes read by this transaction (an empty slice for a
+    /// write), or the error with which it failed.
+    pub fn get<'b>(
+        &self,
+        data: &'b [u8],
+        results: &[BatchResult],
+    ) -> Result<&'b [u8], ResponseCode> {
+        let result = results.get(self.index).ok_or(ResponseCode::BadArg)?;
+        let len = usize::min(result.get()?, self.len);
+
+        data.get(self.offset..self.offset + len)
+            .ok_or(ResponseCode::BadArg)
+    }
+}
+
+#[derive(Copy, Clone, Debug, Default)]
+struct Cursor {
+    task: Option<TaskId>,
+    len: usize,
+    count: usize,
+    read_len: usize,
+}
+
+///
+/// A batch of I2C transactions, to be performed by the I2C server in
+/// response to a single message.
+///
+/// Every other operation on an [`I2cDevice`] is a message to the I2C server, and a
+/// task that polls many devices can spend most of its time blocked on these
+/// messages.  A batch instead gathers up transactions -- on any devices
+/// served by the same I2C server -- and sends them all at once, as an
+/// [`Op::Batch`](crate::Op::Batch).
+///
+/// Transactions are queued with [`I2cDevice::queue_read_reg`] and its
+/// siblings, each of which returns a [`Queued`] handle through which the
+/// transaction's outcome can be found once the batch has been sent:
+///
+/// ```ignore
+/// let mut request = [0u8; 64];
+/// let mut batch = I2cBatch::new(&mut request);
+///
+/// let temp = sensor.queue_read_reg(&mut batch, 0x00u8, 2)?;
+/// let vout = vrm.queue_write_read_reg(&mut batch, 0x8bu8, &[0x00, 1], 2)?;
+///
+/// let mut data = [0u8; 4];
+/// let mut results = [BatchResult::default(); 3];
+/// batch.send(&mut data, &mut results)?;
+///
+/// let temp = temp.get(&data, &results)?;
+/// ```
+///
+/// Transactions are independent of one another: one failing doesn't prevent
+/// the rest from being attempted.  The exception is a transaction that is
+/// *chained* to the one queued before it, which is only attempted if that
+/// one succeeded (and otherwise fails with the same error); this is how
+/// [`I2cDevice::queue_write_read_reg`] assures that a register isn't read
+/// from (say) the wrong PMBus page.
+///
+/// The server performs the transactions in the order in which they were
+/// queued.  To keep mux reconfiguration to a minimum, transactions on
+/// devices behind the same mux segment should be queued together.
+///
+/// The batch is encoded into a caller-provided buffer, in which each
+/// transaction takes [`BATCH_HEADER_LEN`] bytes plus the number of bytes it
+/// writes.
+///
+pub struct I2cBatch<'a> {
+    request: &'a mut [u8],
+    cursor: Cursor,
+}
+
+impl<'a> I2cBatch<'a> {
+    pub fn new(request: &'a mut [u8]) -> Self {
+        Self {
+            request,
+            cursor: Cursor::default(),
+        }
+    }
+
+    /// Returns the number of transactions queued.
+    pub fn len(&self) -> usize {
+        self.cursor.count
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.cursor.count == 0
+    }
+
+    /// Returns the number of bytes of read data that the queued transactions
+    /// need, which is the least size of the `data` buffer for
+    /// [`I2cBatch::send`].
+    pub fn read_len(&self) -> usize {
+        self.cursor.read_len
+    }
+
+    /// Empties the batch, so that it can be used again.
+    pub fn clear(&mut self) {
+        self.cursor = Cursor::default();
+    }
+
+    pub(crate) fn task(&self) -> Option<TaskId> {
+        self.cursor.task
+    }
+
+    pub(crate) fn request(&self) -> &[u8] {
+        &self.request[..self.cursor.len]
+    }
+
+    //
+    // Checks the lengths of the buffers handed to `send`.
+    //
+    pub(crate) fn check(
+        &self,
+        data: &[u8],
+        results: &[BatchResult],
+    ) -> Result<(), ResponseCode> {
+        if data.len() < self.cursor.read_len || results.len() < self.len() {
+            Err(ResponseCode::BadArg)
+        } else {
+            Ok(())
+        }
+    }
+
+    fn push(
+        &mut self,
+        device: &I2cDevice,
+        write: &[u8],
+        read_len: usize,
+        block: bool,
+        chained: bool,
+    ) -> Result<Queued, ResponseCode> {
+        let cursor = &mut self.cursor;
+
+        //
+        // All of the devices in a batch must be served by the same server,
+        // and each transaction is subject to the same limits as a pair of
+        // leases in a `WriteRead`.
+        //
+        if cursor.task.map_or(false, |task| task != device.task)
+            || (write.is_empty() && read_len == 0)
+            || write.len() > 255
+            || read_len > 255
+            || (chained && cursor.count == 0)
+        {
+            return Err(ResponseCode::BadArg);
+        }
+
+        let start = cursor.len + BATCH_HEADER_LEN;
+        let end = start + write.len();
+
+        if end > self.request.len() {
+            return Err(ResponseCode::BadArg);
+        }
+
+        let header = BatchHeader {
+            address: device.address,
+            controller: device.controller,
+            port: device.port,
+            segment: device.segment,
+            write_len: write.len() as u8,
+            read_len: read_len as u8,
+            block,
+            chained,
+        };
+
+        self.request[cursor.len..start].copy_from_slice(&header.marshal());
+        self.request[start..end].copy_from_slice(write);
+
+        let queued = Queued {
+            index: cursor.count,
+            offset: cursor.read_len,
+            len: read_len,
+        };
+
+        cursor.task = Some(device.task);
+        cursor.len = end;
+        cursor.count += 1;
+        cursor.read_len += read_len;
+
+        Ok(queued)
+    }
+}
+
+impl I2cDevice {
+    /// Queues a register read of `len` bytes in a batch; see
+    /// [`I2cDevice::read_reg`].
+    pub fn queue_read_reg<R: AsBytes>(
+        &self,
+        batch: &mut I2cBatch<'_>,
+        reg: R,
+        len: usize,
+    ) -> Result<Queued, ResponseCode> {
+        batch.push(self, reg.as_bytes(), len, false, false)
+    }
+
+    /// Queues an SMBus block read of at most `len` bytes in a batch; see
+    /// [`I2cDevice::read_block`].
+    pub fn queue_read_block<R: AsBytes>(
+        &self,
+        batch: &mut I2cBatch<'_>,
+        reg: R,
+        len: usize,
+    ) -> Result<Queued, ResponseCode> {
+        batch.push(self, reg.as_bytes(), len, true, false)
+    }
+
+    /// Queues a write in a batch; see [`I2cDevice::write`].
+    pub fn queue_write(
+        &self,
+        batch: &mut I2cBatch<'_>,
+        buffer: &[u8],
+    ) -> Result<Queued, ResponseCode> {
+        batch.push(self, buffer, 0, false, false)
+    }
+
+    /// Queues a write and then a register read of `len` bytes, chained to
+    /// the write, in a batch; see [`I2cDevice::write_read_reg`].  The
+    /// returned handle is that of the read.
+    pub fn queue_write_read_reg<R: AsBytes>(
+        &self,
+        batch: &mut I2cBatch<'_>,
+        reg: R,
+        buffer: &[u8],
+        len: usize,
+    ) -> Result<Queued, ResponseCode> {
+        let cursor = batch.cursor;
+
+        batch
+            .push(self, buffer, 0, false, false)
+            .and_then(|_| batch.push(self, reg.as_bytes(), len, false, true))
+            .map_err(|code| {
+                // Don't leave half of the pair behind.
+                batch.cursor = cursor;
+                code
+            })
+    }
+}
+
+#[cfg(target_os = "none")]
+impl I2cBatch<'_> {
+    ///
+    /// Sends the batch to the I2C server, which performs each transaction in
+    /// turn.  The bytes read are written to `data`, which must be at least
+    /// [`I2cBatch::read_len`] bytes long, and the outcome of each transaction
+    /// to `results`, which must have room for [`I2cBatch::len`] of them.
+    ///
+    /// Returns the number of transactions that succeeded.  An error is
+    /// returned only if the batch itself couldn't be performed; the
+    /// transactions' own errors are in `results`.
+    ///
+    pub fn send(
+        &self,
+        data: &mut [u8],
+        results: &mut [BatchResult],
+    ) -> Result<usize, ResponseCode> {
+        use userlib::{sys_send, Lease};
+
+        self.check(data, results)?;
+
+        let task = match self.task() {
+            Some(task) => task,
+            None => return Ok(0),
+        };
+
+        let count = self.len() as u32;
+        let mut response = 0_usize;
+
+        let (code, _) = sys_send(
+            task,
+            crate::Op::Batch as u16,
+            &count.to_le_bytes(),
+            response.as_bytes_mut(),
+            &[
+                Lease::from(self.request()),
+                Lease::from(&mut data[..self.read_len()]),
+                Lease::from(results[..self.len()].as_bytes_mut()),
+            ],
+        );
+
+        crate::response_code(code, response)
+    }
+}
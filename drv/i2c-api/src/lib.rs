@@ -19,12 +19,46 @@
 //! - The segment on the multiplexer, if a multiplexer is specified
 //! - The address of the device itself
 //!
+//! # Batched transactions
+//!
+//! Each [`I2cDevice`] operation is a message to the I2C server.  To perform
+//! many transactions -- on any number of devices -- with a single message,
+//! queue them in an [`I2cBatch`].
+//!
+//! # Bus health
+//!
+//! The I2C server counts, for each bus and each mux segment, the errors that
+//! it sees and the recoveries that it makes; see [`I2cStats`].  It can also
+//! scan a bus for the devices that are present.
+//!
+//! # Testing on the host
+//!
+//! In hosted builds, there is no I2C server to send messages to; instead,
+//! [`I2cDevice`] operations are performed against a simulated bus, which
+//! allows device drivers to be unit-tested.  See the [`mock`] module.
+//!
 
 #![no_std]
 
+#[cfg(not(target_os = "none"))]
+extern crate std;
+
+mod batch;
+#[cfg(target_os = "none")]
+mod scan;
+mod stats;
+
+#[cfg(not(target_os = "none"))]
+pub mod mock;
+
+#[cfg(target_os = "none")]
 use zerocopy::{AsBytes, FromBytes};
 
+pub use batch::*;
 pub use drv_i2c_types::*;
+#[cfg(target_os = "none")]
+pub use scan::*;
+pub use stats::*;
 use userlib::*;
 
 ///
@@ -124,20 +158,21 @@ impl I2cDevice {
     }
 }
 
-impl I2cDevice {
-    fn response_code<V>(&self, code: u32, val: V) -> Result<V, ResponseCode> {
-        if code != 0 {
-            if let Some(_g) = userlib::extract_new_generation(code) {
-                panic!("i2c reset");
-            }
-
-            Err(ResponseCode::from_u32(code)
-                .ok_or(ResponseCode::BadResponse)?)
-        } else {
-            Ok(val)
+#[cfg(target_os = "none")]
+fn response_code<V>(code: u32, val: V) -> Result<V, ResponseCode> {
+    if code != 0 {
+        if let Some(_g) = userlib::extract_new_generation(code) {
+            panic!("i2c reset");
         }
+
+        Err(ResponseCode::from_u32(code).ok_or(ResponseCode::BadResponse)?)
+    } else {
+        Ok(val)
     }
+}
 
+#[cfg(target_os = "none")]
+impl I2cDevice {
     ///
     /// Reads a register, with register address of type R and value of type V.
     ///
@@ -175,7 +210,7 @@ impl I2cDevice {
             &[Lease::from(reg.as_bytes()), Lease::from(val.as_bytes_mut())],
         );
 
-        self.response_code(code, val)
+        response_code(code, val)
     }
 
     ///
@@ -203,7 +238,7 @@ impl I2cDevice {
             &[Lease::from(reg.as_bytes()), Lease::from(buf)],
         );
 
-        self.response_code(code, response)
+        response_code(code, response)
     }
 
     ///
@@ -233,7 +268,7 @@ impl I2cDevice {
             &[Lease::from(reg.as_bytes()), Lease::from(buf)],
         );
 
-        self.response_code(code, response)
+        response_code(code, response)
     }
 
     ///
@@ -260,7 +295,7 @@ impl I2cDevice {
             &[Lease::read_only(&[]), Lease::from(val.as_bytes_mut())],
         );
 
-        self.response_code(code, val)
+        response_code(code, val)
     }
 
     ///
@@ -284,7 +319,7 @@ impl I2cDevice {
             &[Lease::read_only(&[]), Lease::from(buf)],
         );
 
-        self.response_code(code, response)
+        response_code(code, response)
     }
 
     ///
@@ -307,7 +342,7 @@ impl I2cDevice {
             &[Lease::from(buffer), Lease::read_only(&[])],
         );
 
-        self.response_code(code, ())
+        response_code(code, ())
     }
 
     ///
@@ -344,7 +379,7 @@ impl I2cDevice {
             ],
         );
 
-        self.response_code(code, val)
+        response_code(code, val)
     }
 
     ///
@@ -386,7 +421,7 @@ impl I2cDevice {
             ],
         );
 
-        self.response_code(code, response)
+        response_code(code, response)
     }
 
     ///
@@ -421,7 +456,7 @@ impl I2cDevice {
             ],
         );
 
-        self.response_code(code, ())
+        response_code(code, ())
     }
 
     ///
@@ -461,6 +496,6 @@ impl I2cDevice {
             ],
         );
 
-        self.response_code(code, val)
+        response_code(code, val)
     }
 }
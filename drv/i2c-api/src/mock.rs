@@ -0,0 +1,1134 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! A simulated I2C bus, for testing drivers on the host
+//!
+//! In hosted builds, [`I2cDevice`] doesn't send messages to an I2C server
+//! task; its operations are instead carried out against a [`Bus`], which
+//! stands in for the server and everything wired to it.  Devices on the bus
+//! are [`Model`]s -- typically a [`RegisterMap`] -- attached at a full
+//! controller/port/mux/segment/address location:
+//!
+//! ```ignore
+//! let bus = Bus::new();
+//! let device = bus.device(Controller::I2C2, PortIndex(0), None, 0x48);
+//! let map = bus.attach(&device, RegisterMap::new());
+//! map.borrow_mut().set(0x0f, &[0x01, 0x17]);
+//!
+//! assert_eq!(device.read_reg::<u8, [u8; 2]>(0x0f), Ok([0x01, 0x17]));
+//! ```
+//!
+//! The bus follows the server's rules: a device that isn't present NAKs its
+//! address (`NoDevice`), a mux segment is enabled before a device behind it
+//! is addressed (and disabled again before anything else on the bus is), and
+//! an error that would cause the server to reset the bus leaves the mux
+//! state unknown.  Failures can be scripted for a device, a mux or a whole
+//! bus with [`Bus::fail_next`], [`Bus::fail_after`], [`Bus::fail_mux_next`]
+//! and [`Bus::fail_bus_next`].
+//!
+//! Each thread has its own set of buses, so tests can run in parallel.
+
+use std::cell::{RefCell, RefMut};
+use std::collections::VecDeque;
+use std::rc::Rc;
+use std::vec::Vec;
+
+use userlib::{FromPrimitive, TaskId};
+use zerocopy::{AsBytes, FromBytes};
+
+use crate::{
+    BatchHeader, BatchResult, Controller, I2cBatch, I2cDevice, Marshal, Mux,
+    PortIndex, ReservedAddress, ResponseCode, Segment, BATCH_HEADER_LEN,
+};
+
+/// A simulated I2C device.
+///
+/// Each call is one transfer: the bytes sent to the device after its address
+/// has been acknowledged, or the bytes it sends back.  An SMBus block read is
+/// seen by the model as a read of 256 bytes, of which the bus uses the first
+/// (the count) and as many more as the count says.
+pub trait Model {
+    fn write(&mut self, data: &[u8]) -> Result<(), ResponseCode>;
+    fn read(&mut self, data: &mut [u8]) -> Result<(), ResponseCode>;
+}
+
+///
+/// A device made of registers, selected by writing a register number as the
+/// first byte of a transfer.  Any further bytes in the write are stored in
+/// the register; a subsequent read returns the register's contents (padded
+/// with `0xff`, as a released bus would read).  Writing a register that
+/// isn't in the map is NAK'd, as most devices do.
+///
+/// A register can also be *scoped* by the values of other registers: this
+/// models PMBus `PAGE` and `PHASE`, where the same command code reads a
+/// different value depending on the rail or phase that was last selected.
+///
+#[derive(Clone, Debug, Default)]
+pub struct RegisterMap {
+    registers: Vec<Register>,
+    auto_increment: bool,
+    pointer: u8,
+    writes: Vec<Vec<u8>>,
+}
+
+#[derive(Clone, Debug)]
+struct Register {
+    scope: Vec<(u8, u8)>,
+    code: u8,
+    value: Vec<u8>,
+}
+
+impl RegisterMap {
+    /// Returns an empty map in which each register has its own width, as is
+    /// the case for PMBus devices and most 16-bit sensors.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns an empty map of byte-wide registers in which reads and writes
+    /// longer than a byte carry on into the following registers.
+    pub fn auto_increment() -> Self {
+        Self {
+            auto_increment: true,
+            ..Self::default()
+        }
+    }
+
+    /// Sets a register that reads the same whatever else has been written.
+    pub fn set(&mut self, code: u8, value: &[u8]) -> &mut Self {
+        self.set_scoped(&[], code, value)
+    }
+
+    /// Sets a register as it reads when each register in `scope` holds the
+    /// given value.  Where more than one definition of a register applies,
+    /// the most specific wins.
+    pub fn set_scoped(
+        &mut self,
+        scope: &[(u8, u8)],
+        code: u8,
+        value: &[u8],
+    ) -> &mut Self {
+        match self
+            .registers
+            .iter_mut()
+            .find(|r| r.code == code && r.scope == scope)
+        {
+            Some(r) => r.value = value.to_vec(),
+            None => self.registers.push(Register {
+                scope: scope.to_vec(),
+                code,
+                value: value.to_vec(),
+            }),
+        }
+
+        self
+    }
+
+    /// Sets a register to a little-endian SMBus word.
+    pub fn set_word(&mut self, code: u8, value: u16) -> &mut Self {
+        self.set(code, &value.to_le_bytes())
+    }
+
+    /// Sets a register to be read with an SMBus block read.
+    pub fn set_block(&mut self, code: u8, data: &[u8]) -> &mut Self {
+        let mut value = Vec::with_capacity(data.len() + 1);
+        value.push(data.len() as u8);
+        value.extend_from_slice(data);
+        self.set(code, &value)
+    }
+
+    /// Returns the contents of a register as currently selected.
+    pub fn get(&self, code: u8) -> Option<&[u8]> {
+        self.lookup(code).map(|i| &self.registers[i].value[..])
+    }
+
+    /// Returns every write that stored data in the device, oldest first, each
+    /// starting with its register number.  (Writes that only select a
+    /// register to read aren't included.)
+    pub fn writes(&self) -> &[Vec<u8>] {
+        &self.writes
+    }
+
+    fn selected(&self, code: u8) -> Option<u8> {
+        self.registers
+            .iter()
+            .find(|r| r.code == code && r.scope.is_empty())
+            .and_then(|r| r.value.first().copied())
+    }
+
+    //
+    // Finds the register that `code` currently addresses, preferring the most
+    // specific scope.
+    //
+    fn lookup(&self, code: u8) -> Option<usize> {
+        self.registers
+            .iter()
+            .enumerate()
+            .filter(|(_, r)| r.code == code)
+            .filter(|(_, r)| {
+                r.scope.iter().all(|&(s, v)| self.selected(s) == Some(v))
+            })
+            .max_by_key(|(_, r)| r.scope.len())
+            .map(|(i, _)| i)
+    }
+}
+
+impl Model for RegisterMap {
+    fn write(&mut self, data: &[u8]) -> Result<(), ResponseCode> {
+        let (&code, payload) =
+            data.split_first().ok_or(ResponseCode::BadArg)?;
+
+        if !payload.is_empty() {
+            self.writes.push(data.to_vec());
+        }
+
+        if self.auto_increment {
+            for (i, &byte) in payload.iter().enumerate() {
+                let ndx = self
+                    .lookup(code.wrapping_add(i as u8))
+                    .ok_or(ResponseCode::NoRegister)?;
+                self.registers[ndx].value = std::vec![byte];
+            }
+
+            if payload.is_empty() {
+                self.lookup(code).ok_or(ResponseCode::NoRegister)?;
+            }
+        } else {
+            let ndx = self.lookup(code).ok_or(ResponseCode::NoRegister)?;
+
+            if !payload.is_empty() {
+                self.registers[ndx].value = payload.to_vec();
+            }
+        }
+
+        self.pointer = code;
+        Ok(())
+    }
+
+    fn read(&mut self, data: &mut [u8]) -> Result<(), ResponseCode> {
+        data.fill(0xff);
+
+        if self.auto_increment {
+            for (i, byte) in data.iter_mut().enumerate() {
+                if let Some(v) = self.get(self.pointer.wrapping_add(i as u8)) {
+                    *byte = v.first().copied().unwrap_or(0xff);
+                }
+            }
+        } else if let Some(v) = self.get(self.pointer) {
+            let n = usize::min(v.len(), data.len());
+            data[..n].copy_from_slice(&v[..n]);
+        }
+
+        Ok(())
+    }
+}
+
+struct Target {
+    controller: Controller,
+    port: PortIndex,
+    segment: Option<(Mux, Segment)>,
+    address: u8,
+    present: bool,
+    script: VecDeque<Option<ResponseCode>>,
+    model: Option<Rc<RefCell<dyn Model>>>,
+}
+
+struct MuxDevice {
+    controller: Controller,
+    port: PortIndex,
+    id: Mux,
+    segments: u8,
+    present: bool,
+    script: VecDeque<ResponseCode>,
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+enum MuxState {
+    Enabled(Mux, Segment),
+    Unknown,
+}
+
+#[derive(Default)]
+struct State {
+    targets: Vec<Target>,
+    muxes: Vec<MuxDevice>,
+    muxmap: Vec<((Controller, PortIndex), MuxState)>,
+    bus_script: Vec<((Controller, PortIndex), ResponseCode)>,
+}
+
+std::thread_local! {
+    static BUSES: RefCell<Vec<Rc<RefCell<State>>>> = RefCell::new(Vec::new());
+}
+
+///
+/// A simulated I2C server and the buses behind it.  [`I2cDevice`]s made by
+/// [`Bus::device`] are routed here; a `Bus` is cheap to clone, and clones
+/// refer to the same simulation.
+///
+#[derive(Clone)]
+pub struct Bus {
+    task: TaskId,
+    state: Rc<RefCell<State>>,
+}
+
+impl Default for Bus {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Bus {
+    pub fn new() -> Self {
+        let state = Rc::new(RefCell::new(State::default()));
+
+        let task = BUSES.with(|buses| {
+            let mut buses = buses.borrow_mut();
+            buses.push(state.clone());
+            TaskId(buses.len() as u16 - 1)
+        });
+
+        Self { task, state }
+    }
+
+    /// Returns the device at the given location.  Nothing answers there
+    /// until a model is attached with [`Bus::attach`].
+    pub fn device(
+        &self,
+        controller: Controller,
+        port: PortIndex,
+        segment: Option<(Mux, Segment)>,
+        address: u8,
+    ) -> I2cDevice {
+        I2cDevice::new(self.task, controller, port, segment, address)
+    }
+
+    /// Attaches a model at `device`'s location, returning a handle through
+    /// which the test can change the model's state or inspect it.
+    pub fn attach<M: Model + 'static>(
+        &self,
+        device: &I2cDevice,
+        model: M,
+    ) -> Rc<RefCell<M>> {
+        let model = Rc::new(RefCell::new(model));
+        let mut target = self.target(device);
+        target.model = Some(model.clone());
+        target.present = true;
+        model
+    }
+
+    /// Adds a mux with `segments` segments to a bus.  (The mux's own address
+    /// doesn't matter to the simulation, which knows it by its identifier.)
+    pub fn add_mux(
+        &self,
+        controller: Controller,
+        port: PortIndex,
+        id: Mux,
+        segments: u8,
+    ) {
+        self.state.borrow_mut().muxes.push(MuxDevice {
+            controller,
+            port,
+            id,
+            segments,
+            present: true,
+            script: VecDeque::new(),
+        });
+    }
+
+    /// Removes a device from the bus (or puts it back), as if it had been
+    /// unplugged or had lost power.  A missing device NAKs its address.
+    pub fn set_present(&self, device: &I2cDevice, present: bool) {
+        self.target(device).present = present;
+    }
+
+    /// Removes a mux from its bus (or puts it back).  Devices behind a
+    /// missing mux can't be reached.
+    pub fn set_mux_present(
+        &self,
+        controller: Controller,
+        port: PortIndex,
+        id: Mux,
+        present: bool,
+    ) {
+        if let Some(mut mux) = self.mux(controller, port, id) {
+            mux.present = present;
+        }
+    }
+
+    /// Fails the next transfer to `device` with `code`.  Failures queue up:
+    /// calling this twice fails the next two transfers.
+    pub fn fail_next(&self, device: &I2cDevice, code: ResponseCode) {
+        self.target(device).script.push_back(Some(code));
+    }
+
+    /// Lets the next `n` transfers to `device` go through, then fails the
+    /// one after with `code`.
+    pub fn fail_after(&self, device: &I2cDevice, n: usize, code: ResponseCode) {
+        let mut target = self.target(device);
+        target.script.extend(std::iter::repeat(None).take(n));
+        target.script.push_back(Some(code));
+    }
+
+    /// Fails the next operation on the given mux -- enabling or disabling a
+    /// segment -- with `code`.
+    pub fn fail_mux_next(
+        &self,
+        controller: Controller,
+        port: PortIndex,
+        id: Mux,
+        code: ResponseCode,
+    ) {
+        if let Some(mut mux) = self.mux(controller, port, id) {
+            mux.script.push_back(code);
+        }
+    }
+
+    /// Fails the next transfer on the given bus, whatever it's addressed
+    /// to, with `code` (e.g. `BusLocked` or `ControllerBusy`).
+    pub fn fail_bus_next(
+        &self,
+        controller: Controller,
+        port: PortIndex,
+        code: ResponseCode,
+    ) {
+        self.state
+            .borrow_mut()
+            .bus_script
+            .push(((controller, port), code));
+    }
+
+    /// Returns the mux segment currently enabled on a bus, if any.
+    pub fn enabled_segment(
+        &self,
+        controller: Controller,
+        port: PortIndex,
+    ) -> Option<(Mux, Segment)> {
+        self.state.borrow().enabled((controller, port))
+    }
+
+    fn target(&self, device: &I2cDevice) -> RefMut<'_, Target> {
+        RefMut::map(self.state.borrow_mut(), |state| {
+            let found = state.targets.iter().position(|t| {
+                t.controller == device.controller
+                    && t.port == device.port
+                    && t.segment == device.segment
+                    && t.address == device.address
+            });
+
+            let ndx = found.unwrap_or_else(|| {
+                state.targets.push(Target {
+                    controller: device.controller,
+                    port: device.port,
+                    segment: device.segment,
+                    address: device.address,
+                    present: false,
+                    script: VecDeque::new(),
+                    model: None,
+                });
+                state.targets.len() - 1
+            });
+
+            &mut state.targets[ndx]
+        })
+    }
+
+    fn mux(
+        &self,
+        controller: Controller,
+        port: PortIndex,
+        id: Mux,
+    ) -> Option<RefMut<'_, MuxDevice>> {
+        let state = self.state.borrow_mut();
+        let ndx = state.muxes.iter().position(|m| {
+            m.controller == controller && m.port == port && m.id == id
+        })?;
+
+        Some(RefMut::map(state, |state| &mut state.muxes[ndx]))
+    }
+}
+
+fn reset_needed(code: ResponseCode) -> bool {
+    matches!(
+        code,
+        ResponseCode::BusLocked
+            | ResponseCode::BusLockedMux
+            | ResponseCode::BusReset
+            | ResponseCode::BusResetMux
+            | ResponseCode::BusError
+            | ResponseCode::ControllerBusy
+    )
+}
+
+impl State {
+    fn enabled(&self, bus: (Controller, PortIndex)) -> Option<(Mux, Segment)> {
+        self.muxmap
+            .iter()
+            .find(|(b, _)| *b == bus)
+            .and_then(|(_, state)| match state {
+                MuxState::Enabled(mux, segment) => Some((*mux, *segment)),
+                MuxState::Unknown => None,
+            })
+    }
+
+    fn set_muxstate(&mut self, bus: (Controller, PortIndex), s: MuxState) {
+        self.muxmap.retain(|(b, _)| *b != bus);
+        self.muxmap.push((bus, s));
+    }
+
+    fn clear_muxstate(&mut self, bus: (Controller, PortIndex)) {
+        self.muxmap.retain(|(b, _)| *b != bus);
+    }
+
+    //
+    // Resetting a bus resets its muxes, after which the server no longer
+    // knows which segment is enabled.
+    //
+    fn reset(&mut self, bus: (Controller, PortIndex)) {
+        if self.muxes.iter().any(|m| (m.controller, m.port) == bus) {
+            self.set_muxstate(bus, MuxState::Unknown);
+        }
+    }
+
+    fn mux_op(
+        &mut self,
+        bus: (Controller, PortIndex),
+        id: Mux,
+        segment: Option<Segment>,
+    ) -> Result<(), ResponseCode> {
+        let mux = self
+            .muxes
+            .iter_mut()
+            .find(|m| (m.controller, m.port) == bus && m.id == id)
+            .ok_or(ResponseCode::MuxNotFound)?;
+
+        if let Some(code) = mux.script.pop_front() {
+            return Err(code);
+        }
+
+        if !mux.present {
+            return Err(ResponseCode::MuxMissing);
+        }
+
+        match segment {
+            Some(s) if s as u8 > mux.segments => {
+                Err(ResponseCode::SegmentNotFound)
+            }
+            _ => Ok(()),
+        }
+    }
+
+    //
+    // Mirrors the server's `configure_mux`: get the bus into a known state
+    // with exactly the requested segment (if any) enabled.
+    //
+    fn configure_mux(
+        &mut self,
+        bus: (Controller, PortIndex),
+        mux: Option<(Mux, Segment)>,
+    ) -> Result<(), ResponseCode> {
+        let current = self
+            .muxmap
+            .iter()
+            .find(|(b, _)| *b == bus)
+            .map(|(_, state)| *state);
+
+        match current {
+            Some(MuxState::Enabled(id, segment)) => {
+                if mux == Some((id, segment)) {
+                    return Ok(());
+                }
+
+                if mux.map(|(m, _)| m) != Some(id) {
+                    if let Err(code) = self.mux_op(bus, id, None) {
+                        self.set_muxstate(bus, MuxState::Unknown);
+                        return Err(code);
+                    }
+
+                    self.clear_muxstate(bus);
+                }
+            }
+
+            Some(MuxState::Unknown) => {
+                let ids = self
+                    .muxes
+                    .iter()
+                    .filter(|m| (m.controller, m.port) == bus)
+                    .map(|m| m.id)
+                    .collect::<Vec<_>>();
+
+                for id in ids {
+                    match self.mux_op(bus, id, None) {
+                        Ok(()) | Err(ResponseCode::MuxMissing) => {}
+                        Err(code) => return Err(code),
+                    }
+                }
+
+                self.clear_muxstate(bus);
+            }
+
+            None => {}
+        }
+
+        if let Some((id, segment)) = mux {
+            if let Err(code) = self.mux_op(bus, id, Some(segment)) {
+                self.set_muxstate(bus, MuxState::Unknown);
+                return Err(code);
+            }
+
+            self.set_muxstate(bus, MuxState::Enabled(id, segment));
+        }
+
+        Ok(())
+    }
+
+    fn model(
+        &mut self,
+        device: &I2cDevice,
+    ) -> Result<Rc<RefCell<dyn Model>>, ResponseCode> {
+        let bus = (device.controller, device.port);
+        let enabled = self.enabled(bus).filter(|(id, _)| {
+            self.muxes.iter().any(|m| {
+                (m.controller, m.port) == bus && m.id == *id && m.present
+            })
+        });
+
+        //
+        // A device on the bus itself can always be seen; one behind a mux
+        // only while its segment is enabled (and the mux is still there).
+        //
+        let target = self
+            .targets
+            .iter_mut()
+            .find(|t| {
+                (t.controller, t.port) == bus
+                    && t.address == device.address
+                    && (t.segment.is_none() || t.segment == enabled)
+            })
+            .ok_or(ResponseCode::NoDevice)?;
+
+        if let Some(Some(code)) = target.script.pop_front() {
+            return Err(code);
+        }
+
+        match (&target.model, target.present) {
+            (Some(model), true) => Ok(model.clone()),
+            _ => Err(ResponseCode::NoDevice),
+        }
+    }
+
+    fn transfer(
+        &mut self,
+        device: &I2cDevice,
+        write: &[u8],
+        read: &mut [u8],
+        block: bool,
+    ) -> Result<usize, ResponseCode> {
+        let bus = (device.controller, device.port);
+
+        if let Some(ndx) = self.bus_script.iter().position(|(b, _)| *b == bus) {
+            return Err(self.bus_script.remove(ndx).1);
+        }
+
+        let model = self.model(device)?;
+        let mut model = model.borrow_mut();
+
+        if !write.is_empty() {
+            model.write(write)?;
+        }
+
+        if block {
+            let mut raw = [0xff; 256];
+            model.read(&mut raw)?;
+            let n = usize::from(raw[0]);
+
+            if n > read.len() {
+                return Err(ResponseCode::BadArg);
+            }
+
+            read[..n].copy_from_slice(&raw[1..=n]);
+            Ok(n)
+        } else if !read.is_empty() {
+            model.read(read)?;
+            Ok(read.len())
+        } else {
+            Ok(0)
+        }
+    }
+}
+
+//
+// The equivalent of the server's handling of a WriteRead or WriteReadBlock
+// message, one write/read pair per pair of leases.  Only the final read of a
+// WriteReadBlock is a block read.
+//
+fn write_read(
+    device: &I2cDevice,
+    block: bool,
+    pairs: &mut [(&[u8], &mut [u8])],
+) -> Result<usize, ResponseCode> {
+    if ReservedAddress::from_u8(device.address).is_some() {
+        return Err(ResponseCode::ReservedAddress);
+    }
+
+    let state = BUSES
+        .with(|buses| buses.borrow().get(usize::from(device.task.0)).cloned())
+        .expect("I2cDevice not made by a mock::Bus");
+    let mut state = state.borrow_mut();
+    let bus = (device.controller, device.port);
+
+    if let Err(code) = state.configure_mux(bus, device.segment) {
+        if reset_needed(code) {
+            state.reset(bus);
+        }
+        return Err(code);
+    }
+
+    let last = pairs.len() - 1;
+    let mut total = 0;
+
+    for (i, (write, read)) in pairs.iter_mut().enumerate() {
+        if write.is_empty() && read.is_empty() {
+            return Err(ResponseCode::BadArg);
+        }
+
+        if write.len() > 255 || read.len() > 255 {
+            return Err(ResponseCode::BadArg);
+        }
+
+        match state.transfer(device, write, read, block && i == last) {
+            Ok(n) => total += n,
+            Err(code) => {
+                if reset_needed(code) {
+                    state.reset(bus);
+                }
+                return Err(code);
+            }
+        }
+    }
+
+    Ok(total)
+}
+
+impl I2cDevice {
+    /// Hosted version of the register read; see the target build's
+    /// documentation for each of these operations.
+    pub fn read_reg<R: AsBytes, V: AsBytes + FromBytes>(
+        &self,
+        reg: R,
+    ) -> Result<V, ResponseCode> {
+        let mut val = V::new_zeroed();
+        write_read(self, false, &mut [(reg.as_bytes(), val.as_bytes_mut())])?;
+        Ok(val)
+    }
+
+    pub fn read_reg_into<R: AsBytes>(
+        &self,
+        reg: R,
+        buf: &mut [u8],
+    ) -> Result<usize, ResponseCode> {
+        write_read(self, false, &mut [(reg.as_bytes(), buf)])
+    }
+
+    pub fn read_block<R: AsBytes>(
+        &self,
+        reg: R,
+        buf: &mut [u8],
+    ) -> Result<usize, ResponseCode> {
+        write_read(self, true, &mut [(reg.as_bytes(), buf)])
+    }
+
+    pub fn read<V: AsBytes + FromBytes>(&self) -> Result<V, ResponseCode> {
+        let mut val = V::new_zeroed();
+        write_read(self, false, &mut [(&[], val.as_bytes_mut())])?;
+        Ok(val)
+    }
+
+    pub fn read_into(&self, buf: &mut [u8]) -> Result<usize, ResponseCode> {
+        write_read(self, false, &mut [(&[], buf)])
+    }
+
+    pub fn write(&self, buffer: &[u8]) -> Result<(), ResponseCode> {
+        write_read(self, false, &mut [(buffer, &mut [])])?;
+        Ok(())
+    }
+
+    pub fn write_read_reg<R: AsBytes, V: AsBytes + FromBytes>(
+        &self,
+        reg: R,
+        buffer: &[u8],
+    ) -> Result<V, ResponseCode> {
+        let mut val = V::new_zeroed();
+        write_read(
+            self,
+            false,
+            &mut [(buffer, &mut []), (reg.as_bytes(), val.as_bytes_mut())],
+        )?;
+        Ok(val)
+    }
+
+    pub fn write_read_block<R: AsBytes>(
+        &self,
+        reg: R,
+        buffer: &[u8],
+        out: &mut [u8],
+    ) -> Result<usize, ResponseCode> {
+        write_read(self, true, &mut [(buffer, &mut []), (reg.as_bytes(), out)])
+    }
+
+    pub fn write_write(
+        &self,
+        first: &[u8],
+        second: &[u8],
+    ) -> Result<(), ResponseCode> {
+        write_read(self, false, &mut [(first, &mut []), (second, &mut [])])?;
+        Ok(())
+    }
+
+    pub fn write_write_read_reg<R: AsBytes, V: AsBytes + FromBytes>(
+        &self,
+        reg: R,
+        first: &[u8],
+        second: &[u8],
+    ) -> Result<V, ResponseCode> {
+        let mut val = V::new_zeroed();
+        write_read(
+            self,
+            false,
+            &mut [
+                (first, &mut []),
+                (second, &mut []),
+                (reg.as_bytes(), val.as_bytes_mut()),
+            ],
+        )?;
+        Ok(val)
+    }
+}
+
+impl I2cBatch<'_> {
+    /// Hosted version of [`I2cBatch::send`], in which each transaction is
+    /// performed just as a `WriteRead` (or `WriteReadBlock`) with a single
+    /// pair of leases would be.
+    pub fn send(
+        &self,
+        data: &mut [u8],
+        results: &mut [BatchResult],
+    ) -> Result<usize, ResponseCode> {
+        self.check(data, results)?;
+
+        let task = match self.task() {
+            Some(task) => task,
+            None => return Ok(0),
+        };
+
+        let mut request = self.request();
+        let mut offset = 0;
+        let mut succeeded = 0;
+        let mut last = Ok(0);
+
+        for result in &mut results[..self.len()] {
+            let (header, rest) = request.split_at(BATCH_HEADER_LEN);
+            let header = BatchHeader::unmarshal(header.try_into().unwrap())?;
+            let (write, rest) = rest.split_at(usize::from(header.write_len));
+            let read_len = usize::from(header.read_len);
+            let read = &mut data[offset..offset + read_len];
+
+            request = rest;
+            offset += read_len;
+
+            if !header.chained || last.is_ok() {
+                let device = I2cDevice::new(
+                    task,
+                    header.controller,
+                    header.port,
+                    header.segment,
+                    header.address,
+                );
+
+                last = write_read(&device, header.block, &mut [(write, read)]);
+            }
+
+            if last.is_ok() {
+                succeeded += 1;
+            }
+
+            *result = BatchResult::from(last);
+        }
+
+        Ok(succeeded)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BUS: (Controller, PortIndex) = (Controller::I2C2, PortIndex(0));
+
+    fn sensor(bus: &Bus, segment: Option<(Mux, Segment)>) -> I2cDevice {
+        let device = bus.device(BUS.0, BUS.1, segment, 0x48);
+        let map = bus.attach(&device, RegisterMap::new());
+        map.borrow_mut().set(0x00, &[0x12, 0x34]).set(0x01, &[0x56]);
+        device
+    }
+
+    #[test]
+    fn registers() {
+        let bus = Bus::new();
+        let device = sensor(&bus, None);
+
+        assert_eq!(device.read_reg::<u8, [u8; 2]>(0x00), Ok([0x12, 0x34]));
+        assert_eq!(device.read_reg::<u8, u8>(0x01), Ok(0x56));
+
+        // Reading past the end of a register reads a released bus.
+        assert_eq!(device.read_reg::<u8, [u8; 2]>(0x01), Ok([0x56, 0xff]));
+
+        // A read without a register write reads the last register.
+        assert_eq!(device.read::<u8>(), Ok(0x56));
+
+        device.write(&[0x01, 0x78]).unwrap();
+        assert_eq!(device.read_reg::<u8, u8>(0x01), Ok(0x78));
+
+        assert_eq!(
+            device.read_reg::<u8, u8>(0x02),
+            Err(ResponseCode::NoRegister)
+        );
+    }
+
+    #[test]
+    fn auto_increment() {
+        let bus = Bus::new();
+        let device = bus.device(BUS.0, BUS.1, None, 0x20);
+        let map = bus.attach(&device, RegisterMap::auto_increment());
+
+        for reg in 0..4 {
+            map.borrow_mut().set(reg, &[0]);
+        }
+
+        device.write(&[0x01, 0xaa, 0xbb]).unwrap();
+        assert_eq!(device.read_reg::<u8, [u8; 3]>(0x00), Ok([0, 0xaa, 0xbb]));
+        assert_eq!(map.borrow().get(0x02), Some(&[0xbb][..]));
+        assert_eq!(map.borrow().writes(), &[std::vec![0x01, 0xaa, 0xbb]]);
+
+        // Running off the end of the map is NAK'd.
+        assert_eq!(
+            device.write(&[0x03, 0x01, 0x02]),
+            Err(ResponseCode::NoRegister)
+        );
+    }
+
+    #[test]
+    fn block_read() {
+        let bus = Bus::new();
+        let device = bus.device(BUS.0, BUS.1, None, 0x10);
+        let map = bus.attach(&device, RegisterMap::new());
+        map.borrow_mut().set_block(0x9a, b"MODEL");
+
+        let mut buf = [0u8; 8];
+        assert_eq!(device.read_block(0x9au8, &mut buf), Ok(5));
+        assert_eq!(&buf[..5], b"MODEL");
+
+        // A block longer than the buffer is refused, as the server does.
+        let mut buf = [0u8; 4];
+        assert_eq!(
+            device.read_block(0x9au8, &mut buf),
+            Err(ResponseCode::BadArg)
+        );
+    }
+
+    #[test]
+    fn scoped_registers() {
+        let bus = Bus::new();
+        let device = bus.device(BUS.0, BUS.1, None, 0x60);
+        let map = bus.attach(&device, RegisterMap::new());
+        map.borrow_mut()
+            .set(0x00, &[0])
+            .set(0x8b, &[0x00, 0x01])
+            .set_scoped(&[(0x00, 1)], 0x8b, &[0x00, 0x02]);
+
+        assert_eq!(
+            device.write_read_reg::<u8, [u8; 2]>(0x8b, &[0x00, 0]),
+            Ok([0x00, 0x01])
+        );
+        assert_eq!(
+            device.write_read_reg::<u8, [u8; 2]>(0x8b, &[0x00, 1]),
+            Ok([0x00, 0x02])
+        );
+
+        // Rails without a value of their own see the unscoped one.
+        assert_eq!(
+            device.write_read_reg::<u8, [u8; 2]>(0x8b, &[0x00, 2]),
+            Ok([0x00, 0x01])
+        );
+    }
+
+    #[test]
+    fn missing_devices() {
+        let bus = Bus::new();
+        let device = sensor(&bus, None);
+        let nobody = bus.device(BUS.0, BUS.1, None, 0x49);
+
+        assert_eq!(nobody.read::<u8>(), Err(ResponseCode::NoDevice));
+
+        bus.set_present(&device, false);
+        assert_eq!(device.read::<u8>(), Err(ResponseCode::NoDevice));
+        bus.set_present(&device, true);
+        assert!(device.read::<u8>().is_ok());
+
+        let reserved = bus.device(BUS.0, BUS.1, None, 0x00);
+        assert_eq!(reserved.read::<u8>(), Err(ResponseCode::ReservedAddress));
+    }
+
+    #[test]
+    fn scripted_failures() {
+        let bus = Bus::new();
+        let device = sensor(&bus, None);
+
+        bus.fail_next(&device, ResponseCode::NoRegister);
+        bus.fail_next(&device, ResponseCode::BusError);
+        assert_eq!(device.read::<u8>(), Err(ResponseCode::NoRegister));
+        assert_eq!(device.read::<u8>(), Err(ResponseCode::BusError));
+        assert!(device.read::<u8>().is_ok());
+
+        // Each write/read pair is a transfer of its own.
+        bus.fail_after(&device, 1, ResponseCode::NoDevice);
+        assert_eq!(
+            device.write_read_reg::<u8, u8>(0x01, &[0x01, 0x00]),
+            Err(ResponseCode::NoDevice)
+        );
+        assert_eq!(device.read_reg::<u8, u8>(0x01), Ok(0x00));
+
+        bus.fail_bus_next(BUS.0, BUS.1, ResponseCode::BusLocked);
+        assert_eq!(device.read::<u8>(), Err(ResponseCode::BusLocked));
+        assert!(device.read::<u8>().is_ok());
+    }
+
+    #[test]
+    fn muxes() {
+        let bus = Bus::new();
+        bus.add_mux(BUS.0, BUS.1, Mux::M1, 4);
+
+        let behind = sensor(&bus, Some((Mux::M1, Segment::S2)));
+        let root = bus.device(BUS.0, BUS.1, None, 0x50);
+        bus.attach(&root, RegisterMap::new())
+            .borrow_mut()
+            .set(0x00, &[0x01]);
+
+        assert!(behind.read::<u8>().is_ok());
+        assert_eq!(
+            bus.enabled_segment(BUS.0, BUS.1),
+            Some((Mux::M1, Segment::S2))
+        );
+
+        // Talking to anything else disables the segment...
+        assert!(root.read::<u8>().is_ok());
+        assert_eq!(bus.enabled_segment(BUS.0, BUS.1), None);
+
+        // ...and the device behind it can't be seen from the wrong segment.
+        let wrong =
+            bus.device(BUS.0, BUS.1, Some((Mux::M1, Segment::S3)), 0x48);
+        assert_eq!(wrong.read::<u8>(), Err(ResponseCode::NoDevice));
+
+        let absent =
+            bus.device(BUS.0, BUS.1, Some((Mux::M1, Segment::S5)), 0x48);
+        assert_eq!(absent.read::<u8>(), Err(ResponseCode::SegmentNotFound));
+
+        let unknown =
+            bus.device(BUS.0, BUS.1, Some((Mux::M2, Segment::S1)), 0x48);
+        assert_eq!(unknown.read::<u8>(), Err(ResponseCode::MuxNotFound));
+    }
+
+    #[test]
+    fn mux_failures() {
+        let bus = Bus::new();
+        bus.add_mux(BUS.0, BUS.1, Mux::M1, 4);
+        let behind = sensor(&bus, Some((Mux::M1, Segment::S1)));
+
+        bus.fail_mux_next(BUS.0, BUS.1, Mux::M1, ResponseCode::BusLockedMux);
+        assert_eq!(behind.read::<u8>(), Err(ResponseCode::BusLockedMux));
+        assert!(behind.read::<u8>().is_ok());
+
+        // A bus error resets the muxes, leaving no segment known to be on.
+        bus.fail_next(&behind, ResponseCode::BusError);
+        assert_eq!(behind.read::<u8>(), Err(ResponseCode::BusError));
+        assert_eq!(bus.enabled_segment(BUS.0, BUS.1), None);
+        assert!(behind.read::<u8>().is_ok());
+
+        // A mux that disappears takes its segments with it.
+        bus.set_mux_present(BUS.0, BUS.1, Mux::M1, false);
+        assert_eq!(behind.read::<u8>(), Err(ResponseCode::NoDevice));
+
+        // Disabling the segment then fails once, after which the rest of the
+        // bus carries on without the mux.
+        let root = bus.device(BUS.0, BUS.1, None, 0x50);
+        bus.attach(&root, RegisterMap::new());
+        assert_eq!(root.write(&[0x00]), Err(ResponseCode::MuxMissing));
+        assert_eq!(root.write(&[0x00]), Err(ResponseCode::NoRegister));
+        assert_eq!(behind.read::<u8>(), Err(ResponseCode::MuxMissing));
+    }
+
+    #[test]
+    fn batch() {
+        let bus = Bus::new();
+        let device = sensor(&bus, None);
+        let missing = bus.device(BUS.0, BUS.1, None, 0x49);
+
+        let mut request = [0u8; 64];
+        let mut batch = I2cBatch::new(&mut request);
+
+        let a = device.queue_read_reg(&mut batch, 0x00u8, 2).unwrap();
+        let b = missing.queue_read_reg(&mut batch, 0x00u8, 1).unwrap();
+        let c = device.queue_write(&mut batch, &[0x01, 0x9a]).unwrap();
+        let d = device.queue_read_reg(&mut batch, 0x01u8, 1).unwrap();
+
+        // The write fails, so the read chained to it isn't attempted.
+        let e = device
+            .queue_write_read_reg(&mut batch, 0x01u8, &[0x07, 0x00], 1)
+            .unwrap();
+
+        assert_eq!(batch.len(), 6);
+        assert_eq!(batch.read_len(), 5);
+
+        let mut data = [0u8; 5];
+        let mut results = [BatchResult::default(); 6];
+        assert_eq!(batch.send(&mut data, &mut results), Ok(3));
+
+        assert_eq!(a.get(&data, &results), Ok(&[0x12, 0x34][..]));
+        assert_eq!(b.get(&data, &results), Err(ResponseCode::NoDevice));
+        assert_eq!(c.get(&data, &results), Ok(&[][..]));
+        assert_eq!(d.get(&data, &results), Ok(&[0x9a][..]));
+        assert_eq!(e.get(&data, &results), Err(ResponseCode::NoRegister));
+
+        assert_eq!(
+            batch.send(&mut data, &mut results[..5]),
+            Err(ResponseCode::BadArg)
+        );
+    }
+
+    #[test]
+    fn batch_limits() {
+        let bus = Bus::new();
+        let device = sensor(&bus, None);
+
+        let mut request = [0u8; 2 * BATCH_HEADER_LEN + 2];
+        let mut batch = I2cBatch::new(&mut request);
+        device.queue_read_reg(&mut batch, 0x00u8, 2).unwrap();
+
+        // Room for the write, but not the read: neither is queued.
+        assert!(device
+            .queue_write_read_reg(&mut batch, 0x01u8, &[0x00], 1)
+            .is_err());
+        assert_eq!(batch.len(), 1);
+
+        // Devices on another server can't share the batch.
+        let other = Bus::new().device(BUS.0, BUS.1, None, 0x48);
+        assert!(other.queue_read_reg(&mut batch, 0x00u8, 1).is_err());
+
+        assert!(device.queue_write(&mut batch, &[]).is_err());
+        assert!(device.queue_read_reg(&mut batch, 0x00u8, 256).is_err());
+
+        batch.clear();
+        assert!(batch.is_empty());
+        assert!(other.queue_read_reg(&mut batch, 0x00u8, 1).is_ok());
+    }
+}
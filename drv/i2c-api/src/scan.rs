@@ -0,0 +1,39 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Bus scans; see [`scan`]
+
+use crate::{
+    AddressSet, Controller, Marshal, Mux, Op, PortIndex, ResponseCode, Segment,
+};
+use userlib::{sys_send, TaskId};
+use zerocopy::AsBytes;
+
+///
+/// Has the I2C server `task` scan a bus -- or, if a segment is given, a
+/// segment of one of its muxes -- returning the addresses at which a device
+/// acknowledged a read.  A scan of a segment finds the devices on the bus
+/// outside of any segment and the muxes themselves, too.
+///
+/// A scan fails if the bus does anything other than NAK or acknowledge,
+/// in which case the bus is reset as it would be for any other transaction.
+///
+pub fn scan(
+    task: TaskId,
+    controller: Controller,
+    port: PortIndex,
+    segment: Option<(Mux, Segment)>,
+) -> Result<AddressSet, ResponseCode> {
+    let mut found = AddressSet::default();
+
+    let (code, _) = sys_send(
+        task,
+        Op::Scan as u16,
+        &Marshal::marshal(&(0, controller, port, segment)),
+        found.0.as_bytes_mut(),
+        &[],
+    );
+
+    crate::response_code(code, found)
+}
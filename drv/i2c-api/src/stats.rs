@@ -0,0 +1,83 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Bus health counters; see [`I2cStats`]
+
+use zerocopy::{AsBytes, FromBytes};
+
+/// In an [`Op::ReadStats`](crate::Op::ReadStats), the flag that zeroes the
+/// counters once they have been read.
+pub const STATS_CLEAR: u8 = 1 << 0;
+
+///
+/// Counters kept by the I2C server for a bus (that is, a controller and
+/// port) or for a segment of one of the bus's muxes, as returned by
+/// [`read_stats`](crate::read_stats).
+///
+/// Everything that happens on a segment happens on its bus too, so a bus's
+/// counters include those of all of its segments.  Resets and SCL wiggles
+/// are of the bus as a whole, and are only counted for the bus.  Counters
+/// saturate rather than wrap.
+///
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq, AsBytes, FromBytes)]
+#[repr(C)]
+pub struct I2cStats {
+    /// Requests to perform a transaction, whether or not they succeeded
+    pub transactions: u32,
+    /// Transactions in which the device didn't acknowledge its address
+    pub address_naks: u32,
+    /// Transactions in which the device didn't acknowledge a byte written
+    pub data_naks: u32,
+    /// Transactions in which the controller lost arbitration
+    pub arbitration_losses: u32,
+    /// Transactions in which the bus was held and didn't come free
+    pub timeouts: u32,
+    /// Transactions in which the controller saw a misplaced start or stop
+    pub bus_errors: u32,
+    /// Transactions in which the controller was unexpectedly busy
+    pub controller_busy: u32,
+    /// Failures to configure a mux before a transaction
+    pub mux_errors: u32,
+    /// Resets of the controller and of the muxes on the bus
+    pub bus_resets: u32,
+    /// Clocks given on SCL, at boot, to free SDA from a stuck device
+    pub scl_wiggles: u32,
+    /// The address of the device that most recently didn't acknowledge its
+    /// address or its data, or zero if none has
+    pub last_nak_address: u8,
+    /// The address of the device in the most recent transaction to fail
+    /// for any other reason, or zero if none has
+    pub last_error_address: u8,
+    _reserved: [u8; 2],
+}
+
+///
+/// Reads the counters that the I2C server `task` keeps for a bus -- or, if a
+/// segment is given, for a segment of one of its muxes.  If `clear` is set,
+/// the counters are zeroed once read, so that the next read returns only
+/// what has happened since.
+///
+#[cfg(target_os = "none")]
+pub fn read_stats(
+    task: userlib::TaskId,
+    controller: crate::Controller,
+    port: crate::PortIndex,
+    segment: Option<(crate::Mux, crate::Segment)>,
+    clear: bool,
+) -> Result<I2cStats, crate::ResponseCode> {
+    use crate::Marshal;
+
+    let flags = if clear { STATS_CLEAR } else { 0 };
+    let mut stats = I2cStats::default();
+
+    let (code, _) = userlib::sys_send(
+        task,
+        crate::Op::ReadStats as u16,
+        &Marshal::marshal(&(flags, controller, port, segment)),
+        stats.as_bytes_mut(),
+        &[],
+    );
+
+    crate::response_code(code, stats)
+}
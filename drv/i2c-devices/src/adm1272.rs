@@ -91,7 +91,7 @@ impl Adm1272 {
     pub fn new(device: &I2cDevice, rsense: Ohms) -> Self {
         Self {
             device: *device,
-            rsense: (rsense.0 * 1000.0).round() as i32,
+            rsense: FloatCore::round(rsense.0 * 1000.0) as i32,
             coefficients: Cell::new(None),
             config: Cell::new(None),
         }
@@ -300,3 +300,92 @@ impl VoltageSensor<Error> for Adm1272 {
         Ok(Volts(vout.get(&self.load_coefficients()?.voltage)?.0))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models;
+    use drv_i2c_api::mock::Bus;
+
+    const PMON_CONFIG: u8 = 0xd4;
+
+    fn adm1272(
+        bus: &Bus,
+    ) -> (I2cDevice, std::rc::Rc<core::cell::RefCell<models::Adm1272>>) {
+        let device = bus.device(Controller::I2C2, PortIndex(0), None, 0x10);
+        let model = bus.attach(&device, models::Adm1272::new());
+        (device, model)
+    }
+
+    #[test]
+    fn validate() {
+        let bus = Bus::new();
+        let (device, model) = adm1272(&bus);
+
+        assert_eq!(Adm1272::validate(&device), Ok(true));
+
+        model
+            .borrow_mut()
+            .set_block(CommandCode::MFR_MODEL as u8, b"ADM1272-1A");
+        assert_eq!(Adm1272::validate(&device), Ok(false));
+
+        bus.set_present(&device, false);
+        assert_eq!(
+            Adm1272::validate(&device),
+            Err(Error::BadValidation {
+                cmd: CommandCode::MFR_MODEL as u8,
+                code: ResponseCode::NoDevice,
+            })
+        );
+    }
+
+    #[test]
+    fn vin() {
+        let bus = Bus::new();
+        let (device, model) = adm1272(&bus);
+        let adm1272 = Adm1272::new(&device, Ohms(0.001));
+
+        // At 60 V, m = 6770 and R = -2: 812 reads as 812 * 100 / 6770 V.
+        model
+            .borrow_mut()
+            .set_word(CommandCode::READ_VIN as u8, 812);
+
+        let vin = adm1272.read_vin().unwrap();
+        assert!((vin.0 - 11.994).abs() < 0.001);
+
+        // Sampling VIN had to be turned on, once.
+        assert_eq!(
+            model.borrow().writes(),
+            &[std::vec![PMON_CONFIG, 0x04, 0x00]]
+        );
+
+        adm1272.read_vin().unwrap();
+        assert_eq!(model.borrow().writes().len(), 1);
+    }
+
+    #[test]
+    fn errors() {
+        let bus = Bus::new();
+        let (device, _) = adm1272(&bus);
+        let adm1272 = Adm1272::new(&device, Ohms(0.001));
+
+        bus.fail_next(&device, ResponseCode::BusLocked);
+        assert_eq!(
+            adm1272.read_vin(),
+            Err(Error::BadRead {
+                cmd: PMON_CONFIG,
+                code: ResponseCode::BusLocked,
+            })
+        );
+
+        // The configuration is read, then written; fail the write.
+        bus.fail_after(&device, 1, ResponseCode::NoRegister);
+        assert_eq!(
+            adm1272.read_vout(),
+            Err(Error::BadWrite {
+                cmd: PMON_CONFIG,
+                code: ResponseCode::NoRegister,
+            })
+        );
+    }
+}
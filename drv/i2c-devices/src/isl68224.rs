@@ -142,3 +142,82 @@ impl CurrentSensor<Error> for Isl68224 {
         Ok(Amperes(iout.get()?.0))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models;
+    use drv_i2c_api::mock::Bus;
+
+    //
+    // Put the part behind a mux, to make sure that the driver doesn't care.
+    //
+    fn isl68224(bus: &Bus) -> I2cDevice {
+        let (controller, port) = (Controller::I2C2, PortIndex(1));
+        bus.add_mux(controller, port, Mux::M1, 4);
+
+        let segment = Some((Mux::M1, Segment::S2));
+        bus.device(controller, port, segment, 0x60)
+    }
+
+    #[test]
+    fn validate() {
+        let bus = Bus::new();
+        let device = isl68224(&bus);
+
+        assert!(matches!(
+            Isl68224::validate(&device),
+            Err(Error::BadValidation {
+                code: ResponseCode::NoDevice,
+                ..
+            })
+        ));
+
+        bus.attach(&device, models::Isl68224::new());
+        assert!(matches!(Isl68224::validate(&device), Ok(true)));
+    }
+
+    #[test]
+    fn rails() {
+        let bus = Bus::new();
+        let device = isl68224(&bus);
+        let model = bus.attach(&device, models::Isl68224::new());
+
+        model
+            .borrow_mut()
+            .set_rail_word(2, CommandCode::READ_VOUT, 0x0260)
+            .set_rail_word(2, CommandCode::READ_TEMPERATURE_1, 61);
+
+        let rail = Isl68224::new(&device, 2);
+        assert_eq!(rail.read_vout().unwrap(), Volts(1.1875));
+        assert_eq!(rail.read_temperature().unwrap(), Celsius(61.0));
+
+        assert_eq!(Isl68224::new(&device, 0).read_vout().unwrap(), Volts(0.0));
+    }
+
+    #[test]
+    fn errors() {
+        let bus = Bus::new();
+        let device = isl68224(&bus);
+        bus.attach(&device, models::Isl68224::new());
+
+        bus.set_mux_present(Controller::I2C2, PortIndex(1), Mux::M1, false);
+        assert!(matches!(
+            Isl68224::validate(&device),
+            Err(Error::BadValidation {
+                code: ResponseCode::MuxMissing,
+                ..
+            })
+        ));
+
+        bus.set_mux_present(Controller::I2C2, PortIndex(1), Mux::M1, true);
+        bus.fail_next(&device, ResponseCode::NoDevice);
+        assert!(matches!(
+            Isl68224::new(&device, 1).turn_off(),
+            Err(Error::BadRead {
+                cmd: 0x01,
+                code: ResponseCode::NoDevice,
+            })
+        ));
+    }
+}
@@ -20,6 +20,7 @@
 //! - [`max6634`]: MAX6634 temperature sensor
 //! - [`max31790`]: MAX31790 fan controller
 //! - [`mcp9808`]: MCP9808 temperature sensor
+//! - `models`: simulated devices, for testing drivers on the host
 //! - [`mwocp68`]: Murata power shelf
 //! - [`nvme_bmc`]: NVMe basic management control
 //! - [`pca9538`]: PCA9538 GPIO expander
@@ -35,6 +36,9 @@
 
 #![no_std]
 
+#[cfg(test)]
+extern crate std;
+
 use drv_i2c_api::{I2cDevice, ResponseCode};
 use pmbus::commands::CommandCode;
 
@@ -230,6 +234,9 @@ pub mod max31790;
 pub mod max5970;
 pub mod max6634;
 pub mod mcp9808;
+#[cfg(not(target_os = "none"))]
+pub mod models;
+#[cfg(target_os = "none")]
 pub mod mwocp68;
 pub mod nvme_bmc;
 pub mod pca9538;
@@ -353,3 +353,80 @@ impl Validate<ResponseCode> for Max31790 {
         Ok(pwm_13.is_some() && pwm_46.is_some())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models;
+    use drv_i2c_api::mock::Bus;
+
+    #[test]
+    fn initialize() {
+        let bus = Bus::new();
+        let device = bus.device(Controller::I2C2, PortIndex(0), None, 0x20);
+        let model = bus.attach(&device, models::Max31790::new());
+
+        assert_eq!(Max31790::validate(&device), Ok(true));
+
+        let max31790 = Max31790::new(&device);
+        max31790.initialize().unwrap();
+
+        let model = model.borrow();
+        assert_eq!(
+            model.get(Register::GlobalConfiguration as u8),
+            Some(&[0x20][..])
+        );
+
+        for fan in 0..MAX_FANS {
+            let fan = Fan::try_from(fan).unwrap();
+            let config = FanConfiguration(
+                model.get(fan.configuration() as u8).unwrap()[0],
+            );
+            assert!(config.tach_input_enable());
+            assert_eq!(model.pwm_target(fan.0), 0);
+        }
+    }
+
+    #[test]
+    fn fans() {
+        let bus = Bus::new();
+        let device = bus.device(Controller::I2C2, PortIndex(0), None, 0x20);
+        let model = bus.attach(&device, models::Max31790::new());
+        let max31790 = Max31790::new(&device);
+        let fan = Fan::try_from(2).unwrap();
+
+        // A fan that hasn't been seen to turn is stopped.
+        assert_eq!(max31790.fan_rpm(fan), Ok(Rpm(0)));
+
+        model.borrow_mut().set_tach_count(2, 1920);
+        assert_eq!(max31790.fan_rpm(fan), Ok(Rpm(512)));
+
+        max31790.set_pwm(fan, PWMDuty(100)).unwrap();
+        assert_eq!(model.borrow().pwm_target(2), 0b1_1111_1111);
+
+        max31790.set_pwm(fan, PWMDuty(50)).unwrap();
+        assert_eq!(model.borrow().pwm_target(2), 255);
+        assert_eq!(
+            model.borrow().writes().last().unwrap(),
+            &[Register::PWMOut3TargetDutyCycleMSB as u8, 0x7f, 0x80]
+        );
+
+        assert_eq!(Fan::try_from(MAX_FANS), Err(()));
+    }
+
+    #[test]
+    fn validate() {
+        let bus = Bus::new();
+        let device = bus.device(Controller::I2C2, PortIndex(0), None, 0x20);
+        let model = bus.attach(&device, models::Max31790::new());
+
+        // 0b1100 isn't a frequency the part knows.
+        model
+            .borrow_mut()
+            .set(Register::PWMFrequency as u8, &[0xc4]);
+        assert_eq!(Max31790::validate(&device), Ok(false));
+
+        bus.set_present(&device, false);
+        assert_eq!(Max31790::validate(&device), Err(ResponseCode::NoDevice));
+    }
+}
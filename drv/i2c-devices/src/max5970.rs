@@ -229,7 +229,7 @@ impl Max5970 {
         Self {
             device: *device,
             rail,
-            rsense: (rsense.0 * 1000.0).round() as i32,
+            rsense: FloatCore::round(rsense.0 * 1000.0) as i32,
         }
     }
 
@@ -0,0 +1,326 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Simulated devices, for testing drivers on the host
+//!
+//! Each model is a [`RegisterMap`] populated the way the part reads out of
+//! reset (or near enough for its driver), attached to a
+//! [`drv_i2c_api::mock::Bus`].  The models have setters for the readings
+//! that tests most often want to vary, and dereference to their map for
+//! anything else -- including [`RegisterMap::writes`], to see what a driver
+//! did to the device.
+
+use core::ops::{Deref, DerefMut};
+use drv_i2c_api::mock::{Model, RegisterMap};
+use drv_i2c_api::ResponseCode;
+use pmbus::commands::CommandCode;
+use userlib::units::*;
+
+macro_rules! register_model {
+    ($model:ident) => {
+        impl Default for $model {
+            fn default() -> Self {
+                Self::new()
+            }
+        }
+
+        impl Deref for $model {
+            type Target = RegisterMap;
+
+            fn deref(&self) -> &RegisterMap {
+                &self.0
+            }
+        }
+
+        impl DerefMut for $model {
+            fn deref_mut(&mut self) -> &mut RegisterMap {
+                &mut self.0
+            }
+        }
+
+        impl Model for $model {
+            fn write(&mut self, data: &[u8]) -> Result<(), ResponseCode> {
+                self.0.write(data)
+            }
+
+            fn read(&mut self, data: &mut [u8]) -> Result<(), ResponseCode> {
+                self.0.read(data)
+            }
+        }
+    };
+}
+
+/// TMP117 temperature sensor
+pub struct Tmp117(RegisterMap);
+
+register_model!(Tmp117);
+
+impl Tmp117 {
+    pub fn new() -> Self {
+        use crate::tmp117::Register::*;
+
+        let mut map = RegisterMap::new();
+
+        //
+        // Until its first conversion completes, the part reads -256 C.
+        //
+        map.set(TempResult as u8, &[0x80, 0x00])
+            .set(Configuration as u8, &[0x02, 0x20])
+            .set(THighLimit as u8, &[0x60, 0x00])
+            .set(TLowLimit as u8, &[0x80, 0x00])
+            .set(EEPROMUnlock as u8, &[0x00, 0x00])
+            .set(EEPROM1 as u8, &[0x00, 0x00])
+            .set(EEPROM2 as u8, &[0x00, 0x00])
+            .set(TempOffset as u8, &[0x00, 0x00])
+            .set(EEPROM3 as u8, &[0x00, 0x00])
+            .set(DeviceID as u8, &[0x01, 0x17]);
+
+        Self(map)
+    }
+
+    pub fn set_temperature(&mut self, t: Celsius) -> &mut Self {
+        use crate::tmp117::Register::*;
+        let raw = (t.0 * 128.0) as i16;
+        self.0.set(TempResult as u8, &raw.to_be_bytes());
+        self
+    }
+}
+
+/// TMP451 temperature sensor
+pub struct Tmp451(RegisterMap);
+
+register_model!(Tmp451);
+
+impl Tmp451 {
+    pub fn new() -> Self {
+        use crate::tmp451::Register::*;
+
+        let mut map = RegisterMap::new();
+
+        for reg in [
+            LocalTempHiByte,
+            RemoteTempHiByte,
+            Status,
+            OneShotStart,
+            RemoteTempLoByte,
+            RemoteTempOffsetHiByte,
+            RemoteTempOffsetLoByte,
+            RemoteTempHighLimitLoByte,
+            RemoteTempLowLimitLoByte,
+            LocalTempLoByte,
+            EtaFactorCorrection,
+        ] {
+            map.set(reg as u8, &[0x00]);
+        }
+
+        map.set(Config as u8, &[0x00])
+            .set(ConverstionRate as u8, &[0x07])
+            .set(LocalTempHighLimit as u8, &[0x55])
+            .set(LocalTempLowLimit as u8, &[0x00])
+            .set(RemoteTempHighLimitHiByte as u8, &[0x55])
+            .set(RemoteTempLowLimitHiByte as u8, &[0x00])
+            .set(RemoteTempThermBLimit as u8, &[0x6c])
+            .set(LocalTempThermBLimit as u8, &[0x55])
+            .set(ThermBHysteresis as u8, &[0x0a])
+            .set(ConsecutiveAlertB as u8, &[0x01])
+            .set(DigitalFilterControl as u8, &[0x00])
+            .set(ManufacturerId as u8, &[0x55]);
+
+        Self(map)
+    }
+
+    /// Sets the reading for a target, in the part's default (0 to 127 C)
+    /// range.
+    pub fn set_temperature(
+        &mut self,
+        target: crate::tmp451::Target,
+        t: Celsius,
+    ) -> &mut Self {
+        use crate::tmp451::{Register::*, Target};
+
+        let (hi, lo) = match target {
+            Target::Local => (LocalTempHiByte, LocalTempLoByte),
+            Target::Remote => (RemoteTempHiByte, RemoteTempLoByte),
+        };
+
+        let whole = t.0 as u8;
+        let sixteenths = ((t.0 - f32::from(whole)) / 0.0625) as u8;
+
+        self.0
+            .set(hi as u8, &[whole])
+            .set(lo as u8, &[sixteenths << 4]);
+        self
+    }
+}
+
+/// MAX31790 fan controller
+pub struct Max31790(RegisterMap);
+
+register_model!(Max31790);
+
+impl Max31790 {
+    pub fn new() -> Self {
+        use crate::max31790::Register;
+        use num_traits::FromPrimitive;
+
+        let mut map = RegisterMap::auto_increment();
+
+        for code in 0..=Register::UserByte14 as u8 {
+            if Register::from_u8(code).is_some() {
+                map.set(code, &[0x00]);
+            }
+        }
+
+        map.set(Register::GlobalConfiguration as u8, &[0x20])
+            .set(Register::PWMFrequency as u8, &[0x44]);
+
+        //
+        // The tach counts read as all ones until a fan is seen to turn.
+        //
+        let mut model = Self(map);
+
+        for fan in 0..crate::max31790::MAX_FANS {
+            model.set_tach_count(fan, 0x7ff);
+        }
+
+        model
+    }
+
+    /// Sets the 11-bit tach count of a fan, by its 0-based index.
+    pub fn set_tach_count(&mut self, fan: u8, count: u16) -> &mut Self {
+        use crate::max31790::Register;
+
+        let msb = Register::Tach1CountMSB as u8 + (fan << 1);
+        self.0
+            .set(msb, &[(count >> 3) as u8])
+            .set(msb + 1, &[((count & 0b111) << 5) as u8]);
+        self
+    }
+
+    /// Returns the 9-bit PWM target duty cycle of a fan, by its 0-based
+    /// index.
+    pub fn pwm_target(&self, fan: u8) -> u16 {
+        use crate::max31790::Register;
+
+        let msb = Register::PWMOut1TargetDutyCycleMSB as u8 + (fan << 1);
+        let hi = self.0.get(msb).unwrap()[0];
+        let lo = self.0.get(msb + 1).unwrap()[0];
+        u16::from_be_bytes([hi, lo]) >> 7
+    }
+}
+
+/// ADM1272 hot-swap controller
+///
+/// The readings are raw PMBus words; what they decode to depends on
+/// `PMON_CONFIG`, which reads 0 (all sampling off; 60 V and 15 mV ranges) to
+/// begin with.
+pub struct Adm1272(RegisterMap);
+
+register_model!(Adm1272);
+
+impl Adm1272 {
+    pub fn new() -> Self {
+        use pmbus::commands::adm1272::{
+            PEAK_IOUT, PMON_CONFIG, READ_IOUT, READ_TEMPERATURE_1, READ_VIN,
+            READ_VOUT,
+        };
+
+        let mut map = RegisterMap::new();
+
+        map.set_block(CommandCode::MFR_ID as u8, b"ADI")
+            .set_block(CommandCode::MFR_MODEL as u8, b"ADM1272-2A")
+            .set_word(PMON_CONFIG::CommandData::code(), 0)
+            .set_word(READ_VIN::CommandData::code(), 0)
+            .set_word(READ_VOUT::CommandData::code(), 0)
+            .set_word(READ_IOUT::CommandData::code(), 0)
+            .set_word(READ_TEMPERATURE_1::CommandData::code(), 0)
+            .set_word(PEAK_IOUT::CommandData::code(), 0);
+
+        Self(map)
+    }
+}
+
+//
+// The Renesas digital multiphase controllers share a register layout; the
+// readings are per rail, selected with PAGE.
+//
+fn renesas(device_id: &[u8; 4], rails: u8) -> RegisterMap {
+    let mut map = RegisterMap::new();
+
+    map.set(CommandCode::PAGE as u8, &[0])
+        .set(CommandCode::VOUT_MODE as u8, &[0x17])
+        .set_block(CommandCode::IC_DEVICE_ID as u8, device_id);
+
+    for rail in 0..rails {
+        let page = [(CommandCode::PAGE as u8, rail)];
+
+        map.set_scoped(&page, CommandCode::OPERATION as u8, &[0x80]);
+
+        for cmd in [
+            CommandCode::VOUT_COMMAND,
+            CommandCode::READ_VOUT,
+            CommandCode::READ_IOUT,
+            CommandCode::READ_TEMPERATURE_1,
+        ] {
+            map.set_scoped(&page, cmd as u8, &[0, 0]);
+        }
+    }
+
+    map
+}
+
+//
+// Sets a word as read from one rail of a paged device.
+//
+fn set_rail_word(map: &mut RegisterMap, rail: u8, cmd: CommandCode, raw: u16) {
+    map.set_scoped(
+        &[(CommandCode::PAGE as u8, rail)],
+        cmd as u8,
+        &raw.to_le_bytes(),
+    );
+}
+
+/// RAA229618 power controller, with two rails.  `VOUT_MODE` is linear with
+/// an exponent of -9.
+pub struct Raa229618(RegisterMap);
+
+register_model!(Raa229618);
+
+impl Raa229618 {
+    pub fn new() -> Self {
+        Self(renesas(&[0x00, 0x99, 0xd2, 0x49], 2))
+    }
+
+    pub fn set_rail_word(
+        &mut self,
+        rail: u8,
+        cmd: CommandCode,
+        raw: u16,
+    ) -> &mut Self {
+        set_rail_word(&mut self.0, rail, cmd, raw);
+        self
+    }
+}
+
+/// ISL68224 power controller, with three rails.  `VOUT_MODE` is linear with
+/// an exponent of -9.
+pub struct Isl68224(RegisterMap);
+
+register_model!(Isl68224);
+
+impl Isl68224 {
+    pub fn new() -> Self {
+        Self(renesas(&[0x00, 0x52, 0xd2, 0x49], 3))
+    }
+
+    pub fn set_rail_word(
+        &mut self,
+        rail: u8,
+        cmd: CommandCode,
+        raw: u16,
+    ) -> &mut Self {
+        set_rail_word(&mut self.0, rail, cmd, raw);
+        self
+    }
+}
@@ -158,3 +158,103 @@ impl CurrentSensor<Error> for Raa229618 {
         Ok(Amperes(iout.get()?.0))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models;
+    use drv_i2c_api::mock::Bus;
+
+    #[test]
+    fn validate() {
+        let bus = Bus::new();
+        let device = bus.device(Controller::I2C3, PortIndex(0), None, 0x60);
+        bus.attach(&device, models::Raa229618::new());
+
+        assert_eq!(Raa229618::validate(&device), Ok(true));
+
+        // An ISL68224 answers its IC_DEVICE_ID, but isn't the right part.
+        let other = bus.device(Controller::I2C3, PortIndex(0), None, 0x61);
+        bus.attach(&other, models::Isl68224::new());
+        assert_eq!(Raa229618::validate(&other), Ok(false));
+    }
+
+    #[test]
+    fn rails() {
+        let bus = Bus::new();
+        let device = bus.device(Controller::I2C3, PortIndex(0), None, 0x60);
+        let model = bus.attach(&device, models::Raa229618::new());
+
+        //
+        // VOUT_MODE is linear with an exponent of -9; the temperatures are
+        // in whole degrees.
+        //
+        model
+            .borrow_mut()
+            .set_rail_word(0, CommandCode::READ_VOUT, 0x0180)
+            .set_rail_word(1, CommandCode::READ_VOUT, 0x0300)
+            .set_rail_word(0, CommandCode::READ_TEMPERATURE_1, 45)
+            .set_rail_word(1, CommandCode::READ_TEMPERATURE_1, 52);
+
+        let vdd = Raa229618::new(&device, 0);
+        let vddio = Raa229618::new(&device, 1);
+
+        assert_eq!(vdd.read_vout(), Ok(Volts(0.75)));
+        assert_eq!(vddio.read_vout(), Ok(Volts(1.5)));
+        assert_eq!(vdd.read_temperature(), Ok(Celsius(45.0)));
+        assert_eq!(vddio.read_temperature(), Ok(Celsius(52.0)));
+    }
+
+    #[test]
+    fn turn_off() {
+        let bus = Bus::new();
+        let device = bus.device(Controller::I2C3, PortIndex(0), None, 0x60);
+        let model = bus.attach(&device, models::Raa229618::new());
+        let operation = CommandCode::OPERATION as u8;
+
+        Raa229618::new(&device, 1).turn_off().unwrap();
+        assert_eq!(model.borrow().get(operation), Some(&[0x00][..]));
+
+        // The other rail is left alone.
+        Raa229618::new(&device, 0).read_vout().unwrap();
+        assert_eq!(model.borrow().get(operation), Some(&[0x80][..]));
+    }
+
+    #[test]
+    fn errors() {
+        let bus = Bus::new();
+        let device = bus.device(Controller::I2C3, PortIndex(0), None, 0x60);
+        bus.attach(&device, models::Raa229618::new());
+        let raa229618 = Raa229618::new(&device, 0);
+
+        // Selecting the page goes through; the read itself fails.
+        bus.fail_after(&device, 1, ResponseCode::NoRegister);
+        assert_eq!(
+            raa229618.read_iout(),
+            Err(Error::BadRead {
+                cmd: CommandCode::READ_IOUT as u8,
+                code: ResponseCode::NoRegister,
+            })
+        );
+
+        bus.fail_bus_next(
+            Controller::I2C3,
+            PortIndex(0),
+            ResponseCode::BusLocked,
+        );
+        assert_eq!(
+            raa229618.read_vout(),
+            Err(Error::BadRead {
+                cmd: CommandCode::READ_VOUT as u8,
+                code: ResponseCode::BusLocked,
+            })
+        );
+
+        assert_eq!(
+            Raa229618::new(&device, 0).set_vout(Volts(3.3)),
+            Err(Error::InvalidData {
+                err: pmbus::Error::ValueOutOfRange,
+            })
+        );
+    }
+}
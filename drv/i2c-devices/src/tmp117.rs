@@ -84,3 +84,50 @@ impl TempSensor<Error> for Tmp117 {
         Ok(convert(self.read_reg(Register::TempResult)?))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models;
+    use drv_i2c_api::mock::Bus;
+
+    #[test]
+    fn temperature() {
+        let bus = Bus::new();
+        let device = bus.device(Controller::I2C2, PortIndex(0), None, 0x48);
+        let model = bus.attach(&device, models::Tmp117::new());
+
+        assert!(matches!(Tmp117::validate(&device), Ok(true)));
+
+        let tmp117 = Tmp117::new(&device);
+        assert_eq!(tmp117.read_temperature().unwrap(), Celsius(-256.0));
+
+        model.borrow_mut().set_temperature(Celsius(31.25));
+        assert_eq!(tmp117.read_temperature().unwrap(), Celsius(31.25));
+
+        model.borrow_mut().set_temperature(Celsius(-40.5));
+        assert_eq!(tmp117.read_temperature().unwrap(), Celsius(-40.5));
+    }
+
+    #[test]
+    fn errors() {
+        let bus = Bus::new();
+        let device = bus.device(Controller::I2C2, PortIndex(0), None, 0x48);
+        let model = bus.attach(&device, models::Tmp117::new());
+
+        // Something answers, but it isn't a TMP117.
+        model
+            .borrow_mut()
+            .set(Register::DeviceID as u8, &[0x01, 0x16]);
+        assert!(matches!(Tmp117::validate(&device), Ok(false)));
+
+        bus.set_present(&device, false);
+        assert!(matches!(
+            Tmp117::new(&device).read_temperature(),
+            Err(Error::BadRegisterRead {
+                reg: Register::TempResult,
+                code: ResponseCode::NoDevice,
+            })
+        ));
+    }
+}
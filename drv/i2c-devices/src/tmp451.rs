@@ -116,3 +116,63 @@ impl TempSensor<Error> for Tmp451 {
         Ok(Celsius(f32::from(hi) + f32::from(lo >> 4) * 0.0625f32))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models;
+    use drv_i2c_api::mock::Bus;
+
+    #[test]
+    fn temperature() {
+        let bus = Bus::new();
+        let device = bus.device(Controller::I2C2, PortIndex(0), None, 0x4c);
+        let model = bus.attach(&device, models::Tmp451::new());
+
+        assert!(matches!(Tmp451::validate(&device), Ok(true)));
+
+        model
+            .borrow_mut()
+            .set_temperature(Target::Local, Celsius(38.5))
+            .set_temperature(Target::Remote, Celsius(71.0625));
+
+        let local = Tmp451::new(&device, Target::Local);
+        let remote = Tmp451::new(&device, Target::Remote);
+
+        assert_eq!(local.read_temperature().unwrap(), Celsius(38.5));
+        assert_eq!(remote.read_temperature().unwrap(), Celsius(71.0625));
+    }
+
+    #[test]
+    fn errors() {
+        let bus = Bus::new();
+        let device = bus.device(Controller::I2C2, PortIndex(0), None, 0x4c);
+        bus.attach(&device, models::Tmp451::new());
+
+        // The low byte is read second; a failure there is reported as such.
+        bus.fail_after(&device, 1, ResponseCode::BusError);
+        assert!(matches!(
+            Tmp451::new(&device, Target::Remote).read_temperature(),
+            Err(Error::BadRegisterRead {
+                reg: Register::RemoteTempLoByte,
+                code: ResponseCode::BusError,
+            })
+        ));
+
+        assert!(matches!(
+            Tmp451::new(&device, Target::Local)
+                .write_reg(Register::Config, 0x04),
+            Ok(())
+        ));
+
+        bus.fail_next(&device, ResponseCode::NoRegister);
+        assert!(matches!(
+            Tmp451::new(&device, Target::Local)
+                .write_reg(Register::Config, 0x04),
+            Err(Error::BadRegisterWrite {
+                reg: Register::Config,
+                code: ResponseCode::NoRegister,
+            })
+        ));
+    }
+}
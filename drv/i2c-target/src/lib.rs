@@ -0,0 +1,225 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Virtual devices, presented by a controller operating as a target
+//!
+//! A task that wants to appear on a bus as one or more I2C devices -- say, an
+//! emulated FRU EEPROM, or a PMBus endpoint -- implements [`I2cTarget`] for
+//! each of them (or uses [`RegisterFile`] for the common case of a device
+//! that is a simple array of registers), and hands them to a driver's target
+//! loop (e.g., `drv_stm32xx_i2c::target::serve`).
+//!
+//! This crate has no hardware dependencies, so devices can be tested on the
+//! host.
+
+#![no_std]
+
+///
+/// A device presented to the bus.  All calls are made with the clock
+/// stretched, so they should be quick.
+///
+pub trait I2cTarget {
+    /// The 7-bit address at which the device responds.
+    fn address(&self) -> u8;
+
+    /// Called when the device is addressed, at the start (or a repeated
+    /// start) of a transaction.  Returning false declines the transaction,
+    /// which NACKs it.
+    fn start(&mut self) -> bool {
+        true
+    }
+
+    /// Called with each byte written to the device.
+    fn write(&mut self, byte: u8);
+
+    /// Returns the next byte read from the device, or `None` if it has
+    /// nothing more to say (in which case filler is sent).
+    fn read(&mut self) -> Option<u8>;
+
+    /// Called when a transaction that the device accepted ends with a STOP.
+    /// A repeated start doesn't end the transaction; if it addresses the
+    /// device again, [`I2cTarget::start`] is called instead, and if it
+    /// addresses another device, this device won't hear of it.
+    fn stop(&mut self) {}
+}
+
+///
+/// A device whose registers are an array of bytes, indexed by an 8-bit
+/// pointer: the first byte of each write sets the pointer, and every byte
+/// thereafter -- written or read -- is at the pointer, which then advances
+/// (wrapping at the end of the array).  This is the model of most small
+/// EEPROMs, and of many simple sensors.
+///
+pub struct RegisterFile<'a> {
+    address: u8,
+    registers: &'a mut [u8],
+    pointer: usize,
+    addressing: bool,
+    writable: bool,
+}
+
+impl<'a> RegisterFile<'a> {
+    pub fn new(address: u8, registers: &'a mut [u8]) -> Self {
+        Self {
+            address,
+            registers,
+            pointer: 0,
+            addressing: false,
+            writable: true,
+        }
+    }
+
+    /// Ignores writes to the registers (other than to set the pointer).
+    pub fn read_only(mut self) -> Self {
+        self.writable = false;
+        self
+    }
+
+    pub fn registers(&self) -> &[u8] {
+        self.registers
+    }
+
+    pub fn registers_mut(&mut self) -> &mut [u8] {
+        self.registers
+    }
+
+    fn advance(&mut self) {
+        self.pointer = (self.pointer + 1) % self.registers.len();
+    }
+}
+
+impl I2cTarget for RegisterFile<'_> {
+    fn address(&self) -> u8 {
+        self.address
+    }
+
+    fn start(&mut self) -> bool {
+        self.addressing = true;
+        !self.registers.is_empty()
+    }
+
+    fn write(&mut self, byte: u8) {
+        if self.addressing {
+            self.pointer = usize::from(byte) % self.registers.len();
+            self.addressing = false;
+            return;
+        }
+
+        if self.writable {
+            self.registers[self.pointer] = byte;
+        }
+
+        self.advance();
+    }
+
+    fn read(&mut self) -> Option<u8> {
+        let byte = self.registers[self.pointer];
+        self.advance();
+        Some(byte)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn write(device: &mut dyn I2cTarget, bytes: &[u8]) {
+        assert!(device.start());
+        for &byte in bytes {
+            device.write(byte);
+        }
+        device.stop();
+    }
+
+    fn write_read(device: &mut dyn I2cTarget, pointer: u8, buf: &mut [u8]) {
+        assert!(device.start());
+        device.write(pointer);
+        assert!(device.start());
+        for byte in buf {
+            *byte = device.read().unwrap();
+        }
+        device.stop();
+    }
+
+    #[test]
+    fn writes_at_pointer() {
+        let mut registers = [0u8; 8];
+        let mut file = RegisterFile::new(0x50, &mut registers);
+
+        write(&mut file, &[2, 0xaa, 0xbb]);
+        assert_eq!(file.registers(), [0, 0, 0xaa, 0xbb, 0, 0, 0, 0]);
+    }
+
+    #[test]
+    fn reads_at_pointer() {
+        let mut registers = [1, 2, 3, 4, 5, 6, 7, 8];
+        let mut file = RegisterFile::new(0x50, &mut registers);
+        let mut buf = [0u8; 3];
+
+        write_read(&mut file, 4, &mut buf);
+        assert_eq!(buf, [5, 6, 7]);
+
+        // A read without a write carries on from where we left off.
+        assert!(file.start());
+        assert_eq!(file.read(), Some(8));
+        file.stop();
+    }
+
+    #[test]
+    fn pointer_wraps() {
+        let mut registers = [1, 2, 3, 4];
+        let mut file = RegisterFile::new(0x50, &mut registers);
+        let mut buf = [0u8; 3];
+
+        write_read(&mut file, 3, &mut buf);
+        assert_eq!(buf, [4, 1, 2]);
+
+        // Pointers past the end wrap too.
+        write(&mut file, &[6, 0xff, 0xfe, 0xfd]);
+        assert_eq!(file.registers(), [0xfd, 2, 0xff, 0xfe]);
+    }
+
+    #[test]
+    fn read_only_sets_pointer() {
+        let mut registers = [1, 2, 3, 4];
+        let mut file = RegisterFile::new(0x50, &mut registers).read_only();
+        let mut buf = [0u8; 1];
+
+        write(&mut file, &[1, 0xaa, 0xbb]);
+        assert_eq!(file.registers(), [1, 2, 3, 4]);
+
+        // The pointer still advanced past the bytes we ignored.
+        assert!(file.start());
+        assert_eq!(file.read(), Some(4));
+        file.stop();
+
+        write_read(&mut file, 2, &mut buf);
+        assert_eq!(buf, [3]);
+    }
+
+    #[test]
+    fn repeated_start_resets_pointer() {
+        let mut registers = [0u8; 4];
+        let mut file = RegisterFile::new(0x50, &mut registers);
+
+        assert!(file.start());
+        file.write(1);
+        file.write(0xaa);
+        assert!(file.start());
+        file.write(3);
+        file.write(0xbb);
+        file.stop();
+
+        assert_eq!(file.registers(), [0, 0xaa, 0, 0xbb]);
+    }
+
+    #[test]
+    fn empty_declines() {
+        let mut registers = [];
+        let mut file = RegisterFile::new(0x50, &mut registers);
+
+        assert_eq!(file.address(), 0x50);
+        assert!(!file.start());
+    }
+}
@@ -36,6 +36,46 @@ pub enum Op {
     /// without interruption, this logic would not work, but that would be a
     /// very strange device indeed.
     WriteReadBlock = 2,
+
+    /// A `Batch` operation performs a list of independent transactions,
+    /// potentially on different devices and buses, in a single message.  It
+    /// takes three leases:
+    ///
+    /// - The request (read-only): for each transaction, a header giving the
+    ///   device, the number of bytes to write and to read and some flags,
+    ///   followed by the bytes to write.
+    /// - The read data (writable): the bytes read by each transaction, one
+    ///   after another, each transaction being allotted its full read length
+    ///   whether or not it succeeds.
+    /// - The results (writable): for each transaction, its response code (or
+    ///   zero on success) and the number of bytes read.
+    ///
+    /// The message itself is the number of transactions, as a little-endian
+    /// `u32`.  The client API's `I2cBatch` takes care of the encoding.
+    Batch = 3,
+
+    /// A `ReadStats` operation returns the health counters that the server
+    /// keeps for a bus, or for a segment of one of its muxes.  It takes no
+    /// leases; the message is that of a `WriteRead`, but with the address
+    /// replaced by flags -- of which the only one is to clear the counters
+    /// once they've been read.
+    ReadStats = 4,
+
+    /// A `Scan` operation looks for devices on a bus, or on a segment of one
+    /// of its muxes, by attempting a one-byte read at every address that
+    /// isn't reserved.  It takes no leases; the message is that of a
+    /// `WriteRead` with an address of zero, and the reply is the
+    /// [`AddressSet`] of the addresses that acknowledged.  Note that a
+    /// segment's scan will also find its muxes and the devices on the bus
+    /// outside of any segment.
+    ///
+    /// A scan is 112 back-to-back transactions in one message, during which
+    /// the server does nothing else.  Each takes ~11 bit times if the address
+    /// is NAK'd and ~20 if it's acknowledged, so a scan holds the server (and
+    /// the bus) for roughly 12-25 ms at 100 kHz, or a quarter of that at
+    /// 400 kHz, plus per-transaction overhead -- long enough that a scan
+    /// shouldn't be made while anything is polling devices on a deadline.
+    Scan = 5,
 }
 
 /// The response code returned from the I2C server.  These response codes pretty
@@ -221,3 +261,209 @@ pub enum Segment {
     S7 = 7,
     S8 = 8,
 }
+
+///
+/// A set of 7-bit I2C addresses, such as those found by a scan of a bus.
+///
+#[derive(
+    Copy,
+    Clone,
+    Debug,
+    Default,
+    Eq,
+    PartialEq,
+    SerializedSize,
+    Serialize,
+    Deserialize,
+)]
+pub struct AddressSet(pub [u8; 16]);
+
+impl AddressSet {
+    //
+    // Returns the byte and the bit within it for an address, if it's a 7-bit
+    // address.
+    //
+    fn bit(addr: u8) -> Option<(usize, u8)> {
+        if addr <= 0x7f {
+            Some((usize::from(addr >> 3), 1 << (addr & 0b111)))
+        } else {
+            None
+        }
+    }
+
+    pub fn insert(&mut self, addr: u8) {
+        if let Some((byte, bit)) = Self::bit(addr) {
+            self.0[byte] |= bit;
+        }
+    }
+
+    pub fn remove(&mut self, addr: u8) {
+        if let Some((byte, bit)) = Self::bit(addr) {
+            self.0[byte] &= !bit;
+        }
+    }
+
+    pub fn contains(&self, addr: u8) -> bool {
+        Self::bit(addr).map_or(false, |(byte, bit)| self.0[byte] & bit != 0)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.iter().all(|&byte| byte == 0)
+    }
+
+    /// Returns the addresses that are in this set but not in `other`.
+    pub fn difference(&self, other: &AddressSet) -> AddressSet {
+        let mut rval = *self;
+
+        for (byte, other) in rval.0.iter_mut().zip(other.0.iter()) {
+            *byte &= !other;
+        }
+
+        rval
+    }
+
+    /// Returns the addresses in the set, in ascending order.
+    pub fn iter(&self) -> impl Iterator<Item = u8> + '_ {
+        (0..=0x7f).filter(|&addr| self.contains(addr))
+    }
+}
+
+///
+/// The result of a census of a bus -- or of a segment of one of its muxes:
+/// what a scan of it found, compared to the devices that should be there.
+///
+#[derive(
+    Copy, Clone, Debug, Eq, PartialEq, Serialize, Deserialize, SerializedSize,
+)]
+pub struct I2cCensus {
+    /// Addresses at which a device was expected but none answered
+    pub missing: AddressSet,
+    /// Addresses at which a device answered but none was expected
+    pub unexpected: AddressSet,
+}
+
+impl I2cCensus {
+    ///
+    /// Compares the addresses `found` by a scan of `bus` to the `devices`
+    /// that are expected on it, each given by its bus and address.  A scan of
+    /// a segment also finds the muxes (at `mux_addresses`) and the devices
+    /// that are on the bus outside of any segment, which are neither missing
+    /// from the segment nor unexpected on it.
+    ///
+    pub fn new(
+        bus: (Controller, PortIndex, Option<(Mux, Segment)>),
+        mux_addresses: &[u8],
+        devices: impl IntoIterator<
+            Item = ((Controller, PortIndex, Option<(Mux, Segment)>), u8),
+        >,
+        found: &AddressSet,
+    ) -> Self {
+        let (controller, port, segment) = bus;
+        let mut expected = AddressSet::default();
+        let mut visible = AddressSet::default();
+
+        for ((c, p, s), address) in devices {
+            if c != controller || p != port {
+                continue;
+            }
+
+            if s == segment {
+                expected.insert(address);
+            } else if s.is_none() {
+                visible.insert(address);
+            }
+        }
+
+        for &addr in mux_addresses {
+            visible.insert(addr);
+        }
+
+        Self {
+            missing: expected.difference(found),
+            unexpected: found.difference(&expected).difference(&visible),
+        }
+    }
+
+    /// Returns true if everything expected, and nothing else, was found.
+    pub fn is_complete(&self) -> bool {
+        self.missing.is_empty() && self.unexpected.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BUS: (Controller, PortIndex) = (Controller::I2C2, PortIndex(0));
+    const SEGMENT: Option<(Mux, Segment)> = Some((Mux::M1, Segment::S2));
+
+    type Location = (Controller, PortIndex, Option<(Mux, Segment)>);
+
+    // A device on the bus outside of any segment, one on the segment we
+    // scan, one on another segment, and one on another bus.
+    const DEVICES: [(Location, u8); 4] = [
+        ((BUS.0, BUS.1, None), 0x48),
+        ((BUS.0, BUS.1, SEGMENT), 0x50),
+        ((BUS.0, BUS.1, Some((Mux::M1, Segment::S3))), 0x51),
+        ((Controller::I2C3, BUS.1, None), 0x52),
+    ];
+
+    const MUXES: [u8; 1] = [0x70];
+
+    fn set(addresses: &[u8]) -> AddressSet {
+        let mut set = AddressSet::default();
+        addresses.iter().for_each(|&addr| set.insert(addr));
+        set
+    }
+
+    fn census(segment: Option<(Mux, Segment)>, found: &[u8]) -> I2cCensus {
+        I2cCensus::new((BUS.0, BUS.1, segment), &MUXES, DEVICES, &set(found))
+    }
+
+    #[test]
+    fn complete_bus() {
+        // The mux answers outside of any segment, too.
+        let census = census(None, &[0x48, 0x70]);
+        assert!(census.is_complete());
+    }
+
+    #[test]
+    fn complete_segment() {
+        let census = census(SEGMENT, &[0x48, 0x50, 0x70]);
+        assert!(census.is_complete());
+    }
+
+    #[test]
+    fn segment_needs_only_its_devices() {
+        // The devices outside of any segment (and the mux) may be seen on a
+        // segment, but aren't required there.
+        let census = census(SEGMENT, &[0x50]);
+        assert!(census.is_complete());
+    }
+
+    #[test]
+    fn missing() {
+        let census = census(SEGMENT, &[0x48, 0x70]);
+        assert_eq!(census.missing, set(&[0x50]));
+        assert!(census.unexpected.is_empty());
+    }
+
+    #[test]
+    fn unexpected() {
+        // A device from another segment showing up here (say, because the
+        // mux is misbehaving) is unexpected, as is one from another bus.
+        let census = census(SEGMENT, &[0x50, 0x51, 0x52]);
+        assert!(census.missing.is_empty());
+        assert_eq!(census.unexpected, set(&[0x51, 0x52]));
+        assert!(!census.is_complete());
+    }
+
+    #[test]
+    fn bus_ignores_segments() {
+        // A scan of the bus alone can't see into any segment, so segments'
+        // devices aren't expected; if they answer, they're unexpected.
+        let census = census(None, &[0x48, 0x50]);
+        assert!(census.missing.is_empty());
+        assert_eq!(census.unexpected, set(&[0x50]));
+    }
+}
@@ -67,6 +67,9 @@ fn main() -> ! {
                 caller.reply(0);
                 Ok(())
             }
+            Op::Batch | Op::ReadStats | Op::Scan => {
+                Err(ResponseCode::OperationNotSupported)
+            }
         });
     }
 }
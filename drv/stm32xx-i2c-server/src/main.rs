@@ -15,6 +15,9 @@ use fixedmap::*;
 use ringbuf::*;
 use userlib::*;
 
+mod stats;
+use stats::Stats;
+
 task_slot!(SYS, sys);
 
 fn lookup_controller<'a, 'b>(
@@ -247,9 +250,11 @@ fn reset(
     port: PortIndex,
     muxes: &[I2cMux<'_>],
     muxmap: &mut MuxMap,
+    stats: &mut Stats,
 ) {
     let bus = (controller.controller, port);
     ringbuf_entry!(Trace::Reset(bus));
+    stats.reset(bus);
 
     let sys = SYS.get_task_id();
     let sys = Sys::from(sys);
@@ -290,10 +295,79 @@ fn reset_if_needed(
     port: PortIndex,
     muxes: &[I2cMux<'_>],
     muxmap: &mut MuxMap,
+    stats: &mut Stats,
 ) {
     if reset_needed(code) {
-        reset(controller, port, muxes, muxmap)
+        reset(controller, port, muxes, muxmap, stats)
+    }
+}
+
+///
+/// Readies a bus for a transaction with a device on it (or on one of its
+/// mux segments), returning the bus's controller.  If the mux can't be
+/// configured, the bus is reset if the error calls for it.  The transaction
+/// is counted against the bus (and segment) here.
+///
+fn select_bus<'a, 'b>(
+    controllers: &'a [I2cController<'b>],
+    pins: &[I2cPins],
+    muxes: &[I2cMux<'_>],
+    portmap: &mut PortMap,
+    muxmap: &mut MuxMap,
+    stats: &mut Stats,
+    ctrl: &I2cControl,
+    device: (Controller, PortIndex, Option<(Mux, Segment)>),
+) -> Result<&'a I2cController<'b>, ResponseCode> {
+    let (controller, port, mux) = device;
+
+    let controller = lookup_controller(controllers, controller)?;
+    validate_port(pins, controller.controller, port)?;
+
+    let bus = (controller.controller, port);
+    stats.transaction(bus, mux);
+
+    configure_port(portmap, controller, port, pins);
+
+    match configure_mux(muxmap, controller, port, mux, muxes, ctrl) {
+        Ok(_) => Ok(controller),
+        Err(code) => {
+            ringbuf_entry!(Trace::MuxError(code.into()));
+            stats.mux_failed(bus, mux);
+            reset_if_needed(code, controller, port, muxes, muxmap, stats);
+            Err(code)
+        }
+    }
+}
+
+///
+/// Handles an error from a transaction with the device at `addr`, counting
+/// it and resetting the bus if the error calls for it.
+///
+fn transaction_failed(
+    code: ResponseCode,
+    addr: u8,
+    mux: Option<(Mux, Segment)>,
+    controller: &I2cController<'_>,
+    port: PortIndex,
+    muxes: &[I2cMux<'_>],
+    muxmap: &mut MuxMap,
+    stats: &mut Stats,
+) {
+    //
+    // NoDevice errors aren't hugely interesting -- but on any other error,
+    // we want to record the address of the failing device, the error code
+    // and the mux+segment (if specified).
+    //
+    if code != ResponseCode::NoDevice {
+        ringbuf_entry!(Trace::Error(addr, code.into()));
+
+        if let Some(mux) = mux {
+            ringbuf_entry!(Trace::SegmentOnError(mux));
+        }
     }
+
+    stats.failed((controller.controller, port), mux, addr, code);
+    reset_if_needed(code, controller, port, muxes, muxmap, stats);
 }
 
 include!(concat!(env!("OUT_DIR"), "/i2c_config.rs"));
@@ -327,10 +401,11 @@ fn main() -> ! {
     // This is our actual mutable state
     let mut portmap = PortMap::default();
     let mut muxmap = MuxMap::default();
+    let mut stats = Stats::new(&pins, &muxes);
 
     // Turn the actual peripheral on so that we can interact with it.
     turn_on_i2c(&controllers);
-    configure_pins(&controllers, &pins, &mut portmap);
+    configure_pins(&controllers, &pins, &mut portmap, &mut stats);
     configure_controllers(&controllers);
 
     // Field messages.
@@ -351,6 +426,7 @@ fn main() -> ! {
         &pins,
         &mut portmap,
         &mut muxmap,
+        &mut stats,
         &ctrl,
     );
 
@@ -374,32 +450,16 @@ fn main() -> ! {
                     return Err(ResponseCode::ReservedAddress);
                 }
 
-                let controller = lookup_controller(&controllers, controller)?;
-                validate_port(&pins, controller.controller, port)?;
-
-                configure_port(&mut portmap, controller, port, &pins);
-
-                match configure_mux(
-                    &mut muxmap,
-                    controller,
-                    port,
-                    mux,
+                let controller = select_bus(
+                    &controllers,
+                    &pins,
                     &muxes,
+                    &mut portmap,
+                    &mut muxmap,
+                    &mut stats,
                     &ctrl,
-                ) {
-                    Ok(_) => {}
-                    Err(code) => {
-                        ringbuf_entry!(Trace::MuxError(code.into()));
-                        reset_if_needed(
-                            code,
-                            controller,
-                            port,
-                            &muxes,
-                            &mut muxmap,
-                        );
-                        return Err(code);
-                    }
-                }
+                    (controller, port, mux),
+                )?;
 
                 let mut total = 0;
 
@@ -455,26 +515,15 @@ fn main() -> ! {
                         &ctrl,
                     ) {
                         Err(code) => {
-                            //
-                            // NoDevice errors aren't hugely interesting --
-                            // but on any other error, we want to record the
-                            // address of the failing device, the error code
-                            // and the mux+segment (if specified).
-                            //
-                            if code != ResponseCode::NoDevice {
-                                ringbuf_entry!(Trace::Error(addr, code.into()));
-
-                                if let Some(mux) = mux {
-                                    ringbuf_entry!(Trace::SegmentOnError(mux));
-                                }
-                            }
-
-                            reset_if_needed(
+                            transaction_failed(
                                 code,
+                                addr,
+                                mux,
                                 controller,
                                 port,
                                 &muxes,
                                 &mut muxmap,
+                                &mut stats,
                             );
                             return Err(code);
                         }
@@ -487,6 +536,246 @@ fn main() -> ! {
                 caller.reply(total);
                 Ok(())
             }
+
+            Op::Batch => {
+                let lease_count = msg.lease_count();
+
+                let (payload, caller) = msg
+                    .fixed::<[u8; 4], usize>()
+                    .ok_or(ResponseCode::BadArg)?;
+
+                if lease_count != 3 {
+                    return Err(ResponseCode::IllegalLeaseCount);
+                }
+
+                let count = u32::from_le_bytes(*payload) as usize;
+                let result_size = core::mem::size_of::<BatchResult>();
+                let results_len = count
+                    .checked_mul(result_size)
+                    .ok_or(ResponseCode::BadArg)?;
+
+                let request = caller.borrow(0);
+                let reqinfo = request.info().ok_or(ResponseCode::BadArg)?;
+                let data = caller.borrow(1);
+                let datainfo = data.info().ok_or(ResponseCode::BadArg)?;
+                let results = caller.borrow(2);
+                let resinfo = results.info().ok_or(ResponseCode::BadArg)?;
+
+                if !reqinfo.attributes.contains(LeaseAttributes::READ)
+                    || !datainfo.attributes.contains(LeaseAttributes::WRITE)
+                    || !resinfo.attributes.contains(LeaseAttributes::WRITE)
+                    || resinfo.len < results_len
+                {
+                    return Err(ResponseCode::BadArg);
+                }
+
+                let header_at = |offset| {
+                    let header = request
+                        .read_at::<[u8; BATCH_HEADER_LEN]>(offset)
+                        .ok_or(ResponseCode::BadArg)?;
+                    BatchHeader::unmarshal(&header)
+                };
+
+                //
+                // Before touching any bus, walk the request to make sure that
+                // it's well-formed and that the read data will fit.
+                //
+                let mut offset = 0;
+                let mut read_len = 0;
+
+                for _ in 0..count {
+                    let header = header_at(offset)?;
+                    offset += BATCH_HEADER_LEN + usize::from(header.write_len);
+                    read_len += usize::from(header.read_len);
+                }
+
+                if offset > reqinfo.len || read_len > datainfo.len {
+                    return Err(ResponseCode::BadArg);
+                }
+
+                let mut offset = 0;
+                let mut read_offset = 0;
+                let mut succeeded = 0_usize;
+                let mut last = Ok(0);
+
+                for i in 0..count {
+                    let header = header_at(offset)?;
+                    let wpos = offset + BATCH_HEADER_LEN;
+                    let wlen = usize::from(header.write_len);
+                    let rpos = read_offset;
+                    let rlen = usize::from(header.read_len);
+
+                    offset = wpos + wlen;
+                    read_offset += rlen;
+
+                    let addr = header.address;
+
+                    let result = if header.chained && last.is_err() {
+                        // The transaction this one depends on failed.
+                        last
+                    } else if ReservedAddress::from_u8(addr).is_some() {
+                        Err(ResponseCode::ReservedAddress)
+                    } else if wlen == 0 && rlen == 0 {
+                        Err(ResponseCode::BadArg)
+                    } else {
+                        select_bus(
+                            &controllers,
+                            &pins,
+                            &muxes,
+                            &mut portmap,
+                            &mut muxmap,
+                            &mut stats,
+                            &ctrl,
+                            (header.controller, header.port, header.segment),
+                        )
+                        .and_then(|controller| {
+                            let mut nread = 0;
+
+                            controller
+                                .write_read(
+                                    addr,
+                                    wlen,
+                                    |pos| request.read_at(wpos + pos),
+                                    if header.block {
+                                        ReadLength::Variable
+                                    } else {
+                                        ReadLength::Fixed(rlen)
+                                    },
+                                    |pos, byte| {
+                                        // A block read can't overrun the
+                                        // space allotted to it.
+                                        if pos >= rlen {
+                                            return None;
+                                        }
+
+                                        if pos + 1 > nread {
+                                            nread = pos + 1;
+                                        }
+
+                                        data.write_at(rpos + pos, byte)
+                                    },
+                                    &ctrl,
+                                )
+                                .map(|_| nread)
+                                .map_err(|code| {
+                                    transaction_failed(
+                                        code,
+                                        addr,
+                                        header.segment,
+                                        controller,
+                                        header.port,
+                                        &muxes,
+                                        &mut muxmap,
+                                        &mut stats,
+                                    );
+                                    code
+                                })
+                        })
+                    };
+
+                    if result.is_ok() {
+                        succeeded += 1;
+                    }
+
+                    results
+                        .write_at(i * result_size, BatchResult::from(result))
+                        .ok_or(ResponseCode::BadArg)?;
+
+                    last = result;
+                }
+
+                caller.reply(succeeded);
+                Ok(())
+            }
+
+            Op::ReadStats => {
+                let (payload, caller) = msg
+                    .fixed::<[u8; 4], I2cStats>()
+                    .ok_or(ResponseCode::BadArg)?;
+
+                let (flags, controller, port, mux) =
+                    Marshal::unmarshal(payload)?;
+
+                if flags & !STATS_CLEAR != 0 {
+                    return Err(ResponseCode::BadArg);
+                }
+
+                let controller = lookup_controller(&controllers, controller)?;
+                validate_port(&pins, controller.controller, port)?;
+
+                let bus = (controller.controller, port);
+                let clear = flags & STATS_CLEAR != 0;
+
+                caller.reply(stats.read(bus, mux, clear)?);
+                Ok(())
+            }
+
+            Op::Scan => {
+                let (payload, caller) = msg
+                    .fixed::<[u8; 4], [u8; 16]>()
+                    .ok_or(ResponseCode::BadArg)?;
+
+                let (flags, controller, port, mux) =
+                    Marshal::unmarshal(payload)?;
+
+                if flags != 0 {
+                    return Err(ResponseCode::BadArg);
+                }
+
+                let controller = select_bus(
+                    &controllers,
+                    &pins,
+                    &muxes,
+                    &mut portmap,
+                    &mut muxmap,
+                    &mut stats,
+                    &ctrl,
+                    (controller, port, mux),
+                )?;
+
+                let mut found = AddressSet::default();
+
+                for addr in 0..=0x7f {
+                    if ReservedAddress::from_u8(addr).is_some() {
+                        continue;
+                    }
+
+                    //
+                    // We look for a device by reading a byte from it, which
+                    // (unlike a zero-length write) every device supports.
+                    // An absent device is what we're here to find, so it
+                    // isn't counted as a NAK; anything else is an error
+                    // like any other, and ends the scan.
+                    //
+                    match controller.write_read(
+                        addr,
+                        0,
+                        |_| None,
+                        ReadLength::Fixed(1),
+                        |_, _| Some(()),
+                        &ctrl,
+                    ) {
+                        Ok(_) => found.insert(addr),
+                        Err(ResponseCode::NoDevice) => {}
+                        Err(code) => {
+                            transaction_failed(
+                                code,
+                                addr,
+                                mux,
+                                controller,
+                                port,
+                                &muxes,
+                                &mut muxmap,
+                                &mut stats,
+                            );
+                            return Err(code);
+                        }
+                    }
+                }
+
+                caller.reply(found.0);
+                Ok(())
+            }
         });
     }
 }
@@ -584,7 +873,7 @@ fn configure_port(
 ///
 /// [0] Analog Devices. AN-686: Implementing an I2C Reset. 2003.
 ///
-fn wiggle_scl(sys: &Sys, scl: PinSet, sda: PinSet) {
+fn wiggle_scl(sys: &Sys, scl: PinSet, sda: PinSet) -> u8 {
     let mut wiggles = 0_u8;
     sys.gpio_set(scl);
 
@@ -642,12 +931,14 @@ fn wiggle_scl(sys: &Sys, scl: PinSet, sda: PinSet) {
     }
 
     ringbuf_entry!(Trace::Wiggles(wiggles));
+    wiggles
 }
 
 fn configure_pins(
     controllers: &[I2cController<'_>],
     pins: &[I2cPins],
     map: &mut PortMap,
+    stats: &mut Stats,
 ) {
     let sys = SYS.get_task_id();
     let sys = Sys::from(sys);
@@ -657,7 +948,8 @@ fn configure_pins(
     // transaction.
     //
     for pin in pins {
-        wiggle_scl(&sys, pin.scl, pin.sda);
+        let wiggles = wiggle_scl(&sys, pin.scl, pin.sda);
+        stats.wiggles((pin.controller, pin.port), wiggles);
     }
 
     for pin in pins {
@@ -708,6 +1000,7 @@ fn configure_muxes(
     pins: &[I2cPins],
     map: &mut PortMap,
     muxmap: &mut MuxMap,
+    stats: &mut Stats,
     ctrl: &I2cControl,
 ) {
     let sys = SYS.get_task_id();
@@ -753,7 +1046,7 @@ fn configure_muxes(
                         ringbuf_entry!(Trace::SegmentFailed(code.into()));
 
                         if reset_needed(code) && !reset_attempted {
-                            reset(controller, mux.port, muxes, muxmap);
+                            reset(controller, mux.port, muxes, muxmap, stats);
                             reset_attempted = true;
                             continue;
                         }
@@ -773,7 +1066,9 @@ fn configure_muxes(
                 }
                 Err(code) => {
                     ringbuf_entry!(Trace::ConfigureFailed(code.into()));
-                    reset_if_needed(code, controller, mux.port, muxes, muxmap);
+                    reset_if_needed(
+                        code, controller, mux.port, muxes, muxmap, stats,
+                    );
                 }
             }
         }
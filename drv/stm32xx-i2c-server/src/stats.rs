@@ -0,0 +1,270 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Health counters for each bus and for each segment of each mux
+
+use crate::i2c_config::{NMUXES, NPORTS};
+use drv_i2c_api::*;
+use drv_stm32xx_i2c::{I2cMux, I2cPins};
+
+/// The most segments that a mux can have
+const MAX_SEGMENTS: usize = Segment::S8 as usize;
+
+///
+/// The failures of a transaction that we count.  (Errors not here -- bad
+/// arguments and the like -- say nothing about the health of the bus.)
+///
+#[derive(Copy, Clone)]
+enum Event {
+    AddressNak,
+    DataNak,
+    ArbitrationLost,
+    Timeout,
+    BusError,
+    ControllerBusy,
+}
+
+impl Event {
+    fn from_code(code: ResponseCode) -> Option<Self> {
+        match code {
+            ResponseCode::NoDevice => Some(Event::AddressNak),
+            ResponseCode::NoRegister => Some(Event::DataNak),
+            ResponseCode::BusReset | ResponseCode::BusResetMux => {
+                Some(Event::ArbitrationLost)
+            }
+            ResponseCode::BusLocked | ResponseCode::BusLockedMux => {
+                Some(Event::Timeout)
+            }
+            ResponseCode::BusError => Some(Event::BusError),
+            ResponseCode::ControllerBusy => Some(Event::ControllerBusy),
+            _ => None,
+        }
+    }
+}
+
+///
+/// The counters for a segment.  A system can have dozens of segments, so
+/// these are kept more compactly than the counters for a bus; they are
+/// widened into an [`I2cStats`] when read.
+///
+#[derive(Copy, Clone, Default)]
+struct SegmentStats {
+    transactions: u32,
+    address_naks: u16,
+    data_naks: u16,
+    arbitration_losses: u16,
+    timeouts: u16,
+    bus_errors: u16,
+    controller_busy: u16,
+    mux_errors: u16,
+    last_nak_address: u8,
+    last_error_address: u8,
+}
+
+impl From<SegmentStats> for I2cStats {
+    fn from(segment: SegmentStats) -> Self {
+        let mut stats = I2cStats::default();
+
+        stats.transactions = segment.transactions;
+        stats.address_naks = segment.address_naks.into();
+        stats.data_naks = segment.data_naks.into();
+        stats.arbitration_losses = segment.arbitration_losses.into();
+        stats.timeouts = segment.timeouts.into();
+        stats.bus_errors = segment.bus_errors.into();
+        stats.controller_busy = segment.controller_busy.into();
+        stats.mux_errors = segment.mux_errors.into();
+        stats.last_nak_address = segment.last_nak_address;
+        stats.last_error_address = segment.last_error_address;
+        stats
+    }
+}
+
+trait Counters {
+    fn transaction(&mut self);
+    fn failed(&mut self, event: Event, addr: u8);
+    fn mux_failed(&mut self);
+}
+
+macro_rules! bump {
+    ($counter:expr) => {
+        $counter = $counter.saturating_add(1)
+    };
+}
+
+macro_rules! counters {
+    ($stats:ty) => {
+        impl Counters for $stats {
+            fn transaction(&mut self) {
+                bump!(self.transactions);
+            }
+
+            fn failed(&mut self, event: Event, addr: u8) {
+                match event {
+                    Event::AddressNak => bump!(self.address_naks),
+                    Event::DataNak => bump!(self.data_naks),
+                    Event::ArbitrationLost => bump!(self.arbitration_losses),
+                    Event::Timeout => bump!(self.timeouts),
+                    Event::BusError => bump!(self.bus_errors),
+                    Event::ControllerBusy => bump!(self.controller_busy),
+                }
+
+                match event {
+                    Event::AddressNak | Event::DataNak => {
+                        self.last_nak_address = addr;
+                    }
+                    _ => {
+                        self.last_error_address = addr;
+                    }
+                }
+            }
+
+            fn mux_failed(&mut self) {
+                bump!(self.mux_errors);
+            }
+        }
+    };
+}
+
+counters!(I2cStats);
+counters!(SegmentStats);
+
+///
+/// The counters for every bus and every segment, indexed in the same order
+/// as the pins and the muxes in our configuration.
+///
+pub struct Stats {
+    buses: [(Controller, PortIndex); NPORTS],
+    bus_stats: [I2cStats; NPORTS],
+    muxes: [(Controller, PortIndex, Mux); NMUXES],
+    segment_stats: [[SegmentStats; MAX_SEGMENTS]; NMUXES],
+}
+
+impl Stats {
+    pub fn new(pins: &[I2cPins; NPORTS], muxes: &[I2cMux<'_>; NMUXES]) -> Self {
+        Self {
+            buses: core::array::from_fn(|i| (pins[i].controller, pins[i].port)),
+            bus_stats: [I2cStats::default(); NPORTS],
+            muxes: core::array::from_fn(|i| {
+                (muxes[i].controller, muxes[i].port, muxes[i].id)
+            }),
+            segment_stats: [[SegmentStats::default(); MAX_SEGMENTS]; NMUXES],
+        }
+    }
+
+    fn bus(&mut self, bus: (Controller, PortIndex)) -> Option<&mut I2cStats> {
+        let index = self.buses.iter().position(|&b| b == bus)?;
+        Some(&mut self.bus_stats[index])
+    }
+
+    fn segment(
+        &mut self,
+        bus: (Controller, PortIndex),
+        segment: Option<(Mux, Segment)>,
+    ) -> Option<&mut SegmentStats> {
+        let (id, segment) = segment?;
+        let index = self
+            .muxes
+            .iter()
+            .position(|&(c, p, m)| (c, p) == bus && m == id)?;
+
+        self.segment_stats[index].get_mut(segment as usize - 1)
+    }
+
+    fn count(
+        &mut self,
+        bus: (Controller, PortIndex),
+        segment: Option<(Mux, Segment)>,
+        mut func: impl FnMut(&mut dyn Counters),
+    ) {
+        if let Some(stats) = self.bus(bus) {
+            func(stats);
+        }
+
+        if let Some(stats) = self.segment(bus, segment) {
+            func(stats);
+        }
+    }
+
+    /// Counts a transaction on a bus -- and on a segment, if one is given.
+    pub fn transaction(
+        &mut self,
+        bus: (Controller, PortIndex),
+        segment: Option<(Mux, Segment)>,
+    ) {
+        self.count(bus, segment, |stats| stats.transaction());
+    }
+
+    /// Counts the failure of a transaction with the device at `addr`.
+    pub fn failed(
+        &mut self,
+        bus: (Controller, PortIndex),
+        segment: Option<(Mux, Segment)>,
+        addr: u8,
+        code: ResponseCode,
+    ) {
+        if let Some(event) = Event::from_code(code) {
+            self.count(bus, segment, |stats| stats.failed(event, addr));
+        }
+    }
+
+    /// Counts a failure to configure the mux for a transaction.
+    pub fn mux_failed(
+        &mut self,
+        bus: (Controller, PortIndex),
+        segment: Option<(Mux, Segment)>,
+    ) {
+        self.count(bus, segment, |stats| stats.mux_failed());
+    }
+
+    pub fn reset(&mut self, bus: (Controller, PortIndex)) {
+        if let Some(stats) = self.bus(bus) {
+            bump!(stats.bus_resets);
+        }
+    }
+
+    pub fn wiggles(&mut self, bus: (Controller, PortIndex), wiggles: u8) {
+        if let Some(stats) = self.bus(bus) {
+            stats.scl_wiggles =
+                stats.scl_wiggles.saturating_add(wiggles.into());
+        }
+    }
+
+    ///
+    /// Returns the counters for a bus, or for a segment if one is given,
+    /// zeroing them if `clear` is set.
+    ///
+    pub fn read(
+        &mut self,
+        bus: (Controller, PortIndex),
+        segment: Option<(Mux, Segment)>,
+        clear: bool,
+    ) -> Result<I2cStats, ResponseCode> {
+        let stats = match segment {
+            None => {
+                let stats = self.bus(bus).ok_or(ResponseCode::BadPort)?;
+                let rval = *stats;
+
+                if clear {
+                    *stats = I2cStats::default();
+                }
+
+                rval
+            }
+            Some(_) => {
+                let stats = self
+                    .segment(bus, segment)
+                    .ok_or(ResponseCode::MuxNotFound)?;
+                let rval = I2cStats::from(*stats);
+
+                if clear {
+                    *stats = SegmentStats::default();
+                }
+
+                rval
+            }
+        };
+
+        Ok(stats)
+    }
+}
@@ -47,6 +47,7 @@ pub type Isr = device::i2c1::isr::R;
 pub mod ltc4306;
 pub mod max7358;
 pub mod pca9548;
+pub mod target;
 
 use ringbuf::*;
 use userlib::*;
@@ -137,6 +138,21 @@ pub struct I2cMux<'a> {
     pub address: u8,
 }
 
+///
+/// The addresses to which a controller operating as a target responds.
+///
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum TargetAddresses {
+    /// Every address, leaving it to the `initiate` callback to decline all
+    /// but those of interest.  Note that this means that we stretch the clock
+    /// on all traffic on the bus, whether it's for us or not.
+    All,
+    /// A single address; traffic for any other is ignored by the hardware.
+    One(u8),
+    /// Two addresses; traffic for any other is ignored by the hardware.
+    Two(u8, u8),
+}
+
 ///
 /// An enum describing the amount to read
 ///
@@ -788,7 +804,7 @@ impl I2cController<'_> {
         Ok(())
     }
 
-    fn configure_as_target(&self) {
+    fn configure_as_target(&self, addresses: TargetAddresses) {
         let i2c = self.registers;
 
         // Disable PE
@@ -796,6 +812,10 @@ impl I2cController<'_> {
 
         self.configure_timing(i2c);
 
+        //
+        // The own addresses can only be changed while they're disabled, so
+        // disable both before enabling whichever we need.
+        //
         #[rustfmt::skip]
         i2c.oar1.modify(|_, w| { w
             .oa1en().clear_bit()                    // own-address disable 
@@ -803,10 +823,41 @@ impl I2cController<'_> {
 
         #[rustfmt::skip]
         i2c.oar2.modify(|_, w| { w
-            .oa2en().set_bit()                  // own-address-2 enable
-            .oa2msk().bits(0b111)                // mask 7 == match all
+            .oa2en().clear_bit()                // own-address-2 disable
         });
 
+        let own_address1 = |addr: u8| {
+            #[rustfmt::skip]
+            i2c.oar1.modify(|_, w| { w
+                .oa1().bits(u16::from(addr) << 1)   // 7-bit address in [7:1]
+                .oa1mode().clear_bit()              // 7-bit mode
+                .oa1en().set_bit()                  // own-address enable
+            });
+        };
+
+        match addresses {
+            TargetAddresses::All => {
+                #[rustfmt::skip]
+                i2c.oar2.modify(|_, w| { w
+                    .oa2en().set_bit()              // own-address-2 enable
+                    .oa2msk().bits(0b111)           // mask 7 == match all
+                });
+            }
+            TargetAddresses::One(addr) => {
+                own_address1(addr);
+            }
+            TargetAddresses::Two(addr1, addr2) => {
+                own_address1(addr1);
+
+                #[rustfmt::skip]
+                i2c.oar2.modify(|_, w| { w
+                    .oa2().bits(addr2)              // 7-bit address
+                    .oa2en().set_bit()              // own-address-2 enable
+                    .oa2msk().bits(0)               // mask 0 == exact match
+                });
+            }
+        }
+
         #[rustfmt::skip]
         i2c.cr1.modify(|_, w| { w
             .gcen().clear_bit()           // disable General Call
@@ -824,16 +875,50 @@ impl I2cController<'_> {
         i2c.cr1.modify(|_, w| w.pe().set_bit());
     }
 
+    ///
+    /// Operates the controller as a target that responds to every address,
+    /// calling `initiate` with the address of each transaction to see if we
+    /// want to participate in it, and then `rxbyte` and `txbyte` to receive
+    /// and transmit its bytes.  The clock is stretched while they run.
+    ///
+    /// To also hear when transactions end, use
+    /// [`I2cController::operate_as_target_at`].
+    ///
     pub fn operate_as_target(
         &self,
         ctrl: &I2cControl,
+        initiate: impl FnMut(u8) -> bool,
+        rxbyte: impl FnMut(u8, u8),
+        txbyte: impl FnMut(u8) -> Option<u8>,
+    ) -> ! {
+        self.operate_as_target_at(
+            ctrl,
+            TargetAddresses::All,
+            initiate,
+            rxbyte,
+            txbyte,
+            |_| {},
+        )
+    }
+
+    ///
+    /// Operates the controller as a target, as with
+    /// [`I2cController::operate_as_target`], but responding only to the
+    /// specified `addresses`, and calling `stop` with the address of each
+    /// transaction that we participated in when it ends with a STOP.
+    ///
+    pub fn operate_as_target_at(
+        &self,
+        ctrl: &I2cControl,
+        addresses: TargetAddresses,
         mut initiate: impl FnMut(u8) -> bool,
         mut rxbyte: impl FnMut(u8, u8),
         mut txbyte: impl FnMut(u8) -> Option<u8>,
+        mut stop: impl FnMut(u8),
     ) -> ! {
         // Note: configure_as_target toggles the CR1.PE bit, which has the side
         // effect of clearing all flags.
-        self.configure_as_target();
+        self.configure_as_target(addresses);
 
         let i2c = self.registers;
         let notification = self.notification;
@@ -887,8 +972,9 @@ impl I2cController<'_> {
             // sent to any other device on the bus, and send responses that keep
             // SDA in its recessive (high) state so the other device can talk.
             //
-            // This means we will inject our clock stretching intervals into
-            // _all traffic_ and is probably worth fixing (TODO).
+            // This means that, if we are responding to all addresses, we will
+            // inject our clock stretching intervals into _all traffic_; to
+            // avoid this, specify the addresses of interest.
             let initiated = initiate(addr);
 
             if !initiated {
@@ -950,6 +1036,11 @@ impl I2cController<'_> {
                     if isr.stopf().is_stop() {
                         ringbuf_entry!(Trace::Stop);
                         i2c.icr.write(|w| w.stopcf().set_bit());
+
+                        if initiated {
+                            stop(addr);
+                        }
+
                         continue 'addrloop;
                     }
 
@@ -1026,6 +1117,11 @@ impl I2cController<'_> {
                 // response to TXIS below.
                 if isr.stopf().is_stop() {
                     i2c.icr.write(|w| w.stopcf().set_bit());
+
+                    if initiated {
+                        stop(addr);
+                    }
+
                     break 'txloop;
                 }
 
@@ -0,0 +1,66 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Virtual devices, presented by a controller operating as a target
+//!
+//! A task that wants to appear on a bus as one or more I2C devices -- say, an
+//! emulated FRU EEPROM, or a PMBus endpoint -- implements [`I2cTarget`] for
+//! each of them (or uses [`RegisterFile`] for the common case of a device
+//! that is a simple array of registers), and hands them to [`serve`].  The
+//! trait and [`RegisterFile`] are defined in the hardware-independent
+//! `drv-i2c-target` crate, so that devices can be tested on the host.
+
+use crate::{I2cControl, I2cController, TargetAddresses};
+use core::cell::RefCell;
+
+pub use drv_i2c_target::{I2cTarget, RegisterFile};
+
+///
+/// Operates `controller` as a target, presenting `devices` on its bus.  If
+/// there are no more than two devices, the controller is configured to
+/// respond to their addresses alone; otherwise, it responds to (and stretches
+/// the clock on) every address, NACKing those that aren't ours.
+///
+pub fn serve(
+    controller: &I2cController<'_>,
+    ctrl: &I2cControl,
+    devices: &mut [&mut dyn I2cTarget],
+) -> ! {
+    let addresses = match devices {
+        [device] => TargetAddresses::One(device.address()),
+        [device1, device2] => {
+            TargetAddresses::Two(device1.address(), device2.address())
+        }
+        _ => TargetAddresses::All,
+    };
+
+    let devices = RefCell::new(devices);
+
+    let with_device = |addr: u8, func: &mut dyn FnMut(&mut dyn I2cTarget)| {
+        let mut devices = devices.borrow_mut();
+
+        if let Some(device) =
+            devices.iter_mut().find(|device| device.address() == addr)
+        {
+            func(&mut **device);
+        }
+    };
+
+    controller.operate_as_target_at(
+        ctrl,
+        addresses,
+        |addr| {
+            let mut initiated = false;
+            with_device(addr, &mut |device| initiated = device.start());
+            initiated
+        },
+        |addr, byte| with_device(addr, &mut |device| device.write(byte)),
+        |addr| {
+            let mut byte = None;
+            with_device(addr, &mut |device| byte = device.read());
+            byte
+        },
+        |addr| with_device(addr, &mut |device| device.stop()),
+    )
+}
@@ -3,5 +3,8 @@
 // file, You can obtain one at https://mozilla.org/MPL/2.0/.
 
 fn main() {
-    build_util::expose_m_profile();
+    // Hosted builds (for unit tests) have no M-profile to expose.
+    if build_util::target_os() == "none" {
+        build_util::expose_m_profile();
+    }
 }
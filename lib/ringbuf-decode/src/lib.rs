@@ -0,0 +1,613 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Host-side decoding of ring buffers declared with the `ringbuf` crate.
+//!
+//! Ring buffers built with the `ringbuf` crate's `header` feature start with a
+//! `RingbufHeader` that describes their layout,
+//! so ring buffers can be found in a memory image (or a core dump) by looking
+//! for the header's magic number, and decoded without debug information.
+//! The ELF files for the tasks are only needed to put names to the ring
+//! buffers, and to read counter names out of flash.
+//!
+//! Payloads are returned as raw bytes; making sense of them is up to the
+//! caller, who knows what type they are.
+//!
+//! Ring buffers can also be read from a running system over the network, if
+//! its supervisor exports them; see [`rpc`].
+
+use std::collections::BTreeMap;
+
+pub mod rpc;
+
+use anyhow::{anyhow, bail, Result};
+use serde::Serialize;
+
+/// `RINGBUF_MAGIC` from the `ringbuf` crate
+pub const MAGIC: u32 = u32::from_le_bytes(*b"RBUF");
+
+/// The version of the header layout that we understand
+pub const VERSION: u8 = 1;
+
+/// Set in `Header::flags` if entries carry a timestamp
+pub const FLAG_TIMESTAMPS: u8 = 1 << 0;
+
+/// Size of a `RingbufHeader` on our 32-bit targets
+pub const HEADER_SIZE: usize = 28;
+
+/// `RINGBUF_ENTRY_PREFIX_SIZE` from the `ringbuf` crate, without and with
+/// timestamps; a timestamp directly follows the shorter prefix.
+pub const ENTRY_PREFIX_SIZE: u16 = 8;
+pub const TIMESTAMPED_ENTRY_PREFIX_SIZE: u16 = 16;
+
+/// Memory contents gathered from raw images and ELF files.
+///
+/// Where sources overlap, the one added first wins, so add dumps of live
+/// memory before the ELF files that describe its initial contents.
+#[derive(Default)]
+pub struct Memory {
+    regions: Vec<(u32, Vec<u8>)>,
+}
+
+impl Memory {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds a raw image of memory starting at `base`.
+    pub fn add_raw(&mut self, base: u32, data: Vec<u8>) {
+        self.regions.push((base, data));
+    }
+
+    /// Adds the loadable contents of an ELF file, which may be a task or
+    /// kernel image or a core dump.
+    pub fn add_elf(&mut self, data: &[u8]) -> Result<()> {
+        let elf = goblin::elf::Elf::parse(data)?;
+        for phdr in &elf.program_headers {
+            if phdr.p_type != goblin::elf::program_header::PT_LOAD
+                || phdr.p_filesz == 0
+            {
+                continue;
+            }
+            let start = phdr.p_offset as usize;
+            let end = start + phdr.p_filesz as usize;
+            let contents = data.get(start..end).ok_or_else(|| {
+                anyhow!("segment at {:#x} is past end of file", phdr.p_vaddr)
+            })?;
+            self.add_raw(phdr.p_vaddr as u32, contents.to_vec());
+        }
+        Ok(())
+    }
+
+    /// Reads `len` bytes at `addr`, which must all come from one source.
+    pub fn read(&self, addr: u32, len: usize) -> Result<&[u8]> {
+        for (base, data) in &self.regions {
+            let bytes = addr
+                .checked_sub(*base)
+                .map(|offset| offset as usize)
+                .and_then(|offset| data.get(offset..offset + len));
+            if let Some(bytes) = bytes {
+                return Ok(bytes);
+            }
+        }
+        bail!("{len} bytes at {addr:#x} are not in memory")
+    }
+
+    fn read_u16(&self, addr: u32) -> Result<u16> {
+        Ok(u16::from_le_bytes(self.read(addr, 2)?.try_into().unwrap()))
+    }
+
+    fn read_u32(&self, addr: u32) -> Result<u32> {
+        Ok(u32::from_le_bytes(self.read(addr, 4)?.try_into().unwrap()))
+    }
+
+    fn read_u64(&self, addr: u32) -> Result<u64> {
+        Ok(u64::from_le_bytes(self.read(addr, 8)?.try_into().unwrap()))
+    }
+}
+
+/// A decoded `RingbufHeader`
+#[derive(Clone, Debug, Serialize)]
+pub struct Header {
+    pub flags: u8,
+    pub entries: u16,
+    pub entry_size: u16,
+    pub payload_offset: u16,
+    pub payload_size: u16,
+    pub buffer_offset: u16,
+    pub counters_offset: u16,
+    pub counters_len: u16,
+    /// Address and length of the counter names, which live in flash
+    pub counter_names: (u32, u32),
+}
+
+impl Header {
+    /// Parses a header from `bytes`, returning `None` if it isn't one we
+    /// understand or doesn't make sense.
+    pub fn parse(bytes: &[u8]) -> Option<Self> {
+        if bytes.len() < HEADER_SIZE {
+            return None;
+        }
+        let u16_at =
+            |i: usize| u16::from_le_bytes(bytes[i..i + 2].try_into().unwrap());
+        let u32_at =
+            |i: usize| u32::from_le_bytes(bytes[i..i + 4].try_into().unwrap());
+
+        if u32_at(0) != MAGIC || bytes[4] != VERSION {
+            return None;
+        }
+        let header = Header {
+            flags: bytes[5],
+            entries: u16_at(6),
+            entry_size: u16_at(8),
+            payload_offset: u16_at(10),
+            payload_size: u16_at(12),
+            buffer_offset: u16_at(14),
+            counters_offset: u16_at(16),
+            counters_len: u16_at(18),
+            counter_names: (u32_at(20), u32_at(24)),
+        };
+
+        let fixed = if header.timestamps() {
+            TIMESTAMPED_ENTRY_PREFIX_SIZE
+        } else {
+            ENTRY_PREFIX_SIZE
+        };
+        let payload_end = usize::from(header.payload_offset)
+            + usize::from(header.payload_size);
+        let buffer_end = usize::from(header.buffer_offset)
+            + usize::from(header.entries) * usize::from(header.entry_size);
+        let sane = header.entries > 0
+            && usize::from(header.buffer_offset) >= HEADER_SIZE
+            && header.payload_offset >= fixed
+            && payload_end <= usize::from(header.entry_size)
+            && (header.counters_len == 0
+                || usize::from(header.counters_offset) >= buffer_end);
+        sane.then_some(header)
+    }
+
+    pub fn timestamps(&self) -> bool {
+        self.flags & FLAG_TIMESTAMPS != 0
+    }
+}
+
+/// One ring buffer entry
+#[derive(Clone, Debug, Serialize)]
+pub struct Entry {
+    /// Position in the buffer
+    pub index: usize,
+    pub line: u16,
+    pub generation: u16,
+    pub count: u32,
+    /// Kernel time of the most recent occurrence, if timestamps are enabled
+    pub timestamp: Option<u64>,
+    pub payload: Vec<u8>,
+}
+
+/// A decoded ring buffer
+#[derive(Clone, Debug, Serialize)]
+pub struct Ringbuf {
+    /// Address of the header
+    pub address: u32,
+    pub header: Header,
+    /// Entries that have been recorded, oldest first
+    pub entries: Vec<Entry>,
+    /// Counters by name, in declaration order; empty if the ring buffer
+    /// doesn't count its entries. Names fall back to the counter's index if
+    /// they can't be read.
+    pub counters: Vec<(String, u32)>,
+}
+
+/// Reads the ring buffer whose header is at `address`.
+pub fn read(mem: &Memory, address: u32) -> Result<Ringbuf> {
+    let header = Header::parse(mem.read(address, HEADER_SIZE)?)
+        .ok_or_else(|| anyhow!("no ring buffer header at {address:#x}"))?;
+
+    let buffer = address + u32::from(header.buffer_offset);
+    let mut slots = vec![];
+    for index in 0..usize::from(header.entries) {
+        let base = buffer + (index * usize::from(header.entry_size)) as u32;
+        let timestamp = if header.timestamps() {
+            Some(mem.read_u64(base + u32::from(ENTRY_PREFIX_SIZE))?)
+        } else {
+            None
+        };
+        let payload = mem.read(
+            base + u32::from(header.payload_offset),
+            header.payload_size.into(),
+        )?;
+        slots.push(Entry {
+            index,
+            line: mem.read_u16(base)?,
+            generation: mem.read_u16(base + 2)?,
+            count: mem.read_u32(base + 4)?,
+            timestamp,
+            payload: payload.to_vec(),
+        });
+    }
+
+    let counters = read_counters(mem, address, &header)?;
+
+    Ok(Ringbuf {
+        address,
+        header,
+        entries: oldest_first(slots),
+        counters,
+    })
+}
+
+/// Puts entries in the order they were recorded, dropping unused ones.
+///
+/// Slots are written in order, with each pass over the buffer bumping their
+/// generation, so the newest entry is the last one sharing slot 0's
+/// generation. This saves us from having to decode `Ringbuf::last`, whose
+/// layout is up to the compiler.
+fn oldest_first(mut slots: Vec<Entry>) -> Vec<Entry> {
+    if slots.first().map_or(true, |e| e.count == 0) {
+        return vec![];
+    }
+    let current = slots[0].generation;
+    let last = slots
+        .iter()
+        .rposition(|e| e.count != 0 && e.generation == current)
+        .unwrap();
+    slots.rotate_left(last + 1);
+    slots.retain(|e| e.count != 0);
+    slots
+}
+
+fn read_counters(
+    mem: &Memory,
+    address: u32,
+    header: &Header,
+) -> Result<Vec<(String, u32)>> {
+    let len = usize::from(header.counters_len);
+    let base = address + u32::from(header.counters_offset);
+    let values = (0..len)
+        .map(|i| mem.read_u32(base + 4 * i as u32))
+        .collect::<Result<Vec<_>>>()?;
+
+    // The names are a `&[&str]`, which is a (pointer, length) pair of
+    // (pointer, length) pairs. They're in flash, so may not be available.
+    let names = || -> Result<Vec<String>> {
+        let (ptr, n) = header.counter_names;
+        if n as usize != len {
+            bail!("counter names don't match counters");
+        }
+        (0..n)
+            .map(|i| {
+                let s = ptr + 8 * i;
+                let bytes =
+                    mem.read(mem.read_u32(s)?, mem.read_u32(s + 4)? as usize)?;
+                Ok(String::from_utf8_lossy(bytes).into_owned())
+            })
+            .collect()
+    };
+    let names =
+        names().unwrap_or_else(|_| (0..len).map(|i| i.to_string()).collect());
+
+    Ok(names.into_iter().zip(values).collect())
+}
+
+/// Finds the headers of all ring buffers in `mem`.
+pub fn find(mem: &Memory) -> Vec<u32> {
+    let mut found = vec![];
+    for (base, data) in &mem.regions {
+        // Headers are 4-byte aligned, as is everything we load.
+        for offset in (0..data.len().saturating_sub(HEADER_SIZE - 1)).step_by(4)
+        {
+            let address = base + offset as u32;
+            if Header::parse(&data[offset..]).is_some()
+                && !found.contains(&address)
+            {
+                found.push(address);
+            }
+        }
+    }
+    found.sort_unstable();
+    found
+}
+
+/// A ring buffer's static, from an ELF symbol table
+#[derive(Clone, Debug, Serialize)]
+pub struct Symbol {
+    pub name: String,
+    pub address: u32,
+    pub size: u32,
+}
+
+/// Lists the ring buffers declared in an ELF file: that is, statics whose
+/// name ends in `RINGBUF`, as the `ringbuf!` documentation recommends.
+pub fn symbols(data: &[u8]) -> Result<Vec<Symbol>> {
+    let elf = goblin::elf::Elf::parse(data)?;
+    let mut out = vec![];
+    for sym in elf.syms.iter() {
+        if sym.st_type() != goblin::elf::sym::STT_OBJECT {
+            continue;
+        }
+        let name = match elf.strtab.get_at(sym.st_name) {
+            Some(name) => demangle(name),
+            None => continue,
+        };
+        if name.ends_with("RINGBUF") {
+            out.push(Symbol {
+                name,
+                address: sym.st_value as u32,
+                size: sym.st_size as u32,
+            });
+        }
+    }
+    Ok(out)
+}
+
+/// Names each of `headers` after the symbol that contains it, if any.
+///
+/// The header isn't at the symbol's address, since ring buffers are wrapped
+/// in a `StaticCell`.
+pub fn name_headers(
+    headers: &[u32],
+    symbols: &[Symbol],
+) -> BTreeMap<u32, String> {
+    headers
+        .iter()
+        .map(|&h| {
+            let name = symbols
+                .iter()
+                .find(|s| (s.address..s.address + s.size).contains(&h))
+                .map(|s| s.name.clone())
+                .unwrap_or_else(|| format!("{h:#010x}"));
+            (h, name)
+        })
+        .collect()
+}
+
+/// Turns a legacy-mangled Rust symbol into its path, leaving anything else
+/// alone. `_ZN12task_thermal7RINGBUF17h0123456789abcdefE` becomes
+/// `task_thermal::RINGBUF`.
+fn demangle(sym: &str) -> String {
+    let mut rest = match sym.strip_prefix("_ZN") {
+        Some(rest) => rest,
+        None => return sym.to_owned(),
+    };
+    let mut parts = vec![];
+    while !rest.starts_with('E') {
+        let digits = rest.bytes().take_while(u8::is_ascii_digit).count();
+        let len = match rest[..digits].parse::<usize>() {
+            Ok(len) if digits + len <= rest.len() => len,
+            _ => return sym.to_owned(),
+        };
+        parts.push(&rest[digits..digits + len]);
+        rest = &rest[digits + len..];
+    }
+    if let Some(hash) = parts.last() {
+        if hash.len() == 17 && hash.starts_with('h') {
+            parts.pop();
+        }
+    }
+    parts.join("::")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BASE: u32 = 0x2000_0000;
+
+    /// Lays out a ring buffer of `u32` payloads, the way the `ringbuf` crate
+    /// does, with slots given as `(line, generation, count, payload)`.
+    fn image(slots: &[(u16, u16, u32, u32)], counters: &[u32]) -> Memory {
+        let buffer_offset = HEADER_SIZE + 8;
+        let counters_offset = buffer_offset + 12 * slots.len();
+        let names_addr = BASE + (counters_offset + 4 * counters.len()) as u32;
+
+        let mut data = vec![];
+        data.extend(MAGIC.to_le_bytes());
+        data.extend([VERSION, 0]);
+        for v in [slots.len(), 12, 8, 4, buffer_offset, counters_offset] {
+            data.extend((v as u16).to_le_bytes());
+        }
+        data.extend((counters.len() as u16).to_le_bytes());
+        data.extend(names_addr.to_le_bytes());
+        data.extend((counters.len() as u32).to_le_bytes());
+
+        // `last`, which we ignore
+        data.extend([0xff; 8]);
+        for &(line, generation, count, payload) in slots {
+            data.extend(line.to_le_bytes());
+            data.extend(generation.to_le_bytes());
+            data.extend(count.to_le_bytes());
+            data.extend(payload.to_le_bytes());
+        }
+        for c in counters {
+            data.extend(c.to_le_bytes());
+        }
+
+        // Names, as (pointer, length) pairs followed by their text
+        let text = names_addr + 8 * counters.len() as u32;
+        for i in 0..counters.len() as u32 {
+            data.extend((text + i).to_le_bytes());
+            data.extend(1u32.to_le_bytes());
+        }
+        data.extend((0..counters.len()).map(|i| b'A' + i as u8));
+
+        let mut mem = Memory::new();
+        mem.add_raw(BASE, data);
+        mem
+    }
+
+    fn payloads(rb: &Ringbuf) -> Vec<u32> {
+        rb.entries
+            .iter()
+            .map(|e| u32::from_le_bytes(e.payload[..].try_into().unwrap()))
+            .collect()
+    }
+
+    #[test]
+    fn empty() {
+        let mem = image(&[(0, 0, 0, 0); 4], &[]);
+        assert_eq!(find(&mem), vec![BASE]);
+        let rb = read(&mem, BASE).unwrap();
+        assert!(rb.entries.is_empty());
+        assert!(rb.counters.is_empty());
+    }
+
+    #[test]
+    fn partly_full() {
+        let mem = image(
+            &[(10, 1, 1, 100), (11, 1, 3, 101), (0, 0, 0, 0), (0, 0, 0, 0)],
+            &[],
+        );
+        let rb = read(&mem, BASE).unwrap();
+        assert_eq!(payloads(&rb), vec![100, 101]);
+        assert_eq!(rb.entries[1].count, 3);
+        assert_eq!(rb.entries[1].line, 11);
+    }
+
+    #[test]
+    fn wrapped() {
+        let mem = image(
+            &[(1, 8, 1, 4), (1, 8, 1, 5), (1, 7, 1, 2), (1, 7, 1, 3)],
+            &[],
+        );
+        assert_eq!(payloads(&read(&mem, BASE).unwrap()), vec![2, 3, 4, 5]);
+
+        let mem = image(
+            &[(1, 7, 1, 0), (1, 7, 1, 1), (1, 7, 1, 2), (1, 7, 1, 3)],
+            &[],
+        );
+        assert_eq!(payloads(&read(&mem, BASE).unwrap()), vec![0, 1, 2, 3]);
+    }
+
+    #[test]
+    fn generation_wraps() {
+        let mem = image(&[(1, 0, 1, 1), (1, u16::MAX, 1, 0)], &[]);
+        assert_eq!(payloads(&read(&mem, BASE).unwrap()), vec![0, 1]);
+    }
+
+    #[test]
+    fn counters() {
+        let mem = image(&[(1, 1, 1, 0); 2], &[5, 0, 7]);
+        let rb = read(&mem, BASE).unwrap();
+        assert_eq!(
+            rb.counters,
+            vec![("A".into(), 5), ("B".into(), 0), ("C".into(), 7)]
+        );
+    }
+
+    #[test]
+    fn counters_without_names() {
+        let mem = image(&[(1, 1, 1, 0); 2], &[5, 6]);
+        let mut data = mem.read(BASE, 36 + 24 + 8).unwrap().to_vec();
+        // Point the names somewhere we don't have.
+        data[20..24].copy_from_slice(&0x0800_0000u32.to_le_bytes());
+        let mut mem = Memory::new();
+        mem.add_raw(BASE, data);
+
+        let rb = read(&mem, BASE).unwrap();
+        assert_eq!(rb.counters, vec![("0".into(), 5), ("1".into(), 6)]);
+    }
+
+    #[test]
+    fn rejects_garbage() {
+        let mut mem = Memory::new();
+        let mut data = MAGIC.to_le_bytes().to_vec();
+        data.resize(64, 0);
+        mem.add_raw(BASE, data);
+        assert!(find(&mem).is_empty());
+        assert!(read(&mem, BASE).is_err());
+    }
+
+    /// Offset of `field` within `base`
+    fn offset<T, F>(base: &T, field: *const F) -> usize {
+        field as usize - base as *const T as usize
+    }
+
+    /// Checks that the headers `ringbuf` generates describe its actual
+    /// layout, and that we read them from where it puts things.
+    #[test]
+    fn matches_ringbuf_layout() {
+        use core::ptr::addr_of;
+        use ringbuf::{Count, CountedRingbuf, RingbufHeader};
+
+        #[derive(Copy, Clone, Debug, PartialEq, Count)]
+        enum Trace {
+            None,
+            Read(u8, u64),
+        }
+
+        let crb = CountedRingbuf::<Trace, 3>::new(Trace::None);
+        let rb = &crb.ringbuf;
+        let h = &rb.header;
+        let entry = &rb.buffer[0];
+
+        assert_eq!(offset(rb, addr_of!(rb.buffer)), h.buffer_offset.into());
+        assert_eq!(
+            offset(entry, addr_of!(entry.payload)),
+            h.payload_offset.into()
+        );
+        assert_eq!(core::mem::size_of_val(entry), h.entry_size.into());
+        assert_eq!(
+            core::mem::size_of_val(&entry.payload),
+            h.payload_size.into()
+        );
+        assert_eq!(h.entries, 3);
+        assert_eq!(
+            offset(&crb, addr_of!(crb.counters)),
+            h.counters_offset.into()
+        );
+        assert_eq!(h.counters_len, 2);
+
+        assert_eq!(
+            offset(entry, addr_of!(entry.count)) + 4,
+            ringbuf::RINGBUF_ENTRY_PREFIX_SIZE
+        );
+        assert_eq!(
+            usize::from(ENTRY_PREFIX_SIZE),
+            ringbuf::RINGBUF_ENTRY_PREFIX_SIZE
+        );
+        assert_eq!(offset(entry, addr_of!(entry.line)), 0);
+        assert_eq!(offset(entry, addr_of!(entry.generation)), 2);
+
+        // `Header::parse` reads the fields from these offsets. The counter
+        // names, a pointer and a length, come next; on our 32-bit targets
+        // that's at 20, but here they may be padded out.
+        let word = core::mem::size_of::<usize>();
+        for (field, expected) in [
+            (offset(h, addr_of!(h.magic)), 0),
+            (offset(h, addr_of!(h.version)), 4),
+            (offset(h, addr_of!(h.flags)), 5),
+            (offset(h, addr_of!(h.entries)), 6),
+            (offset(h, addr_of!(h.entry_size)), 8),
+            (offset(h, addr_of!(h.payload_offset)), 10),
+            (offset(h, addr_of!(h.payload_size)), 12),
+            (offset(h, addr_of!(h.buffer_offset)), 14),
+            (offset(h, addr_of!(h.counters_offset)), 16),
+            (offset(h, addr_of!(h.counters_len)), 18),
+            (
+                offset(h, addr_of!(h.counter_names)),
+                (20 + word - 1) / word * word,
+            ),
+        ] {
+            assert_eq!(field, expected);
+        }
+        assert_eq!(
+            core::mem::size_of::<RingbufHeader>(),
+            offset(h, addr_of!(h.counter_names)) + 2 * word
+        );
+        assert_eq!(HEADER_SIZE, 20 + 2 * 4);
+    }
+
+    #[test]
+    fn demangling() {
+        assert_eq!(
+            demangle("_ZN12task_thermal7RINGBUF17h0123456789abcdefE"),
+            "task_thermal::RINGBUF"
+        );
+        assert_eq!(
+            demangle("_ZN15drv_i2c_devices8max3179016MAX31790_RINGBUF17h0123456789abcdefE"),
+            "drv_i2c_devices::max31790::MAX31790_RINGBUF"
+        );
+        assert_eq!(demangle("HUBRIS_IMAGE_ID"), "HUBRIS_IMAGE_ID");
+    }
+}
@@ -0,0 +1,124 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+use std::path::PathBuf;
+
+use anyhow::{anyhow, bail, Context, Result};
+use clap::Parser;
+
+use ringbuf_decode::Memory;
+
+#[derive(Debug, Parser)]
+#[clap(
+    max_term_width = 80,
+    about = "dump the ring buffers in a Hubris memory image or core dump"
+)]
+struct Args {
+    /// Raw memory image, given as FILE@ADDRESS
+    #[clap(long)]
+    image: Vec<String>,
+
+    /// Address of a running system's udprpc socket, e.g. `[fe80::1%2]:998`,
+    /// to fetch the ring buffers exported by its supervisor
+    #[clap(long)]
+    rpc: Option<String>,
+
+    /// Core dump, or task or kernel ELF file (for symbols and flash)
+    #[clap(long)]
+    elf: Vec<PathBuf>,
+
+    /// Print the ring buffers as JSON
+    #[clap(long)]
+    json: bool,
+
+    /// Only show ring buffers whose name contains this
+    name: Option<String>,
+}
+
+fn main() -> Result<()> {
+    let args = Args::parse();
+
+    // Live memory goes first, so that it wins over the initial contents
+    // described by ELF files.
+    let mut mem = Memory::new();
+    if let Some(addr) = &args.rpc {
+        let mut client = ringbuf_decode::rpc::Client::connect(addr.as_str())
+            .with_context(|| format!("connecting to {addr}"))?;
+        client.fetch(&mut mem)?;
+    }
+    for image in &args.image {
+        let (file, addr) = image
+            .rsplit_once('@')
+            .ok_or_else(|| anyhow!("expected FILE@ADDRESS, got {image}"))?;
+        let addr = match addr.strip_prefix("0x") {
+            Some(hex) => u32::from_str_radix(hex, 16),
+            None => addr.parse(),
+        }
+        .with_context(|| format!("bad address in {image}"))?;
+        let data =
+            std::fs::read(file).with_context(|| format!("reading {file}"))?;
+        mem.add_raw(addr, data);
+    }
+    let mut symbols = vec![];
+    for path in &args.elf {
+        let data = std::fs::read(path)
+            .with_context(|| format!("reading {}", path.display()))?;
+        mem.add_elf(&data)
+            .with_context(|| format!("loading {}", path.display()))?;
+        symbols.extend(ringbuf_decode::symbols(&data)?);
+    }
+    if args.image.is_empty() && args.elf.is_empty() && args.rpc.is_none() {
+        bail!("nothing to read; give --rpc, or at least one --image or --elf");
+    }
+
+    let headers = ringbuf_decode::find(&mem);
+    let names = ringbuf_decode::name_headers(&headers, &symbols);
+
+    let mut found = std::collections::BTreeMap::new();
+    for (addr, name) in names {
+        if args
+            .name
+            .as_ref()
+            .map_or(true, |n| name.contains(n.as_str()))
+        {
+            found.insert(name, ringbuf_decode::read(&mem, addr)?);
+        }
+    }
+
+    if args.json {
+        println!("{}", serde_json::to_string_pretty(&found)?);
+        return Ok(());
+    }
+
+    for (name, rb) in &found {
+        println!("ring buffer {name} at {:#010x}:", rb.address);
+        let timestamps = rb.header.timestamps();
+        print!("{:>4} {:>5} {:>5} {:>8}", "NDX", "LINE", "GEN", "COUNT");
+        if timestamps {
+            print!(" {:>12}", "TIME");
+        }
+        println!(" PAYLOAD");
+        for e in &rb.entries {
+            print!(
+                "{:>4} {:>5} {:>5} {:>8}",
+                e.index, e.line, e.generation, e.count
+            );
+            if let Some(t) = e.timestamp {
+                print!(" {t:>12}");
+            }
+            let payload: Vec<_> =
+                e.payload.iter().map(|b| format!("{b:02x}")).collect();
+            println!(" [{}]", payload.join(" "));
+        }
+        if !rb.counters.is_empty() {
+            println!("counters:");
+            for (counter, n) in &rb.counters {
+                println!("{n:>12} {counter}");
+            }
+        }
+        println!();
+    }
+
+    Ok(())
+}
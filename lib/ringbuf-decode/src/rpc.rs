@@ -0,0 +1,207 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Fetching ring buffers over the network
+//!
+//! A supervisor built with its `ringbuf-export` feature will hand out the
+//! contents of every ring buffer in the system. This talks to it through
+//! `udprpc`, which passes raw IPC messages on to a task; the layouts here
+//! must agree with `task/udprpc` and `idl/jefe.idol`.
+
+use std::net::{ToSocketAddrs, UdpSocket};
+use std::time::Duration;
+
+use anyhow::{bail, Context, Result};
+use serde::Serialize;
+
+use crate::Memory;
+
+/// The supervisor is always task 0.
+const JEFE: u16 = 0;
+
+/// Op codes, which Idol assigns from 1 in the order that ops are declared
+const GET_RINGBUF_EXPORT: u16 = 16;
+const READ_RINGBUF: u16 = 17;
+
+/// `RINGBUF_READ_SIZE` in the supervisor's API
+const READ_SIZE: usize = 16;
+
+/// `RingbufExportError::InvalidIndex`, which marks the end of the exports
+const INVALID_INDEX: u32 = 2;
+
+/// First byte of each `udprpc` reply
+const RPC_OK: u8 = 0;
+const RPC_BAD_IMAGE_ID: u8 = 2;
+
+/// How many times to send a request before giving up on a reply
+const ATTEMPTS: usize = 3;
+
+/// A ring buffer exported by the supervisor
+#[derive(Clone, Debug, Serialize)]
+pub struct Export {
+    pub task: u16,
+    pub address: u32,
+    pub size: u32,
+}
+
+pub struct Client {
+    socket: UdpSocket,
+    /// The image ID that `udprpc` expects; we learn this from its first
+    /// reply, which saves needing the archive.
+    image_id: u64,
+}
+
+impl Client {
+    /// Connects to `udprpc` at `addr`.
+    pub fn connect(addr: impl ToSocketAddrs) -> Result<Self> {
+        let addr = addr
+            .to_socket_addrs()?
+            .next()
+            .context("no address to connect to")?;
+        let local = if addr.is_ipv4() {
+            "0.0.0.0:0"
+        } else {
+            "[::]:0"
+        };
+        let socket = UdpSocket::bind(local)?;
+        socket.connect(addr)?;
+        socket.set_read_timeout(Some(Duration::from_secs(1)))?;
+        Ok(Self {
+            socket,
+            image_id: 0,
+        })
+    }
+
+    /// Calls `op` on the supervisor, returning its reply or the error code
+    /// from the call.
+    fn call(
+        &mut self,
+        op: u16,
+        args: &[u8],
+        nreply: usize,
+    ) -> Result<std::result::Result<Vec<u8>, u32>> {
+        // Two goes: the first may only tell us the image ID.
+        for _ in 0..2 {
+            let mut request = vec![];
+            request.extend(self.image_id.to_le_bytes());
+            request.extend(JEFE.to_le_bytes());
+            request.extend(op.to_le_bytes());
+            request.extend((nreply as u16).to_le_bytes());
+            request.extend((args.len() as u16).to_le_bytes());
+            request.extend(args);
+
+            let reply = self.exchange(&request)?;
+            match reply.first() {
+                Some(&RPC_OK) if reply.len() >= 5 => {
+                    let rc = u32::from_be_bytes(reply[1..5].try_into()?);
+                    return Ok(match rc {
+                        0 => Ok(reply[5..].to_vec()),
+                        rc => Err(rc),
+                    });
+                }
+                Some(&RPC_BAD_IMAGE_ID) if reply.len() >= 9 => {
+                    self.image_id = u64::from_le_bytes(reply[1..9].try_into()?);
+                }
+                _ => bail!("bad reply from udprpc: {reply:x?}"),
+            }
+        }
+        bail!("udprpc keeps rejecting our image ID")
+    }
+
+    /// Sends `request`, retrying if we don't hear back. This is fine because
+    /// everything we call is idempotent.
+    fn exchange(&self, request: &[u8]) -> Result<Vec<u8>> {
+        let mut buf = [0; 1024];
+        for _ in 0..ATTEMPTS {
+            self.socket.send(request)?;
+            match self.socket.recv(&mut buf) {
+                Ok(n) => return Ok(buf[..n].to_vec()),
+                Err(e)
+                    if matches!(
+                        e.kind(),
+                        std::io::ErrorKind::WouldBlock
+                            | std::io::ErrorKind::TimedOut
+                    ) =>
+                {
+                    continue
+                }
+                Err(e) => return Err(e.into()),
+            }
+        }
+        bail!("no reply from udprpc after {ATTEMPTS} attempts")
+    }
+
+    /// Lists the ring buffers that the supervisor exports.
+    pub fn exports(&mut self) -> Result<Vec<Export>> {
+        let mut out = vec![];
+        for index in 0u32.. {
+            // Hubpack: the index, then the reply's fields, all little-endian
+            let reply = match self.call(
+                GET_RINGBUF_EXPORT,
+                &index.to_le_bytes(),
+                10,
+            )? {
+                Ok(reply) if reply.len() == 10 => reply,
+                Ok(reply) => bail!("bad export description: {reply:x?}"),
+                Err(INVALID_INDEX) => break,
+                Err(rc) => bail!("get_ringbuf_export failed: {rc}"),
+            };
+            out.push(Export {
+                task: u16::from_le_bytes(reply[0..2].try_into()?),
+                address: u32::from_le_bytes(reply[2..6].try_into()?),
+                size: u32::from_le_bytes(reply[6..10].try_into()?),
+            });
+        }
+        Ok(out)
+    }
+
+    /// Reads the `index`th exported ring buffer.
+    pub fn read(&mut self, index: u32, export: &Export) -> Result<Vec<u8>> {
+        let mut out = vec![];
+        while out.len() < export.size as usize {
+            let mut args = index.to_le_bytes().to_vec();
+            args.extend((out.len() as u32).to_le_bytes());
+            let chunk = match self.call(READ_RINGBUF, &args, READ_SIZE)? {
+                Ok(chunk) if chunk.len() == READ_SIZE => chunk,
+                Ok(chunk) => bail!("short read of {} bytes", chunk.len()),
+                Err(rc) => bail!("read_ringbuf failed: {rc}"),
+            };
+            let n = READ_SIZE.min(export.size as usize - out.len());
+            out.extend(&chunk[..n]);
+        }
+        Ok(out)
+    }
+
+    /// Reads every exported ring buffer into `mem`.
+    pub fn fetch(&mut self, mem: &mut Memory) -> Result<Vec<Export>> {
+        let exports = self.exports()?;
+        for (index, export) in exports.iter().enumerate() {
+            let data = self.read(index as u32, export).with_context(|| {
+                format!("reading ring buffer at {:#x}", export.address)
+            })?;
+            mem.add_raw(export.address, data);
+        }
+        Ok(exports)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Idol numbers ops in declaration order, so make sure ours match.
+    #[test]
+    fn op_codes() {
+        let idol = include_str!("../../../idl/jefe.idol");
+        let ops: Vec<_> = idol
+            .lines()
+            .filter_map(|l| l.strip_prefix("        \""))
+            .filter_map(|l| l.strip_suffix("\": ("))
+            .collect();
+        let code =
+            |name| ops.iter().position(|&op| op == name).map(|i| i as u16 + 1);
+        assert_eq!(code("get_ringbuf_export"), Some(GET_RINGBUF_EXPORT));
+        assert_eq!(code("read_ringbuf"), Some(READ_RINGBUF));
+    }
+}
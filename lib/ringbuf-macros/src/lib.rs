@@ -0,0 +1,81 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Derive macro for `ringbuf::Count`; see the `ringbuf` crate for how it's
+//! used.
+
+use proc_macro::TokenStream;
+use quote::{format_ident, quote};
+use syn::{parse_macro_input, Data, DeriveInput, Fields};
+
+/// Derives `ringbuf::Count` for an enum, with one counter per variant.
+///
+/// The counters live in a generated `repr(C)` struct named after the enum
+/// (`Trace` gets `TraceCounters`), with a `u32` field per variant.
+#[proc_macro_derive(Count)]
+pub fn derive_count(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    let data = match &input.data {
+        Data::Enum(data) => data,
+        _ => {
+            return syn::Error::new_spanned(
+                &input.ident,
+                "Count can only be derived for enums",
+            )
+            .to_compile_error()
+            .into();
+        }
+    };
+
+    let name = &input.ident;
+    let vis = &input.vis;
+    let counters = format_ident!("{}Counters", name);
+    let (impl_generics, ty_generics, where_clause) =
+        input.generics.split_for_impl();
+
+    let variants: Vec<_> = data.variants.iter().map(|v| &v.ident).collect();
+    let names: Vec<_> = variants.iter().map(|v| v.to_string()).collect();
+    let arms = data.variants.iter().map(|v| {
+        let ident = &v.ident;
+        let pattern = match &v.fields {
+            Fields::Named(_) => quote! { Self::#ident { .. } },
+            Fields::Unnamed(_) => quote! { Self::#ident(..) },
+            Fields::Unit => quote! { Self::#ident },
+        };
+        quote! {
+            #pattern => {
+                counters.#ident = counters.#ident.wrapping_add(1);
+            }
+        }
+    });
+
+    quote! {
+        #[doc = concat!("Per-variant counters for [`", stringify!(#name), "`]")]
+        #[allow(non_snake_case)]
+        #[derive(Copy, Clone, Debug)]
+        #[repr(C)]
+        #vis struct #counters {
+            #( pub #variants: u32, )*
+        }
+
+        impl #impl_generics ringbuf::Count for #name #ty_generics
+            #where_clause
+        {
+            type Counters = #counters;
+
+            const NEW_COUNTERS: #counters = #counters {
+                #( #variants: 0, )*
+            };
+
+            const NAMES: &'static [&'static str] = &[ #( #names, )* ];
+
+            fn count(&self, counters: &mut #counters) {
+                match self {
+                    #( #arms )*
+                }
+            }
+        }
+    }
+    .into()
+}
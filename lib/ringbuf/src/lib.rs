@@ -62,6 +62,40 @@
 //! ringbuf_entry!((temp, Some(Register::TempMSB)));
 //! ```
 //!
+//! In hosted builds (that is, when unit-testing code on a workstation), ring
+//! buffers behave as if the `disabled` feature were set: nothing is recorded,
+//! and tests running on several threads can't trip over a buffer's borrow.
+//!
+//! ## Counting events
+//!
+//! A ring buffer only holds its most recent entries, which makes it hard to
+//! tell how often something happened over the life of a task.  If the payload
+//! is an enum, deriving [`Count`] for it and declaring the ring buffer with
+//! [`counted_ringbuf!`] keeps a count of each variant alongside the entries;
+//! these counts keep going after the entries themselves have scrolled off:
+//!
+//! ```
+//! #[derive(Copy, Clone, PartialEq, Count)]
+//! enum Trace {
+//!     None,
+//!     Timeout,
+//!     Read(u8),
+//! }
+//!
+//! counted_ringbuf!(Trace, 16, Trace::None);
+//! ```
+//!
+//! Entries are added with [`ringbuf_entry!`], exactly as for any other ring
+//! buffer.  Repeated entries that are folded into a single entry's `count`
+//! are still counted individually.
+//!
+//! ## Timestamps
+//!
+//! Enabling the `timestamps` feature adds a `timestamp` to every entry,
+//! holding the kernel timer (see `userlib::sys_get_timer`) at the time the
+//! entry was most recently recorded.  Because features are unified across a
+//! task's dependencies, this applies to every ring buffer in the task.
+//!
 //! ## Inspecting a ring buffer via Humility
 //!
 //! Humility has built-in support for dumping a ring buffer, and will (by
@@ -135,6 +169,39 @@
 //!      )
 //!    },...
 //! ```
+//!
+//! ## Decoding without Humility
+//!
+//! Enabling the `header` feature starts every ring buffer with a
+//! [`RingbufHeader`] describing its layout, so it can be found and decoded
+//! from a raw memory image or a core dump without debug information.  (Like
+//! `timestamps`, this applies to every ring buffer in the task; a task will
+//! usually forward it through a feature of its own.)  This costs 28 bytes per
+//! ring buffer, and fixes the layout of entries, which may add padding to
+//! them.  The `ringbuf-decode` crate does this on the
+//! host; since it has no DWARF to go on, payloads are left as raw bytes for
+//! the caller to interpret.  Its command-line tool takes core dumps and task
+//! ELF files (for names), and raw images given with their load address:
+//!
+//! ```console
+//! $ cargo run -p ringbuf-decode -- --image ram.bin@0x20000000 \
+//!     --elf target/gimlet-c/dist/thermal THERMAL
+//! ```
+//!
+//! ## Reading ring buffers remotely
+//!
+//! Each ring buffer is also recorded in its task's `.ringbuf_registry`
+//! section, which isn't loaded and so costs nothing.  If the supervisor is
+//! built with its `ringbuf-export` feature (which needs the kernel's `dump`
+//! feature), the build system hands it the registries of every task, and it
+//! serves their ring buffers with its `get_ringbuf_export` and `read_ringbuf`
+//! operations.  Through `udprpc`, these can be used over the network, and
+//! decoded as above if the tasks have the `header` feature:
+//!
+//! ```console
+//! $ cargo run -p ringbuf-decode -- --rpc '[fe80::c1d:93ff:fe20:2c2e%2]:998' \
+//!     --elf target/gimletlet/dist/thermal
+//! ```
 
 #![no_std]
 
@@ -142,6 +209,9 @@
 /// macros is guaranteed to be able to find them.
 pub use static_cell::StaticCell;
 
+/// Derives [`Count`] for an enum, counting each variant separately.
+pub use ringbuf_macros::Count;
+
 /// Declares a ringbuffer in the current module or context.
 ///
 /// `ringbuf!(NAME, Type, N, expr)` makes a ringbuffer named `NAME`,
@@ -156,28 +226,21 @@ pub use static_cell::StaticCell;
 ///
 /// To support the common case of having one quickly-installed ringbuffer per
 /// module, if you omit the name, it will default to `__RINGBUF`.
-#[cfg(not(feature = "disabled"))]
+#[cfg(all(target_os = "none", not(feature = "disabled")))]
 #[macro_export]
 macro_rules! ringbuf {
     ($name:ident, $t:ty, $n:expr, $init:expr) => {
         #[used]
         static $name: $crate::StaticCell<$crate::Ringbuf<$t, $n>> =
-            $crate::StaticCell::new($crate::Ringbuf {
-                last: None,
-                buffer: [$crate::RingbufEntry {
-                    line: 0,
-                    generation: 0,
-                    count: 0,
-                    payload: $init,
-                }; $n],
-            });
+            $crate::StaticCell::new($crate::Ringbuf::new($init));
+        $crate::register_ringbuf!($name);
     };
     ($t:ty, $n:expr, $init:expr) => {
         $crate::ringbuf!(__RINGBUF, $t, $n, $init);
     };
 }
 
-#[cfg(feature = "disabled")]
+#[cfg(any(feature = "disabled", not(target_os = "none")))]
 #[macro_export]
 macro_rules! ringbuf {
     ($name:ident, $t:ty, $n:expr, $init:expr) => {
@@ -190,15 +253,64 @@ macro_rules! ringbuf {
     };
 }
 
+/// Declares a ringbuffer that also counts each variant of its payload.
+///
+/// This takes the same arguments as [`ringbuf!`], but `Type` must implement
+/// [`Count`] (which can be derived for enums).  The actual type of `name` will
+/// be `StaticCell<CountedRingbuf<T, N>>`; entries are added with
+/// [`ringbuf_entry!`] as usual.
+#[cfg(all(target_os = "none", not(feature = "disabled")))]
+#[macro_export]
+macro_rules! counted_ringbuf {
+    ($name:ident, $t:ty, $n:expr, $init:expr) => {
+        #[used]
+        static $name: $crate::StaticCell<$crate::CountedRingbuf<$t, $n>> =
+            $crate::StaticCell::new($crate::CountedRingbuf::new($init));
+        $crate::register_ringbuf!($name);
+    };
+    ($t:ty, $n:expr, $init:expr) => {
+        $crate::counted_ringbuf!(__RINGBUF, $t, $n, $init);
+    };
+}
+
+#[cfg(any(feature = "disabled", not(target_os = "none")))]
+#[macro_export]
+macro_rules! counted_ringbuf {
+    ($name:ident, $t:ty, $n:expr, $init:expr) => {
+        #[allow(dead_code)]
+        const _: $t = $init;
+    };
+    ($t:ty, $n:expr, $init:expr) => {
+        #[allow(dead_code)]
+        const _: $t = $init;
+    };
+}
+
+/// Records a ringbuffer in the `.ringbuf_registry` section, so that the build
+/// system can find it; see [`RegistryEntry`]. This is used by the declaring
+/// macros, and isn't useful by itself.
+#[doc(hidden)]
+#[macro_export]
+macro_rules! register_ringbuf {
+    ($name:ident) => {
+        const _: () = {
+            #[used]
+            #[link_section = ".ringbuf_registry"]
+            static ENTRY: $crate::RegistryEntry =
+                $crate::RegistryEntry::for_ringbuf(&$name);
+        };
+    };
+}
+
 /// Inserts data into a named ringbuffer (which should have been declared with
-/// the `ringbuf!` macro).
+/// the `ringbuf!` or `counted_ringbuf!` macro).
 ///
 /// `ringbuf_entry!(NAME, expr)` will insert `expr` into the ringbuffer called
 /// `NAME`.
 ///
 /// If you declared your ringbuffer without a name, you can also use this
 /// without a name, and it will default to `__RINGBUF`.
-#[cfg(not(feature = "disabled"))]
+#[cfg(all(target_os = "none", not(feature = "disabled")))]
 #[macro_export]
 macro_rules! ringbuf_entry {
     ($buf:expr, $payload:expr) => {{
@@ -208,8 +320,8 @@ macro_rules! ringbuf_entry {
         let (p, buf) = ($payload, &$buf);
         // Invoke these functions using slightly weird syntax to avoid
         // accidentally calling a _different_ routine called borrow_mut or
-        // entry.
-        $crate::Ringbuf::entry(
+        // record_entry.
+        $crate::RecordEntry::record_entry(
             &mut *$crate::StaticCell::borrow_mut(buf),
             line!() as u16,
             p,
@@ -220,7 +332,7 @@ macro_rules! ringbuf_entry {
     };
 }
 
-#[cfg(feature = "disabled")]
+#[cfg(any(feature = "disabled", not(target_os = "none")))]
 #[macro_export]
 macro_rules! ringbuf_entry {
     ($buf:expr, $payload:expr) => {{
@@ -233,7 +345,7 @@ macro_rules! ringbuf_entry {
 }
 
 /// Inserts data into an unnamed ringbuffer at the root of this crate
-#[cfg(not(feature = "disabled"))]
+#[cfg(all(target_os = "none", not(feature = "disabled")))]
 #[allow(clippy::crate_in_macro_def)]
 #[macro_export]
 macro_rules! ringbuf_entry_root {
@@ -242,7 +354,7 @@ macro_rules! ringbuf_entry_root {
     };
 }
 
-#[cfg(feature = "disabled")]
+#[cfg(any(feature = "disabled", not(target_os = "none")))]
 #[macro_export]
 macro_rules! ringbuf_entry_root {
     ($payload:expr) => {{
@@ -256,26 +368,147 @@ macro_rules! ringbuf_entry_root {
 /// the most recent entry (in terms of both `line` and `payload`), `count` will
 /// be incremented rather than generating a new entry.
 ///
+/// With the `header` feature, the layout is fixed so that [`RingbufHeader`]
+/// can describe it; `timestamp` is only present with the `timestamps` feature.
+///
 #[derive(Debug, Copy, Clone)]
+#[cfg_attr(feature = "header", repr(C))]
 pub struct RingbufEntry<T: Copy + PartialEq> {
     pub line: u16,
     pub generation: u16,
     pub count: u32,
+    #[cfg(feature = "timestamps")]
+    pub timestamp: u64,
     pub payload: T,
 }
 
+/// Value of [`RingbufHeader::magic`], spelling `RBUF` in memory.
+pub const RINGBUF_MAGIC: u32 = u32::from_le_bytes(*b"RBUF");
+
+/// Version of the layout described by [`RingbufHeader`].
+pub const RINGBUF_VERSION: u8 = 1;
+
+/// Set in [`RingbufHeader::flags`] if entries carry a `timestamp`.
+pub const RINGBUF_FLAG_TIMESTAMPS: u8 = 1 << 0;
+
+/// Size of the fields before the payload of a [`RingbufEntry`] with the
+/// `header` feature: `line`, `generation` and `count`, then maybe `timestamp`.
+pub const RINGBUF_ENTRY_PREFIX_SIZE: usize =
+    if cfg!(feature = "timestamps") { 16 } else { 8 };
+
+///
+/// Describes the layout of the ring buffer that it starts (with the `header`
+/// feature), so that tools can
+/// find and decode ring buffers in memory without debug information.  All
+/// offsets are in bytes; `buffer_offset` and `counters_offset` are from the
+/// start of the header, and `payload_offset` from the start of each entry.
+/// `counters_len` is zero for a ring buffer without counters.
+///
+#[derive(Debug, Copy, Clone)]
+#[repr(C)]
+pub struct RingbufHeader {
+    pub magic: u32,
+    pub version: u8,
+    pub flags: u8,
+    pub entries: u16,
+    pub entry_size: u16,
+    pub payload_offset: u16,
+    pub payload_size: u16,
+    pub buffer_offset: u16,
+    pub counters_offset: u16,
+    pub counters_len: u16,
+    pub counter_names: &'static [&'static str],
+}
+
+// Tools that read the header without debug information count on this.
+#[cfg(target_pointer_width = "32")]
+const _: () = assert!(core::mem::size_of::<RingbufHeader>() == 28);
+
+#[cfg(feature = "header")]
+const fn round_up(n: usize, align: usize) -> usize {
+    (n + align - 1) & !(align - 1)
+}
+
+/// Converts a size or offset for [`RingbufHeader`], failing the build if it
+/// doesn't fit.
+#[cfg(feature = "header")]
+const fn header_u16(n: usize) -> u16 {
+    assert!(n <= u16::MAX as usize, "ring buffer too large to describe");
+    n as u16
+}
+
+#[cfg(feature = "header")]
+impl RingbufHeader {
+    const fn new<T: Copy + PartialEq, const N: usize>() -> Self {
+        use core::mem::{align_of, size_of};
+
+        let flags = if cfg!(feature = "timestamps") {
+            RINGBUF_FLAG_TIMESTAMPS
+        } else {
+            0
+        };
+        let payload_offset =
+            round_up(RINGBUF_ENTRY_PREFIX_SIZE, align_of::<T>());
+
+        // These follow the repr(C) layout of `Ringbuf`.
+        let last_offset =
+            round_up(size_of::<RingbufHeader>(), align_of::<Option<usize>>());
+        let buffer_offset = round_up(
+            last_offset + size_of::<Option<usize>>(),
+            align_of::<RingbufEntry<T>>(),
+        );
+
+        Self {
+            magic: RINGBUF_MAGIC,
+            version: RINGBUF_VERSION,
+            flags,
+            entries: header_u16(N),
+            entry_size: header_u16(size_of::<RingbufEntry<T>>()),
+            payload_offset: header_u16(payload_offset),
+            payload_size: header_u16(size_of::<T>()),
+            buffer_offset: header_u16(buffer_offset),
+            counters_offset: 0,
+            counters_len: 0,
+            counter_names: &[],
+        }
+    }
+}
+
 ///
 /// A ring buffer of parametrized type and size.  In practice, instantiating
 /// this directly is strange -- see the [`ringbuf!`] macro.
 ///
 #[derive(Debug)]
+#[cfg_attr(feature = "header", repr(C))]
 pub struct Ringbuf<T: Copy + PartialEq, const N: usize> {
+    #[cfg(feature = "header")]
+    pub header: RingbufHeader,
     pub last: Option<usize>,
     pub buffer: [RingbufEntry<T>; N],
 }
 
 impl<T: Copy + PartialEq, const N: usize> Ringbuf<T, { N }> {
+    /// Makes an empty ring buffer, with every entry's payload set to `init`.
+    pub const fn new(init: T) -> Self {
+        Self {
+            #[cfg(feature = "header")]
+            header: RingbufHeader::new::<T, N>(),
+            last: None,
+            buffer: [RingbufEntry {
+                line: 0,
+                generation: 0,
+                count: 0,
+                #[cfg(feature = "timestamps")]
+                timestamp: 0,
+                payload: init,
+            }; N],
+        }
+    }
+
     pub fn entry(&mut self, line: u16, payload: T) {
+        #[cfg(feature = "timestamps")]
+        let now = userlib::sys_get_timer().now;
+
         let ndx = match self.last {
             None => 0,
             Some(last) => {
@@ -286,6 +519,10 @@ impl<T: Copy + PartialEq, const N: usize> Ringbuf<T, { N }> {
                     // count.
                     if let Some(new_count) = ent.count.checked_add(1) {
                         ent.count = new_count;
+                        #[cfg(feature = "timestamps")]
+                        {
+                            ent.timestamp = now;
+                        }
                         return;
                     }
                 }
@@ -303,7 +540,130 @@ impl<T: Copy + PartialEq, const N: usize> Ringbuf<T, { N }> {
         ent.payload = payload;
         ent.count = 1;
         ent.generation = ent.generation.wrapping_add(1);
+        #[cfg(feature = "timestamps")]
+        {
+            ent.timestamp = now;
+        }
 
         self.last = Some(ndx);
     }
 }
+
+///
+/// Counts occurrences of each variant of a payload type, for use with
+/// [`counted_ringbuf!`].  This is normally derived rather than implemented by
+/// hand.
+///
+/// `Counters` must be `repr(C)` and consist of `NAMES.len()` `u32` counters,
+/// in the same order as `NAMES`, so that tools can decode it from memory.
+///
+pub trait Count {
+    type Counters: Copy + core::fmt::Debug;
+
+    /// All counters at zero
+    const NEW_COUNTERS: Self::Counters;
+
+    /// Name of each counter
+    const NAMES: &'static [&'static str];
+
+    /// Bumps the counter for `self`.
+    fn count(&self, counters: &mut Self::Counters);
+}
+
+///
+/// A ring buffer that also counts every entry recorded in it, by variant.  See
+/// the [`counted_ringbuf!`] macro.
+///
+#[derive(Debug)]
+#[cfg_attr(feature = "header", repr(C))]
+pub struct CountedRingbuf<T: Count + Copy + PartialEq, const N: usize> {
+    pub ringbuf: Ringbuf<T, N>,
+    pub counters: T::Counters,
+}
+
+impl<T: Count + Copy + PartialEq, const N: usize> CountedRingbuf<T, { N }> {
+    /// Makes an empty ring buffer with all counters at zero.
+    pub const fn new(init: T) -> Self {
+        #[cfg(feature = "header")]
+        let ringbuf = {
+            use core::mem::{align_of, size_of};
+
+            let mut ringbuf = Ringbuf::new(init);
+            ringbuf.header.counters_offset = header_u16(round_up(
+                size_of::<Ringbuf<T, N>>(),
+                align_of::<T::Counters>(),
+            ));
+            ringbuf.header.counters_len = header_u16(T::NAMES.len());
+            ringbuf.header.counter_names = T::NAMES;
+            ringbuf
+        };
+        #[cfg(not(feature = "header"))]
+        let ringbuf = Ringbuf::new(init);
+
+        Self {
+            ringbuf,
+            counters: T::NEW_COUNTERS,
+        }
+    }
+
+    pub fn entry(&mut self, line: u16, payload: T) {
+        payload.count(&mut self.counters);
+        self.ringbuf.entry(line, payload);
+    }
+}
+
+///
+/// Implemented by both kinds of ring buffer, so that [`ringbuf_entry!`] works
+/// with either.
+///
+pub trait RecordEntry {
+    type Payload;
+
+    fn record_entry(&mut self, line: u16, payload: Self::Payload);
+}
+
+impl<T: Copy + PartialEq, const N: usize> RecordEntry for Ringbuf<T, { N }> {
+    type Payload = T;
+
+    fn record_entry(&mut self, line: u16, payload: T) {
+        self.entry(line, payload)
+    }
+}
+
+impl<T: Count + Copy + PartialEq, const N: usize> RecordEntry
+    for CountedRingbuf<T, { N }>
+{
+    type Payload = T;
+
+    fn record_entry(&mut self, line: u16, payload: T) {
+        self.entry(line, payload)
+    }
+}
+
+///
+/// Location of a ring buffer, as recorded in the `.ringbuf_registry` section.
+///
+/// Like the task slot table, this section is treated as debug information: it
+/// isn't loaded, and costs nothing at runtime.  The build system reads it out
+/// of each task to tell the supervisor where their ring buffers are, if the
+/// supervisor has been built to export them (see its `ringbuf-export`
+/// feature).
+///
+#[repr(C)]
+pub struct RegistryEntry {
+    address: *const (),
+    size: usize,
+}
+
+impl RegistryEntry {
+    pub const fn for_ringbuf<T>(ringbuf: &'static T) -> Self {
+        Self {
+            address: ringbuf as *const T as *const (),
+            size: core::mem::size_of::<T>(),
+        }
+    }
+}
+
+// SAFETY: a `RegistryEntry` only ever lives in the (unloaded)
+// `.ringbuf_registry` section, so its pointer is never used at runtime.
+unsafe impl Sync for RegistryEntry {}
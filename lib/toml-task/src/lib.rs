@@ -21,6 +21,17 @@ pub struct Task<T = ordered_toml::Value> {
     pub stacksize: Option<u32>,
     #[serde(default)]
     pub start: bool,
+    /// Run at the priority of the most important task blocked on this one,
+    /// when that is more important than `priority`.
+    #[serde(default)]
+    pub inherit_priority: bool,
+    /// Run this task in the non-secure state on an ARMv8-M part with
+    /// TrustZone. Tasks are secure by default.
+    #[serde(default)]
+    pub non_secure: bool,
+    /// If set, the supervisor will fault this task if it goes longer than this
+    /// many milliseconds between heartbeats, or from starting to its first.
+    pub watchdog_ms: Option<u32>,
 
     #[serde(default)]
     pub uses: Vec<String>,
@@ -35,6 +46,12 @@ pub struct Task<T = ordered_toml::Value> {
     #[serde(default)]
     pub extern_regions: Vec<String>,
 
+    /// Tasks in `task_slots` whose SENDs from this task are left out of the
+    /// IPC priority and deadlock checks, because they're known to be safe
+    /// (for instance, because they only ever use `SEND_TIMEOUT`).
+    #[serde(default)]
+    pub ipc_exempt: Vec<String>,
+
     // Order matters here:
     // TOML serialization doesn't allow us to put a value type after any Table
     // type, so we put all of our `IndexMap` (and `config`, which often contains
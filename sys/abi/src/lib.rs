@@ -216,6 +216,13 @@ pub const fn extract_new_generation(code: u32) -> Option<Generation> {
 /// Response code returned by the kernel if a lender has defected.
 pub const DEFECT: u32 = 1;
 
+/// Response code returned by the kernel if a `SEND_TIMEOUT` reached its
+/// deadline before the peer replied.
+///
+/// This sits just below the range of dead codes, so it can't be mistaken for
+/// one by `extract_new_generation`.
+pub const SEND_TIMEOUT_CODE: u32 = 0xffff_fe00;
+
 /// State used to make scheduling decisions.
 #[derive(Copy, Clone, Debug, Eq, PartialEq, Deserialize, Serialize)]
 pub enum TaskState {
@@ -444,6 +451,7 @@ pub enum Sysnum {
     RefreshTaskId = 10,
     Post = 11,
     ReplyFault = 12,
+    SendTimeout = 13,
 }
 
 /// We're using an explicit `TryFrom` impl for `Sysnum` instead of
@@ -467,6 +475,7 @@ impl core::convert::TryFrom<u32> for Sysnum {
             10 => Ok(Self::RefreshTaskId),
             11 => Ok(Self::Post),
             12 => Ok(Self::ReplyFault),
+            13 => Ok(Self::SendTimeout),
             _ => Err(()),
         }
     }
@@ -488,6 +497,8 @@ pub enum Kipcnum {
     Reset = 5,
     GetTaskDumpRegion = 6,
     ReadTaskDumpRegion = 7,
+    FindFaultedTasks = 8,
+    ReadTaskRuntime = 9,
 }
 
 impl core::convert::TryFrom<u16> for Kipcnum {
@@ -502,11 +513,18 @@ impl core::convert::TryFrom<u16> for Kipcnum {
             5 => Ok(Self::Reset),
             6 => Ok(Self::GetTaskDumpRegion),
             7 => Ok(Self::ReadTaskDumpRegion),
+            8 => Ok(Self::FindFaultedTasks),
+            9 => Ok(Self::ReadTaskRuntime),
             _ => Err(()),
         }
     }
 }
 
+/// Number of memory regions the kernel gives each task. The build system
+/// spends any that a task doesn't otherwise need on splitting its memory, so
+/// the two must agree.
+pub const REGIONS_PER_TASK: usize = 8;
+
 pub const HEADER_MAGIC: u32 = 0x64_CE_D6_CA;
 pub const CABOOSE_MAGIC: u32 = 0xCAB0_005E;
 
@@ -41,6 +41,15 @@ fn process_config() -> Result<Generated> {
         ron::de::from_str(&build_util::env_var("HUBRIS_KCONFIG")?)
             .context("parsing kconfig from HUBRIS_KCONFIG")?;
 
+    // TrustZone support costs context switch time and text, so we only build
+    // it in if some task actually runs non-secure.
+    if kconfig.tasks.iter().any(|t| t.non_secure) {
+        if !build_util::target().starts_with("thumbv8m") {
+            bail!("non-secure tasks require an ARMv8-M target");
+        }
+        println!("cargo:rustc-cfg=hubris_trustzone");
+    }
+
     // The kconfig data structure keeps things somewhat abstract to give us, the
     // kernel, more freedom about our internal implementation choices. However,
     // this means we have to do some preprocessing before it's useful.
@@ -87,6 +96,7 @@ fn process_config() -> Result<Generated> {
     // Now, generate the TaskDesc literals. These rely on the region table
     // because they address it by index at the moment.
     let mut task_descs = vec![];
+    let idle_priority = kconfig.tasks.iter().map(|task| task.priority).max();
 
     for (i, task) in kconfig.tasks.iter().enumerate() {
         // Work out the region indices for each of this task's regions.
@@ -127,10 +137,24 @@ fn process_config() -> Result<Generated> {
 
         let index = u16::try_from(i).expect("over 2**16 tasks??");
         let priority = task.priority;
-        let flags = if task.start_at_boot {
-            quote::quote! { TaskFlags::START_AT_BOOT }
-        } else {
+        let mut flags = vec![];
+        if task.start_at_boot {
+            flags.push(quote::quote! { TaskFlags::START_AT_BOOT });
+        }
+        if task.inherit_priority {
+            flags.push(quote::quote! { TaskFlags::INHERIT_PRIORITY });
+        }
+        if task.non_secure {
+            flags.push(quote::quote! { TaskFlags::NON_SECURE });
+        }
+        // xtask guarantees that the idle task is alone at the lowest priority.
+        if Some(task.priority) == idle_priority {
+            flags.push(quote::quote! { TaskFlags::IDLE });
+        }
+        let flags = if flags.is_empty() {
             quote::quote! { TaskFlags::empty() }
+        } else {
+            quote::quote! { #(#flags)|* }
         };
         task_descs.push(quote::quote! {
             TaskDesc {
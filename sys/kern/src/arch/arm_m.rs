@@ -11,7 +11,8 @@
 //! So, at each SysTick interrupt, we increment the `TICKS` global that contains
 //! the real kernel timestamp. This has the downside that we take regular
 //! interrupts to maintain `TICKS`, but has the upside that we don't need
-//! special SoC support for timing.
+//! special SoC support for timing. (With the `tickless` feature, we stop taking
+//! those interrupts while the idle task runs; see the `tickless` submodule.)
 //!
 //! # Notes on ARM-M interrupts
 //!
@@ -69,6 +70,16 @@
 //! We might later decide that most ISRs (including ticks) tend to trigger
 //! context switches, and just always do full save/restore, eliminating PendSV.
 //! We'll see.
+//!
+//! # TrustZone
+//!
+//! On ARMv8-M parts with the Security Extension, the kernel runs in the secure
+//! state, and so do tasks by default. If any task is configured as non-secure,
+//! the kernel is built with `hubris_trustzone`, and the `trustzone` submodule
+//! takes care of the extra state: it marks the current task's memory
+//! non-secure in the SAU, programs the non-secure MPU, and forwards exceptions
+//! raised in the non-secure state (including `SVC`) back to the kernel by way of
+//! PendSV.
 
 use core::arch;
 use core::sync::atomic::{AtomicBool, AtomicPtr, AtomicU32, Ordering};
@@ -88,6 +99,13 @@ use abi::FaultSource;
 use armv8_m_mpu::{disable_mpu, enable_mpu};
 use unwrap_lite::UnwrapLite;
 
+#[cfg(feature = "tickless")]
+mod tickless;
+#[cfg(feature = "tickless")]
+pub use tickless::{set_idle_hooks, IdleHooks};
+#[cfg(hubris_trustzone)]
+mod trustzone;
+
 macro_rules! uassert {
     ($cond : expr) => {
         if !$cond {
@@ -157,15 +175,76 @@ pub struct SavedState {
     #[cfg(any(armv7m, armv8m))]
     s31: u32,
     // NOTE: the above fields must be kept contiguous!
+    /// The non-secure state has its own banked stack pointer, which we need to
+    /// keep track of separately. The save sequences find this by its offset
+    /// from `s16`, so it must directly follow `s31`.
+    #[cfg(hubris_trustzone)]
+    psp_ns: u32,
+}
+
+/// Assembly to stash the non-secure stack pointer after the rest of a task's
+/// saved state, given a register pointing at its `s16`. Clobbers r3. This
+/// expands to nothing unless TrustZone support is built in.
+#[cfg(hubris_trustzone)]
+macro_rules! save_psp_ns {
+    ($s16:literal) => {
+        concat!(
+            "
+            .inst.w 0xf3ef8389      @ mrs r3, PSP_NS
+            str r3, [",
+            $s16,
+            ", #64]
+            "
+        )
+    };
+}
+#[cfg(not(hubris_trustzone))]
+macro_rules! save_psp_ns {
+    ($s16:literal) => {
+        ""
+    };
+}
+
+/// Counterpart to `save_psp_ns!` for the restore sequences.
+#[cfg(hubris_trustzone)]
+macro_rules! restore_psp_ns {
+    ($s16:literal) => {
+        concat!(
+            "
+            ldr r3, [",
+            $s16,
+            ", #64]
+            .inst.w 0xf3838889      @ msr PSP_NS, r3
+            "
+        )
+    };
+}
+#[cfg(not(hubris_trustzone))]
+macro_rules! restore_psp_ns {
+    ($s16:literal) => {
+        ""
+    };
 }
 
 /// Map the volatile registers to (architecture-independent) syscall argument
 /// and return slots.
 impl task::ArchState for SavedState {
+    #[cfg(not(hubris_trustzone))]
     fn stack_pointer(&self) -> u32 {
         self.psp
     }
 
+    #[cfg(hubris_trustzone)]
+    fn stack_pointer(&self) -> u32 {
+        // A task interrupted in the non-secure state has its frame on the
+        // non-secure stack.
+        if self.exc_return & trustzone::EXC_RETURN_S == 0 {
+            self.psp_ns
+        } else {
+            self.psp
+        }
+    }
+
     /// Reads syscall argument register 0.
     fn arg0(&self) -> u32 {
         self.r4
@@ -264,8 +343,8 @@ const INITIAL_FPSCR: u32 = 0;
 /// bit 6 = S = secure or non-secure stack used
 /// bit 0 = ES = the security domain the exception was taken to
 /// These need to be consistent! The failure mode is a secure fault otherwise.
-/// We currently assume that TrustZone has not been enabled (even on the parts
-/// that support it) (and that bit 6 and bit 0 can always be set).
+/// The kernel always runs secure, so bit 0 can always be set; bit 6 is cleared
+/// for non-secure tasks (see the `trustzone` module) and otherwise set.
 const EXC_RETURN_CONST: u32 = 0xFFFFFFED;
 
 // Because debuggers need to know the clock frequency to set the SWO clock
@@ -334,6 +413,11 @@ pub fn reinitialize(task: &mut task::Task) {
 
     // Finally, record the EXC_RETURN we'll use to enter the task.
     task.save_mut().exc_return = EXC_RETURN_CONST;
+
+    #[cfg(hubris_trustzone)]
+    {
+        trustzone::reinitialize(task);
+    }
 }
 
 #[cfg(any(armv6m, armv7m))]
@@ -434,6 +518,21 @@ pub fn apply_memory_protection(task: &task::Task) {
         // aliasing....
         &*cortex_m::peripheral::MPU::PTR
     };
+    program_mpu(mpu, task);
+
+    #[cfg(hubris_trustzone)]
+    {
+        trustzone::apply_memory_protection(task);
+    }
+}
+
+/// Loads `task`'s region table into `mpu`, which may be either the secure or
+/// the non-secure MPU.
+#[cfg(armv8m)]
+fn program_mpu(
+    mpu: &cortex_m::peripheral::mpu::RegisterBlock,
+    task: &task::Task,
+) {
     unsafe {
         disable_mpu(mpu);
     }
@@ -641,6 +740,12 @@ pub fn start_first_task(tick_divisor: u32, task: &mut task::Task) -> ! {
         mpu.ctrl.write(ENABLE | PRIVDEFENA);
     }
 
+    // Safety: we haven't started any tasks yet, non-secure or otherwise.
+    #[cfg(hubris_trustzone)]
+    unsafe {
+        trustzone::start();
+    }
+
     CURRENT_TASK_PTR.store(task, Ordering::Relaxed);
 
     extern "C" {
@@ -843,7 +948,9 @@ pub unsafe extern "C" fn SVCall() {
                     @ now, store volatile registers, plus the PSP in r12, plus LR.
                     stm r2!, {{r4-r12, lr}}
                     vstm r2, {{s16-s31}}
-
+                    ",
+                    save_psp_ns!("r2"),
+                    "
                     @ syscall number is passed in r11. Move it into r0 to pass it as
                     @ an argument to the handler, then call the handler.
                     movs r0, r11
@@ -857,6 +964,9 @@ pub unsafe extern "C" fn SVCall() {
                     ldm r0!, {{r4-r12, lr}}
                     vldm r0, {{s16-s31}}
                     msr PSP, r12
+                    ",
+                    restore_psp_ns!("r0"),
+                    "
 
                     @ resume
                     bx lr
@@ -924,29 +1034,27 @@ static TICKS: [AtomicU32; 2] = {
 #[no_mangle]
 pub unsafe extern "C" fn SysTick() {
     crate::profiling::event_timer_isr_enter();
+
+    let current = CURRENT_TASK_PTR.load(Ordering::Relaxed);
+    uassert!(!current.is_null()); // tick before kernel started?
+
+    // Safety: we're dereferencing the current task pointer, which we're
+    // trusting the rest of this module to maintain correctly.
+    let current = usize::from(unsafe { (*current).descriptor().index });
+
     with_task_table(|tasks| {
-        // Load the time before this tick event.
-        let t0 = TICKS[0].load(Ordering::Relaxed);
-        let t1 = TICKS[1].load(Ordering::Relaxed);
-
-        // Advance the kernel's notion of time by adding 1. Laboriously.
-        let (t0, t1) = if let Some(t0p) = t0.checked_add(1) {
-            // Incrementing t0 did not roll over, no need to update t1.
-            TICKS[0].store(t0p, Ordering::Relaxed);
-            (t0p, t1)
-        } else {
-            // Incrementing t0 overflowed. We need to also increment t1. We use
-            // normal checked addition for this, not wrapping, because this
-            // should not be able to overflow under normal operation, and would
-            // almost certainly indicate state corruption that we'd like to
-            // discover.
-            TICKS[0].store(0, Ordering::Relaxed);
-            TICKS[1].store(t1 + 1, Ordering::Relaxed);
-            (0, t1 + 1)
-        };
+        // Each SysTick normally stands for one tick, but tickless idle may have
+        // stretched this one.
+        #[cfg(feature = "tickless")]
+        let elapsed = tickless::period_ended();
+        #[cfg(not(feature = "tickless"))]
+        let elapsed = 1;
+
+        // Whoever we interrupted gets billed for this tick.
+        tasks[current].charge_ticks(elapsed);
 
         // Process any timers.
-        let now = Timestamp::from([t0, t1]);
+        let now = advance_ticks(elapsed);
         let switch = task::process_timers(tasks, now);
 
         // If any timers fired, we need to defer a context switch, because the entry
@@ -954,10 +1062,45 @@ pub unsafe extern "C" fn SysTick() {
         if switch != task::NextTask::Same {
             pend_context_switch_from_isr();
         }
+
+        // Otherwise, if we're idling, there's no need to tick again until the
+        // next deadline.
+        #[cfg(feature = "tickless")]
+        {
+            if switch == task::NextTask::Same {
+                tickless::idle(tasks, current, now);
+            }
+        }
     });
     crate::profiling::event_timer_isr_exit();
 }
 
+/// Advances the kernel's notion of time by `ticks`, returning the new time.
+fn advance_ticks(ticks: u32) -> Timestamp {
+    // Load the time before this tick event.
+    let t0 = TICKS[0].load(Ordering::Relaxed);
+    let t1 = TICKS[1].load(Ordering::Relaxed);
+
+    // Advance it. Laboriously.
+    let (t0, t1) = if let Some(t0p) = t0.checked_add(ticks) {
+        // Incrementing t0 did not roll over, no need to update t1.
+        TICKS[0].store(t0p, Ordering::Relaxed);
+        (t0p, t1)
+    } else {
+        // Incrementing t0 overflowed. We need to also increment t1. We use
+        // normal checked addition for this, not wrapping, because this
+        // should not be able to overflow under normal operation, and would
+        // almost certainly indicate state corruption that we'd like to
+        // discover.
+        let t0p = t0.wrapping_add(ticks);
+        TICKS[0].store(t0p, Ordering::Relaxed);
+        TICKS[1].store(t1 + 1, Ordering::Relaxed);
+        (t0p, t1 + 1)
+    };
+
+    Timestamp::from([t0, t1])
+}
+
 fn pend_context_switch_from_isr() {
     // This sets the bit to pend a PendSV interrupt. PendSV will happen after
     // the current ISR (and any chained ISRs) returns, and perform the context
@@ -1031,7 +1174,9 @@ pub unsafe extern "C" fn PendSV() {
                     @ now, store volatile registers, plus the PSP in r12, plus LR.
                     stm r1!, {{r4-r12, lr}}
                     vstm r1, {{s16-s31}}
-
+                    ",
+                    save_psp_ns!("r1"),
+                    "
                     bl pendsv_entry
 
                     @ we're returning back to *some* task, maybe not the same one.
@@ -1042,6 +1187,9 @@ pub unsafe extern "C" fn PendSV() {
                     ldm r0!, {{r4-r12, lr}}
                     vldm r0, {{s16-s31}}
                     msr PSP, r12
+                    ",
+                    restore_psp_ns!("r0"),
+                    "
 
                     @ resume
                     bx lr
@@ -1064,6 +1212,16 @@ unsafe extern "C" fn pendsv_entry() {
     let current = CURRENT_TASK_PTR.load(Ordering::Relaxed);
     uassert!(!current.is_null()); // irq before kernel started?
 
+    // Exceptions raised by non-secure tasks are forwarded to us here. Handling
+    // one may change the current task.
+    #[cfg(hubris_trustzone)]
+    let current = {
+        // Safety: the PendSV entry sequence saves state just as the SVCall
+        // one does, which is what this requires.
+        unsafe { trustzone::handle_forwarded_exception(current) };
+        CURRENT_TASK_PTR.load(Ordering::Relaxed)
+    };
+
     // Safety: we're dereferencing the current task pointer, which we're
     // trusting the rest of this module to maintain correctly.
     let current = usize::from(unsafe { (*current).descriptor().index });
@@ -1108,7 +1266,9 @@ pub unsafe extern "C" fn DefaultHandler() {
         // 4=MemManage is handled below
         // 5=BusFault is handled below
         // 6=UsageFault is handled below
-        // 7-10 are currently reserved
+        // 7=SecureFault is handled below on TrustZone builds, and otherwise
+        //   can't occur
+        // 8-10 are currently reserved
         // 11=SVCall is handled above by its own handler
         12 => panic!("DebugMon"),
         // 13 is currently reserved
@@ -1117,9 +1277,26 @@ pub unsafe extern "C" fn DefaultHandler() {
         x if x >= 16 => {
             // Hardware interrupt
             let irq_num = exception_num - 16;
-            let owner = crate::startup::HUBRIS_IRQ_TASK_LOOKUP
+
+            // If this interrupt cut short a tickless idle period, catch the
+            // clock up before anything else looks at it.
+            #[cfg(feature = "tickless")]
+            let woken = tickless::interrupted();
+            #[cfg(not(feature = "tickless"))]
+            let woken = false;
+
+            let owner = match crate::startup::HUBRIS_IRQ_TASK_LOOKUP
                 .get(abi::InterruptNum(irq_num))
-                .unwrap_or_else(|| panic!("unhandled IRQ {irq_num}"));
+            {
+                Some(owner) => owner,
+                // A chip's idle hook may wake us with an interrupt of its own,
+                // which it has already dealt with.
+                None if woken => {
+                    crate::profiling::event_isr_exit();
+                    return;
+                }
+                None => panic!("unhandled IRQ {irq_num}"),
+            };
 
             let switch = with_task_table(|tasks| {
                 disable_irq(irq_num);
@@ -1166,6 +1343,8 @@ enum FaultType {
     MemoryManagement = 4,
     BusFault = 5,
     UsageFault = 6,
+    #[cfg(hubris_trustzone)]
+    SecureFault = 7,
 }
 
 #[naked]
@@ -1199,7 +1378,9 @@ unsafe extern "C" fn configurable_fault() {
             @ lazy saving...
             mov r2, r0
             stm r2!, {{r4-r12, lr}}
-
+            ",
+            save_psp_ns!("r2"),
+            "
             @ Pull our fault number out of IPSR, allowing for program text to be
             @ shared across all configurable faults.  (Note that the exception
             @ number is the bottom 9 bits, but we need only look at the bottom 4
@@ -1218,7 +1399,9 @@ unsafe extern "C" fn configurable_fault() {
             ldm r0!, {{r4-r12, lr}}
             vldm r0, {{s16-s31}}
             msr PSP, r12
-
+            ",
+            restore_psp_ns!("r0"),
+            "
             @ resume
             bx lr
             ",
@@ -1263,6 +1446,19 @@ pub unsafe extern "C" fn UsageFault() {
     unsafe { arch::asm!("b {0}", sym configurable_fault, options(noreturn)) }
 }
 
+/// Initial entry point for handling a secure fault, which is how attempts by
+/// non-secure tasks to reach secure memory are reported.
+#[allow(non_snake_case)]
+#[no_mangle]
+#[naked]
+#[cfg(hubris_trustzone)]
+pub unsafe extern "C" fn SecureFault() {
+    // Safety: this is merely a call (a tailcall, really) to a different handler
+    // -- we're doing it this way simply because the other handler does context
+    // save, so we can't go up into Rust here.
+    unsafe { arch::asm!("b {0}", sym configurable_fault, options(noreturn)) }
+}
+
 /// Initial entry point for handling a hard fault (ARMv6).
 #[allow(non_snake_case)]
 #[no_mangle]
@@ -1594,6 +1790,31 @@ unsafe extern "C" fn handle_fault(
             },
             false,
         ),
+
+        #[cfg(hubris_trustzone)]
+        FaultType::SecureFault => {
+            // The Secure Fault Status Register isn't modeled by `cortex_m`.
+            const SFSR: *mut u32 = 0xE000_EDE4 as *mut u32;
+            const SFAR: *const u32 = 0xE000_EDE8 as *const u32;
+            const SFARVALID: u32 = 1 << 6;
+            // Safety: SFSR is write-one-to-clear, and we're responsible for
+            // clearing it, same as CFSR below.
+            let sfsr = unsafe { core::ptr::read_volatile(SFSR) };
+            unsafe {
+                core::ptr::write_volatile(SFSR, sfsr);
+            }
+            (
+                FaultInfo::MemoryAccess {
+                    address: if sfsr & SFARVALID != 0 {
+                        Some(unsafe { core::ptr::read_volatile(SFAR) })
+                    } else {
+                        None
+                    },
+                    source: FaultSource::User,
+                },
+                false,
+            )
+        }
     };
 
     // Because we are responsible for clearing all conditions, we write back
@@ -0,0 +1,249 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Tickless idle.
+//!
+//! Hubris doesn't time-slice, so the only thing the periodic tick does is
+//! notice when timers expire. While the idle task is running, every tick before
+//! the next deadline is pure overhead, and it wakes the processor from sleep
+//! besides. With the `tickless` feature, when SysTick finds that it has
+//! interrupted the idle task, it reprograms itself to fire once, at the next
+//! deadline, instead of every tick until then.
+//!
+//! If some other interrupt wakes the processor first, `DefaultHandler` calls
+//! `interrupted` before dispatching it. That works out how many whole ticks
+//! have passed, adds them to the kernel's clock, and puts SysTick back on its
+//! normal schedule by having it fire at the end of the tick in progress.
+//!
+//! SysTick is clocked by the core, and so stops in most chips' deeper sleep
+//! states. A chip that wants to use those can install `IdleHooks`, which take
+//! over waking up -- usually with a low-power timer -- and report back how long
+//! the processor slept.
+
+use core::sync::atomic::{AtomicBool, AtomicPtr, AtomicU32, Ordering};
+
+use super::{CLOCK_FREQ_KHZ, CURRENT_TASK_PTR};
+use crate::descs::TaskFlags;
+use crate::startup::with_task_table;
+use crate::task;
+use crate::time::Timestamp;
+
+/// Chip-specific support for sleeping more deeply than SysTick allows. Install
+/// with `set_idle_hooks`.
+pub struct IdleHooks {
+    /// Called from the SysTick handler when the idle task is running and no
+    /// deadline falls due for `ticks` ticks (`u32::MAX` if none is set at all).
+    ///
+    /// To take over, the hook arms a wakeup interrupt to arrive no later than
+    /// that, prepares whatever sleep state the idle task's `WFI` should enter
+    /// (e.g. by setting `SLEEPDEEP`), and returns `true`; the kernel then stops
+    /// SysTick. Returning `false` leaves the kernel to stretch SysTick instead,
+    /// which is the right answer for waits too short to be worth it.
+    pub enter: fn(ticks: u32) -> bool,
+    /// Called on the first interrupt, of any kind, after `enter` returns
+    /// `true`. This must undo whatever `enter` did, including disarming its
+    /// wakeup interrupt, and return how many whole ticks passed in the
+    /// meantime. If the interrupt was the hook's own and no task owns it, the
+    /// kernel drops it after this returns.
+    pub exit: fn() -> u32,
+}
+
+/// Chip hooks installed by `set_idle_hooks`, or null.
+static IDLE_HOOKS: AtomicPtr<IdleHooks> = AtomicPtr::new(core::ptr::null_mut());
+
+/// How many ticks the SysTick period in progress stands for. This is 1 unless
+/// we've stretched it, and 0 while an idle hook has stopped SysTick.
+static PERIOD_TICKS: AtomicU32 = AtomicU32::new(1);
+
+/// Set when SysTick's reload value is something other than one tick, so that
+/// the next SysTick knows to put it back.
+static RELOAD_ALTERED: AtomicBool = AtomicBool::new(false);
+
+/// SysTick's counter is only 24 bits wide, which bounds how far we can stretch
+/// a period.
+const SYST_MAX_RELOAD: u32 = 0x00FF_FFFF;
+
+const SYST_CSR_ENABLE: u32 = 1 << 0;
+
+/// Installs chip-specific idle hooks.
+///
+/// # Safety
+///
+/// This must be called before `start_kernel`, and only once.
+pub unsafe fn set_idle_hooks(hooks: &'static IdleHooks) {
+    IDLE_HOOKS.store(hooks as *const _ as *mut _, Ordering::Relaxed);
+}
+
+fn syst() -> &'static cortex_m::peripheral::syst::RegisterBlock {
+    // Safety: we're only ever going to take shared references to this, and
+    // only from the kernel, which can't be preempted by anything else that
+    // touches SysTick.
+    unsafe { &*cortex_m::peripheral::SYST::PTR }
+}
+
+/// Restarts SysTick's count from the top, with a period of `cycles`.
+fn restart(cycles: u32) {
+    let syst = syst();
+    // Safety: reprogramming SysTick can only make our clock wrong, which is
+    // bad, but not unsafe.
+    unsafe {
+        // A reload value of zero would stop the interrupt entirely.
+        syst.rvr.write(cycles.saturating_sub(1).max(1));
+        // Writing any value clears the count, so it reloads on the next cycle.
+        syst.cvr.write(0);
+    }
+}
+
+/// Called by the SysTick handler to find out how many ticks the period that
+/// just ended stood for. Puts SysTick back to one tick per period if needed.
+pub(super) fn period_ended() -> u32 {
+    let ticks = PERIOD_TICKS.load(Ordering::Relaxed);
+    if RELOAD_ALTERED.load(Ordering::Relaxed) {
+        restart(CLOCK_FREQ_KHZ.load(Ordering::Relaxed));
+        RELOAD_ALTERED.store(false, Ordering::Relaxed);
+        PERIOD_TICKS.store(1, Ordering::Relaxed);
+    }
+    ticks
+}
+
+/// Called by the SysTick handler after processing timers, when that didn't
+/// make any other task runnable. If `current` is the idle task, arranges for
+/// the next SysTick to arrive at the next deadline, or hands off to the chip's
+/// idle hook.
+pub(super) fn idle(tasks: &[task::Task], current: usize, now: Timestamp) {
+    if !tasks[current].descriptor().flags.contains(TaskFlags::IDLE) {
+        return;
+    }
+
+    let ticks = match task::next_deadline(tasks) {
+        Some(deadline) => {
+            let ticks = u64::from(deadline).saturating_sub(u64::from(now));
+            u32::try_from(ticks).unwrap_or(u32::MAX)
+        }
+        None => u32::MAX,
+    };
+    // Timers that are due now, or on the very next tick, don't leave anything
+    // to save.
+    if ticks <= 1 {
+        return;
+    }
+
+    let hooks = IDLE_HOOKS.load(Ordering::Relaxed);
+    if !hooks.is_null() {
+        // Safety: this pointer can only have come from the &'static passed to
+        // set_idle_hooks.
+        let hooks = unsafe { &*hooks };
+        if (hooks.enter)(ticks) {
+            // Safety: stopping SysTick is safe; the chip has promised to wake
+            // us up instead.
+            unsafe {
+                syst().csr.modify(|v| v & !SYST_CSR_ENABLE);
+            }
+            PERIOD_TICKS.store(0, Ordering::Relaxed);
+            return;
+        }
+    }
+
+    let divisor = CLOCK_FREQ_KHZ.load(Ordering::Relaxed);
+    let ticks = ticks.min(SYST_MAX_RELOAD / divisor);
+    if ticks > 1 {
+        restart(ticks * divisor);
+        PERIOD_TICKS.store(ticks, Ordering::Relaxed);
+        RELOAD_ALTERED.store(true, Ordering::Relaxed);
+    }
+}
+
+/// Called by `DefaultHandler` before dispatching an interrupt. If we were
+/// idling tickless, accounts for the time that has passed and goes back to
+/// ticking normally.
+///
+/// Returns `true` if the chip's idle hook had stopped SysTick, in which case
+/// the interrupt may be the hook's own wakeup source.
+pub(super) fn interrupted() -> bool {
+    match PERIOD_TICKS.load(Ordering::Relaxed) {
+        // Business as usual.
+        1 => false,
+
+        0 => {
+            // Safety: we only get into this state with hooks installed; see
+            // `idle`.
+            let hooks = unsafe { &*IDLE_HOOKS.load(Ordering::Relaxed) };
+            let slept = (hooks.exit)();
+
+            restart(CLOCK_FREQ_KHZ.load(Ordering::Relaxed));
+            // Safety: this is restarting the SysTick we stopped in `idle`.
+            unsafe {
+                syst().csr.modify(|v| v | SYST_CSR_ENABLE);
+            }
+            PERIOD_TICKS.store(1, Ordering::Relaxed);
+
+            // The chip may have overslept a deadline, so unlike the stretched
+            // case below, we need to check the timers ourselves.
+            catch_up(slept, true);
+            true
+        }
+
+        _ => {
+            let syst = syst();
+
+            // Stop SysTick while we look at it, so that it can't wrap between
+            // our reading the count and reprogramming it. The clock loses the
+            // few cycles that it's stopped for.
+            //
+            // Safety: we start it again below, on every path.
+            unsafe {
+                syst.csr.modify(|v| v & !SYST_CSR_ENABLE);
+            }
+
+            // If the period ran out before we stopped it, SysTick is pending
+            // and will account for all of it.
+            if cortex_m::peripheral::SCB::is_pendst_pending() {
+                // Safety: this is restarting the SysTick we just stopped.
+                unsafe {
+                    syst.csr.modify(|v| v | SYST_CSR_ENABLE);
+                }
+                return false;
+            }
+
+            // The count runs down from the reload value to zero, and then
+            // takes a cycle to reload, so a period is one cycle longer than
+            // the reload value.
+            let divisor = CLOCK_FREQ_KHZ.load(Ordering::Relaxed);
+            let cycles = syst.rvr.read() + 1 - syst.cvr.read();
+            // Fire again at the end of the tick we're partway through, at which
+            // point SysTick will see RELOAD_ALTERED and restore the usual
+            // period.
+            restart(divisor - cycles % divisor);
+            // Safety: this is restarting the SysTick we stopped above.
+            unsafe {
+                syst.csr.modify(|v| v | SYST_CSR_ENABLE);
+            }
+            PERIOD_TICKS.store(1, Ordering::Relaxed);
+
+            // The period was chosen to end at the next deadline, so no timers
+            // can have come due yet.
+            catch_up(cycles / divisor, false);
+            false
+        }
+    }
+}
+
+/// Advances the clock by `ticks` spent in the idle task, and, if requested,
+/// processes timers.
+fn catch_up(ticks: u32, check_timers: bool) {
+    let current = CURRENT_TASK_PTR.load(Ordering::Relaxed);
+    // Safety: we're dereferencing the current task pointer, which we're
+    // trusting the rest of this module to maintain correctly.
+    let current = usize::from(unsafe { (*current).descriptor().index });
+
+    with_task_table(|tasks| {
+        tasks[current].charge_ticks(ticks);
+        let now = super::advance_ticks(ticks);
+        if check_timers
+            && task::process_timers(tasks, now) != task::NextTask::Same
+        {
+            super::pend_context_switch_from_isr();
+        }
+    });
+}
@@ -0,0 +1,347 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Support for running tasks in the non-secure state on ARMv8-M parts with the
+//! Security Extension.
+//!
+//! The kernel, and any task not marked `non-secure` in the app config, runs
+//! secure. A non-secure task differs in three ways:
+//!
+//! 1. It's entered with an `EXC_RETURN` that selects the non-secure stack, so
+//!    its exception frame lives at `PSP_NS` rather than `PSP`.
+//! 2. While it's current, its memory regions -- peripherals included -- are
+//!    marked non-secure in the SAU and loaded into the non-secure MPU.
+//!    Everything else, including the memory of every other task, stays secure
+//!    and is unreachable from it.
+//! 3. Its exceptions -- notably `SVC` -- are taken to the *non-secure*
+//!    vector table. We point that at a single trampoline, which calls back
+//!    into the kernel through a secure gateway. The gateway records the
+//!    exception number and pends PendSV, and the PendSV handler then treats
+//!    the exception as if it had been taken by the kernel directly.
+//!
+//! Interrupts are left targeting the secure state, so they continue to be
+//! routed to their owning tasks by the kernel as usual.
+
+use core::arch;
+use core::sync::atomic::{AtomicU32, Ordering};
+
+use super::{Cfsr, SavedState};
+use crate::descs::TaskFlags;
+use crate::startup::with_task_table;
+use crate::task;
+use abi::{FaultInfo, FaultSource};
+
+/// The `S` bit of `EXC_RETURN`, which selects the secure stack on return.
+pub(super) const EXC_RETURN_S: u32 = 1 << 6;
+
+/// `EXC_RETURN` used to enter a non-secure task. This is the usual value with
+/// `S` cleared; `ES` stays set because the exception is still being taken to
+/// (and returned from) the secure kernel.
+const EXC_RETURN_NON_SECURE: u32 = super::EXC_RETURN_CONST & !EXC_RETURN_S;
+
+/// Non-secure Access Control Register.
+const NSACR: *mut u32 = 0xE000_ED8C as *mut u32;
+/// Non-secure alias of the Vector Table Offset Register.
+const VTOR_NS: *mut u32 = 0xE002_ED08 as *mut u32;
+/// Non-secure alias of the System Handler Control and State Register.
+const SHCSR_NS: *mut u32 = 0xE002_ED24 as *mut u32;
+/// Non-secure alias of the Configurable Fault Status Register.
+const CFSR_NS: *mut u32 = 0xE002_ED28 as *mut u32;
+/// Non-secure alias of the MemManage Fault Address Register.
+const MMFAR_NS: *const u32 = 0xE002_ED34 as *const u32;
+/// Non-secure alias of the MPU.
+const MPU_NS: *const cortex_m::peripheral::mpu::RegisterBlock =
+    0xE002_ED90 as *const _;
+
+/// Security Attribution Unit registers.
+const SAU_CTRL: *mut u32 = 0xE000_EDD0 as *mut u32;
+const SAU_TYPE: *const u32 = 0xE000_EDD4 as *const u32;
+const SAU_RNR: *mut u32 = 0xE000_EDD8 as *mut u32;
+const SAU_RBAR: *mut u32 = 0xE000_EDDC as *mut u32;
+const SAU_RLAR: *mut u32 = 0xE000_EDE0 as *mut u32;
+
+const SAU_RLAR_ENABLE: u32 = 1 << 0;
+const SAU_RLAR_NSC: u32 = 1 << 1;
+
+/// SAU region covering the non-secure trampoline and vector table.
+const SAU_REGION_NS_TEXT: u32 = 0;
+/// SAU region covering the secure gateway veneers.
+const SAU_REGION_VENEERS: u32 = 1;
+/// First SAU region available to describe the current task's memory.
+const SAU_FIRST_TASK_REGION: u32 = 2;
+
+/// Exception number recorded by the secure gateway for PendSV to pick up, or
+/// zero if there isn't one.
+static NS_EXCEPTION: AtomicU32 = AtomicU32::new(0);
+
+/// Exception number for `SVC`.
+const SVCALL: u32 = 11;
+
+extern "C" {
+    // Exposed by the linker script.
+    static __trustzone_ns_base: u32;
+    static __trustzone_ns_limit: u32;
+    static __veneer_base: u32;
+    static __veneer_limit: u32;
+}
+
+fn is_non_secure(task: &task::Task) -> bool {
+    task.descriptor().flags.contains(TaskFlags::NON_SECURE)
+}
+
+/// Fixes up the initial state of a non-secure task, after the common code has
+/// built its first exception frame.
+pub(super) fn reinitialize(task: &mut task::Task) {
+    if !is_non_secure(task) {
+        return;
+    }
+    let save: &mut SavedState = task.save_mut();
+    // The frame is the same either way; it just needs to be found through the
+    // non-secure stack pointer.
+    save.psp_ns = save.psp;
+    save.exc_return = EXC_RETURN_NON_SECURE;
+}
+
+/// Configures the non-secure state before the first task is started.
+///
+/// # Safety
+///
+/// This must be called once, from `start_first_task`, before any task runs.
+pub(super) unsafe fn start() {
+    // Safety: these are all architectural registers, and nothing non-secure
+    // can be running yet for us to disturb.
+    unsafe {
+        // Let non-secure code use the FPU (CP10 and CP11).
+        let nsacr = core::ptr::read_volatile(NSACR);
+        core::ptr::write_volatile(NSACR, nsacr | 0b11 << 10);
+
+        // Non-secure thread mode is unprivileged, like its secure counterpart.
+        arch::asm!(
+            ".inst.w 0xf3808894      @ msr CONTROL_NS, r0",
+            in("r0") 1u32,
+            options(nomem, nostack, preserves_flags),
+        );
+
+        // Route non-secure exceptions to our trampoline, and have the faults
+        // we can diagnose show up there too rather than as HardFault.
+        core::ptr::write_volatile(
+            VTOR_NS,
+            core::ptr::addr_of!(HUBRIS_NS_VECTORS) as u32,
+        );
+        const MEMFAULTENA: u32 = 1 << 16;
+        const USGFAULTENA: u32 = 1 << 18;
+        let shcsr = core::ptr::read_volatile(SHCSR_NS);
+        core::ptr::write_volatile(SHCSR_NS, shcsr | MEMFAULTENA | USGFAULTENA);
+
+        // The trampoline is the one bit of non-secure code the kernel
+        // provides, and the veneers are its only way back in.
+        sau_write(
+            SAU_REGION_NS_TEXT,
+            core::ptr::addr_of!(__trustzone_ns_base) as u32,
+            core::ptr::addr_of!(__trustzone_ns_limit) as u32,
+            SAU_RLAR_ENABLE,
+        );
+        sau_write(
+            SAU_REGION_VENEERS,
+            core::ptr::addr_of!(__veneer_base) as u32,
+            core::ptr::addr_of!(__veneer_limit) as u32,
+            SAU_RLAR_ENABLE | SAU_RLAR_NSC,
+        );
+        for rnr in SAU_FIRST_TASK_REGION..sau_region_count() {
+            sau_write(rnr, 0, 0, 0);
+        }
+        core::ptr::write_volatile(SAU_CTRL, 1);
+    }
+    cortex_m::asm::dsb();
+    cortex_m::asm::isb();
+}
+
+fn sau_region_count() -> u32 {
+    // Safety: this is a read-only identification register.
+    unsafe { core::ptr::read_volatile(SAU_TYPE) & 0xFF }
+}
+
+/// Programs SAU region `rnr` to cover `base..limit` with the given `RLAR`
+/// flags. `base` must be 32-byte aligned; `limit` is exclusive, and is rounded
+/// up to the SAU's 32-byte granule, since peripheral sizes needn't be
+/// multiples of it. An empty range leaves the region disabled.
+///
+/// # Safety
+///
+/// Changing security attribution out from under running code will cause it to
+/// fault, so this must only be called from the kernel.
+unsafe fn sau_write(rnr: u32, base: u32, limit: u32, flags: u32) {
+    // Safety: our caller has promised this is a reasonable thing to do.
+    unsafe {
+        core::ptr::write_volatile(SAU_RNR, rnr);
+        if limit <= base {
+            core::ptr::write_volatile(SAU_RLAR, 0);
+        } else {
+            core::ptr::write_volatile(SAU_RBAR, base);
+            core::ptr::write_volatile(SAU_RLAR, ((limit - 1) & !0x1F) | flags);
+        }
+    }
+}
+
+/// Makes `task`'s memory -- and only `task`'s memory -- visible to the
+/// non-secure state, if `task` is non-secure. This is called after the secure
+/// MPU has been loaded.
+pub(super) fn apply_memory_protection(task: &task::Task) {
+    let non_secure = is_non_secure(task);
+    if non_secure {
+        // Safety: the non-secure MPU is only ever touched by the kernel, and
+        // we're not taking a &mut, same as for the secure one.
+        let mpu_ns = unsafe { &*MPU_NS };
+        super::program_mpu(mpu_ns, task);
+    }
+
+    // Device regions are included: the SAU and the IDAU each get a say in
+    // whether an address is secure, and the more secure answer wins, so a
+    // peripheral the SAU leaves secure can't be reached from the non-secure
+    // state no matter how the rest of the chip is configured.
+    //
+    // The build system checks that every non-secure task's regions fit in
+    // the SAU; should one not, we'd rather find out here than have its
+    // memory quietly stay secure.
+    let mut regions = task
+        .region_table()
+        .iter()
+        .filter(|region| non_secure && region.size != 0);
+    for rnr in SAU_FIRST_TASK_REGION..sau_region_count() {
+        let (base, limit) = regions
+            .next()
+            .map(|region| (region.base, region.base + region.size))
+            .unwrap_or((0, 0));
+        // Safety: the task being switched to isn't running yet, and the
+        // kernel's own memory is never made non-secure.
+        unsafe {
+            sau_write(rnr, base, limit, SAU_RLAR_ENABLE);
+        }
+    }
+    if regions.next().is_some() {
+        panic!("task regions exceed SAU");
+    }
+    cortex_m::asm::dsb();
+    cortex_m::asm::isb();
+}
+
+/// Handles an exception forwarded from the non-secure state, if the secure
+/// gateway recorded one. This may switch tasks, or fault the current one; the
+/// caller is expected to reschedule either way.
+///
+/// # Safety
+///
+/// `task` must be the current task pointer, and its state must have been saved
+/// on entry to PendSV as it would be on entry to SVCall.
+pub(super) unsafe fn handle_forwarded_exception(task: *mut task::Task) {
+    let exception = NS_EXCEPTION.swap(0, Ordering::Relaxed);
+    if exception == 0 {
+        return;
+    }
+
+    if exception == SVCALL {
+        // Safety: the syscall number is in r11 just as it would be for a
+        // secure task, and our caller has met syscall_entry's requirements.
+        unsafe {
+            let nr = (*task).save().r11;
+            crate::syscalls::syscall_entry(nr, task);
+        }
+        return;
+    }
+
+    // Anything else is a fault. The non-secure fault status registers are
+    // banked, so we decode them here rather than in `handle_fault`.
+    //
+    // Safety: these are architectural registers; CFSR is write-one-to-clear.
+    let cfsr =
+        Cfsr::from_bits_truncate(unsafe { core::ptr::read_volatile(CFSR_NS) });
+    let fault = match exception {
+        4 => {
+            if cfsr.contains(Cfsr::IACCVIOL) {
+                FaultInfo::IllegalText
+            } else {
+                FaultInfo::MemoryAccess {
+                    address: if cfsr.contains(Cfsr::MMARVALID) {
+                        Some(unsafe { core::ptr::read_volatile(MMFAR_NS) })
+                    } else {
+                        None
+                    },
+                    source: FaultSource::User,
+                }
+            }
+        }
+        6 if cfsr.contains(Cfsr::DIVBYZERO) => FaultInfo::DivideByZero,
+        6 if cfsr.contains(Cfsr::UNDEFINSTR) => FaultInfo::IllegalInstruction,
+        _ => FaultInfo::InvalidOperation(cfsr.bits()),
+    };
+    unsafe {
+        core::ptr::write_volatile(CFSR_NS, cfsr.bits());
+    }
+
+    // Safety: our caller guarantees `task` is valid; we drop the reference
+    // before taking the task table.
+    let idx = usize::from(unsafe { (*task).descriptor().index });
+    with_task_table(|tasks| {
+        // The faulted task is no longer runnable, so the caller's reschedule
+        // will pick the supervisor (or whoever outranks it) on its own.
+        let _ = task::force_fault(tasks, idx, fault);
+    });
+}
+
+/// Vector table for the non-secure state. The initial stack pointer and reset
+/// entries are never used, since the kernel never resets into non-secure code,
+/// so every slot simply points at the trampoline.
+#[link_section = ".trustzone_ns.vectors"]
+#[used]
+static HUBRIS_NS_VECTORS: [unsafe extern "C" fn(); 16] = [ns_trap; 16];
+
+/// Non-secure exception handler. This runs in non-secure handler mode, and
+/// so can do very little besides tell the kernel which exception it was and
+/// return; the kernel gets control again through PendSV as soon as it does.
+#[naked]
+#[link_section = ".trustzone_ns.text"]
+unsafe extern "C" fn ns_trap() {
+    unsafe {
+        arch::asm!(
+            "
+            mrs r0, IPSR
+            mov r2, lr
+            movw r1, #:lower16:{gateway}
+            movt r1, #:upper16:{gateway}
+            blx r1
+            bx r2
+            ",
+            gateway = sym hubris_ns_gateway,
+            options(noreturn),
+        )
+    }
+}
+
+/// Secure gateway used by `ns_trap`. Takes the non-secure exception number in
+/// r0, stashes it for PendSV, and returns to the non-secure state without
+/// leaving anything secure in the registers.
+#[naked]
+#[no_mangle]
+#[link_section = ".gnu.sgstubs.hubris"]
+unsafe extern "C" fn hubris_ns_gateway() {
+    unsafe {
+        arch::asm!(
+            "
+            .inst.w 0xe97fe97f      @ sg
+            movw r1, #:lower16:{exception}
+            movt r1, #:upper16:{exception}
+            str r0, [r1]
+            movw r1, #0xed04        @ ICSR
+            movt r1, #0xe000
+            mov.w r0, #0x10000000   @ PENDSVSET
+            str r0, [r1]
+            movs r0, #0
+            movs r1, #0
+            .inst.n 0x4774          @ bxns lr
+            ",
+            exception = sym NS_EXCEPTION,
+            options(noreturn),
+        )
+    }
+}
@@ -7,7 +7,7 @@
 use crate::umem::USlice;
 use serde::{Deserialize, Serialize};
 
-pub(crate) const REGIONS_PER_TASK: usize = 8;
+pub(crate) use abi::REGIONS_PER_TASK;
 
 /// Indicates priority of a task.
 ///
@@ -73,7 +73,15 @@ bitflags::bitflags! {
     #[repr(transparent)]
     pub struct TaskFlags: u8 {
         const START_AT_BOOT = 1 << 0;
-        const RESERVED = !1;
+        /// The task runs at the priority of the most important task blocked
+        /// sending to it, if that's more important than its own.
+        const INHERIT_PRIORITY = 1 << 1;
+        /// The task runs in the non-secure state. Only meaningful on ARMv8-M
+        /// parts with TrustZone.
+        const NON_SECURE = 1 << 2;
+        /// The task is the idle task, which runs only when nothing else can.
+        const IDLE = 1 << 3;
+        const RESERVED = !0b1111;
     }
 }
 
@@ -12,6 +12,7 @@ use crate::task::{current_id, ArchState, NextTask, Task};
 use crate::umem::USlice;
 use core::convert::TryFrom;
 use core::mem::size_of;
+use unwrap_lite::UnwrapLite;
 
 /// Message dispatcher.
 pub fn handle_kernel_message(
@@ -31,6 +32,12 @@ pub fn handle_kernel_message(
             read_image_id(tasks, caller, args.response?)
         }
         Ok(Kipcnum::Reset) => reset(tasks, caller, args.message?),
+        Ok(Kipcnum::FindFaultedTasks) => {
+            find_faulted_tasks(tasks, caller, args.response?)
+        }
+        Ok(Kipcnum::ReadTaskRuntime) => {
+            read_task_runtime(tasks, caller, args.message?, args.response?)
+        }
         #[cfg(feature = "dump")]
         Ok(Kipcnum::GetTaskDumpRegion) => {
             get_task_dump_region(tasks, caller, args.message?, args.response?)
@@ -122,7 +129,12 @@ fn restart_task(
         )));
     }
     let old_id = current_id(tasks, index);
+    let waited_on = crate::task::blocked_on(tasks, index);
     tasks[index].reinitialize();
+    if let Some(w) = waited_on {
+        // The restarted task is no longer lending its priority to anyone.
+        crate::task::update_inherited_priority(tasks, w);
+    }
     if start {
         tasks[index].set_healthy_state(SchedState::Runnable);
     }
@@ -223,6 +235,64 @@ fn read_image_id(
     Ok(NextTask::Same)
 }
 
+/// Reports every task that is in the `Faulted` state, as a bitmap in which bit
+/// `i % 8` of byte `i / 8` is set if task `i` is faulted. As with
+/// `serialize_response`, a response buffer too small to hold a bit for every
+/// task is tolerated: nothing is written, and the length we return is the size
+/// that would have worked.
+fn find_faulted_tasks(
+    tasks: &mut [Task],
+    caller: usize,
+    mut response: USlice<u8>,
+) -> Result<NextTask, UserError> {
+    let len = (tasks.len() + 7) / 8;
+
+    // We need to look at every other task while writing into the caller's
+    // memory. The caller itself is sending to us, so it isn't faulted.
+    let (before, rest) = tasks.split_at_mut(caller);
+    let (me, after) = rest.split_first_mut().unwrap_lite();
+    let is_faulted =
+        |task: &Task| matches!(task.state(), TaskState::Faulted { .. });
+    let faulted = before
+        .iter()
+        .map(is_faulted)
+        .chain(core::iter::once(false))
+        .chain(after.iter().map(is_faulted));
+
+    let buf = me.try_write(&mut response)?;
+    if let Some(buf) = buf.get_mut(..len) {
+        buf.fill(0);
+        for (i, _) in faulted.enumerate().filter(|&(_, f)| f) {
+            buf[i / 8] |= 1 << (i % 8);
+        }
+    }
+
+    me.save_mut().set_send_response_and_length(0, len);
+    Ok(NextTask::Same)
+}
+
+fn read_task_runtime(
+    tasks: &mut [Task],
+    caller: usize,
+    message: USlice<u8>,
+    response: USlice<u8>,
+) -> Result<NextTask, UserError> {
+    let index: u32 = deserialize_message(&tasks[caller], message)?;
+    if index as usize >= tasks.len() {
+        return Err(UserError::Unrecoverable(FaultInfo::SyscallUsage(
+            UsageError::TaskOutOfRange,
+        )));
+    }
+    let runtime = tasks[index as usize].runtime();
+
+    let response_len =
+        serialize_response(&mut tasks[caller], response, &runtime)?;
+    tasks[caller]
+        .save_mut()
+        .set_send_response_and_length(0, response_len);
+    Ok(NextTask::Same)
+}
+
 #[cfg(feature = "dump")]
 fn get_task_dump_region(
     tasks: &mut [Task],
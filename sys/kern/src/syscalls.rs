@@ -112,6 +112,7 @@ fn safe_syscall_entry(nr: u32, current: usize, tasks: &mut [Task]) -> NextTask {
         Ok(Sysnum::ReplyFault) => {
             reply_fault(tasks, current).map_err(UserError::from)
         }
+        Ok(Sysnum::SendTimeout) => send_timeout(tasks, current),
         Err(_) => {
             // Bogus syscall number! That's a fault.
             Err(FaultInfo::SyscallUsage(UsageError::BadSyscallNumber).into())
@@ -137,8 +138,47 @@ fn safe_syscall_entry(nr: u32, current: usize, tasks: &mut [Task]) -> NextTask {
 ///
 /// If `caller` is out of range for `tasks`.
 fn send(tasks: &mut [Task], caller: usize) -> Result<NextTask, UserError> {
+    // Forget any deadline left over from an earlier SEND_TIMEOUT, so that our
+    // lease table gets read, and we don't get woken early.
+    tasks[caller].set_send_deadline(None);
+    send_common(tasks, caller)
+}
+
+/// Implementation of the SEND_TIMEOUT IPC primitive.
+///
+/// This behaves like SEND without leases, except that if the caller is still
+/// waiting on the callee -- to receive, or to reply -- when the deadline
+/// passes, the kernel gives up on its behalf and resumes it with
+/// `SEND_TIMEOUT_CODE`. The check happens on timer ticks, so a deadline that
+/// has already passed takes effect at the next tick.
+///
+/// `caller` is a valid task index (i.e. not directly from user code).
+///
+/// # Panics
+///
+/// If `caller` is out of range for `tasks`.
+fn send_timeout(
+    tasks: &mut [Task],
+    caller: usize,
+) -> Result<NextTask, UserError> {
+    let deadline = tasks[caller].save().as_send_timeout_deadline();
+    tasks[caller].set_send_deadline(Some(deadline));
+    let result = send_common(tasks, caller);
+    if result.is_err() {
+        // We never blocked, so there's nothing for the deadline to end.
+        tasks[caller].set_send_deadline(None);
+    }
+    result
+}
+
+/// Shared part of SEND and SEND_TIMEOUT, run after the caller's send deadline
+/// (if any) has been recorded.
+fn send_common(
+    tasks: &mut [Task],
+    caller: usize,
+) -> Result<NextTask, UserError> {
     // Extract callee.
-    let callee_id = tasks[caller].save().as_send_args().callee;
+    let callee_id = tasks[caller].send_args().callee;
 
     // Check IPC filter - TODO
     // Open question: should out-of-range task IDs be handled by faulting below,
@@ -163,7 +203,9 @@ fn send(tasks: &mut [Task], caller: usize) -> Result<NextTask, UserError> {
         match deliver(tasks, caller, callee) {
             Ok(_) => {
                 // Delivery succeeded! The initiating task is now blocked in
-                // reply. Switch directly to the callee.
+                // reply. Switch directly to the callee, lending it our
+                // priority if it wants it.
+                task::update_inherited_priority(tasks, callee);
                 return Ok(NextTask::Specific(callee));
             }
             Err(interact) => {
@@ -181,6 +223,9 @@ fn send(tasks: &mut [Task], caller: usize) -> Result<NextTask, UserError> {
     // Caller needs to block sending, callee is either busy or
     // faulted.
     tasks[caller].set_healthy_state(SchedState::InSend(callee_id));
+    // If the callee inherits priority, it should now get to run at least as
+    // soon as we would have, so it can get around to receiving from us.
+    task::update_inherited_priority(tasks, callee);
     // We may not know what task to run next, but we're pretty sure it isn't the
     // caller.
     Ok(NextTask::Other.combine(next_task))
@@ -343,7 +388,7 @@ fn reply(tasks: &mut [Task], caller: usize) -> Result<NextTask, FaultInfo> {
 
     // Collect information about the callee's reply buffer. This, too, is
     // somewhere we can read infallibly.
-    let send_args = tasks[callee].save().as_send_args();
+    let send_args = tasks[callee].send_args();
     let dest_slice = match send_args.response {
         Ok(buffer) => buffer,
         Err(e) => {
@@ -380,10 +425,29 @@ fn reply(tasks: &mut [Task], caller: usize) -> Result<NextTask, FaultInfo> {
         .save_mut()
         .set_send_response_and_length(reply_args.response_code, amount_copied);
     tasks[callee].set_healthy_state(SchedState::Runnable);
+    // A timely reply ends any SEND_TIMEOUT, which mustn't go on to wake us
+    // (or anyone) when its deadline passes.
+    tasks[callee].set_send_deadline(None);
+
+    // If we were running on priority borrowed from the callee, give it back.
+    let priority = tasks[caller].priority();
+    task::update_inherited_priority(tasks, caller);
+    if tasks[caller].priority() != priority {
+        // Someone else, likely the callee, may now be more important than us.
+        return Ok(NextTask::Other);
+    }
 
-    // KEY ASSUMPTION: sends go from less important tasks to more important
-    // tasks. As a result, Reply doesn't have scheduling implications unless
-    // the task using it faults.
+    // Sends almost always go from less important tasks to more important
+    // tasks (or to tasks that inherit priority, handled above), in which case
+    // the sender can wait until we block. The exception is the supervisor,
+    // which uses SEND_TIMEOUT to talk to less important tasks, and should get
+    // the CPU back as soon as they reply.
+    if tasks[callee]
+        .priority()
+        .is_more_important_than(tasks[caller].priority())
+    {
+        return Ok(NextTask::Specific(callee));
+    }
     Ok(NextTask::Same)
 }
 
@@ -535,7 +599,7 @@ fn borrow_lease(
         return Err(UserError::Recoverable(abi::DEFECT, NextTask::Same));
     }
 
-    let largs = tasks[lender].save().as_send_args();
+    let largs = tasks[lender].send_args();
     let leases = match largs.lease_table {
         Ok(t) => t,
         Err(e) => {
@@ -655,7 +719,7 @@ fn deliver(
     // Collect information on the send from the caller. This information is all
     // stored in infallibly-readable areas, but our accesses can fail if the
     // caller handed us bogus slices.
-    let send_args = tasks[caller].save().as_send_args();
+    let send_args = tasks[caller].send_args();
     let src_slice = send_args.message.map_err(InteractFault::in_src)?;
     let response_capacity =
         send_args.response.map_err(InteractFault::in_src)?.len();
@@ -807,12 +871,18 @@ fn reply_fault(
     }
 
     // Check and deliver the fault. We explicitly discard its scheduling hint,
-    // because the caller is lower priority than we are.
+    // because the caller is lower priority than we are...
+    let priority = tasks[caller].priority();
     let _hint = task::force_fault(
         tasks,
         callee,
         FaultInfo::FromServer(caller_id, reason),
     );
+    // ...unless we were only more important because we'd inherited its
+    // priority, which faulting it just took away.
+    if tasks[caller].priority() != priority {
+        return Ok(NextTask::Other);
+    }
 
     // KEY ASSUMPTION: sends go from less important tasks to more important
     // tasks. As a result, Reply doesn't have scheduling implications unless
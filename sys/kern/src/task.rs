@@ -47,6 +47,15 @@ pub struct Task {
     /// Notification status.
     notifications: u32,
 
+    /// Number of timer ticks that have landed while this task was running,
+    /// since boot. This is not reset on restart.
+    runtime: u64,
+
+    /// Deadline for the task's current `SEND_TIMEOUT`, if it's blocked in one.
+    /// This is cleared when the send ends -- by reply, by timeout, or by the
+    /// task faulting -- and by a plain `SEND`.
+    send_deadline: Option<Timestamp>,
+
     /// Pointer to the ROM descriptor used to create this task, so it can be
     /// restarted.
     descriptor: &'static TaskDesc,
@@ -68,6 +77,8 @@ impl Task {
 
             generation: 0,
             notifications: 0,
+            runtime: 0,
+            send_deadline: None,
             save: crate::arch::SavedState::default(),
             timer: crate::task::TimerState::default(),
         }
@@ -222,11 +233,37 @@ impl Task {
             return true;
         }
         let forbidden = forbidden | RegionAttributes::DEVICE;
-        self.region_table().iter().any(|region| {
-            region.covers(slice)
-                && region.attributes.contains(desired)
+        let usable = |region: &&&RegionDesc| {
+            region.attributes.contains(desired)
                 && !region.attributes.intersects(forbidden)
-        })
+        };
+
+        // On chips that need power-of-two regions, xtask may split a task's
+        // memory across several regions laid end to end, so we hop from region
+        // to region until we reach the end of the slice. Each hop has to land
+        // in a different region, which bounds the number of hops.
+        let table = self.region_table();
+        let mut addr = slice.base_addr();
+        for _ in 0..table.len() {
+            let region = match table
+                .iter()
+                .filter(usable)
+                .find(|region| region.contains(addr))
+            {
+                Some(region) => region,
+                None => return false,
+            };
+            if region.covers(slice) {
+                return true;
+            }
+            // We don't allow regions to butt up against the end of the
+            // address space, so this can't overflow.
+            addr = region.base as usize + region.size as usize;
+            if slice.end_addr() <= addr {
+                return true;
+            }
+        }
+        false
     }
 
     /// Posts a set of notification bits (which might be empty) to this task. If
@@ -308,6 +345,19 @@ impl Task {
         (self.timer.deadline, self.timer.to_post)
     }
 
+    /// Returns the number of ticks charged to this task since boot.
+    pub fn runtime(&self) -> u64 {
+        self.runtime
+    }
+
+    /// Charges `ticks` ticks of CPU time to this task. The tick handler calls
+    /// this for whichever task it interrupted, which makes the count a sample
+    /// of where time goes rather than an exact measurement: a task that always
+    /// yields just before the tick can hide from it.
+    pub fn charge_ticks(&mut self, ticks: u32) {
+        self.runtime = self.runtime.wrapping_add(u64::from(ticks));
+    }
+
     /// Rewrites this task's state back to its initial form, to effect a task
     /// reboot.
     ///
@@ -322,7 +372,9 @@ impl Task {
         self.generation = self.generation.wrapping_add(1);
         self.timer = TimerState::default();
         self.notifications = 0;
+        self.send_deadline = None;
         self.state = TaskState::default();
+        self.priority = Priority(self.descriptor.priority);
 
         crate::arch::reinitialize(self);
     }
@@ -344,6 +396,29 @@ impl Task {
         Generation::from(self.generation as u8 & MASK)
     }
 
+    /// Interprets this task's saved registers as arguments to `SEND`.
+    ///
+    /// `SEND_TIMEOUT` shares `SEND`'s register layout but carries a deadline
+    /// where the lease table would be, so while a timed send is in progress
+    /// this reports an empty lease table instead.
+    pub fn send_args(&self) -> SendArgs {
+        let mut args = self.save.as_send_args();
+        if self.send_deadline.is_some() {
+            args.lease_table = Ok(USlice::empty());
+        }
+        args
+    }
+
+    /// Returns the deadline of this task's in-progress `SEND_TIMEOUT`, if any.
+    pub fn send_deadline(&self) -> Option<Timestamp> {
+        self.send_deadline
+    }
+
+    /// Records the deadline for a `SEND_TIMEOUT`, or clears it for a `SEND`.
+    pub fn set_send_deadline(&mut self, deadline: Option<Timestamp>) {
+        self.send_deadline = deadline;
+    }
+
     /// Returns this task's priority.
     pub fn priority(&self) -> Priority {
         self.priority
@@ -512,6 +587,12 @@ pub trait ArchState: Default {
         }
     }
 
+    /// Interprets arguments as for the `SEND_TIMEOUT` syscall and returns the
+    /// deadline. The remaining arguments match `SEND`, minus the lease table.
+    fn as_send_timeout_deadline(&self) -> Timestamp {
+        Timestamp::from(u64::from(self.arg6()) << 32 | u64::from(self.arg5()))
+    }
+
     /// Interprets arguments as for the `BORROW_*` family of syscalls and
     /// returns the result.
     fn as_borrow_args(&self) -> BorrowArgs {
@@ -760,7 +841,8 @@ impl NextTask {
 /// any that have expired by `current_time` (and disabling them atomically).
 pub fn process_timers(tasks: &mut [Task], current_time: Timestamp) -> NextTask {
     let mut sched_hint = NextTask::Same;
-    for (index, task) in tasks.iter_mut().enumerate() {
+    for index in 0..tasks.len() {
+        let task = &mut tasks[index];
         if let Some(deadline) = task.timer.deadline {
             if deadline <= current_time {
                 task.timer.deadline = None;
@@ -772,10 +854,48 @@ pub fn process_timers(tasks: &mut [Task], current_time: Timestamp) -> NextTask {
                 sched_hint = sched_hint.combine(task_hint)
             }
         }
+        if let Some(deadline) = tasks[index].send_deadline {
+            if deadline <= current_time {
+                sched_hint = sched_hint.combine(expire_send(tasks, index));
+            }
+        }
     }
     sched_hint
 }
 
+/// Returns the earliest deadline, timer or `SEND_TIMEOUT`, of any task in
+/// `tasks`, or `None` if nothing is waiting on the clock.
+pub fn next_deadline(tasks: &[Task]) -> Option<Timestamp> {
+    tasks
+        .iter()
+        .flat_map(|task| [task.timer.deadline, task.send_deadline])
+        .flatten()
+        .min()
+}
+
+/// Abandons the `SEND_TIMEOUT` that `tasks[index]` is blocked in, now that its
+/// deadline has passed, and returns a scheduling hint.
+///
+/// The peer is left alone: if it already received the message, its eventual
+/// reply is discarded, since the sender is no longer in `InReply`.
+fn expire_send(tasks: &mut [Task], index: usize) -> NextTask {
+    let peer = blocked_on(tasks, index);
+    tasks[index].send_deadline = None;
+    let peer = match peer {
+        Some(peer) => peer,
+        // The send finished, or the task has since faulted; nothing to do.
+        None => return NextTask::Same,
+    };
+
+    tasks[index]
+        .save_mut()
+        .set_error_response(abi::SEND_TIMEOUT_CODE);
+    tasks[index].set_healthy_state(SchedState::Runnable);
+    // The peer may have been running on our priority.
+    update_inherited_priority(tasks, peer);
+    NextTask::Specific(index)
+}
+
 /// Checks a user-provided `TaskId` for validity against `table`.
 ///
 /// On success, returns an index that can be used to dereference `table` without
@@ -848,6 +968,60 @@ pub fn priority_scan(
     choice.map(|(idx, _)| idx)
 }
 
+/// Returns the index of the task that `tasks[index]` is blocked on, either in
+/// SEND or waiting for a REPLY, if any. Blocking on the kernel or on a task
+/// that has since restarted doesn't count.
+pub fn blocked_on(tasks: &[Task], index: usize) -> Option<usize> {
+    match tasks[index].state {
+        TaskState::Healthy(
+            SchedState::InSend(peer) | SchedState::InReply(peer),
+        ) => check_task_id_against_table(tasks, peer).ok(),
+        _ => None,
+    }
+}
+
+/// Recomputes the priority of `tasks[index]`, if it inherits priority, and
+/// carries any change along to the task it is itself blocked on.
+///
+/// A task with the `INHERIT_PRIORITY` flag runs at the more important of its
+/// configured priority and the priorities of all tasks waiting on it in SEND or
+/// REPLY. This needs to be called whenever a task starts or stops waiting on
+/// another.
+pub fn update_inherited_priority(tasks: &mut [Task], index: usize) {
+    let mut index = index;
+    // A chain of waiting tasks can't be longer than the task table. Bounding
+    // the loop this way also keeps a deadlocked cycle from hanging us.
+    for _ in 0..tasks.len() {
+        let task = &tasks[index];
+        if !task.descriptor.flags.contains(TaskFlags::INHERIT_PRIORITY) {
+            return;
+        }
+
+        let id = current_id(tasks, index);
+        let mut priority = Priority(task.descriptor.priority);
+        for t in tasks.iter() {
+            if let TaskState::Healthy(
+                SchedState::InSend(peer) | SchedState::InReply(peer),
+            ) = t.state
+            {
+                if peer == id && t.priority.is_more_important_than(priority) {
+                    priority = t.priority;
+                }
+            }
+        }
+
+        if priority == tasks[index].priority {
+            return;
+        }
+        tasks[index].priority = priority;
+
+        match blocked_on(tasks, index) {
+            Some(next) => index = next,
+            None => return,
+        }
+    }
+}
+
 /// Puts a task into a forced fault condition.
 ///
 /// The task is designated by the `index` parameter. We need access to the
@@ -870,7 +1044,13 @@ pub fn force_fault(
     index: usize,
     fault: FaultInfo,
 ) -> NextTask {
+    // A faulted task stops waiting on anyone, which may cost them some
+    // inherited priority.
+    let waited_on = blocked_on(tasks, index);
+
     let task = &mut tasks[index];
+    // Nor is it waiting on the clock to end a SEND_TIMEOUT.
+    task.send_deadline = None;
     task.state = match task.state {
         TaskState::Healthy(sched) => TaskState::Faulted {
             original_state: sched,
@@ -885,6 +1065,9 @@ pub fn force_fault(
             }
         }
     };
+    if let Some(w) = waited_on {
+        update_inherited_priority(tasks, w);
+    }
     let supervisor_awoken =
         tasks[0].post(NotificationSet(HUBRIS_FAULT_NOTIFICATION));
     if supervisor_awoken {
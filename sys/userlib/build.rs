@@ -3,18 +3,11 @@
 // file, You can obtain one at https://mozilla.org/MPL/2.0/.
 
 fn main() -> Result<(), Box<dyn std::error::Error>> {
-    build_util::expose_m_profile();
-
-    // Do an architecture check.
-    if build_util::target_os() != "none" {
-        eprintln!("***********************************************");
-        eprintln!("Hi!");
-        eprintln!("You appear to be building this natively,");
-        eprintln!("i.e. for your workstation. This won't work.");
-        eprintln!("Please specify --target=some-triple, e.g.");
-        eprintln!("--target=thumbv7em-none-eabihf");
-        eprintln!("***********************************************");
-        panic!()
+    // Hosted builds exist only so that crates built on userlib can be
+    // unit-tested; they get panicking syscall stubs and have no M-profile
+    // to expose.
+    if build_util::target_os() == "none" {
+        build_util::expose_m_profile();
     }
 
     Ok(())
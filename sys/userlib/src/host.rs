@@ -0,0 +1,101 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Syscall stubs for hosted builds.
+//!
+//! These take the same arguments as the real (naked) stubs, so the public
+//! `sys_*` wrappers are shared between the two. They are plain Rust functions
+//! rather than `extern "C"` so that their panics can unwind into a test
+//! harness.
+//!
+//! Every stub other than PANIC panics to report the syscall that was
+//! attempted; PANIC turns into an ordinary Rust panic carrying the task's
+//! message, so code under test that calls `sys_panic` fails as expected.
+
+use super::*;
+
+fn unavailable(syscall: &str) -> ! {
+    panic!("{syscall} syscall made in a hosted build")
+}
+
+pub(crate) unsafe fn sys_send_stub(_args: &mut SendArgs<'_>) -> RcLen {
+    unavailable("SEND")
+}
+
+pub(crate) unsafe fn sys_send_timeout_stub(
+    _args: &mut SendTimeoutArgs,
+) -> RcLen {
+    unavailable("SEND_TIMEOUT")
+}
+
+pub(crate) unsafe fn sys_recv_stub(
+    _buffer_ptr: *mut u8,
+    _buffer_len: usize,
+    _notification_mask: u32,
+    _specific_sender: u32,
+    _out: *mut RawRecvMessage,
+) -> u32 {
+    unavailable("RECV")
+}
+
+pub(crate) unsafe fn sys_reply_stub(
+    _peer: u32,
+    _code: u32,
+    _message_ptr: *const u8,
+    _message_len: usize,
+) {
+    unavailable("REPLY")
+}
+
+pub(crate) unsafe fn sys_set_timer_stub(
+    _set_timer: u32,
+    _deadline_lo: u32,
+    _deadline_hi: u32,
+    _notification: u32,
+) {
+    unavailable("SET_TIMER")
+}
+
+pub(crate) unsafe fn sys_borrow_read_stub(_args: *mut BorrowReadArgs) -> RcLen {
+    unavailable("BORROW_READ")
+}
+
+pub(crate) unsafe fn sys_borrow_write_stub(
+    _args: *mut BorrowWriteArgs,
+) -> RcLen {
+    unavailable("BORROW_WRITE")
+}
+
+pub(crate) unsafe fn sys_borrow_info_stub(
+    _lender: u32,
+    _index: usize,
+    _out: *mut RawBorrowInfo,
+) {
+    unavailable("BORROW_INFO")
+}
+
+pub(crate) unsafe fn sys_irq_control_stub(_mask: u32, _enable: u32) {
+    unavailable("IRQ_CONTROL")
+}
+
+pub(crate) unsafe fn sys_panic_stub(msg: *const u8, len: usize) -> ! {
+    let msg = core::slice::from_raw_parts(msg, len);
+    panic!("task panic: {}", core::str::from_utf8(msg).unwrap_or("<?>"))
+}
+
+pub(crate) unsafe fn sys_get_timer_stub(_out: *mut RawTimerState) {
+    unavailable("GET_TIMER")
+}
+
+pub(crate) unsafe fn sys_refresh_task_id_stub(_tid: u32) -> u32 {
+    unavailable("REFRESH_TASK_ID")
+}
+
+pub(crate) unsafe fn sys_post_stub(_tid: u32, _mask: u32) -> u32 {
+    unavailable("POST")
+}
+
+pub(crate) unsafe fn sys_reply_fault_stub(_tid: u32, _reason: u32) {
+    unavailable("REPLY_FAULT")
+}
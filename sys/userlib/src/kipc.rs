@@ -110,3 +110,34 @@ pub fn read_image_id() -> u64 {
     assert_eq!(len, 8); // we *really* expect this to be a u64
     ssmarshal::deserialize(&response[..len]).unwrap_lite().0
 }
+
+/// Fills `faulted` with a bitmap of the tasks that are currently faulted, in
+/// which bit `i % 8` of byte `i / 8` is set if task `i` is. `faulted` must have
+/// room for a bit for every task in the system.
+pub fn find_faulted_tasks(faulted: &mut [u8]) {
+    let (rc, len) = sys_send(
+        TaskId::KERNEL,
+        Kipcnum::FindFaultedTasks as u16,
+        &[],
+        faulted,
+        &[],
+    );
+    assert_eq!(rc, 0);
+    assert!(len <= faulted.len());
+}
+
+/// Returns the number of kernel ticks charged to `task` since boot.
+pub fn read_task_runtime(task: usize) -> u64 {
+    // Coerce `task` to a known size (Rust doesn't assume that usize == u32)
+    let task = task as u32;
+    let mut response = [0; core::mem::size_of::<u64>()];
+    let (rc, len) = sys_send(
+        TaskId::KERNEL,
+        Kipcnum::ReadTaskRuntime as u16,
+        task.as_bytes(),
+        &mut response,
+        &[],
+    );
+    assert_eq!(rc, 0);
+    ssmarshal::deserialize(&response[..len]).unwrap_lite().0
+}
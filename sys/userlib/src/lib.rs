@@ -23,6 +23,13 @@
 //! all registers.
 //!
 //! See: https://github.com/rust-lang/rust/issues/73450#issuecomment-650463347
+//!
+//! # Hosted builds
+//!
+//! So that crates layered on `userlib` (drivers, API crates) can be unit-tested
+//! on a workstation, this crate also builds for hosted targets. There, the
+//! stubs are replaced by the ones in `host`, which panic: there is no kernel
+//! to talk to, so anything under test must not make syscalls.
 
 #![no_std]
 #![feature(asm_const)]
@@ -36,14 +43,20 @@ pub use num_derive::{FromPrimitive, ToPrimitive};
 pub use num_traits::{FromPrimitive, ToPrimitive};
 pub use unwrap_lite::UnwrapLite;
 
+#[cfg(target_os = "none")]
 use core::arch;
 use core::marker::PhantomData;
 
 pub mod hl;
+#[cfg(not(target_os = "none"))]
+mod host;
 pub mod kipc;
 pub mod task_slot;
 pub mod units;
 
+#[cfg(not(target_os = "none"))]
+use host::*;
+
 #[derive(Debug)]
 #[repr(transparent)]
 pub struct Lease<'a> {
@@ -150,6 +163,7 @@ struct SendArgs<'a> {
 /// Core implementation of the SEND syscall.
 ///
 /// See the note on syscall stubs at the top of this module for rationale.
+#[cfg(target_os = "none")]
 #[naked]
 unsafe extern "C" fn sys_send_stub(_args: &mut SendArgs<'_>) -> RcLen {
     cfg_if::cfg_if! {
@@ -219,6 +233,129 @@ unsafe extern "C" fn sys_send_stub(_args: &mut SendArgs<'_>) -> RcLen {
     }
 }
 
+/// Sends a message to `target` without leases, giving up if `target` hasn't
+/// replied by the time the kernel timer reaches `deadline`.
+///
+/// On timeout, this returns `(SEND_TIMEOUT_CODE, 0)`. Note that the message
+/// may still have been delivered, and `target` may be partway through
+/// handling it; any reply it sends afterwards is discarded. Deadlines are
+/// checked on timer ticks, so one that is already in the past will expire at
+/// the next tick unless the send completes first.
+///
+/// Because a timed-out send leaves the recipient with nothing to borrow from,
+/// this can't carry leases. It's meant for tasks (like the supervisor) that
+/// need to talk to tasks they don't trust to respond promptly.
+#[inline(always)]
+pub fn sys_send_timeout(
+    target: TaskId,
+    operation: u16,
+    outgoing: &[u8],
+    incoming: &mut [u8],
+    deadline: u64,
+) -> (u32, usize) {
+    let mut args = SendTimeoutArgs {
+        packed_target_operation: u32::from(target.0) << 16
+            | u32::from(operation),
+        outgoing_ptr: outgoing.as_ptr(),
+        outgoing_len: outgoing.len(),
+        incoming_ptr: incoming.as_mut_ptr(),
+        incoming_len: incoming.len(),
+        deadline_lo: deadline as u32,
+        deadline_hi: (deadline >> 32) as u32,
+    };
+    unsafe { sys_send_timeout_stub(&mut args).into() }
+}
+
+#[allow(dead_code)] // this gets used from asm
+#[repr(C)] // field order matters
+struct SendTimeoutArgs {
+    packed_target_operation: u32,
+    outgoing_ptr: *const u8,
+    outgoing_len: usize,
+    incoming_ptr: *mut u8,
+    incoming_len: usize,
+    deadline_lo: u32,
+    deadline_hi: u32,
+}
+
+/// Core implementation of the SEND_TIMEOUT syscall.
+///
+/// This is `sys_send_stub` with a different syscall number; the deadline rides
+/// in the registers that would otherwise hold the lease table.
+///
+/// See the note on syscall stubs at the top of this module for rationale.
+#[cfg(target_os = "none")]
+#[naked]
+unsafe extern "C" fn sys_send_timeout_stub(
+    _args: &mut SendTimeoutArgs,
+) -> RcLen {
+    cfg_if::cfg_if! {
+        if #[cfg(armv6m)] {
+            arch::asm!("
+                @ Spill the registers we're about to use to pass stuff.
+                push {{r4-r7, lr}}
+                mov r4, r8
+                mov r5, r9
+                mov r6, r10
+                mov r7, r11
+                push {{r4-r7}}
+                @ Load the constant syscall number.
+                eors r4, r4
+                adds r4, #{sysnum}
+                mov r11, r4
+                @ Load in args from the struct.
+                ldm r0!, {{r4-r7}}
+                ldm r0, {{r0-r2}}
+                mov r8, r0
+                mov r9, r1
+                mov r10, r2
+
+                @ To the kernel!
+                svc #0
+
+                @ Move the two results back into their return positions.
+                mov r0, r4
+                mov r1, r5
+                @ Restore the registers we used.
+                pop {{r4-r7}}
+                mov r8, r4
+                mov r9, r5
+                mov r10, r6
+                mov r11, r7
+                pop {{r4-r7, pc}}
+                ",
+                sysnum = const Sysnum::SendTimeout as u32,
+                options(noreturn),
+            )
+        } else if #[cfg(any(armv7m, armv8m))] {
+            arch::asm!("
+                @ Spill the registers we're about to use to pass stuff.
+                push {{r4-r11}}
+                @ Load in args from the struct.
+                ldm r0, {{r4-r10}}
+                @ Load the constant syscall number.
+                mov r11, {sysnum}
+
+                @ To the kernel!
+                svc #0
+
+                @ Move the two results back into their return positions.
+                mov r0, r4
+                mov r1, r5
+                @ Restore the registers we used.
+                pop {{r4-r11}}
+                @ Fin.
+                bx lr
+                ",
+                sysnum = const Sysnum::SendTimeout as u32,
+                options(noreturn),
+            )
+        } else {
+            compile_error!("missing sys_send_timeout_stub for ARM profile");
+        }
+    }
+}
+
 /// Performs an "open" RECV that will accept messages from any task or
 /// notifications from the kernel.
 ///
@@ -327,6 +464,7 @@ pub struct RecvMessage {
 /// Core implementation of the RECV syscall.
 ///
 /// See the note on syscall stubs at the top of this module for rationale.
+#[cfg(target_os = "none")]
 #[naked]
 #[must_use]
 unsafe extern "C" fn sys_recv_stub(
@@ -444,6 +582,7 @@ pub fn sys_reply(peer: TaskId, code: u32, message: &[u8]) {
 /// Core implementation of the REPLY syscall.
 ///
 /// See the note on syscall stubs at the top of this module for rationale.
+#[cfg(target_os = "none")]
 #[naked]
 unsafe extern "C" fn sys_reply_stub(
     _peer: u32,
@@ -543,6 +682,7 @@ pub fn sys_set_timer(deadline: Option<u64>, notifications: u32) {
 /// Core implementation of the SET_TIMER syscall.
 ///
 /// See the note on syscall stubs at the top of this module for rationale.
+#[cfg(target_os = "none")]
 #[naked]
 unsafe extern "C" fn sys_set_timer_stub(
     _set_timer: u32,
@@ -631,6 +771,7 @@ pub fn sys_borrow_read(
 /// Core implementation of the BORROW_READ syscall.
 ///
 /// See the note on syscall stubs at the top of this module for rationale.
+#[cfg(target_os = "none")]
 #[naked]
 unsafe extern "C" fn sys_borrow_read_stub(_args: *mut BorrowReadArgs) -> RcLen {
     cfg_if::cfg_if! {
@@ -726,6 +867,7 @@ pub fn sys_borrow_write(
 /// Core implementation of the BORROW_WRITE syscall.
 ///
 /// See the note on syscall stubs at the top of this module for rationale.
+#[cfg(target_os = "none")]
 #[naked]
 unsafe extern "C" fn sys_borrow_write_stub(
     _args: *mut BorrowWriteArgs,
@@ -843,6 +985,7 @@ pub struct BorrowInfo {
 /// Core implementation of the BORROW_INFO syscall.
 ///
 /// See the note on syscall stubs at the top of this module for rationale.
+#[cfg(target_os = "none")]
 #[naked]
 unsafe extern "C" fn sys_borrow_info_stub(
     _lender: u32,
@@ -919,6 +1062,7 @@ pub fn sys_irq_control(mask: u32, enable: bool) {
 /// Core implementation of the IRQ_CONTROL syscall.
 ///
 /// See the note on syscall stubs at the top of this module for rationale.
+#[cfg(target_os = "none")]
 #[naked]
 unsafe extern "C" fn sys_irq_control_stub(_mask: u32, _enable: u32) {
     cfg_if::cfg_if! {
@@ -986,6 +1130,7 @@ pub fn sys_panic(msg: &[u8]) -> ! {
 /// Core implementation of the PANIC syscall.
 ///
 /// See the note on syscall stubs at the top of this module for rationale.
+#[cfg(target_os = "none")]
 #[naked]
 unsafe extern "C" fn sys_panic_stub(_msg: *const u8, _len: usize) -> ! {
     cfg_if::cfg_if! {
@@ -1096,6 +1241,7 @@ struct RawTimerState {
 /// Core implementation of the GET_TIMER syscall.
 ///
 /// See the note on syscall stubs at the top of this module for rationale.
+#[cfg(target_os = "none")]
 #[naked]
 unsafe extern "C" fn sys_get_timer_stub(_out: *mut RawTimerState) {
     cfg_if::cfg_if! {
@@ -1163,6 +1309,7 @@ unsafe extern "C" fn sys_get_timer_stub(_out: *mut RawTimerState) {
 #[doc(hidden)]
 #[no_mangle]
 #[link_section = ".text.start"]
+#[cfg(target_os = "none")]
 #[naked]
 pub unsafe extern "C" fn _start() -> ! {
     // Provided by the user program:
@@ -1293,7 +1440,7 @@ pub unsafe extern "C" fn _start() -> ! {
 /// task, to ensure that memory is available for the panic message, even if the
 /// resources have been trimmed aggressively using `xtask sizes` and `humility
 /// stackmargin`.
-#[cfg(feature = "panic-messages")]
+#[cfg(all(target_os = "none", feature = "panic-messages"))]
 #[panic_handler]
 fn panic(info: &core::panic::PanicInfo<'_>) -> ! {
     // Implementation Note
@@ -1440,7 +1587,7 @@ fn panic(info: &core::panic::PanicInfo<'_>) -> ! {
 /// Panic handler for tasks without the `panic-messages` feature enabled. This
 /// kills the task with a fixed message, `"PANIC"`. While this is less helpful
 /// than a proper panic message, the stack trace can still be informative.
-#[cfg(not(feature = "panic-messages"))]
+#[cfg(all(target_os = "none", not(feature = "panic-messages")))]
 #[panic_handler]
 fn panic(_: &core::panic::PanicInfo<'_>) -> ! {
     sys_panic(b"PANIC")
@@ -1455,6 +1602,7 @@ pub fn sys_refresh_task_id(task_id: TaskId) -> TaskId {
 /// Core implementation of the REFRESH_TASK_ID syscall.
 ///
 /// See the note on syscall stubs at the top of this module for rationale.
+#[cfg(target_os = "none")]
 #[naked]
 unsafe extern "C" fn sys_refresh_task_id_stub(_tid: u32) -> u32 {
     cfg_if::cfg_if! {
@@ -1524,6 +1672,7 @@ pub fn sys_post(task_id: TaskId, bits: u32) -> u32 {
 /// Core implementation of the POST syscall.
 ///
 /// See the note on syscall stubs at the top of this module for rationale.
+#[cfg(target_os = "none")]
 #[naked]
 unsafe extern "C" fn sys_post_stub(_tid: u32, _mask: u32) -> u32 {
     cfg_if::cfg_if! {
@@ -1594,6 +1743,7 @@ pub fn sys_reply_fault(task_id: TaskId, reason: ReplyFaultReason) {
 /// Core implementation of the REPLY_FAULT syscall.
 ///
 /// See the note on syscall stubs at the top of this module for rationale.
+#[cfg(target_os = "none")]
 #[naked]
 unsafe extern "C" fn sys_reply_fault_stub(_tid: u32, _reason: u32) {
     cfg_if::cfg_if! {
@@ -72,6 +72,8 @@ pub enum Functions {
         (Controller, PortIndex, Mux, Segment, u8, u8, usize, usize),
         ResponseCode,
     ),
+    #[cfg(feature = "i2c")]
+    I2cStats((Controller, PortIndex, Mux, Segment, bool), ResponseCode),
     #[cfg(feature = "gpio")]
     GpioInput(drv_stm32xx_sys_api::Port, u32),
     #[cfg(feature = "gpio")]
@@ -130,19 +132,9 @@ pub enum Functions {
 }
 
 #[cfg(feature = "i2c")]
-#[allow(clippy::type_complexity)] // TODO - type is indeed not fantastic
-fn i2c_args(
+fn i2c_bus_args(
     stack: &[Option<u32>],
-) -> Result<
-    (
-        Controller,
-        PortIndex,
-        Option<(Mux, Segment)>,
-        u8,
-        Option<u8>,
-    ),
-    Failure,
-> {
+) -> Result<(Controller, PortIndex, Option<(Mux, Segment)>), Failure> {
     let controller = match stack[0] {
         Some(controller) => match Controller::from_u32(controller) {
             Some(controller) => controller,
@@ -179,6 +171,25 @@ fn i2c_args(
         _ => None,
     };
 
+    Ok((controller, port, mux))
+}
+
+#[cfg(feature = "i2c")]
+#[allow(clippy::type_complexity)] // TODO - type is indeed not fantastic
+fn i2c_args(
+    stack: &[Option<u32>],
+) -> Result<
+    (
+        Controller,
+        PortIndex,
+        Option<(Mux, Segment)>,
+        u8,
+        Option<u8>,
+    ),
+    Failure,
+> {
+    let (controller, port, mux) = i2c_bus_args(stack)?;
+
     let addr = match stack[4] {
         Some(addr) => addr as u8,
         None => return Err(Failure::Fault(Fault::EmptyParameter(4))),
@@ -340,6 +351,48 @@ fn i2c_bulk_write(
     }
 }
 
+#[cfg(feature = "i2c")]
+fn i2c_stats(
+    stack: &[Option<u32>],
+    _data: &[u8],
+    rval: &mut [u8],
+) -> Result<usize, Failure> {
+    use zerocopy::AsBytes;
+
+    //
+    // We need 5 parameters: the controller, port, mux and segment (the last
+    // two of which may be empty, for the bus itself), and whether the
+    // counters should be cleared once read.
+    //
+    if stack.len() < 5 {
+        return Err(Failure::Fault(Fault::MissingParameters));
+    }
+
+    let fp = stack.len() - 5;
+    let (controller, port, mux) = i2c_bus_args(&stack[fp..])?;
+
+    let clear = match stack[fp + 4] {
+        Some(clear) => clear != 0,
+        None => return Err(Failure::Fault(Fault::EmptyParameter(4))),
+    };
+
+    let task = I2C.get_task_id();
+
+    match drv_i2c_api::read_stats(task, controller, port, mux, clear) {
+        Ok(stats) => {
+            let bytes = stats.as_bytes();
+
+            if rval.len() < bytes.len() {
+                return Err(Failure::Fault(Fault::ReturnValueOverflow));
+            }
+
+            rval[..bytes.len()].copy_from_slice(bytes);
+            Ok(bytes.len())
+        }
+        Err(err) => Err(Failure::FunctionError(err.into())),
+    }
+}
+
 #[cfg(feature = "gpio")]
 fn gpio_args(
     stack: &[Option<u32>],
@@ -532,6 +585,8 @@ pub(crate) static HIFFY_FUNCS: &[Function] = &[
     i2c_write,
     #[cfg(feature = "i2c")]
     i2c_bulk_write,
+    #[cfg(feature = "i2c")]
+    i2c_stats,
     #[cfg(feature = "gpio")]
     gpio_input,
     #[cfg(feature = "gpio")]
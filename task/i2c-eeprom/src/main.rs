@@ -0,0 +1,135 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Emulated I2C EEPROM
+//!
+//! This task uses the I2C controller configured as a target to present a
+//! 256-byte, AT24-style EEPROM to whatever is on the other end of the bus.
+//! Its contents are held in RAM, and so don't survive a restart.  This is
+//! useful for exercising the target support in our I2C driver, and for
+//! testing the controllers on the other end of the bus against a device
+//! whose behavior we can see: each completed write is recorded in our
+//! ringbuf when the transaction ends.
+//!
+
+#![no_std]
+#![no_main]
+
+use drv_stm32xx_i2c::target::{self, I2cTarget, RegisterFile};
+use drv_stm32xx_i2c::{I2cControl, I2cPins};
+use drv_stm32xx_sys_api::{OutputType, Pull, Speed, Sys};
+use ringbuf::{ringbuf, ringbuf_entry};
+use userlib::{sys_irq_control, sys_recv_closed, task_slot, TaskId};
+
+task_slot!(SYS, sys);
+
+/// The address at which we respond, which is that of an AT24 with its
+/// address pins tied low.
+const ADDRESS: u8 = 0x50;
+
+#[derive(Copy, Clone, PartialEq)]
+enum Trace {
+    Ready,
+    Written { offset: u8, len: u16 },
+    None,
+}
+
+ringbuf!(Trace, 16, Trace::None);
+
+include!(concat!(env!("OUT_DIR"), "/i2c_config.rs"));
+
+///
+/// Our EEPROM, which is a [`RegisterFile`] that also keeps track of where
+/// each transaction writes, so that it can report the write at its STOP.
+///
+struct Eeprom<'a> {
+    registers: RegisterFile<'a>,
+
+    /// The offset written by this transaction, and the number of bytes
+    /// written there, if the transaction has written anything yet.
+    written: Option<(u8, u16)>,
+}
+
+impl I2cTarget for Eeprom<'_> {
+    fn address(&self) -> u8 {
+        self.registers.address()
+    }
+
+    fn start(&mut self) -> bool {
+        // A repeated start begins a new write, so we only keep track of the
+        // last one.
+        self.written = None;
+        self.registers.start()
+    }
+
+    fn write(&mut self, byte: u8) {
+        self.written = match self.written {
+            None => Some((byte, 0)),
+            Some((offset, len)) => Some((offset, len.saturating_add(1))),
+        };
+
+        self.registers.write(byte);
+    }
+
+    fn read(&mut self) -> Option<u8> {
+        self.registers.read()
+    }
+
+    fn stop(&mut self) {
+        match self.written.take() {
+            Some((offset, len)) if len > 0 => {
+                ringbuf_entry!(Trace::Written { offset, len });
+            }
+            _ => {}
+        }
+    }
+}
+
+fn configure_pins(pins: &[I2cPins]) {
+    let sys = Sys::from(SYS.get_task_id());
+
+    for pin in pins {
+        for gpio_pin in &[pin.scl, pin.sda] {
+            sys.gpio_configure_alternate(
+                *gpio_pin,
+                OutputType::OpenDrain,
+                Speed::High,
+                Pull::None,
+                pin.function,
+            );
+        }
+    }
+}
+
+#[export_name = "main"]
+fn main() -> ! {
+    let controller = &i2c_config::controllers()[0];
+    let pins = i2c_config::pins();
+
+    let sys = Sys::from(SYS.get_task_id());
+    controller.enable(&sys);
+    configure_pins(&pins);
+
+    let mut contents = [0xffu8; 256];
+
+    let mut eeprom = Eeprom {
+        registers: RegisterFile::new(ADDRESS, &mut contents),
+        written: None,
+    };
+
+    let ctrl = I2cControl {
+        enable: |notification| {
+            sys_irq_control(notification, true);
+        },
+        wfi: |notification| {
+            let _ = sys_recv_closed(&mut [], notification, TaskId::KERNEL);
+        },
+    };
+
+    ringbuf_entry!(Trace::Ready);
+
+    target::serve(controller, &ctrl, &mut [&mut eeprom]);
+}
+
+include!(concat!(env!("OUT_DIR"), "/notifications.rs"));
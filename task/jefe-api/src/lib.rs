@@ -8,9 +8,42 @@
 
 use derive_idol_err::IdolError;
 pub use dump_agent_api::DumpAgentError;
+use hubpack::SerializedSize;
 use serde::{Deserialize, Serialize};
 use userlib::*;
 
+/// Operation code used when the supervisor warns a task listed in its
+/// `on-reset` config that the system is about to reset.
+///
+/// The message and reply are both empty. The supervisor first posts the task's
+/// `reset-notice` notification, and then sends this with `SEND_TIMEOUT`, so the
+/// task has until its configured deadline to get ready and reply before the
+/// reset goes ahead regardless. Tasks pick it up with [`handle_reset_notice`].
+pub const RESET_NOTICE_OP: u16 = 0xffff;
+
+/// Answers a reset notice from the supervisor, calling `prepare` to get ready
+/// for the reset before replying.
+///
+/// A task listed in the supervisor's `on-reset` config must have a
+/// notification named `reset-notice`, and should call this when that
+/// notification fires -- typically from its Idol server's
+/// `handle_notification`. This takes the supervisor's message with a closed
+/// receive, so it never reaches the task's Idol dispatcher, which would answer
+/// an operation it doesn't know with `REPLY_FAULT` and so fault the supervisor.
+/// For the same reason, the task must keep `reset-notice` in its notification
+/// mask whenever it does an open receive: notifications are delivered ahead of
+/// messages, so the notice is always seen first.
+pub fn handle_reset_notice(prepare: impl FnOnce()) {
+    // The supervisor is never restarted, so its generation is always zero.
+    let jefe = TaskId::for_index_and_gen(0, Generation::ZERO);
+    if let Ok(rm) = sys_recv_closed(&mut [], 0, jefe) {
+        if rm.operation == u32::from(RESET_NOTICE_OP) {
+            prepare();
+        }
+        sys_reply(rm.sender, 0, &[]);
+    }
+}
+
 /// Platform-agnostic (but heavily influenced) reset status bits.
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
 #[repr(C)]
@@ -34,6 +67,59 @@ pub enum DumpAreaError {
     AlreadyInUse,
 }
 
+/// Errors from operations that name a task by index.
+#[derive(Copy, Clone, Debug, FromPrimitive, Eq, PartialEq, IdolError)]
+#[repr(C)]
+pub enum TaskIndexError {
+    InvalidIndex = 1,
+}
+
+/// Errors from reading ring buffers exported by the supervisor.
+#[derive(Copy, Clone, Debug, FromPrimitive, Eq, PartialEq, IdolError)]
+#[repr(C)]
+pub enum RingbufExportError {
+    /// The supervisor wasn't built with the `ringbuf-export` feature.
+    NotSupported = 1,
+    /// There is no exported ring buffer with this index.
+    InvalidIndex,
+    /// The offset is past the end of the ring buffer.
+    BadOffset,
+}
+
+/// Number of bytes returned by each `read_ringbuf` call.
+///
+/// Idol sizes our reply buffer for the largest reply of any operation, whether
+/// or not `ringbuf-export` is enabled, so this is kept no bigger than the
+/// replies we already send (`get_fault_history`'s, in particular) to avoid
+/// growing the supervisor's stack in every image.
+pub const RINGBUF_READ_SIZE: usize = 16;
+
+/// A ring buffer exported by the supervisor, so that it can be read without a
+/// debugger. Exported ring buffers are numbered from 0, with no gaps.
+#[derive(
+    Copy, Clone, Debug, Deserialize, Eq, PartialEq, Serialize, SerializedSize,
+)]
+pub struct RingbufExport {
+    /// Index of the task that owns the ring buffer.
+    pub task: u16,
+    /// Address of the ring buffer's static, in the task's memory.
+    pub address: u32,
+    /// Size of the static, which includes its `StaticCell`.
+    pub size: u32,
+}
+
+/// What the supervisor remembers about a task's faults since boot.
+#[derive(Copy, Clone, Debug, Default, Serialize, Deserialize)]
+pub struct FaultHistory {
+    /// Number of faults we've seen from this task.
+    pub fault_count: u32,
+    /// Number of times we've restarted this task because it faulted.
+    pub restart_count: u32,
+    /// The most recent fault, if any, along with the kernel timestamp at
+    /// which we noticed it.
+    pub last_fault: Option<(FaultInfo, u64)>,
+}
+
 impl Jefe {
     /// Asks the supervisor to restart the current task without recording a
     /// fault.
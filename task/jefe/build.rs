@@ -2,7 +2,7 @@
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at https://mozilla.org/MPL/2.0/.
 
-use anyhow::{Context, Result};
+use anyhow::{bail, Context, Result};
 use serde::Deserialize;
 use std::collections::{BTreeMap, BTreeSet};
 use std::io::Write;
@@ -10,6 +10,19 @@ use std::io::Write;
 fn main() -> Result<()> {
     let cfg = build_util::task_maybe_config::<Config>()?.unwrap_or_default();
 
+    // Exported ring buffers can hold anything any task has logged, so we want
+    // whoever turns them on to also decide who can read them.
+    if std::env::var_os("CARGO_FEATURE_RINGBUF_EXPORT").is_some() {
+        for op in ["get_ringbuf_export", "read_ringbuf"] {
+            if !cfg.allowed_callers.contains_key(op) {
+                bail!(
+                    "the ringbuf-export feature is enabled, but \
+                     allowed-callers doesn't list who may call {op}"
+                );
+            }
+        }
+    }
+
     let allowed_callers = build_util::task_ids()
         .remap_allowed_caller_names_to_ids(&cfg.allowed_callers)?;
 
@@ -48,6 +61,39 @@ fn main() -> Result<()> {
         writeln!(out, "];")?;
     }
 
+    {
+        // A task that can't receive the notice would have our message land in
+        // its Idol dispatcher, which faults the sender of anything it doesn't
+        // recognize.
+        let configs = build_util::all_task_full_configs_toml()?;
+        for name in cfg.on_reset.keys() {
+            let t = configs.get(name).with_context(|| {
+                format!("on-reset lists task {name}, which does not exist")
+            })?;
+            if !t.notifications.iter().any(|n| n == "reset-notice") {
+                bail!(
+                    "on-reset lists task {name}, which has no `reset-notice` \
+                     notification with which to receive it"
+                );
+            }
+        }
+
+        let count = cfg.on_reset.len();
+        writeln!(
+            out,
+            "pub(crate) const RESET_NOTICE_LIST: [({task}, u32, u32); {count}] \
+             = [",
+        )?;
+        for (name, ms) in cfg.on_reset {
+            writeln!(
+                out,
+                "    ({task}::{name}, \
+                 crate::notifications::{name}::RESET_NOTICE_MASK, {ms}),"
+            )?;
+        }
+        writeln!(out, "];")?;
+    }
+
     {
         let count = cfg.tasks_to_hold.len();
         writeln!(out, "pub(crate) const HELD_TASKS: [{task}; {count}] = [",)?;
@@ -57,6 +103,44 @@ fn main() -> Result<()> {
         writeln!(out, "];")?;
     }
 
+    {
+        let task_ids = build_util::task_ids();
+        let mut watchdogs = vec![];
+        for (name, t) in build_util::all_task_full_configs_toml()? {
+            if let Some(ms) = t.watchdog_ms {
+                if task_ids.get(&name) == Some(0) {
+                    bail!("the supervisor cannot have a watchdog");
+                }
+                watchdogs.push((name, ms));
+            }
+        }
+
+        let count = watchdogs.len();
+        writeln!(
+            out,
+            "pub(crate) const WATCHDOGS: [({task}, u32); {count}] = [",
+        )?;
+        for (name, ms) in watchdogs {
+            writeln!(out, "    ({task}::{name}, {ms}),")?;
+        }
+        writeln!(out, "];")?;
+    }
+
+    match cfg.restart_policy {
+        Some(p) => writeln!(
+            out,
+            "pub(crate) const RESTART_POLICY: Option<crate::RestartPolicy> = \
+             Some(crate::RestartPolicy {{ \
+                 max_restarts: {}, window_ms: {}, backoff_ms: {} }});",
+            p.max_restarts, p.window_ms, p.backoff_ms,
+        )?,
+        None => writeln!(
+            out,
+            "pub(crate) const RESTART_POLICY: Option<crate::RestartPolicy> = \
+             None;"
+        )?,
+    }
+
     #[cfg(feature = "dump")]
     output_dump_areas(&mut out)?;
     Ok(())
@@ -70,6 +154,10 @@ struct Config {
     /// notification name (in the target task)
     #[serde(default)]
     on_state_change: BTreeMap<String, String>,
+    /// Tasks to warn before a requested reset, as a map from task name to the
+    /// number of milliseconds we're willing to wait for each to reply.
+    #[serde(default)]
+    on_reset: BTreeMap<String, u32>,
     /// Map of operation names to tasks allowed to call them.
     #[serde(default)]
     allowed_callers: BTreeMap<String, Vec<String>>,
@@ -77,6 +165,23 @@ struct Config {
     /// failure, unless overridden at runtime through Humility.
     #[serde(default)]
     tasks_to_hold: BTreeSet<String>,
+    /// Limits on restarting tasks that fault repeatedly. If omitted, faulted
+    /// tasks are always restarted immediately.
+    #[serde(default)]
+    restart_policy: Option<RestartPolicy>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+struct RestartPolicy {
+    /// Number of restarts allowed within `window_ms` before a task is held.
+    max_restarts: u32,
+    /// Length of the window over which restarts are counted.
+    window_ms: u64,
+    /// Delay before the second restart within the window; it doubles for each
+    /// restart after that. Zero restarts immediately every time.
+    #[serde(default)]
+    backoff_ms: u64,
 }
 
 #[cfg(feature = "dump")]
@@ -161,6 +161,7 @@ fn check_inner(states: &mut [TaskStatus]) -> Result<bool, Error> {
             // must issue Release, below. This means it's useful for starting
             // the task but still catching it on the _next_ fault.
             kipc::restart_task(ndx, true);
+            state.started(sys_get_timer().now);
         }
 
         Request::Release => {
@@ -168,9 +169,12 @@ fn check_inner(states: &mut [TaskStatus]) -> Result<bool, Error> {
             // not only the disposition change, but may also have to restart the
             // task to clear a held fault.
             state.disposition = Disposition::Restart;
+            // Give it a fresh start against the restart policy, too.
+            state.restarts_in_window = 0;
             if state.holding_fault {
                 state.holding_fault = false;
                 kipc::restart_task(ndx, true);
+                state.started(sys_get_timer().now);
             }
         }
 
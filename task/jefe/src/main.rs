@@ -17,11 +17,12 @@
 //!
 //! It's unwise for the supervisor to use `SEND`, ever, except to talk to the
 //! kernel. This is because a `SEND` to a misbehaving task could block forever,
-//! taking out the supervisor. The long-term idea is to provide some sort of
-//! asynchronous messaging from the supervisor to less-trusted tasks, but that
-//! doesn't exist yet, so we're mostly using RECV/REPLY and notifications. This
-//! means that hardware drivers required for this task must be built in instead
-//! of running in separate tasks.
+//! taking out the supervisor. When the supervisor does need to talk to a
+//! less-trusted task, it uses `SEND_TIMEOUT`, which the kernel abandons if the
+//! task hasn't replied by a deadline; for now that's only used to warn tasks of
+//! an impending reset. Otherwise we're mostly using RECV/REPLY and
+//! notifications. This means that hardware drivers required for this task must
+//! be built in instead of running in separate tasks.
 
 #![no_std]
 #![no_main]
@@ -31,12 +32,18 @@ mod dump;
 
 mod external;
 
+#[cfg(feature = "ringbuf-export")]
+mod ringbuf_export;
+
 use core::convert::Infallible;
 
 use hubris_num_tasks::NUM_TASKS;
 use humpty::DumpArea;
 use idol_runtime::RequestError;
-use task_jefe_api::{DumpAgentError, ResetReason};
+use task_jefe_api::{
+    DumpAgentError, FaultHistory, ResetReason, RingbufExport,
+    RingbufExportError, TaskIndexError, RESET_NOTICE_OP, RINGBUF_READ_SIZE,
+};
 use userlib::*;
 
 #[derive(Copy, Clone, Debug, Eq, PartialEq, Default)]
@@ -53,14 +60,38 @@ pub enum Disposition {
 // notification, but can otherwise be arbitrary.
 const TIMER_INTERVAL: u64 = 100;
 
+/// Limits on how eagerly we restart a task that keeps faulting, configured
+/// with `restart-policy` in our `app.toml` config.
+#[allow(dead_code)] // never constructed if no policy is configured
+pub(crate) struct RestartPolicy {
+    /// Restarts allowed within `window_ms` before we hold the task instead.
+    pub max_restarts: u32,
+    pub window_ms: u64,
+    /// Delay before the second restart in a window, doubling thereafter.
+    pub backoff_ms: u64,
+}
+
 #[export_name = "main"]
 fn main() -> ! {
     let mut task_states = [TaskStatus::default(); hubris_num_tasks::NUM_TASKS];
     for held_task in generated::HELD_TASKS {
         task_states[held_task as usize].disposition = Disposition::Hold;
     }
+    let now = sys_get_timer().now;
+    for (task, ms) in generated::WATCHDOGS {
+        let status = &mut task_states[task as usize];
+        status.watchdog_ms = Some(ms);
+        // Tasks that aren't started at boot get their watchdog armed when
+        // something starts them.
+        if !matches!(
+            kipc::read_task_status(task as usize),
+            abi::TaskState::Healthy(abi::SchedState::Stopped)
+        ) {
+            status.started(now);
+        }
+    }
 
-    let deadline = sys_get_timer().now + TIMER_INTERVAL;
+    let deadline = now + TIMER_INTERVAL;
 
     sys_set_timer(Some(deadline), notifications::TIMER_MASK);
 
@@ -95,8 +126,25 @@ impl idl::InOrderJefeImpl for ServerImpl<'_> {
         &mut self,
         _msg: &userlib::RecvMessage,
     ) -> Result<(), RequestError<Infallible>> {
-        // If we wanted to broadcast to other tasks that a restart is occuring
-        // here is where we would do so!
+        // Give each task that asked a bounded amount of time to get ready.
+        for (task, mask, ms) in generated::RESET_NOTICE_LIST {
+            let taskid =
+                TaskId::for_index_and_gen(task as usize, Generation::ZERO);
+            let taskid = sys_refresh_task_id(taskid);
+            let deadline = sys_get_timer().now + u64::from(ms);
+            // The notification comes first, so that the task takes our
+            // message with a closed receive rather than its Idol dispatcher.
+            sys_post(taskid, mask);
+            // We don't care how this turns out: a task that is dead, slow, or
+            // confused doesn't get to hold up the reset.
+            let _ = sys_send_timeout(
+                taskid,
+                RESET_NOTICE_OP,
+                &[],
+                &mut [],
+                deadline,
+            );
+        }
         kipc::system_restart();
     }
 
@@ -141,11 +189,37 @@ impl idl::InOrderJefeImpl for ServerImpl<'_> {
         Ok(())
     }
 
+    fn get_fault_history(
+        &mut self,
+        _msg: &userlib::RecvMessage,
+        task_index: u32,
+    ) -> Result<FaultHistory, RequestError<TaskIndexError>> {
+        self.task_states
+            .get(task_index as usize)
+            .map(|s| s.history)
+            .ok_or_else(|| TaskIndexError::InvalidIndex.into())
+    }
+
+    fn heartbeat(
+        &mut self,
+        msg: &userlib::RecvMessage,
+    ) -> Result<(), RequestError<Infallible>> {
+        let status = &mut self.task_states[msg.sender.index()];
+        if let Some(ms) = status.watchdog_ms {
+            status.watchdog_deadline =
+                Some(sys_get_timer().now + u64::from(ms));
+            self.set_timer();
+        }
+        Ok(())
+    }
+
     fn restart_me_raw(
         &mut self,
         msg: &userlib::RecvMessage,
     ) -> Result<(), RequestError<Infallible>> {
         kipc::restart_task(msg.sender.index(), true);
+        self.task_states[msg.sender.index()].started(sys_get_timer().now);
+        self.set_timer();
 
         // Note: the returned value here won't go anywhere because we just
         // unblocked the caller. So this is doing a small amount of unnecessary
@@ -154,6 +228,61 @@ impl idl::InOrderJefeImpl for ServerImpl<'_> {
         Ok(())
     }
 
+    fn get_task_runtime(
+        &mut self,
+        _msg: &userlib::RecvMessage,
+        task_index: u32,
+    ) -> Result<u64, RequestError<TaskIndexError>> {
+        if task_index as usize >= self.task_states.len() {
+            return Err(TaskIndexError::InvalidIndex.into());
+        }
+        Ok(kipc::read_task_runtime(task_index as usize))
+    }
+
+    cfg_if::cfg_if! {
+        if #[cfg(feature = "ringbuf-export")] {
+            fn get_ringbuf_export(
+                &mut self,
+                _msg: &userlib::RecvMessage,
+                index: u32,
+            ) -> Result<RingbufExport, RequestError<RingbufExportError>> {
+                ringbuf_export::get(index).map_err(|e| e.into())
+            }
+
+            fn read_ringbuf(
+                &mut self,
+                _msg: &userlib::RecvMessage,
+                index: u32,
+                offset: u32,
+            ) -> Result<
+                [u8; RINGBUF_READ_SIZE],
+                RequestError<RingbufExportError>,
+            > {
+                ringbuf_export::read(index, offset).map_err(|e| e.into())
+            }
+        } else {
+            fn get_ringbuf_export(
+                &mut self,
+                _msg: &userlib::RecvMessage,
+                _index: u32,
+            ) -> Result<RingbufExport, RequestError<RingbufExportError>> {
+                Err(RingbufExportError::NotSupported.into())
+            }
+
+            fn read_ringbuf(
+                &mut self,
+                _msg: &userlib::RecvMessage,
+                _index: u32,
+                _offset: u32,
+            ) -> Result<
+                [u8; RINGBUF_READ_SIZE],
+                RequestError<RingbufExportError>,
+            > {
+                Err(RingbufExportError::NotSupported.into())
+            }
+        }
+    }
+
     cfg_if::cfg_if! {
         if #[cfg(feature = "dump")] {
             fn get_dump_area(
@@ -281,6 +410,78 @@ impl idl::InOrderJefeImpl for ServerImpl<'_> {
 struct TaskStatus {
     disposition: Disposition,
     holding_fault: bool,
+
+    /// Watchdog period, if this task has one configured.
+    watchdog_ms: Option<u32>,
+    /// Time by which we need another heartbeat. The watchdog is armed when
+    /// the task starts or restarts, pushed back by each heartbeat, and
+    /// disarmed when the task faults.
+    watchdog_deadline: Option<u64>,
+
+    /// If we've put off restarting this task, when we'll get around to it.
+    restart_at: Option<u64>,
+    /// Start of the current window for counting restarts.
+    window_start: u64,
+    /// Restarts we've done on account of faults in the current window.
+    restarts_in_window: u32,
+
+    /// Fault and restart record since boot, for anyone who asks.
+    history: FaultHistory,
+}
+
+/// What to do with a task that has faulted and wants restarting.
+enum RestartPlan {
+    Now,
+    At(u64),
+    Hold,
+}
+
+impl TaskStatus {
+    /// Notes that we've just (re)started this task at time `now`, which gives
+    /// it a full watchdog period to send its first heartbeat.
+    fn started(&mut self, now: u64) {
+        self.watchdog_deadline = self.watchdog_ms.map(|ms| now + u64::from(ms));
+    }
+
+    /// Counts a fault-triggered restart at time `now` against the restart
+    /// policy, and decides when it should happen, if at all.
+    fn plan_restart(&mut self, now: u64) -> RestartPlan {
+        let policy = match &generated::RESTART_POLICY {
+            Some(p) => p,
+            None => return RestartPlan::Now,
+        };
+
+        if self.restarts_in_window == 0
+            || now.saturating_sub(self.window_start) >= policy.window_ms
+        {
+            self.window_start = now;
+            self.restarts_in_window = 0;
+        }
+        self.restarts_in_window += 1;
+
+        if self.restarts_in_window > policy.max_restarts {
+            RestartPlan::Hold
+        } else if self.restarts_in_window == 1 || policy.backoff_ms == 0 {
+            RestartPlan::Now
+        } else {
+            let shift = (self.restarts_in_window - 2).min(16);
+            let delay = (policy.backoff_ms << shift).min(policy.window_ms);
+            RestartPlan::At(now + delay)
+        }
+    }
+}
+
+impl ServerImpl<'_> {
+    /// Sets our timer for the next thing we need to do: our periodic check,
+    /// a watchdog deadline, or a delayed restart, whichever is soonest.
+    fn set_timer(&self) {
+        let next = self
+            .task_states
+            .iter()
+            .flat_map(|s| s.watchdog_deadline.into_iter().chain(s.restart_at))
+            .fold(self.deadline, u64::min);
+        sys_set_timer(Some(next), notifications::TIMER_MASK);
+    }
 }
 
 impl idol_runtime::NotificationHandler for ServerImpl<'_> {
@@ -292,11 +493,36 @@ impl idol_runtime::NotificationHandler for ServerImpl<'_> {
         // Handle any external (debugger) requests.
         external::check(self.task_states);
 
+        let now = sys_get_timer().now;
+
         if bits & notifications::TIMER_MASK != 0 {
-            // If our timer went off, we need to reestablish it
-            if sys_get_timer().now >= self.deadline {
+            // If our periodic deadline passed, push it out
+            if now >= self.deadline {
                 self.deadline += TIMER_INTERVAL;
-                sys_set_timer(Some(self.deadline), notifications::TIMER_MASK);
+            }
+
+            for (i, status) in self.task_states.iter_mut().enumerate() {
+                if status.restart_at.map_or(false, |t| now >= t) {
+                    status.restart_at = None;
+                    // Someone (e.g. a debugger) may have restarted the task
+                    // while we were waiting; don't do it twice.
+                    if let abi::TaskState::Faulted { .. } =
+                        kipc::read_task_status(i)
+                    {
+                        kipc::restart_task(i, true);
+                        status.started(now);
+                        status.history.restart_count =
+                            status.history.restart_count.wrapping_add(1);
+                    }
+                }
+
+                if status.watchdog_deadline.map_or(false, |t| now >= t) {
+                    // Missed its heartbeat. Fault it, and let the usual fault
+                    // handling (below, or on our next trip through the loop)
+                    // take it from there.
+                    status.watchdog_deadline = None;
+                    kipc::fault_task(i);
+                }
             }
         }
 
@@ -306,32 +532,65 @@ impl idol_runtime::NotificationHandler for ServerImpl<'_> {
             // unlikely since a fault causes us to immediately preempt. In any
             // case, let's assume we might have to handle multiple tasks.
             //
-            // TODO: it would be fantastic to have a way of finding this out in
-            // one syscall.
-            for (i, status) in self.task_states.iter_mut().enumerate() {
-                // If we're aware that this task is in a fault state, don't
-                // bother making a syscall to enquire.
-                if status.holding_fault {
+            // The kernel tells us about every faulted task at once, as a
+            // bitmap. Task 0 is us, so start at 1.
+            let mut faulted = [0u8; (NUM_TASKS + 7) / 8];
+            kipc::find_faulted_tasks(&mut faulted);
+
+            for fault_index in 1..NUM_TASKS {
+                if faulted[fault_index / 8] & (1 << (fault_index % 8)) == 0 {
                     continue;
                 }
 
-                if let abi::TaskState::Faulted { .. } =
-                    kipc::read_task_status(i)
+                let status = &mut self.task_states[fault_index];
+
+                // If we're aware that this task is in a fault state, we're
+                // either holding it on purpose or have a restart scheduled;
+                // leave it be.
+                if status.holding_fault || status.restart_at.is_some() {
+                    continue;
+                }
+
+                // It can't very well send heartbeats now.
+                status.watchdog_deadline = None;
+
+                // Make a note of what happened. We have to go back to the
+                // kernel for the details.
+                if let abi::TaskState::Faulted { fault, .. } =
+                    kipc::read_task_status(fault_index)
                 {
-                    #[cfg(feature = "dump")]
-                    {
-                        // We'll ignore the result of dumping; it could fail
-                        // if we're out of space, but we don't have a way of
-                        // dealing with that right now.
-                        //
-                        // TODO: some kind of circular buffer?
-                        _ = dump::dump_task(self.dump_areas, i);
-                    }
+                    status.history.last_fault = Some((fault, now));
+                }
+                status.history.fault_count =
+                    status.history.fault_count.wrapping_add(1);
+
+                #[cfg(feature = "dump")]
+                {
+                    // We'll ignore the result of dumping; it could fail
+                    // if we're out of space, but we don't have a way of
+                    // dealing with that right now.
+                    //
+                    // TODO: some kind of circular buffer?
+                    _ = dump::dump_task(self.dump_areas, fault_index);
+                }
 
-                    if status.disposition == Disposition::Restart {
+                let plan = match status.disposition {
+                    Disposition::Restart => status.plan_restart(now),
+                    Disposition::Hold => RestartPlan::Hold,
+                };
+                match plan {
+                    RestartPlan::Now => {
                         // Stand it back up
-                        kipc::restart_task(i, true);
-                    } else {
+                        kipc::restart_task(fault_index, true);
+                        status.started(now);
+                        status.history.restart_count =
+                            status.history.restart_count.wrapping_add(1);
+                    }
+                    RestartPlan::At(t) => {
+                        // Let it cool off first; the timer will get it.
+                        status.restart_at = Some(t);
+                    }
+                    RestartPlan::Hold => {
                         // Mark this one off so we don't revisit it until
                         // requested.
                         status.holding_fault = true;
@@ -339,6 +598,8 @@ impl idol_runtime::NotificationHandler for ServerImpl<'_> {
                 }
             }
         }
+
+        self.set_timer();
     }
 }
 
@@ -351,6 +612,8 @@ include!(concat!(env!("OUT_DIR"), "/notifications.rs"));
 
 // And the Idol bits
 mod idl {
-    use task_jefe_api::{DumpAgentError, ResetReason};
+    use task_jefe_api::{
+        DumpAgentError, FaultHistory, ResetReason, TaskIndexError,
+    };
     include!(concat!(env!("OUT_DIR"), "/server_stub.rs"));
 }
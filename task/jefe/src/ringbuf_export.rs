@@ -0,0 +1,101 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Ring buffer export for Jefe
+//!
+//! Every ring buffer in the system is listed in its task's (unloaded)
+//! `.ringbuf_registry` section. When we're built with the `ringbuf-export`
+//! feature, the build system gathers those up and patches them into
+//! `EXPORTS`, in the same way that it patches task slots. Since we're the
+//! only task that can read other tasks' memory, that lets us hand out ring
+//! buffer contents to anyone who asks -- in particular, over the network via
+//! `udprpc`, which is how they get read on systems without a debugger
+//! attached.
+//!
+//! Reads aren't synchronized with the owning task, so an entry that's being
+//! written as we read it may come out torn.
+
+use task_jefe_api::{RingbufExport, RingbufExportError, RINGBUF_READ_SIZE};
+use userlib::*;
+use volatile_const::VolatileConst;
+
+/// Number of ring buffers we have room for; the build fails if there are
+/// more than this.
+const CAPACITY: usize = 64;
+
+/// An unused slot in the table, which has a size of 0.
+const UNBOUND: VolatileConst<[u32; 3]> = VolatileConst::new([0; 3]);
+
+/// Each exported ring buffer as `[task index, address, size]`
+#[used]
+static EXPORTS: [VolatileConst<[u32; 3]>; CAPACITY] = [UNBOUND; CAPACITY];
+
+#[repr(C)]
+struct ExportTableEntry {
+    table: *const [u32; 3],
+    capacity: usize,
+}
+
+// This is used as a message to the build system
+#[used]
+#[link_section = ".ringbuf_export_table"]
+static _RINGBUF_EXPORT_TABLE_ENTRY: ExportTableEntry = ExportTableEntry {
+    table: &EXPORTS as *const _ as *const [u32; 3],
+    capacity: CAPACITY,
+};
+
+// SAFETY: as with `CaboosePosTableEntry`, this is only ever constructed right
+// here, pointing at a static, and lives in a section that isn't loaded; it
+// never exists at runtime.
+unsafe impl Sync for ExportTableEntry {}
+
+pub fn get(index: u32) -> Result<RingbufExport, RingbufExportError> {
+    let [task, address, size] = EXPORTS
+        .get(index as usize)
+        .ok_or(RingbufExportError::InvalidIndex)?
+        .get();
+    if size == 0 {
+        return Err(RingbufExportError::InvalidIndex);
+    }
+    Ok(RingbufExport {
+        task: task as u16,
+        address,
+        size,
+    })
+}
+
+pub fn read(
+    index: u32,
+    offset: u32,
+) -> Result<[u8; RINGBUF_READ_SIZE], RingbufExportError> {
+    let export = get(index)?;
+    if offset >= export.size {
+        return Err(RingbufExportError::BadOffset);
+    }
+    let len = (export.size - offset).min(RINGBUF_READ_SIZE as u32);
+    let base = export.address + offset;
+
+    let mut out = [0; RINGBUF_READ_SIZE];
+    if export.task == 0 {
+        // The kernel won't let us read ourselves this way, but we don't need
+        // it to.
+        //
+        // SAFETY: this range was recorded by the build system as one of our
+        // own statics, and nothing else can be using it while we're handling
+        // a message.
+        let ours = unsafe {
+            core::slice::from_raw_parts(base as *const u8, len as usize)
+        };
+        out[..len as usize].copy_from_slice(ours);
+    } else {
+        // The build system gave us this range, and it's in the task's own
+        // memory, so the kernel will be happy with it.
+        kipc::read_task_dump_region(
+            export.task.into(),
+            abi::TaskDumpRegion { base, size: len },
+            &mut out[..len as usize],
+        );
+    }
+    Ok(out)
+}
@@ -43,6 +43,14 @@ fn write_pub_device_descriptions(
             writeln!(file, "            }},")?;
         }
         writeln!(file, "        ],")?;
+        writeln!(
+            file,
+            "        controller: Controller::I2C{},",
+            dev.controller
+        )?;
+        writeln!(file, "        port: PortIndex({}),", dev.port)?;
+        writeln!(file, "        segment: {},", segment(dev.mux, dev.segment))?;
+        writeln!(file, "        address: {:#x},", dev.address)?;
         writeln!(file, "    }},")?;
     }
 
@@ -53,7 +61,39 @@ fn write_pub_device_descriptions(
         "pub static DEVICES: [DeviceDescription; DEVICES_CONST.len()] = DEVICES_CONST;"
     )?;
 
+    let buses = build_i2c::bus_descriptions();
+
+    writeln!(
+        file,
+        "pub static BUSES: [BusDescription; {}] = [",
+        buses.len()
+    )?;
+
+    for bus in buses {
+        writeln!(file, "    BusDescription {{")?;
+        writeln!(
+            file,
+            "        controller: Controller::I2C{},",
+            bus.controller
+        )?;
+        writeln!(file, "        port: PortIndex({}),", bus.port)?;
+        writeln!(file, "        segment: {},", segment(bus.mux, bus.segment))?;
+        writeln!(file, "        mux_addresses: &{:#x?},", bus.mux_addresses)?;
+        writeln!(file, "    }},")?;
+    }
+
+    writeln!(file, "];")?;
+
     file.flush()?;
 
     Ok(())
 }
+
+fn segment(mux: Option<u8>, segment: Option<u8>) -> String {
+    match (mux, segment) {
+        (Some(mux), Some(segment)) => {
+            format!("Some((Mux::M{}, Segment::S{}))", mux, segment)
+        }
+        _ => "None".to_string(),
+    }
+}
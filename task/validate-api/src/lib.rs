@@ -7,10 +7,13 @@
 #![no_std]
 
 use derive_idol_err::IdolError;
-use drv_i2c_api::ResponseCode;
+use drv_i2c_api::{
+    AddressSet, Controller, Mux, PortIndex, ResponseCode, Segment,
+};
 use userlib::*;
 use zerocopy::AsBytes;
 
+pub use drv_i2c_api::I2cCensus;
 pub use task_sensor_api::SensorId;
 
 #[derive(Copy, Clone, Debug, FromPrimitive, Eq, PartialEq, IdolError)]
@@ -70,6 +73,38 @@ pub struct DeviceDescription {
     pub device: &'static str,
     pub description: &'static str,
     pub sensors: &'static [SensorDescription],
+    pub controller: Controller,
+    pub port: PortIndex,
+    pub segment: Option<(Mux, Segment)>,
+    pub address: u8,
+}
+
+///
+/// A bus -- or a segment of one of its muxes -- on which there are devices
+/// in [`DEVICES`].
+///
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct BusDescription {
+    pub controller: Controller,
+    pub port: PortIndex,
+    pub segment: Option<(Mux, Segment)>,
+    /// Addresses of the muxes on the bus, which answer on any segment
+    pub mux_addresses: &'static [u8],
+}
+
+///
+/// Takes a census of a bus (or segment) in [`BUSES`], comparing the addresses
+/// `found` by a scan of it to the devices in [`DEVICES`] that should be there.
+///
+pub fn census(bus: &BusDescription, found: &AddressSet) -> I2cCensus {
+    I2cCensus::new(
+        (bus.controller, bus.port, bus.segment),
+        bus.mux_addresses,
+        DEVICES
+            .iter()
+            .map(|d| ((d.controller, d.port, d.segment), d.address)),
+        found,
+    )
 }
 
 include!(concat!(env!("OUT_DIR"), "/device_descriptions.rs"));
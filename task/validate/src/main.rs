@@ -9,7 +9,7 @@
 
 use idol_runtime::RequestError;
 use ringbuf::*;
-use task_validate_api::{ValidateError, ValidateOk};
+use task_validate_api::{I2cCensus, ValidateError, ValidateOk, BUSES};
 use userlib::*;
 
 include!(concat!(env!("OUT_DIR"), "/i2c_config.rs"));
@@ -20,6 +20,8 @@ struct ServerImpl;
 enum Trace {
     Validate(usize),
     ValidateFailure(drv_i2c_api::ResponseCode),
+    Census(usize),
+    CensusFailure(drv_i2c_api::ResponseCode),
     None,
 }
 
@@ -51,6 +53,31 @@ impl idl::InOrderValidateImpl for ServerImpl {
             },
         }
     }
+
+    fn census_i2c(
+        &mut self,
+        _: &RecvMessage,
+        bus: u32,
+    ) -> Result<I2cCensus, RequestError<ValidateError>> {
+        let index = bus as usize;
+        ringbuf_entry!(Trace::Census(index));
+
+        let bus = BUSES.get(index).ok_or(ValidateError::InvalidDevice)?;
+
+        match drv_i2c_api::scan(
+            I2C.get_task_id(),
+            bus.controller,
+            bus.port,
+            bus.segment,
+        ) {
+            Err(err) => {
+                ringbuf_entry!(Trace::CensusFailure(err));
+                let err: ValidateError = err.into();
+                Err(err.into())
+            }
+            Ok(found) => Ok(task_validate_api::census(bus, &found)),
+        }
+    }
 }
 
 #[export_name = "main"]
@@ -64,7 +91,7 @@ fn main() -> ! {
 }
 
 mod idl {
-    use super::{ValidateError, ValidateOk};
+    use super::{I2cCensus, ValidateError, ValidateOk};
 
     include!(concat!(env!("OUT_DIR"), "/server_stub.rs"));
 }